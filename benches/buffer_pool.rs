@@ -0,0 +1,72 @@
+//! Throughput of decoding synthetic bulk URBs with and without a
+//! [`BufferPool`]. Run with `cargo bench --bench buffer_pool`.
+
+use std::{cell::RefCell, collections::VecDeque, hint::black_box, time::Instant};
+
+use usb_vhci::{
+    ioctl::{Endpoint, IocUrb, UrbHandle, UrbType},
+    BufferPool, Transfer, UrbWithData,
+};
+
+const URBS: u64 = 200_000;
+
+fn urb(index: u64) -> IocUrb {
+    IocUrb {
+        // Sizes of a webcam stream, mostly full bulk packets.
+        buffer_length: [512, 16384, 3072, 512][index as usize % 4],
+        endpoint: Endpoint(0x81),
+        typ: UrbType::Bulk,
+        ..Default::default()
+    }
+}
+
+fn complete(mut urb: UrbWithData) -> UrbWithData {
+    urb.fill_transfer_with(|buf| {
+        buf.fill(0x5a);
+        buf.len()
+    });
+    black_box(urb)
+}
+
+/// URBs a stream keeps queued at the host, so buffers are not freed
+/// right after they were allocated.
+const IN_FLIGHT: usize = 32;
+
+fn run(name: &str, mut decode: impl FnMut(u64) -> UrbWithData, mut done: impl FnMut(UrbWithData)) {
+    let mut queue = VecDeque::with_capacity(IN_FLIGHT);
+    let mut bytes = 0;
+    let start = Instant::now();
+    for index in 0..URBS {
+        if queue.len() == IN_FLIGHT {
+            done(queue.pop_front().unwrap());
+        }
+        let urb = complete(decode(index));
+        bytes += urb.transfer().len() as u64;
+        queue.push_back(urb);
+    }
+    queue.into_iter().for_each(done);
+    let elapsed = start.elapsed();
+    println!(
+        "{name:>8}: {:>10.0} urbs/s, {:>6.0} MiB/s",
+        URBS as f64 / elapsed.as_secs_f64(),
+        bytes as f64 / elapsed.as_secs_f64() / (1 << 20) as f64
+    );
+}
+
+fn main() {
+    run(
+        "fresh",
+        |index| UrbWithData::from_ioctl(urb(index), UrbHandle(index)),
+        drop,
+    );
+
+    let pool = RefCell::new(BufferPool::new());
+    run(
+        "pooled",
+        |index| {
+            let buf = pool.borrow_mut().get();
+            UrbWithData::from_ioctl_with_buffer(urb(index), UrbHandle(index), buf)
+        },
+        |urb| pool.borrow_mut().put(urb.into_buffer()),
+    );
+}
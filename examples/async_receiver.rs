@@ -0,0 +1,85 @@
+//! Fetches work in async code while a device is plugged in, until
+//! the host gave up on it. Needs the `usb-vhci-hcd` and
+//! `usb-vhci-iocifc` kernel modules.
+//!
+//! The futures only need a waker, so `block_on` below can be
+//! swapped for `smol::block_on`, `async_io::block_on` or a tokio
+//! runtime as is.
+
+use std::{
+    future::Future,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+use usb_vhci::{prelude::*, utils::BoundedU8, AsyncController, AsyncWorkReceiver};
+
+/// The host resets a device that doesn't answer a few times before
+/// it gives up.
+const RESETS: usize = 3;
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` on this thread, parking while it is pending.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+async fn run(vhci: &AsyncController, recv: &AsyncWorkReceiver) -> usb_vhci::Result<()> {
+    let mut tracker = PortStateTracker::new();
+    let mut resets = 0;
+    while resets < RESETS {
+        match Work::try_from(recv.fetch_work().await?)? {
+            Work::PortStat(stat) => {
+                for event in tracker.observe(stat) {
+                    println!("{event:?}");
+                    match event {
+                        PortEvent::ResetRequested(port) => {
+                            resets += 1;
+                            vhci.get_ref().port_reset_done(port, true)?
+                        }
+                        PortEvent::ResumeRequested(port) => vhci.get_ref().port_resumed(port)?,
+                        _ => (),
+                    }
+                }
+            }
+            Work::ProcessUrb((urb, handle)) => {
+                // There is no device behind the port, let the host
+                // give up on it.
+                let mut urb = UrbWithData::from_ioctl(urb, handle);
+                urb.set_status(Status::NoResponse);
+                let _ = vhci.giveback(&mut urb).await?;
+            }
+            Work::CancelUrb(_) => (),
+        }
+    }
+    Ok(())
+}
+
+fn main() -> usb_vhci::Result<()> {
+    let (mut vhci, recv) = Controller::open(BoundedU8::new(1).unwrap())?
+        .into_async()
+        .expect("no receiver is out");
+    let port = vhci.get_mut().port_connect_any(DataRate::Full)?;
+    println!("connected {port:?}");
+    let ran = block_on(run(&vhci, &recv));
+    vhci.get_mut().port_disconnect(port)?;
+    println!("disconnected {port:?}");
+    ran
+}
@@ -0,0 +1,143 @@
+//! Attaches two helper devices that answer just enough requests to
+//! enumerate, and prints a table of the attached devices every
+//! second. Needs the `usb-vhci-hcd` and `usb-vhci-iocifc` kernel
+//! modules.
+
+use std::{thread, time::Duration};
+
+use usb_vhci::{
+    prelude::*, usbfs::DescriptorType, utils::BoundedU8, AttachPolicy, AutomatonEvent, DeviceInfo,
+    DeviceRegistry, PortAutomaton, UrbFilter, UrbTarget,
+};
+
+/// Device descriptor with the port number as product ID.
+fn device_descriptor(port: Port) -> Vec<u8> {
+    let mut desc = vec![
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x09, 0x12, 0x00, 0x00, 0x00, 0x01, 0x01,
+        0x02, 0x00, 0x01,
+    ];
+    desc[10] = port.get();
+    desc
+}
+
+/// One configuration with one interface and no endpoints.
+const CONFIG_DESCRIPTOR: [u8; 18] = [
+    0x09, 0x02, 0x12, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, 0x09, 0x04, 0x00, 0x00, 0x00, 0xFF, 0x00,
+    0x00, 0x00,
+];
+
+fn string_descriptor(s: &str) -> Vec<u8> {
+    let mut desc = vec![0, 0x03];
+    desc.extend(s.encode_utf16().flat_map(u16::to_le_bytes));
+    desc[0] = desc.len() as u8;
+    desc
+}
+
+/// Answers the control requests of enumeration and stalls the rest.
+fn answer(port: Port, urb: &mut UrbWithData) {
+    let request = urb
+        .control_packet()
+        .and_then(IocSetupPacket::standard_request);
+    let replied = match request {
+        Some(StandardRequest::GetDescriptor {
+            desc_type, index, ..
+        }) => match (desc_type, index) {
+            (DescriptorType::Device, _) => urb.reply_with(&device_descriptor(port)),
+            (DescriptorType::Configuration, _) => urb.reply_with(&CONFIG_DESCRIPTOR),
+            // English (United States).
+            (DescriptorType::String, 0) => urb.reply_with(&[0x04, 0x03, 0x09, 0x04]),
+            (DescriptorType::String, 1) => urb.reply_with(&string_descriptor("usb_vhci")),
+            (DescriptorType::String, 2) => {
+                urb.reply_with(&string_descriptor(&format!("Helper {}", port.get())))
+            }
+            _ => return urb.stall(),
+        },
+        Some(StandardRequest::SetConfiguration(_)) => return urb.ack(),
+        _ => return urb.stall(),
+    };
+    if replied.is_err() {
+        urb.stall();
+    }
+}
+
+fn print_table(devices: &[DeviceInfo]) {
+    println!("port  addr  vid:pid    state       config  pending  urbs  product");
+    for info in devices {
+        let (id, product) = match &info.descriptor {
+            Some(desc) => (
+                format!("{:04x}:{:04x}", desc.vendor_id, desc.product_id),
+                desc.product.as_deref().unwrap_or("-"),
+            ),
+            None => ("-".to_owned(), "-"),
+        };
+        println!(
+            "{:<4}  {:<4}  {id:<9}  {:<10}  {:<6}  {:<7}  {:<4}  {product}",
+            info.port.get(),
+            info.address,
+            format!("{:?}", info.state),
+            info.configuration,
+            info.pending_urbs,
+            info.metrics.urbs,
+        );
+    }
+    println!();
+}
+
+fn main() -> usb_vhci::Result<()> {
+    let mut vhci = Controller::open(BoundedU8::new(2).unwrap())?;
+    let remote = vhci.remote();
+    let registry = DeviceRegistry::new();
+
+    let printer = registry.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        print_table(&printer.list());
+    });
+
+    for _ in 0..2 {
+        let port = vhci.port_connect_any(DataRate::Full)?;
+        registry.attach(port, DataRate::Full);
+    }
+
+    // URBs carry the device address, not the port. The controller
+    // maps addresses back to ports, and the filter completes URBs
+    // for addresses no device has.
+    let mut automaton = PortAutomaton::new(AttachPolicy::Manual);
+    let filter = UrbFilter::default();
+    let recv = vhci.work_receiver().expect("no receiver is out");
+    for work in recv.iter(TimeoutMillis::MAX) {
+        match work? {
+            Work::PortStat(stat) => {
+                for event in automaton.handle(&mut vhci, stat)? {
+                    if let AutomatonEvent::Disconnected(port) = event {
+                        registry.detach(port);
+                    }
+                }
+            }
+            Work::ProcessUrb((urb, handle)) => {
+                let mut urb = UrbWithData::from_ioctl(urb, handle);
+                match filter.intercept(vhci.device_addresses(), &mut urb) {
+                    UrbTarget::Ours(port) | UrbTarget::DefaultAddress(port) => {
+                        registry.submitted(port);
+                        if urb.needs_fetch_data() {
+                            let _ = remote.fetch_data(&mut urb)?;
+                        }
+                        if !vhci.answer_set_address(&mut urb) {
+                            answer(port, &mut urb);
+                        }
+                        registry.completed(port, &urb);
+                    }
+                    UrbTarget::Foreign => (),
+                }
+                // The new address applies once SET_ADDRESS was given
+                // back, after its status stage.
+                if remote.giveback(&mut urb)? == GivebackOutcome::Completed {
+                    vhci.note_completed_urb(&urb);
+                }
+            }
+            Work::CancelUrb(_) => (),
+        }
+    }
+    // The iterator only ends after an error, which was returned.
+    unreachable!()
+}
@@ -0,0 +1,70 @@
+//! Fetches work on a scoped thread while the main thread plugs a
+//! device in and out. Needs the `usb-vhci-hcd` and `usb-vhci-iocifc`
+//! kernel modules.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use usb_vhci::{prelude::*, utils::BoundedU8};
+
+fn main() -> usb_vhci::Result<()> {
+    let mut vhci = Controller::open(BoundedU8::new(1).unwrap())?;
+    let done = AtomicBool::new(false);
+    let (recv, mut ports) = vhci
+        .work_receiver_scoped()
+        .expect("no owned receiver is out");
+    let remote = ports.remote();
+
+    thread::scope(|s| {
+        let fetcher = s.spawn(|| {
+            let mut tracker = PortStateTracker::new();
+            while !done.load(Ordering::Acquire) {
+                let work = match recv.fetch_checked_work() {
+                    Ok(work) => work,
+                    Err(Error::TimedOut) => continue,
+                    Err(err) => return Err(err),
+                };
+                match work {
+                    Work::PortStat(stat) => {
+                        for event in tracker.observe(stat) {
+                            println!("{event:?}");
+                            match event {
+                                PortEvent::ResetRequested(port) => {
+                                    remote.port_reset_done(port, true)?
+                                }
+                                PortEvent::ResumeRequested(port) => remote.port_resumed(port)?,
+                                _ => (),
+                            }
+                        }
+                    }
+                    Work::ProcessUrb((urb, handle)) => {
+                        // There is no device behind the port, let the
+                        // host give up on it.
+                        let mut urb = UrbWithData::from_ioctl(urb, handle);
+                        urb.set_status(Status::NoResponse);
+                        let _ = remote.giveback(&mut urb)?;
+                    }
+                    Work::CancelUrb(_) => (),
+                }
+            }
+            Ok(())
+        });
+
+        let mut plug = || {
+            thread::sleep(Duration::from_millis(500));
+            let port = ports.port_connect_any(DataRate::Full)?;
+            println!("connected {port:?}");
+            thread::sleep(Duration::from_secs(2));
+            ports.port_disconnect(port)?;
+            println!("disconnected {port:?}");
+            thread::sleep(Duration::from_millis(500));
+            usb_vhci::Result::Ok(())
+        };
+        let plugged = plug();
+        done.store(true, Ordering::Release);
+        fetcher.join().unwrap().and(plugged)
+    })
+}
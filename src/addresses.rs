@@ -0,0 +1,286 @@
+use nohash_hasher::IntMap;
+
+use crate::{
+    ioctl::{Address, UrbHandle},
+    usbfs::Request,
+    Port, Status, Urb, UrbWithData,
+};
+
+/// The address of the device on each port, to route URBs, which only
+/// carry the address, to their port.
+///
+/// A reset puts the device on a port in the default state, where it
+/// answers to address 0 until SET_ADDRESS moves it to its own
+/// address. The hub only enumerates one port at a time, so only the
+/// port reset last is in the default state.
+///
+/// The map can answer SET_ADDRESS itself, see
+/// [`DeviceAddressMap::answer_set_address`].
+#[derive(Debug, Clone, Default)]
+pub struct DeviceAddressMap {
+    addresses: IntMap<Port, Address>,
+
+    /// The port whose device answers to address 0.
+    default: Option<Port>,
+
+    /// A SET_ADDRESS that was answered but whose status stage has not
+    /// completed yet.
+    set_address: Option<(UrbHandle, Port, Address)>,
+}
+
+impl DeviceAddressMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Puts the device on `port` in the default state after a reset.
+    /// Its address is forgotten, and a port that was reset earlier
+    /// and never got an address is left without one.
+    pub fn reset(&mut self, port: Port) {
+        self.addresses.remove(&port);
+        self.default = Some(port);
+        self.set_address = None;
+    }
+
+    /// Moves the device on `port` to `addr`, like SET_ADDRESS does.
+    /// Address 0 puts it back in the default state. A port that held
+    /// `addr` before loses it, as its device must be gone.
+    pub fn assign(&mut self, port: Port, addr: Address) {
+        if addr.is_for_unassigned() {
+            return self.reset(port);
+        }
+        self.addresses.retain(|_, assigned| *assigned != addr);
+        self.addresses.insert(port, addr);
+        if self.default == Some(port) {
+            self.default = None;
+        }
+    }
+
+    /// Forgets the address of `port`, e.g. because its device was
+    /// disconnected.
+    pub fn invalidate(&mut self, port: Port) {
+        self.addresses.remove(&port);
+        if self.default == Some(port) {
+            self.default = None;
+            self.set_address = None;
+        }
+    }
+
+    /// Answers a SET_ADDRESS request to the device in the default
+    /// state and returns whether `urb` was one. Other URBs are left
+    /// alone.
+    ///
+    /// A valid address completes the request successfully, and an
+    /// address outside of 1 to 127 stalls it. The device keeps
+    /// answering to address 0 until the status stage is over, so the
+    /// new address only applies once `urb` was given back and passed
+    /// to [`DeviceAddressMap::status_stage_done`].
+    pub fn answer_set_address(&mut self, urb: &mut UrbWithData) -> bool {
+        let Some(setup) = urb.control_packet() else {
+            return false;
+        };
+        let Some(port) = self.default else {
+            return false;
+        };
+        if setup.req() != Request::STANDARD_DEVICE_SET_ADDRESS || !urb.address().is_for_unassigned()
+        {
+            return false;
+        }
+        match Address::from_set_address_value(setup.value()) {
+            Some(addr) if !addr.is_for_unassigned() => {
+                self.set_address = Some((urb.handle(), port, addr));
+                urb.ack();
+            }
+            _ => urb.stall(),
+        }
+        true
+    }
+
+    /// Applies the address of the SET_ADDRESS request `handle`
+    /// answered by [`DeviceAddressMap::answer_set_address`], once it
+    /// was given back. Returns the port and its new address, or
+    /// `None` for any other URB. A request the host canceled must
+    /// not be passed here, its device stays in the default state.
+    pub fn status_stage_done(&mut self, handle: UrbHandle) -> Option<(Port, Address)> {
+        match self.set_address {
+            Some((pending, port, addr)) if pending == handle => {
+                self.set_address = None;
+                self.assign(port, addr);
+                Some((port, addr))
+            }
+            _ => None,
+        }
+    }
+
+    /// Picks up the address the device on `port` was given by a
+    /// successful SET_ADDRESS. Other URBs are ignored.
+    pub fn observe(&mut self, port: Port, urb: &UrbWithData) {
+        let Some(setup) = urb.control_packet() else {
+            return;
+        };
+        if setup.req() != Request::STANDARD_DEVICE_SET_ADDRESS || Status::Success != urb.status() {
+            return;
+        }
+        match Address::from_set_address_value(setup.value()) {
+            Some(addr) => self.assign(port, addr),
+            None => self.invalidate(port),
+        }
+    }
+
+    /// The address of the device on `port`, or `None` while it has
+    /// none, including in the default state.
+    pub fn address(&self, port: Port) -> Option<Address> {
+        self.addresses.get(&port).copied()
+    }
+
+    /// The port in the default state, if any.
+    pub const fn default_port(&self) -> Option<Port> {
+        self.default
+    }
+
+    /// The port of the device with `addr`. Address 0 belongs to the
+    /// port in the default state.
+    pub fn resolve(&self, addr: Address) -> Option<Port> {
+        if addr.is_for_unassigned() {
+            return self.default;
+        }
+        self.addresses
+            .iter()
+            .find(|(_, &assigned)| assigned == addr)
+            .map(|(&port, _)| port)
+    }
+
+    /// Whether an URB for `urb_address` is for one of the devices,
+    /// see [`DeviceAddressMap::resolve`].
+    pub fn is_for_us(&self, urb_address: Address) -> bool {
+        self.resolve(urb_address).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: u8) -> Address {
+        Address::new(addr).unwrap()
+    }
+
+    #[test]
+    fn reset_during_enumeration() {
+        let mut map = DeviceAddressMap::new();
+        let (one, two) = (Port::new(1).unwrap(), Port::new(2).unwrap());
+        assert!(!map.is_for_us(addr(0)));
+
+        map.reset(one);
+        assert_eq!(map.resolve(addr(0)), Some(one));
+        // The hub gave up on port 1 and resets port 2.
+        map.reset(two);
+        assert_eq!(map.resolve(addr(0)), Some(two));
+        map.assign(two, addr(3));
+        assert_eq!(map.default_port(), None);
+        assert!(!map.is_for_us(addr(0)));
+        assert_eq!(map.resolve(addr(3)), Some(two));
+
+        // Back to port 1, reset again in the middle of enumeration.
+        map.reset(one);
+        map.reset(one);
+        map.assign(one, addr(4));
+        assert_eq!(
+            (map.address(one), map.address(two)),
+            (Some(addr(4)), Some(addr(3)))
+        );
+
+        // A reset of an addressed device drops its address.
+        map.reset(two);
+        assert_eq!(map.address(two), None);
+        assert_eq!(map.resolve(addr(3)), None);
+        assert_eq!(map.resolve(addr(0)), Some(two));
+
+        // SET_ADDRESS 0 moves a device back to the default state.
+        map.assign(one, addr(0));
+        assert_eq!(map.address(one), None);
+        assert_eq!(map.default_port(), Some(one));
+    }
+
+    fn set_address(value: u16, handle: u64) -> UrbWithData {
+        UrbWithData::builder()
+            .control(
+                Request::STANDARD_DEVICE_SET_ADDRESS
+                    .setup(value, 0, 0)
+                    .unwrap(),
+            )
+            .handle(UrbHandle(handle))
+            .build()
+    }
+
+    #[test]
+    fn answers_set_address_back_to_back() {
+        let mut map = DeviceAddressMap::new();
+        let (one, two) = (Port::new(1).unwrap(), Port::new(2).unwrap());
+
+        for (port, value, handle) in [(one, 1, 10), (two, 2, 11)] {
+            map.reset(port);
+            let mut urb = set_address(value, handle);
+            assert!(map.answer_set_address(&mut urb));
+            assert_eq!(urb.status(), Status::Success);
+            // Still at address 0 until the status stage is over.
+            assert_eq!(map.resolve(addr(0)), Some(port));
+            assert_eq!(map.address(port), None);
+            assert_eq!(map.status_stage_done(UrbHandle(99)), None);
+
+            let addr = addr(value as u8);
+            assert_eq!(map.status_stage_done(urb.handle()), Some((port, addr)));
+            assert_eq!(map.resolve(addr), Some(port));
+            assert_eq!(map.default_port(), None);
+        }
+        assert_eq!(
+            (map.address(one), map.address(two)),
+            (Some(addr(1)), Some(addr(2)))
+        );
+
+        // Only requests to the default address of a device in the
+        // default state are answered.
+        let mut addressed = set_address(3, 12);
+        assert!(!map.answer_set_address(&mut addressed));
+        map.reset(one);
+        let mut to_device = UrbWithData::builder()
+            .control(Request::STANDARD_DEVICE_SET_ADDRESS.setup(3, 0, 0).unwrap())
+            .address(addr(2))
+            .build();
+        assert!(!map.answer_set_address(&mut to_device));
+
+        for invalid in [0, 0x80, 0x105] {
+            let mut urb = set_address(invalid, 13);
+            assert!(map.answer_set_address(&mut urb));
+            assert_eq!(urb.status(), Status::Stall);
+            assert_eq!(map.status_stage_done(urb.handle()), None);
+        }
+
+        // A reset in the middle drops the answered request.
+        let mut urb = set_address(4, 14);
+        assert!(map.answer_set_address(&mut urb));
+        map.reset(one);
+        assert_eq!(map.status_stage_done(urb.handle()), None);
+        assert_eq!(map.resolve(addr(4)), None);
+    }
+
+    #[test]
+    fn addresses_are_reused_after_disconnect() {
+        let mut map = DeviceAddressMap::new();
+        let (one, two) = (Port::new(1).unwrap(), Port::new(2).unwrap());
+        map.reset(one);
+        map.assign(one, addr(5));
+
+        map.invalidate(one);
+        assert!(!map.is_for_us(addr(5)));
+        map.reset(two);
+        map.assign(two, addr(5));
+        assert_eq!(map.resolve(addr(5)), Some(two));
+
+        // Port 2 was disconnected without telling the map.
+        map.reset(one);
+        map.assign(one, addr(5));
+        assert_eq!(map.resolve(addr(5)), Some(one));
+        assert_eq!(map.address(two), None);
+    }
+}
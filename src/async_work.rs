@@ -0,0 +1,317 @@
+use std::{
+    future::Future,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+};
+
+use nix::{
+    errno::Errno,
+    sys::eventfd::{EfdFlags, EventFd},
+};
+
+use crate::{
+    ioctl::IocWork, Controller, FetchOutcome, GivebackOutcome, IsoPacketDataMut,
+    IsoPacketGivebackMut, Result, TransferMut, Urb, WorkReceiver,
+};
+
+/// Fetches work in async code, see [`Controller::into_async`]:
+///
+/// ```no_run
+/// # use usb_vhci::Controller;
+/// # async fn run(vhci: Controller) -> usb_vhci::Result<()> {
+/// let (vhci, recv) = vhci.into_async().unwrap();
+/// loop {
+///     let work = recv.fetch_work().await?;
+///     // ...
+/// #   let _ = (&vhci, work);
+/// }
+/// # }
+/// ```
+///
+/// The futures only need a waker, so they run on any executor, e.g.
+/// tokio, async-io or smol. While no work is there, a waiter thread
+/// blocks in the fetch ioctl and wakes the task once work arrived,
+/// see [`WorkReceiver::poll_fetch_work`]. Nothing blocks the executor
+/// and nothing spins.
+#[derive(Debug)]
+pub struct AsyncWorkReceiver {
+    recv: WorkReceiver,
+}
+
+impl AsyncWorkReceiver {
+    pub fn new(recv: WorkReceiver) -> Self {
+        Self { recv }
+    }
+
+    /// Waits for the next work item. Dropping the future loses no
+    /// work, see [`WorkReceiver::poll_fetch_work`].
+    pub fn fetch_work(&self) -> FetchWork<'_> {
+        FetchWork { recv: &self.recv }
+    }
+
+    /// See [`WorkReceiver::try_fetch_work`].
+    pub fn try_fetch_work(&self) -> Result<Option<IocWork>> {
+        self.recv.try_fetch_work()
+    }
+
+    pub const fn get_ref(&self) -> &WorkReceiver {
+        &self.recv
+    }
+
+    pub fn into_inner(self) -> WorkReceiver {
+        self.recv
+    }
+}
+
+/// Future of [`AsyncWorkReceiver::fetch_work`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct FetchWork<'a> {
+    recv: &'a WorkReceiver,
+}
+
+impl Future for FetchWork<'_> {
+    type Output = Result<IocWork>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut work = IocWork::default();
+        self.recv
+            .poll_fetch_work(cx, &mut work)
+            .map(|result| result.map(|()| work))
+    }
+}
+
+/// A [`Controller`] whose work goes to an [`AsyncWorkReceiver`], see
+/// [`Controller::into_async`].
+///
+/// Giving back and fetching the data of an URB never waits for the
+/// host, so they run right away and are only `async` to fit into
+/// async code. They don't need `spawn_blocking` or the like.
+#[derive(Debug)]
+pub struct AsyncController {
+    ctrl: Controller,
+}
+
+impl AsyncController {
+    pub const fn new(ctrl: Controller) -> Self {
+        Self { ctrl }
+    }
+
+    /// See [`Controller::fetch_data`].
+    pub async fn fetch_data(
+        &self,
+        urb: impl Urb + TransferMut + IsoPacketDataMut,
+    ) -> Result<FetchOutcome> {
+        self.ctrl.fetch_data(urb)
+    }
+
+    /// See [`Controller::giveback`].
+    pub async fn giveback(
+        &self,
+        urb: impl Urb + TransferMut + IsoPacketGivebackMut,
+    ) -> Result<GivebackOutcome> {
+        self.ctrl.giveback(urb)
+    }
+
+    /// The controller, e.g. to connect ports.
+    pub const fn get_ref(&self) -> &Controller {
+        &self.ctrl
+    }
+
+    pub fn get_mut(&mut self) -> &mut Controller {
+        &mut self.ctrl
+    }
+
+    pub fn into_inner(self) -> Controller {
+        self.ctrl
+    }
+}
+
+impl Controller {
+    /// Splits the controller for async code. If its receiver is out,
+    /// see [`Controller::work_receiver`], the controller is handed
+    /// back in the `Err`.
+    pub fn into_async(
+        self,
+    ) -> std::result::Result<(AsyncController, AsyncWorkReceiver), Box<Self>> {
+        match self.work_receiver() {
+            Some(recv) => Ok((AsyncController::new(self), AsyncWorkReceiver::new(recv))),
+            None => Err(Box::new(self)),
+        }
+    }
+}
+
+/// A file descriptor that turns readable once a [`WorkReceiver`] has
+/// work, for event loops that wait for readiness, e.g. with mio's
+/// `SourceFd` or tokio's `AsyncFd`.
+///
+/// `usb-vhci-iocifc` has no `poll` support, so the vhci fd itself
+/// never turns readable. Instead, [`WorkReadiness::arm`] leaves the
+/// waiter thread of [`WorkReceiver::poll_ready`] fetching, which
+/// writes to an eventfd once work arrived. Register the readiness for
+/// reading once, then:
+///
+/// ```no_run
+/// # use usb_vhci::{Controller, WorkReadiness};
+/// # fn run(vhci: Controller) -> usb_vhci::Result<()> {
+/// let recv = vhci.work_receiver().unwrap();
+/// let readiness = WorkReadiness::new()?;
+/// // Register `readiness.as_raw_fd()` with the event loop here.
+/// loop {
+///     while let Some(work) = recv.try_fetch_work()? {
+///         // ...
+/// #       let _ = work;
+///     }
+///     if !readiness.arm(&recv) {
+///         // Wait until the event loop reports it readable.
+///         readiness.clear()?;
+///     }
+/// }
+/// # }
+/// ```
+///
+/// The fd stays readable until it is cleared, so it suits both edge
+/// and level triggered event loops.
+#[derive(Debug)]
+pub struct WorkReadiness {
+    notify: Arc<Notify>,
+    waker: Waker,
+}
+
+#[derive(Debug)]
+struct Notify(EventFd);
+
+impl Wake for Notify {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        // Only fails once the counter is about to overflow, and it is
+        // readable then anyway.
+        let _ = self.0.write(1);
+    }
+}
+
+impl WorkReadiness {
+    pub fn new() -> Result<Self> {
+        let fd = EventFd::from_flags(EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+        let notify = Arc::new(Notify(fd));
+        Ok(Self {
+            waker: Waker::from(Arc::clone(&notify)),
+            notify,
+        })
+    }
+
+    /// Makes the fd turn readable once `recv` has work. Returns
+    /// `true` if it has work already, or fetching failed, in which
+    /// case the fd isn't touched and the next fetch returns right
+    /// away.
+    pub fn arm(&self, recv: &WorkReceiver) -> bool {
+        recv.poll_ready(&mut Context::from_waker(&self.waker))
+            .is_ready()
+    }
+
+    /// Makes the fd unreadable again, after the event loop reported
+    /// it.
+    pub fn clear(&self) -> Result<()> {
+        match self.notify.0.read() {
+            Ok(_) | Err(Errno::EAGAIN) => Ok(()),
+            Err(errno) => Err(errno.into()),
+        }
+    }
+}
+
+impl AsFd for WorkReadiness {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.notify.0.as_fd()
+    }
+}
+
+impl AsRawFd for WorkReadiness {
+    fn as_raw_fd(&self) -> RawFd {
+        self.notify.0.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::{self, Thread};
+
+    use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+
+    use super::*;
+    use crate::{
+        controller::tests::{fake_controller, finish_waiting, pretend_waiting},
+        ioctl::Endpoint,
+        Error, UrbWithData,
+    };
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Runs `future` on this thread, parking while it is pending.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn splits_and_joins() {
+        let vhci = fake_controller(1);
+        let recv = vhci.work_receiver().unwrap();
+        let vhci = vhci.into_async().unwrap_err();
+        vhci.return_work_receiver(recv).unwrap();
+
+        let (vhci, recv) = vhci.into_async().unwrap();
+        // /dev/null knows no ioctl.
+        let err = block_on(recv.fetch_work()).unwrap_err();
+        assert!(matches!(err, Error::Ioctl(Errno::ENOTTY)), "{err:?}");
+        let mut urb = UrbWithData::builder().bulk(Endpoint(0x81), &[0; 8]).build();
+        assert!(block_on(vhci.giveback(&mut urb)).is_err());
+
+        let vhci = vhci.into_inner();
+        vhci.return_work_receiver(recv.into_inner()).unwrap();
+        assert!(vhci.work_receiver().is_some());
+    }
+
+    fn readable(readiness: &WorkReadiness, millis: u16) -> bool {
+        let mut fds = [PollFd::new(readiness.as_fd(), PollFlags::POLLIN)];
+        poll(&mut fds, PollTimeout::from(millis)).unwrap() == 1
+    }
+
+    #[test]
+    fn readiness_turns_readable() {
+        let vhci = fake_controller(1);
+        let recv = vhci.work_receiver().unwrap();
+        let readiness = WorkReadiness::new().unwrap();
+
+        pretend_waiting(&recv);
+        assert!(!readiness.arm(&recv));
+        assert!(!readable(&readiness, 0));
+        finish_waiting(&recv);
+        assert!(readable(&readiness, 5000));
+        readiness.clear().unwrap();
+        assert!(!readable(&readiness, 0));
+        readiness.clear().unwrap();
+
+        // The failed fetch is ready, and it is what comes next.
+        assert!(readiness.arm(&recv));
+        let err = recv.try_fetch_work().unwrap_err();
+        assert!(matches!(err, Error::Ioctl(Errno::ENOTTY)), "{err:?}");
+    }
+}
@@ -0,0 +1,417 @@
+use crate::{
+    ioctl::{Address, IocPortStat},
+    Controller, DataRate, PendingUrbs, Port, PortEvent, Result, Status, UrbWithData,
+};
+
+type DeviceGone = Box<dyn FnMut(Port) + Send>;
+
+/// When [`PortAutomaton`] connects a device to a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachPolicy {
+    /// Connects a device with the data rate as soon as the host
+    /// powers a free port.
+    OnPowerOn(DataRate),
+
+    /// Leaves connecting to the application, e.g. to attach devices
+    /// later with [`Controller::port_connect`].
+    Manual,
+}
+
+/// What [`PortAutomaton::handle`] did, or what happened on its own,
+/// for the application to react to.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutomatonEvent {
+    PoweredOn(Port),
+    PoweredOff(Port),
+
+    /// A device was connected to the port.
+    Connected(Port),
+
+    /// The device was disconnected from the port.
+    Disconnected(Port),
+
+    /// The port was reset, and its device is back in the default
+    /// state with address 0.
+    ResetComplete(Port),
+
+    Suspended(Port),
+    Resumed(Port),
+}
+
+impl AutomatonEvent {
+    pub const fn port(&self) -> Port {
+        match self {
+            AutomatonEvent::PoweredOn(port)
+            | AutomatonEvent::PoweredOff(port)
+            | AutomatonEvent::Connected(port)
+            | AutomatonEvent::Disconnected(port)
+            | AutomatonEvent::ResetComplete(port)
+            | AutomatonEvent::Suspended(port)
+            | AutomatonEvent::Resumed(port) => *port,
+        }
+    }
+}
+
+/// Answers the host's port requests, the part every program repeats:
+/// connecting on power-on, completing resets and resumes and
+/// acknowledging suspends.
+///
+/// The addresses of the devices are kept by the controller, which
+/// updates them on every reset and connection change it records, so
+/// URBs can be routed to their port with
+/// [`Controller::device_addresses`]. Pass every URB that was given
+/// back to [`Controller::note_completed_urb`] for it to see
+/// SET_ADDRESS.
+///
+/// When a port powers off or is disconnected, the device on it is
+/// gone. Its address is forgotten, the URBs for it in
+/// [`PortAutomaton::pending_mut`] are given back with
+/// [`Status::DeviceDisconnected`] so the host doesn't wait for them,
+/// and the [`PortAutomaton::on_device_gone`] callback runs so the
+/// application can reset the device's state.
+pub struct PortAutomaton {
+    policy: AttachPolicy,
+    pending: PendingUrbs<UrbWithData>,
+    device_gone: Option<DeviceGone>,
+}
+
+impl std::fmt::Debug for PortAutomaton {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PortAutomaton")
+            .field("policy", &self.policy)
+            .field("pending", &self.pending)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PortAutomaton {
+    pub fn new(policy: AttachPolicy) -> Self {
+        Self {
+            policy,
+            pending: PendingUrbs::new(),
+            device_gone: None,
+        }
+    }
+
+    /// Runs `callback` with the port whenever it powers off or is
+    /// disconnected, after the pending URBs of its device were given
+    /// back. Replaces the previous callback.
+    pub fn on_device_gone(&mut self, callback: impl FnMut(Port) + Send + 'static) {
+        self.device_gone = Some(Box::new(callback));
+    }
+
+    pub const fn policy(&self) -> AttachPolicy {
+        self.policy
+    }
+
+    /// Answers what the host requested and then records `stat` with
+    /// [`Controller::note_port_stat`]. A stat with an invalid port
+    /// index fails with [`Error::InvalidWork`].
+    ///
+    /// If answering fails, e.g. [`Controller::port_reset_done`], the
+    /// stat is not recorded, and handling it again answers the host
+    /// once more instead of leaving the port in reset. The callback
+    /// of [`PortAutomaton::on_device_gone`] can run twice for the
+    /// port then. A failed giveback of the URBs of a gone device is
+    /// reported after the stat was recorded, as those URBs are not
+    /// pending anymore.
+    ///
+    /// [`Error::InvalidWork`]: crate::Error::InvalidWork
+    pub fn handle(
+        &mut self,
+        ctrl: &mut Controller,
+        stat: IocPortStat,
+    ) -> Result<Vec<AutomatonEvent>> {
+        let events = ctrl.port_stat_events(stat)?;
+        let mut gone = Ok(());
+        // A stat is about one port, and a connected port that powers
+        // off is disconnected as well. Its device is gone only once.
+        let mut forgotten = false;
+        let mut handled = Vec::with_capacity(events.len());
+        for event in events {
+            handled.push(match event {
+                PortEvent::PoweredOn(port) => {
+                    if let AttachPolicy::OnPowerOn(rate) = self.policy {
+                        let free = ctrl
+                            .ports()
+                            .any(|info| info.port == port && !info.connected);
+                        if free {
+                            ctrl.port_connect(port, rate)?;
+                        }
+                    }
+                    AutomatonEvent::PoweredOn(port)
+                }
+                PortEvent::PoweredOff(port) => {
+                    gone = gone.and(self.device_gone(ctrl, port));
+                    forgotten = true;
+                    AutomatonEvent::PoweredOff(port)
+                }
+                PortEvent::ConnectionChanged { port, connected } => {
+                    if connected {
+                        AutomatonEvent::Connected(port)
+                    } else {
+                        if !forgotten {
+                            gone = gone.and(self.device_gone(ctrl, port));
+                            forgotten = true;
+                        }
+                        AutomatonEvent::Disconnected(port)
+                    }
+                }
+                PortEvent::ResetRequested(port) => {
+                    ctrl.port_reset_done(port, true)?;
+                    AutomatonEvent::ResetComplete(port)
+                }
+                PortEvent::SuspendRequested(port) => {
+                    ctrl.port_suspended(port)?;
+                    AutomatonEvent::Suspended(port)
+                }
+                PortEvent::ResumeRequested(port) => {
+                    ctrl.port_resumed(port)?;
+                    AutomatonEvent::Resumed(port)
+                }
+            });
+        }
+        ctrl.note_port_stat(stat)?;
+        gone?;
+        Ok(handled)
+    }
+
+    /// URBs waiting for their device, e.g. interrupt URBs it has no
+    /// data for yet. Insert them here to have them failed if the
+    /// device goes away.
+    pub const fn pending(&self) -> &PendingUrbs<UrbWithData> {
+        &self.pending
+    }
+
+    pub fn pending_mut(&mut self) -> &mut PendingUrbs<UrbWithData> {
+        &mut self.pending
+    }
+
+    /// Fails the pending URBs of the device on `port`. Every URB is
+    /// given back before the first failed giveback is reported. The
+    /// controller forgets the device's address once the stat is
+    /// recorded.
+    fn device_gone(&mut self, ctrl: &Controller, port: Port) -> Result<()> {
+        let addresses = ctrl.device_addresses();
+        let address = match addresses.default_port() {
+            Some(default) if default == port => Address::new(0),
+            _ => addresses.address(port),
+        };
+
+        let mut result = Ok(());
+        let gone = self
+            .pending
+            .drain_where(|urb| Some(urb.address()) == address);
+        for (_, mut urb) in gone {
+            urb.set_transferred(0);
+            urb.set_status(Status::DeviceDisconnected);
+            // An URB the host canceled meanwhile is just not delivered.
+            if let Err(err) = ctrl.giveback(&mut urb) {
+                result = result.and(Err(err));
+            }
+        }
+        if let Some(callback) = &mut self.device_gone {
+            callback(port);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{
+        controller::tests::fake_controller,
+        ioctl::{Endpoint, UrbHandle},
+        usbfs::Request,
+        ControlTransaction, PortStatus, Status, Urb,
+    };
+
+    fn stat(port: Port, status: PortStatus) -> IocPortStat {
+        IocPortStat {
+            status: status.bits(),
+            index: port.get(),
+            ..Default::default()
+        }
+    }
+
+    fn set_address(addr: u16) -> UrbWithData {
+        let mut urb = UrbWithData::builder()
+            .control(
+                Request::STANDARD_DEVICE_SET_ADDRESS
+                    .setup(addr, 0, 0)
+                    .unwrap(),
+            )
+            .build();
+        ControlTransaction::new(&mut urb)
+            .unwrap()
+            .complete(Status::Success);
+        urb
+    }
+
+    /// Resets `port` and gives its device `addr`, as if it was
+    /// enumerated. Resets need the kernel, so the stats are recorded
+    /// without the automaton.
+    fn enumerate(vhci: &mut Controller, port: Port, addr: u16) {
+        let connected = PortStatus::POWER | PortStatus::CONNECTION;
+        vhci.note_port_stat(stat(port, connected | PortStatus::RESET))
+            .unwrap();
+        vhci.note_port_stat(stat(port, connected | PortStatus::ENABLE))
+            .unwrap();
+        vhci.note_completed_urb(&set_address(addr));
+    }
+
+    #[test]
+    fn connection_changes_forget_addresses() {
+        let mut automaton = PortAutomaton::new(AttachPolicy::Manual);
+        let port = Port::new(1).unwrap();
+        let five = Address::new(5).unwrap();
+
+        // No ioctl is needed for connection changes, so the fake
+        // controller on /dev/null is enough.
+        let mut vhci = fake_controller(1);
+        let events = automaton
+            .handle(
+                &mut vhci,
+                stat(port, PortStatus::POWER | PortStatus::CONNECTION),
+            )
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                AutomatonEvent::PoweredOn(port),
+                AutomatonEvent::Connected(port)
+            ]
+        );
+        assert_eq!(vhci.device_addresses().address(port), None);
+
+        enumerate(&mut vhci, port, 5);
+        assert_eq!(vhci.device_addresses().resolve(five), Some(port));
+        let events = automaton
+            .handle(&mut vhci, stat(port, PortStatus::POWER))
+            .unwrap();
+        assert_eq!(events, [AutomatonEvent::Disconnected(port)]);
+        assert_eq!(vhci.device_addresses().address(port), None);
+        assert_eq!(vhci.device_addresses().resolve(five), None);
+
+        // Resets need the kernel.
+        let err = automaton
+            .handle(
+                &mut vhci,
+                stat(
+                    port,
+                    PortStatus::POWER | PortStatus::CONNECTION | PortStatus::RESET,
+                ),
+            )
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+    }
+
+    #[test]
+    fn disconnect_fails_pending_urbs() {
+        let mut automaton = PortAutomaton::new(AttachPolicy::Manual);
+        let gone = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&gone);
+        automaton.on_device_gone(move |port| seen.lock().unwrap().push(port));
+
+        let mut vhci = fake_controller(2);
+        let (one, two) = (Port::new(1).unwrap(), Port::new(2).unwrap());
+        automaton
+            .handle(
+                &mut vhci,
+                stat(one, PortStatus::POWER | PortStatus::CONNECTION),
+            )
+            .unwrap();
+
+        enumerate(&mut vhci, one, 5);
+        enumerate(&mut vhci, two, 6);
+        let bulk_in = |addr, handle| {
+            UrbWithData::builder()
+                .bulk(Endpoint(0x81), &[0; 64])
+                .address(Address::new(addr).unwrap())
+                .handle(UrbHandle(handle))
+                .build()
+        };
+        // Half way through a transfer when the device goes away.
+        let mut partial = bulk_in(5, 1);
+        partial.write_transfer(&[0xaa; 32]);
+        automaton.pending_mut().insert(partial.handle(), partial);
+        automaton.pending_mut().insert(UrbHandle(2), bulk_in(5, 2));
+        automaton.pending_mut().insert(UrbHandle(3), bulk_in(6, 3));
+
+        assert_eq!(automaton.pending().len(), 3);
+
+        // Giving back fails on /dev/null, after every URB of the
+        // device was taken out.
+        let err = automaton
+            .handle(&mut vhci, stat(one, PortStatus::POWER))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+        let left: Vec<_> = automaton.pending().handles().collect();
+        assert_eq!(left, [UrbHandle(3)]);
+        assert_eq!(vhci.device_addresses().address(one), None);
+        assert_eq!(
+            vhci.device_addresses().resolve(Address::new(6).unwrap()),
+            Some(two)
+        );
+        assert_eq!(*gone.lock().unwrap(), [one]);
+        // The URBs are not retried, so the disconnect is recorded.
+        assert!(!vhci.is_port_connected(one));
+    }
+
+    #[test]
+    fn power_off_forgets_device_once() {
+        let mut automaton = PortAutomaton::new(AttachPolicy::Manual);
+        let gone = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&gone);
+        automaton.on_device_gone(move |port| seen.lock().unwrap().push(port));
+
+        let mut vhci = fake_controller(1);
+        let port = Port::new(1).unwrap();
+        automaton
+            .handle(
+                &mut vhci,
+                stat(port, PortStatus::POWER | PortStatus::CONNECTION),
+            )
+            .unwrap();
+        enumerate(&mut vhci, port, 5);
+
+        let events = automaton
+            .handle(&mut vhci, stat(port, PortStatus::empty()))
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                AutomatonEvent::PoweredOff(port),
+                AutomatonEvent::Disconnected(port)
+            ]
+        );
+        assert_eq!(*gone.lock().unwrap(), [port]);
+        assert_eq!(vhci.device_addresses().address(port), None);
+    }
+
+    #[test]
+    fn failed_answers_are_retried() {
+        let mut automaton = PortAutomaton::new(AttachPolicy::Manual);
+        let mut vhci = fake_controller(1);
+        let port = Port::new(1).unwrap();
+        let connected = PortStatus::POWER | PortStatus::CONNECTION;
+        automaton.handle(&mut vhci, stat(port, connected)).unwrap();
+
+        // port_reset_done fails on /dev/null, and the reset is still
+        // there to be answered the next time.
+        let reset = stat(port, connected | PortStatus::RESET);
+        for _ in 0..2 {
+            let err = automaton.handle(&mut vhci, reset).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+            assert_eq!(
+                vhci.port_stat_events(reset).unwrap(),
+                [PortEvent::ResetRequested(port)]
+            );
+        }
+        assert!(!vhci.port_status(port).unwrap().0.in_reset());
+    }
+}
@@ -0,0 +1,259 @@
+//! Estimates of the periodic bandwidth the devices of a controller
+//! reserve, to predict when the host refuses a SET_INTERFACE or
+//! SET_CONFIGURATION with `ENOSPC`.
+//!
+//! The bus time of a transaction follows section 5.11.3 of the USB
+//! 2.0 specification, with the host delay and hub setup times Linux
+//! uses in `usb_calc_bus_time`.
+
+use crate::{
+    effective_max_packet,
+    ioctl::{Endpoint, UrbType},
+    speed::{for_each_descriptor, is_endpoint, urb_type},
+    usbfs::Dir,
+    DataRate,
+};
+
+const DESCRIPTOR_TYPE_INTERFACE: u8 = 4;
+
+/// Host delay in ns for full- and low-speed transactions.
+const HOST_DELAY: u64 = 1000;
+
+/// Host delay in ns for high-speed transactions.
+const USB2_HOST_DELAY: u64 = 5;
+
+const HUB_LS_SETUP: u64 = 333;
+
+/// An interrupt or isochronous endpoint of an attached device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeriodicEndpoint {
+    /// Speed of the port the device is connected at.
+    pub rate: DataRate,
+    pub kind: UrbType,
+    pub endpoint: Endpoint,
+    pub w_max_packet_size: u16,
+}
+
+impl PeriodicEndpoint {
+    /// Bus time of one (micro)frame's worth of transactions in µs,
+    /// rounded up.
+    pub fn usecs(&self) -> u32 {
+        let is_iso = UrbType::Iso == self.kind;
+        let is_in = Dir::In == self.endpoint.direction();
+        let bytes = match self.rate {
+            // Reserved multipliers count as the largest legal one.
+            DataRate::High => effective_max_packet(self.w_max_packet_size)
+                .unwrap_or(3 * (self.w_max_packet_size & 0x07FF)),
+            DataRate::Full | DataRate::Low => self.w_max_packet_size,
+        };
+        let bit_time = 7 * 8 * u64::from(bytes) / 6;
+
+        let nsecs = match (self.rate, is_iso) {
+            (DataRate::High, false) => {
+                (55 * 8 * 2083 + 2083 * (3 + bit_time)) / 1000 + USB2_HOST_DELAY
+            }
+            (DataRate::High, true) => {
+                (38 * 8 * 2083 + 2083 * (3 + bit_time)) / 1000 + USB2_HOST_DELAY
+            }
+            (DataRate::Full, _) => {
+                let base = match (is_iso, is_in) {
+                    (false, _) => 9107,
+                    (true, true) => 7268,
+                    (true, false) => 6265,
+                };
+                base + HOST_DELAY + 8354 * (31 + 10 * bit_time) / 1000
+            }
+            (DataRate::Low, _) => {
+                let (base, per_bit) = if is_in {
+                    (64060, 67667)
+                } else {
+                    (64107, 66700)
+                };
+                base + 2 * HUB_LS_SETUP + HOST_DELAY + per_bit * (31 + 10 * bit_time) / 1000
+            }
+        };
+        nsecs.div_ceil(1000) as u32
+    }
+}
+
+/// An endpoint's share of a [`BandwidthReport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointBandwidth {
+    pub endpoint: PeriodicEndpoint,
+    pub usecs: u32,
+}
+
+/// Result of [`BandwidthEstimator::estimate`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BandwidthReport {
+    pub endpoints: Vec<EndpointBandwidth>,
+
+    /// Bus time of the high-speed endpoints per microframe.
+    pub high_speed_usecs: u32,
+
+    /// Bus time of the full- and low-speed endpoints per frame.
+    pub full_speed_usecs: u32,
+
+    /// Whether either exceeds its budget.
+    pub over_budget: bool,
+}
+
+impl BandwidthReport {
+    /// 80% of a 125 µs microframe may be periodic.
+    pub const HIGH_SPEED_BUDGET_USECS: u32 = 100;
+
+    /// 90% of a 1 ms frame may be periodic.
+    pub const FULL_SPEED_BUDGET_USECS: u32 = 900;
+}
+
+/// Adds up the periodic endpoints of all devices on a controller.
+///
+/// The estimate is the worst case: every endpoint is assumed to be
+/// scheduled in the same (micro)frame regardless of its interval,
+/// so the host may accept a little more than this predicts.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthEstimator {
+    endpoints: Vec<PeriodicEndpoint>,
+}
+
+impl BandwidthEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a periodic endpoint. Control and bulk endpoints use no
+    /// reserved bandwidth and are ignored.
+    pub fn add(&mut self, endpoint: PeriodicEndpoint) {
+        if matches!(endpoint.kind, UrbType::Int | UrbType::Iso) {
+            self.endpoints.push(endpoint);
+        }
+    }
+
+    /// Adds the periodic endpoints of `config`, a configuration
+    /// descriptor with everything that follows it, for a device
+    /// connected at `rate`. Interfaces use the alternate setting
+    /// given for them in `alt_settings` as `(interface, setting)`,
+    /// and setting 0 otherwise.
+    pub fn add_config(&mut self, config: &[u8], rate: DataRate, alt_settings: &[(u8, u8)]) {
+        let mut config = config.to_vec();
+        let mut active = false;
+        for_each_descriptor(&mut config, |desc| {
+            if desc[1] == DESCRIPTOR_TYPE_INTERFACE && desc.len() >= 4 {
+                let selected = alt_settings
+                    .iter()
+                    .find(|&&(interface, _)| interface == desc[2])
+                    .map_or(0, |&(_, setting)| setting);
+                active = desc[3] == selected;
+            } else if active && is_endpoint(desc) {
+                self.add(PeriodicEndpoint {
+                    rate,
+                    kind: urb_type(desc[3]),
+                    endpoint: Endpoint(desc[2]),
+                    w_max_packet_size: u16::from_le_bytes([desc[4], desc[5]]),
+                });
+            }
+        });
+    }
+
+    pub fn estimate(&self) -> BandwidthReport {
+        let mut report = BandwidthReport::default();
+        for &endpoint in &self.endpoints {
+            let usecs = endpoint.usecs();
+            match endpoint.rate {
+                DataRate::High => report.high_speed_usecs += usecs,
+                DataRate::Full | DataRate::Low => report.full_speed_usecs += usecs,
+            }
+            report.endpoints.push(EndpointBandwidth { endpoint, usecs });
+        }
+        report.over_budget = report.high_speed_usecs > BandwidthReport::HIGH_SPEED_BUDGET_USECS
+            || report.full_speed_usecs > BandwidthReport::FULL_SPEED_BUDGET_USECS;
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(rate: DataRate, kind: UrbType, ep: u8, size: u16) -> PeriodicEndpoint {
+        PeriodicEndpoint {
+            rate,
+            kind,
+            endpoint: Endpoint(ep),
+            w_max_packet_size: size,
+        }
+    }
+
+    #[test]
+    fn transaction_times() {
+        // Worked through the formulas of section 5.11.3 by hand,
+        // e.g. high-speed interrupt with 64 bytes:
+        // (55 * 8 * 2.083 + 2.083 * (3 + 597)) ns + 5 ns = 2171 ns.
+        let cases = [
+            (endpoint(DataRate::High, UrbType::Int, 0x81, 64), 3),
+            (endpoint(DataRate::High, UrbType::Int, 0x81, 1024), 21),
+            // Three 1024 byte transactions per microframe.
+            (endpoint(DataRate::High, UrbType::Iso, 0x81, 0x1400), 61),
+            (endpoint(DataRate::Full, UrbType::Int, 0x81, 64), 61),
+            (endpoint(DataRate::Full, UrbType::Iso, 0x81, 1023), 807),
+            (endpoint(DataRate::Low, UrbType::Int, 0x81, 8), 118),
+        ];
+        for (endpoint, usecs) in cases {
+            assert_eq!(endpoint.usecs(), usecs, "{endpoint:?}");
+        }
+        // OUT transactions are a little cheaper at full speed.
+        assert!(endpoint(DataRate::Full, UrbType::Iso, 0x01, 1023).usecs() < 807);
+    }
+
+    #[test]
+    fn two_high_bandwidth_cameras_do_not_fit() {
+        let camera = endpoint(DataRate::High, UrbType::Iso, 0x81, 0x1400);
+        let mut estimator = BandwidthEstimator::new();
+        estimator.add(camera);
+        estimator.add(endpoint(DataRate::High, UrbType::Bulk, 0x82, 512));
+        let report = estimator.estimate();
+        assert_eq!(report.endpoints.len(), 1);
+        assert_eq!(report.high_speed_usecs, 61);
+        assert!(!report.over_budget);
+
+        estimator.add(camera);
+        let report = estimator.estimate();
+        assert_eq!(report.high_speed_usecs, 122);
+        assert!(report.over_budget);
+    }
+
+    #[test]
+    fn follows_alt_settings() {
+        let mut config = vec![9, 2, 0, 0, 1, 1, 0, 0x80, 50];
+        // Interface 0: interrupt endpoint.
+        config.extend([9, 4, 0, 0, 1, 3, 0, 0, 0]);
+        config.extend([7, 5, 0x81, 3, 8, 0, 10]);
+        // Interface 1: no bandwidth in setting 0, a large
+        // isochronous endpoint in setting 1.
+        config.extend([9, 4, 1, 0, 0, 1, 2, 0, 0]);
+        config.extend([9, 4, 1, 1, 1, 1, 2, 0, 0]);
+        config.extend([7, 5, 0x82, 1, 0xFF, 0x03, 1]);
+
+        let mut idle = BandwidthEstimator::new();
+        idle.add_config(&config, DataRate::Full, &[]);
+        let idle = idle.estimate();
+        assert_eq!(idle.endpoints.len(), 1);
+        assert_eq!(idle.endpoints[0].endpoint.endpoint, Endpoint(0x81));
+
+        let mut streaming = BandwidthEstimator::new();
+        streaming.add_config(&config, DataRate::Full, &[(1, 1)]);
+        let streaming = streaming.estimate();
+        assert_eq!(streaming.endpoints.len(), 2);
+        assert_eq!(streaming.full_speed_usecs, idle.full_speed_usecs + 807);
+        assert!(!streaming.over_budget);
+
+        streaming_twice_is_over_budget(&config);
+    }
+
+    fn streaming_twice_is_over_budget(config: &[u8]) {
+        let mut estimator = BandwidthEstimator::new();
+        estimator.add_config(config, DataRate::Full, &[(1, 1)]);
+        estimator.add_config(config, DataRate::Full, &[(1, 1)]);
+        assert!(estimator.estimate().over_budget);
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    ioctl::{Address, Endpoint, IocIsoPacketData, IocSetupPacket, IocUrb, UrbHandle, UrbType},
+    usbfs::Dir,
+    IsoPacketDataMut, UrbWithData, MAX_ISO_PACKETS,
+};
+
+/// Handles of fabricated URBs, counting down from the top so they
+/// are unlikely to collide with handles of the kernel.
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Fabricates [`UrbWithData`]s without a kernel, e.g. to feed them
+/// to a device's handlers in tests. See [`UrbWithData::builder`].
+///
+/// Pick the kind of URB with one of [`UrbBuilder::control`],
+/// [`UrbBuilder::bulk`], [`UrbBuilder::interrupt`] or
+/// [`UrbBuilder::iso`], adjust it, and finish with
+/// [`UrbBuilder::build`]. OUT URBs come out as if
+/// [`Remote::fetch_data`] had already filled them in.
+///
+/// [`Remote::fetch_data`]: crate::Remote::fetch_data
+#[derive(Debug, Clone, Default)]
+pub struct UrbBuilder {
+    urb: IocUrb,
+    handle: Option<UrbHandle>,
+    data: Vec<u8>,
+    iso_packets: Vec<IocIsoPacketData>,
+}
+
+impl UrbBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A control URB for `setup` with a buffer of `wLength` bytes.
+    pub fn control(mut self, setup: IocSetupPacket) -> Self {
+        self.urb.typ = UrbType::Ctrl;
+        self.urb.setup_packet = setup;
+        self.urb.endpoint = Endpoint(setup.bm_request_type & 0x80);
+        self.data = vec![0; setup.length().into()];
+        self
+    }
+
+    /// A bulk URB on `ep`. OUT URBs carry `data`, IN URBs get a
+    /// buffer of the same length.
+    pub fn bulk(mut self, ep: Endpoint, data: &[u8]) -> Self {
+        self.urb.typ = UrbType::Bulk;
+        self.urb.endpoint = ep;
+        self.data = data.to_vec();
+        self
+    }
+
+    /// An interrupt URB on `ep` with a buffer of `len` bytes.
+    pub fn interrupt(mut self, ep: Endpoint, interval: i32, len: usize) -> Self {
+        self.urb.typ = UrbType::Int;
+        self.urb.endpoint = ep;
+        self.urb.interval = interval;
+        self.data = vec![0; len];
+        self
+    }
+
+    /// An isochronous URB on `ep` with back to back packets of the
+    /// given lengths.
+    pub fn iso(mut self, ep: Endpoint, packets: &[u32]) -> Self {
+        self.urb.typ = UrbType::Iso;
+        self.urb.endpoint = ep;
+        let mut offset = 0;
+        self.iso_packets = packets
+            .iter()
+            .map(|&packet_length| {
+                let packet = IocIsoPacketData {
+                    offset,
+                    packet_length,
+                };
+                offset += packet_length;
+                packet
+            })
+            .collect();
+        self.data = vec![0; offset as usize];
+        self
+    }
+
+    /// Resizes the transfer buffer to `len` bytes.
+    pub fn buffer(mut self, len: usize) -> Self {
+        self.data.resize(len, 0);
+        self
+    }
+
+    /// Replaces the transfer buffer with `data`, which is what an
+    /// OUT URB carries.
+    pub fn data(mut self, data: &[u8]) -> Self {
+        self.data = data.to_vec();
+        self
+    }
+
+    pub fn handle(mut self, handle: UrbHandle) -> Self {
+        self.handle = Some(handle);
+        self
+    }
+
+    /// The device address the URB is for, 0 by default.
+    pub fn address(mut self, address: Address) -> Self {
+        self.urb.address = address;
+        self
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the URB is not one the kernel would hand out:
+    /// a control URB whose buffer is shorter than `wLength`, a
+    /// non-control URB on endpoint 0, or an isochronous URB whose
+    /// packets do not fit [`MAX_ISO_PACKETS`] or the buffer.
+    pub fn build(self) -> UrbWithData {
+        let Self {
+            mut urb,
+            handle,
+            data,
+            iso_packets,
+        } = self;
+
+        match urb.typ {
+            UrbType::Ctrl => assert!(
+                usize::from(urb.setup_packet.length()) <= data.len(),
+                "control buffer shorter than wLength"
+            ),
+            _ => assert!(
+                !urb.endpoint.is_control_zero(),
+                "only control URBs use endpoint 0"
+            ),
+        }
+        if UrbType::Iso == urb.typ {
+            assert!(iso_packets.len() <= MAX_ISO_PACKETS, "too many iso packets");
+            assert!(
+                iso_packets
+                    .iter()
+                    .all(|packet| (packet.offset + packet.packet_length) as usize <= data.len()),
+                "iso packet outside of the buffer"
+            );
+            urb.packet_count = iso_packets.len() as i32;
+        }
+        urb.buffer_length = data.len().try_into().expect("buffer too large");
+
+        let handle =
+            handle.unwrap_or_else(|| UrbHandle(NEXT_HANDLE.fetch_sub(1, Ordering::Relaxed)));
+        let mut built = UrbWithData::from_ioctl(urb, handle);
+        built.iso_packet_data_mut().copy_from_slice(&iso_packets);
+        if Dir::Out == built.dir() || UrbType::Iso == urb.typ {
+            built.buffer_mut().copy_from_slice(&data);
+        }
+        built
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{usbfs::Request, IsoPacketData, IsoPacketGiveback, Transfer, Urb};
+
+    #[test]
+    fn builds_coherent_urbs() {
+        let setup = Request::STANDARD_DEVICE_GET_DESCRIPTOR
+            .setup(0x0100, 0, 18)
+            .unwrap();
+        let urb = UrbWithData::builder().control(setup).build();
+        assert_eq!(urb.kind(), UrbType::Ctrl);
+        assert_eq!(urb.dir(), Dir::In);
+        assert_eq!(urb.buffer_length(), 18);
+        assert_eq!(urb.ioc_urb().packet_count, 0);
+
+        let urb = UrbWithData::builder()
+            .bulk(Endpoint(0x02), &[1, 2, 3])
+            .handle(UrbHandle(7))
+            .build();
+        assert_eq!(urb.handle(), UrbHandle(7));
+        assert_eq!(urb.transfer(), [1, 2, 3]);
+
+        let urb = UrbWithData::builder()
+            .interrupt(Endpoint(0x81), 8, 64)
+            .build();
+        assert_eq!(urb.ioc_urb().interval, 8);
+        assert_eq!(urb.buffer_length(), 64);
+        assert!(urb.transfer().is_empty());
+
+        let urb = UrbWithData::builder()
+            .iso(Endpoint(0x83), &[4, 2, 4])
+            .build();
+        assert_eq!(urb.ioc_urb().packet_count, 3);
+        assert_eq!(urb.iso_packet_data()[2].offset, 6);
+        assert_eq!(urb.iso_packet_giveback().len(), 3);
+        assert_eq!(urb.buffer_length(), 10);
+    }
+
+    #[test]
+    fn synthetic_handles_differ() {
+        let a = UrbWithData::builder().bulk(Endpoint(0x01), &[]).build();
+        let b = UrbWithData::builder().bulk(Endpoint(0x01), &[]).build();
+        assert_ne!(a.handle(), b.handle());
+    }
+
+    #[test]
+    #[should_panic = "control buffer shorter than wLength"]
+    fn rejects_short_control_buffer() {
+        let setup = Request::STANDARD_DEVICE_GET_STATUS.setup(0, 0, 2).unwrap();
+        UrbWithData::builder().control(setup).buffer(1).build();
+    }
+
+    #[test]
+    #[should_panic = "only control URBs use endpoint 0"]
+    fn rejects_bulk_on_endpoint_zero() {
+        UrbWithData::builder().bulk(Endpoint(0x80), &[]).build();
+    }
+}
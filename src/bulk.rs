@@ -0,0 +1,424 @@
+use std::{
+    collections::VecDeque,
+    io,
+    task::{Context, Poll, Waker},
+};
+
+use crate::{
+    ioctl::{Endpoint, UrbHandle, UrbType},
+    is_short_terminated,
+    usbfs::Dir,
+    Status, Transfer, Urb, UrbWithData,
+};
+
+/// Bulk URBs of one endpoint, queued in the order they arrived.
+#[derive(Debug)]
+struct BulkQueue {
+    ep: Endpoint,
+    pending: VecDeque<UrbWithData>,
+    completed: Vec<UrbWithData>,
+    waker: Option<Waker>,
+}
+
+impl BulkQueue {
+    const fn new(ep: Endpoint) -> Self {
+        Self {
+            ep,
+            pending: VecDeque::new(),
+            completed: Vec::new(),
+            waker: None,
+        }
+    }
+
+    fn push(&mut self, urb: UrbWithData) -> Result<(), UrbWithData> {
+        if UrbType::Bulk != urb.kind() || self.ep != urb.endpoint() {
+            return Err(urb);
+        }
+        self.pending.push_back(urb);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn complete_front(&mut self, transferred: usize) {
+        let mut urb = self.pending.pop_front().unwrap();
+        urb.set_transferred(transferred);
+        urb.set_status(Status::Success);
+        self.completed.push(urb);
+    }
+
+    /// Removes a canceled URB, along with whether it was the front.
+    fn cancel(&mut self, handle: UrbHandle) -> Option<(UrbWithData, bool)> {
+        let index = self.pending.iter().position(|urb| urb.handle() == handle)?;
+        Some((self.pending.remove(index)?, index == 0))
+    }
+}
+
+/// The data the host sends to a bulk OUT endpoint, read as a stream
+/// of bytes.
+///
+/// Push the endpoint's URBs after [`Remote::fetch_data`] filled them
+/// in, read from the reader, and give back the URBs from
+/// [`BulkReader::take_completed`]. A URB is completed once all of
+/// its data has been read.
+///
+/// [`BulkReader::poll_read`] returns [`Poll::Pending`] while no data
+/// is queued and wakes the task on the next push, so it can back an
+/// `AsyncRead` of any runtime. [`io::Read`] fails with
+/// [`io::ErrorKind::WouldBlock`] instead.
+///
+/// [`Remote::fetch_data`]: crate::Remote::fetch_data
+#[derive(Debug)]
+pub struct BulkReader {
+    queue: BulkQueue,
+    max_packet: u16,
+    boundaries: bool,
+
+    /// Bytes of the front URB that have been read.
+    offset: usize,
+}
+
+impl BulkReader {
+    /// A reader for `ep`, whose packets are `max_packet` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ep` is not an OUT endpoint or `max_packet` is zero.
+    pub fn new(ep: Endpoint, max_packet: u16) -> Self {
+        assert!(Dir::Out == ep.direction(), "bulk reader on an IN endpoint");
+        assert!(max_packet != 0, "max packet size must not be zero");
+        Self {
+            queue: BulkQueue::new(ep),
+            max_packet,
+            boundaries: false,
+            offset: 0,
+        }
+    }
+
+    /// Ends reads at a short or zero-length packet, so a single read
+    /// never returns data of two messages. Off by default.
+    pub fn message_boundaries(mut self, boundaries: bool) -> Self {
+        self.boundaries = boundaries;
+        self
+    }
+
+    pub const fn endpoint(&self) -> Endpoint {
+        self.queue.ep
+    }
+
+    /// Queues an OUT URB. URBs of another endpoint or type are
+    /// handed back.
+    pub fn push(&mut self, urb: UrbWithData) -> Result<(), UrbWithData> {
+        self.queue.push(urb)
+    }
+
+    /// Removes a URB the host canceled. The part of it that was
+    /// already read stays read.
+    pub fn cancel(&mut self, handle: UrbHandle) -> Option<UrbWithData> {
+        let (urb, front) = self.queue.cancel(handle)?;
+        if front {
+            self.offset = 0;
+        }
+        Some(urb)
+    }
+
+    /// URBs whose data has been read completely, ready for
+    /// [`Remote::giveback`].
+    ///
+    /// [`Remote::giveback`]: crate::Remote::giveback
+    pub fn take_completed(&mut self) -> Vec<UrbWithData> {
+        std::mem::take(&mut self.queue.completed)
+    }
+
+    fn read_queued(&mut self, buf: &mut [u8]) -> usize {
+        let mut read = 0;
+        while let Some(urb) = self.queue.pending.front() {
+            let data = &urb.transfer()[self.offset..];
+            let n = data.len().min(buf.len() - read);
+            buf[read..read + n].copy_from_slice(&data[..n]);
+            read += n;
+            self.offset += n;
+            if self.offset < urb.transfer().len() {
+                break;
+            }
+
+            let ends_message = is_short_terminated(self.offset, self.max_packet);
+            self.queue.complete_front(self.offset);
+            self.offset = 0;
+            if read == buf.len() || (self.boundaries && ends_message && read != 0) {
+                break;
+            }
+        }
+        read
+    }
+
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<usize> {
+        match self.read_queued(buf) {
+            0 if !buf.is_empty() => {
+                self.queue.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            read => Poll::Ready(read),
+        }
+    }
+}
+
+impl io::Read for BulkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.read_queued(buf) {
+            0 if !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+            read => Ok(read),
+        }
+    }
+}
+
+/// The data a device sends from a bulk IN endpoint, written as a
+/// stream of bytes.
+///
+/// Push the endpoint's URBs as the host submits them, write to the
+/// writer, and give back the URBs from [`BulkWriter::take_completed`].
+/// A URB is completed once its buffer is full. Flushing completes a
+/// partly filled URB, which ends it with a short packet and so marks
+/// the end of a message.
+///
+/// Like [`BulkReader`], [`BulkWriter::poll_write`] returns
+/// [`Poll::Pending`] until the host submits a URB, and
+/// [`io::Write`] fails with [`io::ErrorKind::WouldBlock`].
+#[derive(Debug)]
+pub struct BulkWriter {
+    queue: BulkQueue,
+
+    /// Bytes written to the front URB.
+    filled: usize,
+}
+
+impl BulkWriter {
+    /// # Panics
+    ///
+    /// Panics if `ep` is not an IN endpoint.
+    pub fn new(ep: Endpoint) -> Self {
+        assert!(Dir::In == ep.direction(), "bulk writer on an OUT endpoint");
+        Self {
+            queue: BulkQueue::new(ep),
+            filled: 0,
+        }
+    }
+
+    pub const fn endpoint(&self) -> Endpoint {
+        self.queue.ep
+    }
+
+    /// Queues an IN URB. URBs of another endpoint or type are
+    /// handed back.
+    pub fn push(&mut self, urb: UrbWithData) -> Result<(), UrbWithData> {
+        self.queue.push(urb)
+    }
+
+    /// Removes a URB the host canceled. Data written to it is lost.
+    pub fn cancel(&mut self, handle: UrbHandle) -> Option<UrbWithData> {
+        let (urb, front) = self.queue.cancel(handle)?;
+        if front {
+            self.filled = 0;
+        }
+        Some(urb)
+    }
+
+    /// URBs that have been filled or flushed, ready for
+    /// [`Remote::giveback`].
+    ///
+    /// [`Remote::giveback`]: crate::Remote::giveback
+    pub fn take_completed(&mut self) -> Vec<UrbWithData> {
+        std::mem::take(&mut self.queue.completed)
+    }
+
+    fn write_queued(&mut self, buf: &[u8]) -> usize {
+        let mut written = 0;
+        while let Some(urb) = self.queue.pending.front_mut() {
+            let space = &mut urb.buffer_mut()[self.filled..];
+            let n = space.len().min(buf.len() - written);
+            space[..n].copy_from_slice(&buf[written..written + n]);
+            written += n;
+            self.filled += n;
+            if self.filled < urb.buffer_length() {
+                break;
+            }
+
+            self.queue.complete_front(self.filled);
+            self.filled = 0;
+            if written == buf.len() {
+                break;
+            }
+        }
+        written
+    }
+
+    fn flush_queued(&mut self) {
+        if self.filled != 0 {
+            self.queue.complete_front(self.filled);
+            self.filled = 0;
+        }
+    }
+
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<usize> {
+        match self.write_queued(buf) {
+            0 if !buf.is_empty() => {
+                self.queue.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            written => Poll::Ready(written),
+        }
+    }
+
+    /// Completes the partly filled URB, if any. Never pending.
+    pub fn poll_flush(&mut self, _cx: &mut Context<'_>) -> Poll<()> {
+        self.flush_queued();
+        Poll::Ready(())
+    }
+}
+
+impl io::Write for BulkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.write_queued(buf) {
+            0 if !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+            written => Ok(written),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_queued();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        task::Wake,
+    };
+
+    use super::*;
+
+    const OUT: Endpoint = Endpoint(0x02);
+    const IN: Endpoint = Endpoint(0x81);
+
+    struct CountingWaker(AtomicU32);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn out(data: &[u8]) -> UrbWithData {
+        UrbWithData::builder().bulk(OUT, data).build()
+    }
+
+    fn bulk_in(len: usize) -> UrbWithData {
+        UrbWithData::builder().bulk(IN, &vec![0; len]).build()
+    }
+
+    #[test]
+    fn reads_across_urbs() {
+        let mut reader = BulkReader::new(OUT, 4);
+        reader.push(out(&[1, 2, 3, 4])).unwrap();
+        reader.push(out(&[5, 6])).unwrap();
+
+        let mut buf = [0; 3];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert!(reader.take_completed().is_empty());
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(buf, [4, 5, 6]);
+
+        let completed = reader.take_completed();
+        let lengths: Vec<_> = completed.iter().map(Urb::bytes_transferred).collect();
+        assert_eq!(lengths, [4, 2]);
+        assert!(completed.iter().all(|urb| Status::Success == urb.status()));
+
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn reads_stop_at_message_boundaries() {
+        let mut reader = BulkReader::new(OUT, 4).message_boundaries(true);
+        // A message of exactly one packet, ended by a ZLP.
+        reader.push(out(&[1, 2, 3, 4])).unwrap();
+        reader.push(out(&[])).unwrap();
+        reader.push(out(&[5, 6])).unwrap();
+        reader.push(out(&[7])).unwrap();
+
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(reader.take_completed().len(), 4);
+
+        let mut reader = BulkReader::new(OUT, 4);
+        reader.push(out(&[1, 2])).unwrap();
+        reader.push(out(&[3])).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+    }
+
+    #[test]
+    fn pending_until_pushed() {
+        let counter = Arc::new(CountingWaker(AtomicU32::new(0)));
+        let waker = Waker::from(Arc::clone(&counter));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut reader = BulkReader::new(OUT, 64);
+        let mut buf = [0; 8];
+        assert_eq!(reader.poll_read(&mut cx, &mut buf), Poll::Pending);
+        assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+        reader.push(out(&[9])).unwrap();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+        assert_eq!(reader.poll_read(&mut cx, &mut buf), Poll::Ready(1));
+
+        let mut writer = BulkWriter::new(IN);
+        assert_eq!(writer.poll_write(&mut cx, &[1]), Poll::Pending);
+        writer.push(bulk_in(4)).unwrap();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 2);
+        assert_eq!(writer.poll_write(&mut cx, &[1]), Poll::Ready(1));
+    }
+
+    #[test]
+    fn writes_fill_then_flush() {
+        let mut writer = BulkWriter::new(IN);
+        writer.push(bulk_in(4)).unwrap();
+        writer.push(bulk_in(4)).unwrap();
+
+        assert_eq!(writer.write(&[1, 2, 3, 4, 5, 6]).unwrap(), 6);
+        let full = writer.take_completed();
+        assert_eq!(full.len(), 1);
+        assert_eq!(full[0].transfer(), [1, 2, 3, 4]);
+
+        writer.flush().unwrap();
+        let short = writer.take_completed();
+        assert_eq!(short[0].transfer(), [5, 6]);
+        let err = writer.write(&[7]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn rejects_foreign_urbs_and_cancels() {
+        let mut reader = BulkReader::new(OUT, 4);
+        assert!(reader.push(bulk_in(4)).is_err());
+        let int = UrbWithData::builder().interrupt(OUT, 1, 4).build();
+        assert!(reader.push(int).is_err());
+
+        let first = out(&[1, 2, 3, 4]);
+        let handle = first.handle();
+        reader.push(first).unwrap();
+        reader.push(out(&[5])).unwrap();
+        let mut buf = [0; 2];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert!(reader.cancel(handle).is_some());
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 5);
+    }
+}
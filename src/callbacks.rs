@@ -0,0 +1,178 @@
+use nohash_hasher::IntMap;
+
+use crate::{
+    ioctl::UrbHandle, GivebackOutcome, IsoPacketGivebackMut, Remote, Result, TransferMut, Urb,
+};
+
+/// How an URB with a completion callback ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrbCompletion {
+    /// The URB was given back, see [`GivebackOutcome`].
+    GivenBack(GivebackOutcome),
+
+    /// The host canceled the URB before it was given back.
+    Canceled,
+
+    /// The URB was given up on, e.g. by a timeout.
+    Expired,
+
+    /// The registry was dropped while the URB was outstanding.
+    Abandoned,
+}
+
+type Callback = Box<dyn FnOnce(UrbCompletion) + Send>;
+
+/// Callbacks to run once outstanding URBs are done with, e.g. to
+/// release resources tied to an URB completed by another subsystem.
+///
+/// Every callback runs exactly once: when the URB is given back
+/// through [`CompletionCallbacks::giveback`] or reported with one of
+/// [`CompletionCallbacks::given_back`],
+/// [`CompletionCallbacks::canceled`] or
+/// [`CompletionCallbacks::expired`], or when the registry is dropped.
+///
+/// Callbacks run synchronously on the thread reporting the
+/// completion, after they were removed from the registry. They must
+/// not block on the registry or give back URBs themselves, e.g. when
+/// it is shared behind a mutex that is held while reporting.
+#[derive(Default)]
+pub struct CompletionCallbacks {
+    callbacks: IntMap<UrbHandle, Callback>,
+}
+
+impl CompletionCallbacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `callback` for `handle`. If the handle already has
+    /// one, `callback` is handed back unregistered.
+    pub fn on_complete(
+        &mut self,
+        handle: UrbHandle,
+        callback: impl FnOnce(UrbCompletion) + Send + 'static,
+    ) -> Result<(), Callback> {
+        let callback: Callback = Box::new(callback);
+        if self.callbacks.contains_key(&handle) {
+            return Err(callback);
+        }
+        self.callbacks.insert(handle, callback);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.callbacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    /// Runs the callback of `handle`, if any. Returns whether there
+    /// was one.
+    fn complete(&mut self, handle: UrbHandle, completion: UrbCompletion) -> bool {
+        match self.callbacks.remove(&handle) {
+            Some(callback) => {
+                callback(completion);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Gives back `urb` with [`Remote::giveback`] and runs its
+    /// callback. If giving back fails the URB is still outstanding,
+    /// so the callback stays registered.
+    pub fn giveback(
+        &mut self,
+        remote: &Remote,
+        urb: impl Urb + TransferMut + IsoPacketGivebackMut,
+    ) -> Result<GivebackOutcome> {
+        let handle = urb.handle();
+        let outcome = remote.giveback(urb)?;
+        self.given_back(handle, outcome);
+        Ok(outcome)
+    }
+
+    /// Reports that `handle` was given back some other way.
+    pub fn given_back(&mut self, handle: UrbHandle, outcome: GivebackOutcome) -> bool {
+        self.complete(handle, UrbCompletion::GivenBack(outcome))
+    }
+
+    /// Reports a `CancelUrb` work item for `handle`.
+    pub fn canceled(&mut self, handle: UrbHandle) -> bool {
+        self.complete(handle, UrbCompletion::Canceled)
+    }
+
+    /// Reports that `handle` was given up on.
+    pub fn expired(&mut self, handle: UrbHandle) -> bool {
+        self.complete(handle, UrbCompletion::Expired)
+    }
+}
+
+impl Drop for CompletionCallbacks {
+    fn drop(&mut self) {
+        for (_, callback) in self.callbacks.drain() {
+            callback(UrbCompletion::Abandoned);
+        }
+    }
+}
+
+impl std::fmt::Debug for CompletionCallbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompletionCallbacks")
+            .field("handles", &self.callbacks.keys())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    type Log = Arc<Mutex<Vec<(u64, UrbCompletion)>>>;
+
+    fn register(callbacks: &mut CompletionCallbacks, log: &Log, handle: u64) {
+        let log = Arc::clone(log);
+        let registered = callbacks.on_complete(UrbHandle(handle), move |completion| {
+            log.lock().unwrap().push((handle, completion));
+        });
+        assert!(registered.is_ok());
+    }
+
+    #[test]
+    fn runs_each_callback_once() {
+        let log = Log::default();
+        let mut callbacks = CompletionCallbacks::new();
+        for handle in 1..=5 {
+            register(&mut callbacks, &log, handle);
+        }
+        assert!(callbacks.on_complete(UrbHandle(1), |_| ()).is_err());
+
+        assert!(callbacks.given_back(UrbHandle(1), GivebackOutcome::Completed));
+        assert!(callbacks.given_back(UrbHandle(2), GivebackOutcome::AlreadyCanceled));
+        assert!(callbacks.canceled(UrbHandle(3)));
+        assert!(callbacks.expired(UrbHandle(4)));
+        // Late reports for finished URBs do nothing.
+        assert!(!callbacks.canceled(UrbHandle(1)));
+        assert!(!callbacks.expired(UrbHandle(3)));
+        assert_eq!(callbacks.len(), 1);
+        drop(callbacks);
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                (1, UrbCompletion::GivenBack(GivebackOutcome::Completed)),
+                (
+                    2,
+                    UrbCompletion::GivenBack(GivebackOutcome::AlreadyCanceled)
+                ),
+                (3, UrbCompletion::Canceled),
+                (4, UrbCompletion::Expired),
+                (5, UrbCompletion::Abandoned),
+            ]
+        );
+    }
+}
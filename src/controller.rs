@@ -1,296 +1,655 @@
 use std::{
+    collections::VecDeque,
     io,
     ops::{Add, Sub},
-    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    task::{ready, Context, Poll, Waker},
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
 use bit_vec::BitVec;
+use nix::errno::Errno;
 
 use crate::{
-    ioctl::{self, UrbType}, usbfs::Dir, utils::{BoundedI16, BoundedU8, TimeoutMillis}, DataRate, IsoPacketDataMut, IsoPacketGivebackMut, Port, PortChange, PortStatus, TransferMut, Urb, MAX_ISO_PACKETS
+    ioctl,
+    usbfs::Dir,
+    utils::{BoundedI16, BoundedU8, TimeoutMillis},
+    DataRate, DeviceAddressMap, Error, IsoPacketDataMut, IsoPacketGivebackMut, Port, PortChange,
+    PortEvent, PortFlag, PortStateTracker, PortStatus, PortUpdate, Result, TransferMut, Urb,
+    UrbWithData, MAX_ISO_PACKETS,
 };
 
 static USB_VHCI_DEVICE_FILE: &str = "/dev/usb-vhci";
 
+/// Where a [`TaggedWork`] came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WorkTag {
+    /// Id the kernel assigned to the controller.
+    pub controller_id: i32,
+
+    pub usb_busnum: i32,
+
+    /// Counts the work fetched from the controller, starting at 0.
+    /// Shared by the controller and all of its receivers, so it
+    /// orders work regardless of which of them fetched it.
+    pub seq: u64,
+}
+
+/// Work together with the [`WorkTag`] it was fetched with. Keep the
+/// tag next to the URB to tell which bus its giveback belongs to.
+#[derive(Debug, Clone)]
+pub struct TaggedWork {
+    pub tag: WorkTag,
+    pub work: ioctl::IocWork,
+}
+
+/// Hands out the [`WorkTag`]s of one controller.
+#[derive(Debug, Clone)]
+struct WorkTagger {
+    controller_id: i32,
+    usb_busnum: i32,
+    next_seq: Arc<AtomicU64>,
+}
+
+impl WorkTagger {
+    fn new(controller_id: i32, usb_busnum: i32) -> Self {
+        Self {
+            controller_id,
+            usb_busnum,
+            next_seq: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn tag(&self, work: ioctl::IocWork) -> TaggedWork {
+        TaggedWork {
+            tag: WorkTag {
+                controller_id: self.controller_id,
+                usb_busnum: self.usb_busnum,
+                seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            },
+            work,
+        }
+    }
+}
+
+/// The device fd, shared by a [`Controller`] and all of its handles.
 #[derive(Debug)]
-pub struct WorkReceiver {
-    dev: std::os::unix::io::RawFd,
+struct Device {
+    fd: OwnedFd,
+
+    /// See [`Controller::set_retry_on_eintr`].
+    retry_on_eintr: AtomicBool,
+
+    /// See [`WorkReceiver::poll_fetch_work`].
+    waiter: Mutex<Waiter>,
 }
 
-impl WorkReceiver {
-    const fn new(dev: std::os::unix::io::RawFd) -> Self {
-        Self { dev }
+/// The thread that waits for work on behalf of a pending
+/// [`WorkReceiver::poll_fetch_work`].
+#[derive(Debug, Default)]
+struct Waiter {
+    /// What the thread fetched, for the next fetch to return.
+    ready: Option<Result<ioctl::IocWork>>,
+
+    /// Woken once `ready` is filled.
+    waker: Option<Waker>,
+
+    running: bool,
+
+    /// The last thread started, joined when the device is dropped.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Device {
+    fn retries_on_eintr(&self) -> bool {
+        self.retry_on_eintr.load(Ordering::Relaxed)
     }
 
-    pub fn fetch_work(&self) -> io::Result<ioctl::IocWork> {
-        self.fetch_work_timeout(TimeoutMillis::Time(BoundedI16::new(100).unwrap()))
+    fn waiter(&self) -> MutexGuard<'_, Waiter> {
+        self.waiter.lock().unwrap_or_else(PoisonError::into_inner)
     }
 
-    pub fn fetch_work_timeout(&self, timeout: TimeoutMillis) -> io::Result<ioctl::IocWork> {
-        let mut ioc_work = ioctl::IocWork {
-            timeout: match timeout {
-                // utils::TimeoutMillis::Unlimited => ioctl::USB_VHCI_TIMEOUT_INFINITE,
-                TimeoutMillis::Time(time) => time.get(),
-            },
-            ..Default::default()
-        };
+    /// Takes what the waiter thread fetched, if anything.
+    fn take_ready(&self) -> Option<Result<ioctl::IocWork>> {
+        self.waiter().ready.take()
+    }
+
+    /// Waits up to `millis` for work, see
+    /// [`WorkReceiver::fetch_work_timeout`].
+    fn fetch_work(&self, millis: i16) -> Result<ioctl::IocWork> {
+        fetch_work_on(self.as_fd(), millis, self.retries_on_eintr())
+    }
+
+    /// Starts the waiter thread and marks `waiter` running. The
+    /// thread fetches until work arrives or fetching fails, leaves
+    /// the result in `ready` and wakes the last registered waker.
+    ///
+    /// It fetches through a duplicate of the fd and only holds on to
+    /// the device to leave the result, so dropping the last handle
+    /// drops the device right away. The thread notices between two
+    /// fetches and ends, and the device's drop waits for that, so the
+    /// controller is gone once the drop returns.
+    fn spawn_waiter(self: &Arc<Self>, waiter: &mut Waiter) -> io::Result<()> {
+        const STEP: i16 = 100;
+
+        let dev = Arc::downgrade(self);
+        let fd = self.fd.try_clone()?;
+        let thread = std::thread::Builder::new()
+            .name("usb-vhci-waiter".into())
+            .spawn(move || loop {
+                let Some(retry) = dev.upgrade().map(|dev| dev.retries_on_eintr()) else {
+                    return;
+                };
+                let result = match fetch_work_on(fd.as_fd(), STEP, retry) {
+                    Err(err) if err.is_timeout() => continue,
+                    result => result,
+                };
+                let Some(dev) = dev.upgrade() else {
+                    return;
+                };
+                let mut waiter = dev.waiter();
+                waiter.ready = Some(result);
+                waiter.running = false;
+                let waker = waiter.waker.take();
+                drop(waiter);
+                // Dropping the device here would join this thread.
+                drop(dev);
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+                return;
+            })?;
+        waiter.thread = Some(thread);
+        waiter.running = true;
+        Ok(())
+    }
+
+    /// Calls `ioctl` again while it fails with `EINTR`, unless that
+    /// was turned off.
+    fn retrying<T>(&self, mut ioctl: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+        loop {
+            match ioctl() {
+                Err(Errno::EINTR) if self.retries_on_eintr() => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        let waiter = self
+            .waiter
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(thread) = waiter.thread.take() {
+            // The thread may hold the last reference for a moment,
+            // and can't wait for itself.
+            if thread.thread().id() != std::thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
 
+/// Waits up to `millis` for work on `fd`, see
+/// [`WorkReceiver::fetch_work_timeout`].
+fn fetch_work_on(fd: BorrowedFd<'_>, millis: i16, retry: bool) -> Result<ioctl::IocWork> {
+    let mut raw = ioctl::RawIocWork::default();
+    fetch_retrying(millis, retry, |millis| {
+        raw = ioctl::RawIocWork::with_timeout(millis);
         // SAFETY: We are using a valid file descriptor that we
         //         are sure will last for the entire duration of this
         //         ioctl. We also pass in a valid pointer for this
         //         ioctl's return type.
-        unsafe { ioctl::usb_vhci_fetchwork(self.dev, &raw mut ioc_work).map_err(io::Error::from)? };
+        unsafe { ioctl::usb_vhci_fetchwork(fd.as_raw_fd(), &raw mut raw) }.map(drop)
+    })?;
+    Ok(raw.decode()?)
+}
 
-        Ok(ioc_work)
+impl From<OwnedFd> for Device {
+    fn from(fd: OwnedFd) -> Self {
+        Self {
+            fd,
+            retry_on_eintr: AtomicBool::new(true),
+            waiter: Mutex::default(),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Remote {
-    dev: std::os::unix::io::RawFd,
+impl AsRawFd for Device {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
 }
 
-impl Remote {
-    const fn new(dev: std::os::unix::io::RawFd) -> Self {
-        Self { dev }
+impl AsFd for Device {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
     }
+}
 
-    pub fn fetch_data(&self, mut urb: impl Urb + IsoPacketDataMut + TransferMut) -> io::Result<()> {
-        let buffer_length = urb.transfer_mut().len().try_into().unwrap();
-        let buffer = urb.transfer_mut().as_mut_ptr().cast();
-        let packet_count = urb.iso_packet_data_mut().len();
-        assert!(packet_count <= MAX_ISO_PACKETS);
-
-        let mut ioc_urb_data = ioctl::IocUrbData {
-            handle: urb.handle().get(),
-            buffer,
-            buffer_length,
-            ..Default::default()
-        };
+/// `AsFd` and `AsRawFd` for the types holding a [`Device`].
+macro_rules! device_fd {
+    ($($ty:ty),* $(,)?) => {$(
+        impl AsFd for $ty {
+            fn as_fd(&self) -> BorrowedFd<'_> {
+                self.dev.as_fd()
+            }
+        }
 
-        if 0 < packet_count {
-            ioc_urb_data.iso_packets = urb.iso_packet_data_mut().as_mut_ptr();
-            ioc_urb_data.packet_count = packet_count.try_into().unwrap();
+        impl AsRawFd for $ty {
+            fn as_raw_fd(&self) -> RawFd {
+                self.dev.as_raw_fd()
+            }
         }
+    )*};
+}
 
-        // SAFETY:
-        // - `ioc_iso_packets` is valid and initialized for the ioctl call
-        // - transfer buffer is initialized and its length does not change
-        unsafe {
-            ioctl::usb_vhci_fetchdata(self.dev, &raw mut ioc_urb_data).map_err(io::Error::from)?
-        };
+device_fd!(Controller, Remote, WorkReceiver);
 
-        Ok(())
+/// Fetches work on another thread, see
+/// [`Controller::work_receiver`]. Like [`Remote`], it keeps the
+/// device fd open after the [`Controller`] is dropped.
+#[derive(Debug)]
+pub struct WorkReceiver {
+    dev: Arc<Device>,
+    tagger: WorkTagger,
+}
+
+impl WorkReceiver {
+    fn new(dev: Arc<Device>, tagger: WorkTagger) -> Self {
+        Self { dev, tagger }
     }
 
-    pub fn giveback(&self, mut urb: impl Urb + IsoPacketGivebackMut + TransferMut) -> io::Result<()> {
-        let packet_count = urb.iso_packet_giveback_mut().len();
-        let buffer_len = urb.bytes_transferred();
-        assert!(packet_count <= MAX_ISO_PACKETS);
+    /// Waits up to 100 ms for work.
+    pub fn fetch_work(&self) -> Result<ioctl::IocWork> {
+        self.fetch_work_timeout(TimeoutMillis::Time(BoundedI16::new(100).unwrap()))
+    }
 
-        let mut ioc_giveback = ioctl::IocGiveback {
-            handle: urb.handle().get(),
-            status: urb.status().to_errno_raw(ioctl::UrbType::Iso == urb.kind()),
-            buffer_actual: buffer_len.try_into().unwrap(),
-            ..Default::default()
-        };
+    /// Like [`WorkReceiver::fetch_work`], but checks the work and
+    /// returns it as [`Work`], see [`IocWork::work`]. Work the kernel
+    /// mangled fails with [`Error::InvalidWork`].
+    ///
+    /// [`Work`]: ioctl::Work
+    /// [`IocWork::work`]: ioctl::IocWork::work
+    pub fn fetch_checked_work(&self) -> Result<ioctl::Work> {
+        self.fetch_work().and_then(checked)
+    }
 
-        if Dir::In == urb.dir() && 0 < buffer_len {
-            assert_eq!(buffer_len as usize, urb.transfer_mut().len());
-            ioc_giveback.buffer = urb.transfer_mut().as_mut_ptr().cast();
+    /// Waits up to `timeout` for work. With
+    /// [`TimeoutMillis::Unlimited`] it only returns with work or an
+    /// error other than a timeout.
+    ///
+    /// Whether the kernel reports no work as `ETIMEDOUT` or, on the
+    /// non-blocking fd, as `EAGAIN`, it fails with:
+    ///
+    /// | Timeout | Error |
+    /// |---------|-------|
+    /// | [`TimeoutMillis::IMMEDIATE`] | [`Error::WouldBlock`] |
+    /// | any other | [`Error::TimedOut`] |
+    ///
+    /// An interrupted wait (`EINTR`) is retried with the time that
+    /// is left, see [`Controller::set_retry_on_eintr`]. Work of an
+    /// unknown type fails with [`Error::InvalidWork`], see
+    /// [`RawIocWork::decode`].
+    ///
+    /// [`RawIocWork::decode`]: ioctl::RawIocWork::decode
+    pub fn fetch_work_timeout(&self, timeout: TimeoutMillis) -> Result<ioctl::IocWork> {
+        let millis = match timeout {
+            TimeoutMillis::Unlimited => loop {
+                match self.fetch_work_timeout(TimeoutMillis::MAX) {
+                    Err(err) if err.is_timeout() => continue,
+                    result => return result,
+                }
+            },
+            TimeoutMillis::Time(time) => time.get(),
+        };
+        match self.dev.take_ready() {
+            Some(ready) => ready,
+            None => self.dev.fetch_work(millis),
         }
+    }
 
-        if ioctl::UrbType::Iso == urb.kind() {
-            ioc_giveback.iso_packets = urb.iso_packet_giveback_mut().as_mut_ptr();
-            ioc_giveback.packet_count = packet_count.try_into().unwrap();
-            ioc_giveback.error_count = urb.error_count().into();
-        }
+    /// Like [`WorkReceiver::fetch_work_timeout`], but tags the work
+    /// with its controller and sequence number.
+    pub fn fetch_tagged_work_timeout(&self, timeout: TimeoutMillis) -> Result<TaggedWork> {
+        self.fetch_work_timeout(timeout)
+            .map(|work| self.tagger.tag(work))
+    }
 
-        // SAFETY: All buffers are valid for the ioctl call
-        unsafe {
-            match ioctl::usb_vhci_giveback(self.dev, &raw mut ioc_giveback) {
-                Err(nix::Error::ECANCELED) | Ok(_) => Ok(()),
-                Err(nix) => Err(io::Error::from(nix)),
+    /// Waits up to `timeout` for work, which may be longer than a
+    /// single [`TimeoutMillis`] by fetching in steps. Fails with
+    /// [`Error::TimedOut`] if none arrived.
+    pub fn fetch_work_for(&self, timeout: Duration) -> Result<ioctl::IocWork> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let (step, last) = match TimeoutMillis::from_duration(remaining) {
+                Some(step) => (step, true),
+                None => (TimeoutMillis::MAX, false),
+            };
+            match self.fetch_work_timeout(step) {
+                Err(err) if err.is_timeout() && !last => continue,
+                Err(err) if err.is_timeout() => return Err(Error::TimedOut),
+                result => return result,
             }
         }
     }
 
-    pub fn port_disable(&self, port: Port) -> io::Result<()> {
-        let mut ioc_port_stat = ioctl::IocPortStat {
-            change: PortChange::ENABLE.bits(),
-            index: port.get(),
-            ..Default::default()
-        };
-
-        // SAFETY: Both the file descriptor and raw mut pointer
-        //         are valid for the duration of this ioctl call.
-        unsafe {
-            ioctl::usb_vhci_portstat(self.dev, &raw mut ioc_port_stat).map_err(io::Error::from)?
-        };
-        Ok(())
+    /// Fetches work without blocking, for driving the receiver
+    /// from a hand written future.
+    ///
+    /// `usb-vhci-iocifc` has no `poll` support, so there is no
+    /// readiness to register for, but see [`WorkReadiness`] for
+    /// event loops that need one. When no work is available, a
+    /// waiter thread blocks in the fetch ioctl on behalf of the
+    /// caller and wakes `cx` once work arrived or fetching failed.
+    /// There is one such thread per controller at a time, and it
+    /// ends with the wake. It outlives a dropped caller: it keeps
+    /// fetching until the next work item arrives, or until the
+    /// controller and all of its handles are dropped. The last drop
+    /// waits for it, up to 100 ms.
+    ///
+    /// This is cancellation safe: work the waiter thread fetched is
+    /// kept until the next poll or fetch of any receiver of the
+    /// controller, and it is written to `work` before
+    /// [`Poll::Ready`] is returned. Dropping the caller between two
+    /// polls loses nothing. A blocking fetch running next to a
+    /// pending poll can take newer work than the waiter thread
+    /// holds, though.
+    ///
+    /// [`WorkReadiness`]: crate::WorkReadiness
+    pub fn poll_fetch_work(
+        &self,
+        cx: &mut Context<'_>,
+        work: &mut ioctl::IocWork,
+    ) -> Poll<Result<()>> {
+        loop {
+            ready!(self.poll_ready(cx));
+            // Another receiver of the controller may have been faster.
+            if let Some(ready) = self.dev.take_ready() {
+                return Poll::Ready(ready.map(|fetched| *work = fetched));
+            }
+        }
     }
 
-    pub fn port_resumed(&self, port: Port) -> io::Result<()> {
-        let mut ioc_port_stat = ioctl::IocPortStat {
-            change: PortChange::SUSPEND.bits(),
-            index: port.get(),
-            ..Default::default()
-        };
-
-        // SAFETY: Both the file descriptor and raw mut pointer
-        //         are valid for the duration of this ioctl call.
-        unsafe {
-            ioctl::usb_vhci_portstat(self.dev, &raw mut ioc_port_stat).map_err(io::Error::from)?
+    /// Like [`WorkReceiver::poll_fetch_work`], but leaves the work
+    /// for the next fetch, e.g. [`WorkReceiver::try_fetch_work`]. It
+    /// is ready once there is work, or fetching failed.
+    pub fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut waiter = self.dev.waiter();
+        if waiter.ready.is_some() {
+            return Poll::Ready(());
+        }
+        if waiter.running {
+            waiter.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let ready = match self.dev.fetch_work(0) {
+            Err(err) if err.is_timeout() => match self.dev.spawn_waiter(&mut waiter) {
+                Ok(()) => {
+                    waiter.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                Err(err) => Err(err.into()),
+            },
+            ready => ready,
         };
-        Ok(())
+        waiter.ready = Some(ready);
+        Poll::Ready(())
     }
 
-    pub fn port_overcurrent(&self, port: Port, set: bool) -> io::Result<()> {
-        let mut ioc_port_stat = ioctl::IocPortStat {
-            change: PortChange::OVERCURRENT.bits(),
-            index: port.get(),
-            ..Default::default()
-        };
-        if set {
-            ioc_port_stat.status = PortStatus::OVERCURRENT.bits();
+    /// Iterates over incoming work, fetching with `timeout` each
+    /// time and checking it like [`WorkReceiver::fetch_checked_work`].
+    /// Timeouts are skipped, so the iterator only returns with work
+    /// or an error. The first failed fetch, e.g. with `ENODEV` after
+    /// the controller went away, ends it.
+    pub fn iter(&self, timeout: TimeoutMillis) -> WorkIter<'_> {
+        WorkIter {
+            recv: self,
+            timeout,
+            done: false,
         }
+    }
 
-        // SAFETY: Both the file descriptor and raw mut pointer
-        //         are valid for the duration of this ioctl call.
-        unsafe {
-            ioctl::usb_vhci_portstat(self.dev, &raw mut ioc_port_stat).map_err(io::Error::from)?
-        };
-        Ok(())
+    /// Takes work that is already queued, or returns `None`, e.g. to
+    /// drain the queue with `while let Some(work) =
+    /// recv.try_fetch_work()?`. Fails only if fetching does.
+    pub fn try_fetch_work(&self) -> Result<Option<ioctl::IocWork>> {
+        match self.fetch_work_timeout(TimeoutMillis::IMMEDIATE) {
+            Ok(work) => Ok(Some(work)),
+            Err(err) if err.is_timeout() => Ok(None),
+            Err(err) => Err(err),
+        }
     }
+}
 
-    pub fn port_reset_done(&self, port: Port, enable: bool) -> io::Result<()> {
-        let mut ioc_port_stat = ioctl::IocPortStat {
-            index: port.get(),
-            change: PortChange::RESET.bits(),
-            ..Default::default()
-        };
-        if enable {
-            ioc_port_stat.status = PortStatus::ENABLE.bits();
-        } else {
-            ioc_port_stat.change |= PortChange::ENABLE.bits();
+fn checked(work: ioctl::IocWork) -> Result<ioctl::Work> {
+    Ok(ioctl::Work::try_from(work)?)
+}
+
+/// Iterator over incoming work, see [`WorkReceiver::iter`].
+#[derive(Debug)]
+pub struct WorkIter<'a> {
+    recv: &'a WorkReceiver,
+    timeout: TimeoutMillis,
+    done: bool,
+}
+
+impl Iterator for WorkIter<'_> {
+    type Item = Result<ioctl::Work>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match self.recv.fetch_work_timeout(self.timeout) {
+                Ok(work) => return Some(checked(work)),
+                Err(err) if err.is_timeout() => continue,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
         }
+        None
+    }
+}
 
-        // SAFETY: Both the file descriptor and raw mut pointer
-        //         are valid for the duration of this ioctl call.
-        unsafe {
-            ioctl::usb_vhci_portstat(self.dev, &raw mut ioc_port_stat).map_err(io::Error::from)?
-        };
-        Ok(())
+impl std::iter::FusedIterator for WorkIter<'_> {}
+
+/// Calls `fetch` with the timeout in milliseconds until it isn't
+/// interrupted, or once if `retry` is false, and maps its errors as
+/// documented on [`WorkReceiver::fetch_work_timeout`].
+fn fetch_retrying(
+    millis: i16,
+    retry: bool,
+    mut fetch: impl FnMut(i16) -> nix::Result<()>,
+) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_millis(millis as u64);
+    let mut left = millis;
+    loop {
+        match fetch(left) {
+            Ok(()) => return Ok(()),
+            Err(Errno::EINTR) if retry => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // Both fit, `remaining` is at most `millis`.
+                left = remaining.as_millis() as i16;
+                if millis != 0 && left == 0 {
+                    return Err(Error::TimedOut);
+                }
+            }
+            Err(Errno::EAGAIN | Errno::ETIMEDOUT) if millis == 0 => return Err(Error::WouldBlock),
+            Err(Errno::EAGAIN | Errno::ETIMEDOUT) => return Err(Error::TimedOut),
+            Err(errno) => return Err(errno.into()),
+        }
     }
 }
 
+/// A [`WorkReceiver`] that borrows its [`Controller`], see
+/// [`Controller::work_receiver_scoped`].
 #[derive(Debug)]
-pub struct Controller {
-    dev: std::fs::File,
-    open_ports: BitVec,
-    controller_id: i32,
-    usb_busnum: i32,
-    bus_id: Box<str>,
-    work_recv_split: bool,
+pub struct WorkReceiverRef<'a> {
+    dev: &'a Arc<Device>,
+    tagger: &'a WorkTagger,
 }
 
-impl Controller {
-    pub fn open(num_ports: BoundedU8<1, 32>) -> io::Result<Self> {
-        let device = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .custom_flags(nix::libc::O_NONBLOCK)
-            .open(USB_VHCI_DEVICE_FILE)?;
+impl WorkReceiverRef<'_> {
+    fn receiver(&self) -> WorkReceiver {
+        WorkReceiver::new(Arc::clone(self.dev), self.tagger.clone())
+    }
 
-        let mut ioc_register = ioctl::IocRegister::new(num_ports.get());
+    /// See [`WorkReceiver::fetch_work`].
+    pub fn fetch_work(&self) -> Result<ioctl::IocWork> {
+        self.receiver().fetch_work()
+    }
 
-        // SAFETY: We are using a valid file descriptor that we
-        //         are sure will last for the entire duration of this
-        //         ioctl. We also pass in a valid pointer for this
-        //         ioctl's return type.
-        unsafe {
-            ioctl::usb_vhci_register(device.as_raw_fd(), &raw mut ioc_register)
-                .map_err(io::Error::from)?
-        };
+    /// See [`WorkReceiver::fetch_checked_work`].
+    pub fn fetch_checked_work(&self) -> Result<ioctl::Work> {
+        self.receiver().fetch_checked_work()
+    }
 
-        Ok(Self {
-            dev: device,
-            open_ports: BitVec::from_elem(num_ports.get() as usize, false),
-            controller_id: ioc_register.id,
-            usb_busnum: ioc_register.usb_busnum,
-            bus_id: ioc_register
-                .bus_id()
-                .to_str()
-                .map(|s| s.trim_end_matches('\0'))
-                .map(Box::from)
-                .unwrap(),
-            work_recv_split: false,
-        })
+    /// See [`WorkReceiver::fetch_work_timeout`].
+    pub fn fetch_work_timeout(&self, timeout: TimeoutMillis) -> Result<ioctl::IocWork> {
+        self.receiver().fetch_work_timeout(timeout)
     }
 
-    pub fn free_ports(&self) -> u64 {
-        self.open_ports.count_zeros()
+    /// See [`WorkReceiver::fetch_tagged_work_timeout`].
+    pub fn fetch_tagged_work_timeout(&self, timeout: TimeoutMillis) -> Result<TaggedWork> {
+        self.receiver().fetch_tagged_work_timeout(timeout)
     }
 
-    pub fn is_active(&self) -> bool {
-        !self.open_ports.none()
+    /// See [`WorkReceiver::fetch_work_for`].
+    pub fn fetch_work_for(&self, timeout: Duration) -> Result<ioctl::IocWork> {
+        self.receiver().fetch_work_for(timeout)
+    }
+
+    /// See [`WorkReceiver::try_fetch_work`].
+    pub fn try_fetch_work(&self) -> Result<Option<ioctl::IocWork>> {
+        self.receiver().try_fetch_work()
+    }
+
+    /// See [`WorkReceiver::poll_fetch_work`].
+    pub fn poll_fetch_work(
+        &self,
+        cx: &mut Context<'_>,
+        work: &mut ioctl::IocWork,
+    ) -> Poll<Result<()>> {
+        self.receiver().poll_fetch_work(cx, work)
+    }
+
+    /// See [`WorkReceiver::poll_ready`].
+    pub fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.receiver().poll_ready(cx)
     }
+}
 
-    /// Clones the underlying file descriptor into
-    /// an object with less capabilities than the
-    /// main controller.
+/// The port side of a [`Controller`] while its work is received
+/// elsewhere, see [`Controller::work_receiver_scoped`]. The methods
+/// are those of the controller.
+#[derive(Debug)]
+pub struct PortControl<'a> {
+    dev: &'a Arc<Device>,
+    open_ports: &'a mut BitVec,
+    port_rates: &'a mut [Option<DataRate>],
+    reserved_ports: &'a Arc<AtomicU32>,
+}
+
+impl PortControl<'_> {
     pub fn remote(&self) -> Remote {
-        Remote::new(self.dev.as_raw_fd())
+        Remote::new(Arc::clone(self.dev))
     }
 
-    pub fn work_receiver(&mut self) -> Option<WorkReceiver> {
-        if self.work_recv_split {
-            None
-        } else {
-            self.work_recv_split = true;
-            Some(WorkReceiver::new(self.dev.as_raw_fd()))
-        }
+    /// See [`Controller::free_ports`].
+    pub fn free_ports(&self) -> u64 {
+        unused_ports(self.open_ports, self.reserved_ports).count() as u64
     }
 
-    pub fn return_work_receiver(&mut self, _recv: WorkReceiver) {
-        self.work_recv_split = false;
+    /// See [`Controller::has_free_port`].
+    pub fn has_free_port(&self) -> bool {
+        unused_ports(self.open_ports, self.reserved_ports)
+            .next()
+            .is_some()
     }
 
-    pub fn fetch_work(&self) -> io::Result<ioctl::IocWork> {
-        const DEFAULT_TIMEOUT: TimeoutMillis = TimeoutMillis::Time(BoundedI16::new(100).unwrap());
-        self.fetch_work_timeout(DEFAULT_TIMEOUT)
+    /// See [`Controller::port_usage`].
+    pub fn port_usage(&self) -> PortUsage {
+        PortUsage::new(self.open_ports, self.reserved_ports)
     }
 
-    pub fn fetch_work_timeout(&self, timeout: TimeoutMillis) -> io::Result<ioctl::IocWork> {
-        if self.work_recv_split {
-            Err(io::Error::from(io::ErrorKind::AlreadyExists))?
-        } else {
-            WorkReceiver::new(self.dev.as_raw_fd()).fetch_work_timeout(timeout)
+    /// See [`Controller::try_connect_if`].
+    pub fn try_connect_if(
+        &mut self,
+        data_rate: DataRate,
+        predicate: impl FnOnce(u64) -> bool,
+    ) -> Result<Option<Port>> {
+        let Some(port) = unused_ports(self.open_ports, self.reserved_ports).next() else {
+            return Ok(None);
+        };
+        if !predicate(self.free_ports()) {
+            return Ok(None);
         }
+        self.port_connect_unchecked(port, data_rate)?;
+        Ok(Some(port))
     }
 
-    pub fn fetch_data(&self, urb: impl Urb + TransferMut + IsoPacketDataMut) -> io::Result<()> {
-        Remote::new(self.dev.as_raw_fd()).fetch_data(urb)
+    fn is_reserved(&self, port: Port) -> bool {
+        self.reserved_ports.load(Ordering::Acquire) & PortReservation::mask(port) != 0
     }
 
-    pub fn giveback(&self, urb: impl Urb + TransferMut + IsoPacketGivebackMut) -> io::Result<()> {
-        Remote::new(self.dev.as_raw_fd()).giveback(urb)
+    pub fn port_connect_any(&mut self, data_rate: DataRate) -> Result<Port> {
+        let port = unused_ports(self.open_ports, self.reserved_ports)
+            .next()
+            .ok_or(Error::NoFreePorts)?;
+        self.port_connect_unchecked(port, data_rate)?;
+        Ok(port)
     }
 
-    pub fn port_connect_any(&mut self, data_rate: DataRate) -> io::Result<Port> {
-        let port = Port::new(
-            self.open_ports
-                .iter()
-                .position(|in_use| !in_use)
-                .unwrap()
-                .add(1) as u8,
-        )
-        .unwrap();
-        self.port_connect(port, data_rate)?;
+    /// Index of `port` in `open_ports`, or an error if the
+    /// controller was registered with fewer ports.
+    fn port_index(&self, port: Port) -> Result<usize> {
+        let idx = usize::from(port.get() - 1);
+        if idx < self.open_ports.len() {
+            Ok(idx)
+        } else {
+            Err(Error::InvalidPort(port))
+        }
+    }
+
+    /// See [`Controller::port_connect`].
+    pub fn port_connect(&mut self, port: Port, data_rate: DataRate) -> Result<()> {
+        if self.open_ports[self.port_index(port)?] {
+            return Err(Error::PortInUse(port));
+        }
+        if self.is_reserved(port) {
+            return Err(Error::PortReserved(port));
+        }
+        self.port_connect_unchecked(port, data_rate)
+    }
+
+    /// See [`Controller::port_connect_reserved`].
+    pub fn port_connect_reserved(
+        &mut self,
+        reservation: PortReservation,
+        data_rate: DataRate,
+    ) -> Result<Port> {
+        if !Arc::ptr_eq(&reservation.reserved, self.reserved_ports) {
+            return Err(Error::ForeignReservation);
+        }
+        let port = reservation.port();
+        self.port_connect_unchecked(port, data_rate)?;
         Ok(port)
     }
 
-    pub fn port_connect(&mut self, port: Port, data_rate: DataRate) -> io::Result<()> {
+    fn port_connect_unchecked(&mut self, port: Port, data_rate: DataRate) -> Result<()> {
         let mut status = PortStatus::CONNECTION;
         match data_rate {
             DataRate::Full => (),
@@ -306,17 +665,21 @@ impl Controller {
 
         // SAFETY: Both the file descriptor and raw mut pointer
         //         are valid for the duration of this ioctl call.
-        unsafe {
+        self.dev.retrying(|| unsafe {
             ioctl::usb_vhci_portstat(self.dev.as_raw_fd(), &raw mut ioc_port_stat)
-                .map_err(io::Error::from)?
-        };
+        })?;
 
         self.open_ports.set(port.get().sub(1) as usize, true);
+        self.port_rates[port.get().sub(1) as usize] = Some(data_rate);
 
         Ok(())
     }
 
-    pub fn port_disconnect(&mut self, port: Port) -> io::Result<()> {
+    /// See [`Controller::port_disconnect`].
+    pub fn port_disconnect(&mut self, port: Port) -> Result<()> {
+        if !self.open_ports[self.port_index(port)?] {
+            return Err(Error::PortNotConnected(port));
+        }
         let mut ioc_port_stat = ioctl::IocPortStat {
             change: PortChange::CONNECTION.bits(),
             index: port.get(),
@@ -325,97 +688,1812 @@ impl Controller {
 
         // SAFETY: Both the file descriptor and raw mut pointer
         //         are valid for the duration of this ioctl call.
-        unsafe {
+        self.dev.retrying(|| unsafe {
             ioctl::usb_vhci_portstat(self.dev.as_raw_fd(), &raw mut ioc_port_stat)
-                .map_err(io::Error::from)?
-        };
+        })?;
 
         self.open_ports.set(port.get().sub(1) as usize, false);
+        self.port_rates[port.get().sub(1) as usize] = None;
         Ok(())
     }
+}
 
-    pub fn port_disable(&self, port: Port) -> io::Result<()> {
-        Remote::new(self.dev.as_raw_fd()).port_disable(port)
-    }
+/// How the ports of a controller are used, see
+/// [`Controller::port_usage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PortUsage {
+    pub total: u64,
+    pub connected: u64,
+
+    /// Ports held by a [`PortReservation`] and not yet connected.
+    pub reserved: u64,
+}
+
+/// A port of a controller, see [`Controller::ports`].
+#[derive(Debug, Clone, Copy)]
+pub struct PortInfo {
+    pub port: Port,
+
+    /// Whether a device is connected as far as the controller knows,
+    /// i.e. it was connected and the host did not report it gone.
+    pub connected: bool,
 
-    pub fn port_resumed(&self, port: Port) -> io::Result<()> {
-        Remote::new(self.dev.as_raw_fd()).port_resumed(port)
+    /// Rate the device was connected at, `None` if there is none.
+    pub data_rate: Option<DataRate>,
+
+    /// Last status the host reported, if any, see
+    /// [`Controller::note_port_stat`].
+    pub status: Option<PortStatus>,
+}
+
+impl PortUsage {
+    fn new(open_ports: &BitVec, reserved_ports: &AtomicU32) -> Self {
+        let reserved = reserved_ports.load(Ordering::Acquire);
+        let (mut connected, mut held) = (0, 0);
+        for (idx, in_use) in open_ports.iter().enumerate() {
+            if in_use {
+                connected += 1;
+            } else if reserved & (1 << idx) != 0 {
+                held += 1;
+            }
+        }
+        Self {
+            total: open_ports.len() as u64,
+            connected,
+            reserved: held,
+        }
     }
 
-    pub fn port_overcurrent(&self, port: Port, set: bool) -> io::Result<()> {
-        Remote::new(self.dev.as_raw_fd()).port_overcurrent(port, set)
+    /// Ports that are neither connected nor reserved.
+    pub const fn free(&self) -> u64 {
+        self.total - self.connected - self.reserved
     }
+}
+
+/// Ports that are neither connected nor reserved.
+fn unused_ports<'a>(
+    open_ports: &'a BitVec,
+    reserved_ports: &AtomicU32,
+) -> impl Iterator<Item = Port> + 'a {
+    let reserved = reserved_ports.load(Ordering::Acquire);
+    open_ports
+        .iter()
+        .enumerate()
+        .filter(|&(_, in_use)| !in_use)
+        .map(|(idx, _)| Port::new(idx.add(1) as u8).unwrap())
+        .filter(move |&port| reserved & PortReservation::mask(port) == 0)
+}
 
-    pub fn port_reset_done(&self, port: Port, enable: bool) -> io::Result<()> {
-        Remote::new(self.dev.as_raw_fd()).port_reset_done(port, enable)
+/// Maps the errors of the URB ioctls.
+fn urb_error(errno: Errno) -> Error {
+    match errno {
+        Errno::ENODEV => Error::ControllerGone,
+        errno => errno.into(),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use utils::{BoundedI16, BoundedU8, TimeoutMillis};
+/// Result of [`Remote::fetch_data`].
+#[must_use]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchOutcome {
+    /// The URB's data was copied and it can be processed.
+    Fetched,
 
-    use crate::{utils, PortFlag};
+    /// The host canceled the URB in the meantime.
+    Canceled,
+}
 
-    use super::*;
+/// Result of [`Remote::giveback`].
+#[must_use]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GivebackOutcome {
+    /// The URB was completed with our data and status.
+    Completed,
 
-    const NUM_PORTS: BoundedU8<1, 32> = BoundedU8::new(1).unwrap();
+    /// The host had already canceled the URB, so the kernel
+    /// ignored the giveback.
+    AlreadyCanceled,
+}
 
-    #[test]
-    fn invalid_fd_fails() {
-        let mut vhci = Controller::open(NUM_PORTS).unwrap();
-        let remote = vhci.remote();
-        let port = vhci.port_connect_any(DataRate::Full).unwrap();
-        drop(vhci);
-        dbg!(remote.port_reset_done(port, true).unwrap_err());
+/// A handle to a [`Controller`] for other threads.
+///
+/// It can do everything but connect and disconnect ports and fetch
+/// work. Narrower handles can be split off to pass to code that
+/// should only do one thing:
+///
+/// | Handle            | fetch_data, giveback | reset_done, resumed, suspended, overcurrent | disable, port_update |
+/// |-------------------|:---:|:---:|:---:|
+/// | [`Remote`]        | yes | yes | yes |
+/// | [`GivebackHandle`] | yes | no  | no  |
+/// | [`PortSignaler`]  | no  | yes | no  |
+///
+/// All handles share the device fd with their [`Controller`], and
+/// clones share it too. The kernel keeps the virtual controller and
+/// its ports until the last of them is dropped, so a `Remote` stays
+/// usable after the `Controller` is gone, and can never end up on a
+/// closed or reused fd number.
+#[derive(Debug, Clone)]
+pub struct Remote {
+    dev: Arc<Device>,
+}
+
+impl Remote {
+    const fn new(dev: Arc<Device>) -> Self {
+        Self { dev }
     }
 
-    #[test]
-    fn can_create_vhci() {
-        let _vhci = Controller::open(NUM_PORTS).unwrap();
+    pub fn giveback_handle(&self) -> GivebackHandle {
+        GivebackHandle(self.clone())
     }
 
-    #[test]
-    fn can_connect_disconnect_port() {
-        let mut vhci = Controller::open(NUM_PORTS).unwrap();
-        let port = vhci.port_connect_any(DataRate::High).unwrap();
-        vhci.port_disconnect(port).unwrap();
+    pub fn port_signaler(&self) -> PortSignaler {
+        PortSignaler(self.clone())
     }
 
-    #[test]
-    fn can_fetch_work() {
-        let num_ports = BoundedU8::new(2).unwrap();
-        let mut vhci = Controller::open(num_ports).unwrap();
-        let mut prev = ioctl::IocPortStat::default();
-
-        let _urb = loop {
-            let timeout = TimeoutMillis::Time(BoundedI16::new(500).unwrap());
-            let work = vhci.fetch_work_timeout(timeout).unwrap();
-            // SAFETY: We don't alter the `typ` field, which
-            //         satisfies the safety constraints
-            match unsafe { work.into_inner() } {
-                ioctl::Work::ProcessUrb((urb, _handle)) => break urb,
-                ioctl::Work::CancelUrb(_handle) => unreachable!(),
-                ioctl::Work::PortStat(next) => {
-                    if (!prev.status()).contains(PortStatus::POWER)
-                        && next.status().contains(PortStatus::POWER)
-                    {
-                        vhci.port_connect(next.index(), DataRate::Full).unwrap();
-                    } else if (!prev.status()).contains(PortStatus::RESET)
-                        && next
-                            .status()
-                            .contains(PortStatus::RESET | PortStatus::CONNECTION)
-                    {
-                        vhci.port_reset_done(next.index(), true).unwrap();
-                    } else if (!prev.flags()).contains(PortFlag::RESUMING)
-                        && next.flags().contains(PortFlag::RESUMING)
-                        && next.status().contains(PortStatus::CONNECTION)
+    /// Copies the data of an OUT URB and the packet layout of an
+    /// isochronous URB from the kernel, see
+    /// [`UrbWithData::needs_fetch_data`].
+    ///
+    /// The kernel answers with `ECANCELED` if the host canceled the
+    /// URB after it was fetched, which is reported as
+    /// [`FetchOutcome::Canceled`]. The URB is then already completed
+    /// on the kernel side: drop it without processing it. A giveback
+    /// is not needed, and [`Remote::giveback`] would ignore it anyway.
+    /// A [`CancelUrb`] work item for the same handle may still follow.
+    ///
+    /// [`UrbWithData::needs_fetch_data`]: crate::UrbWithData::needs_fetch_data
+    /// [`CancelUrb`]: ioctl::Work::CancelUrb
+    pub fn fetch_data(
+        &self,
+        mut urb: impl Urb + IsoPacketDataMut + TransferMut,
+    ) -> Result<FetchOutcome> {
+        let buffer_length = urb
+            .transfer_mut()
+            .len()
+            .try_into()
+            .map_err(|_| Error::InvalidUrb("transfer buffer is too large"))?;
+        let buffer = urb.transfer_mut().as_mut_ptr().cast();
+        let packet_count = urb.iso_packet_data_mut().len();
+        if packet_count > MAX_ISO_PACKETS {
+            return Err(Error::InvalidUrb("too many iso packets"));
+        }
+
+        let mut ioc_urb_data = ioctl::IocUrbData {
+            handle: urb.handle().get(),
+            buffer,
+            buffer_length,
+            ..Default::default()
+        };
+
+        if 0 < packet_count {
+            ioc_urb_data.iso_packets = urb.iso_packet_data_mut().as_mut_ptr();
+            ioc_urb_data.packet_count = packet_count as i32;
+        }
+
+        // SAFETY:
+        // - `ioc_iso_packets` is valid and initialized for the ioctl call
+        // - transfer buffer is initialized and its length does not change
+        let result = self.dev.retrying(|| unsafe {
+            ioctl::usb_vhci_fetchdata(self.dev.as_raw_fd(), &raw mut ioc_urb_data)
+        });
+        match result {
+            Ok(_) => Ok(FetchOutcome::Fetched),
+            Err(Errno::ECANCELED) => Ok(FetchOutcome::Canceled),
+            Err(errno) => Err(urb_error(errno)),
+        }
+    }
+
+    /// Completes an URB. If the host canceled it in the meantime,
+    /// the kernel discards the data and the status and this returns
+    /// [`GivebackOutcome::AlreadyCanceled`]. Fails with
+    /// [`Error::ControllerGone`] once the kernel tore the controller
+    /// down.
+    pub fn giveback(
+        &self,
+        mut urb: impl Urb + IsoPacketGivebackMut + TransferMut,
+    ) -> Result<GivebackOutcome> {
+        let packet_count = urb.iso_packet_giveback_mut().len();
+        let buffer_len = urb.bytes_transferred();
+        if packet_count > MAX_ISO_PACKETS {
+            return Err(Error::InvalidUrb("too many iso packets"));
+        }
+        let Ok(buffer_actual) = i32::try_from(buffer_len) else {
+            return Err(Error::InvalidUrb("transfer too large"));
+        };
+
+        let mut ioc_giveback = ioctl::IocGiveback {
+            handle: urb.handle().get(),
+            status: urb.status().to_errno_raw(ioctl::UrbType::Iso == urb.kind()),
+            buffer_actual,
+            ..Default::default()
+        };
+
+        if Dir::In == urb.dir() && 0 < buffer_len {
+            if buffer_len != urb.transfer_mut().len() {
+                return Err(Error::InvalidUrb(
+                    "transfer length differs from bytes transferred",
+                ));
+            }
+            ioc_giveback.buffer = urb.transfer_mut().as_mut_ptr().cast();
+        }
+
+        if ioctl::UrbType::Iso == urb.kind() {
+            ioc_giveback.iso_packets = urb.iso_packet_giveback_mut().as_mut_ptr();
+            ioc_giveback.packet_count = packet_count as i32;
+            ioc_giveback.error_count = urb.error_count().into();
+        }
+
+        // SAFETY: All buffers are valid for the ioctl call
+        let result = self.dev.retrying(|| unsafe {
+            ioctl::usb_vhci_giveback(self.dev.as_raw_fd(), &raw mut ioc_giveback)
+        });
+        match result {
+            Ok(_) => Ok(GivebackOutcome::Completed),
+            Err(Errno::ECANCELED) => Ok(GivebackOutcome::AlreadyCanceled),
+            Err(errno) => Err(urb_error(errno)),
+        }
+    }
+
+    /// Sends a port status update, see [`PortUpdate`]. An update
+    /// [`PortUpdate::try_build`] rejects fails with
+    /// [`Error::InvalidPortUpdate`].
+    pub fn port_update(&self, update: PortUpdate) -> Result<()> {
+        let mut ioc_port_stat = update.try_build()?;
+
+        // SAFETY: Both the file descriptor and raw mut pointer
+        //         are valid for the duration of this ioctl call.
+        self.dev.retrying(|| unsafe {
+            ioctl::usb_vhci_portstat(self.dev.as_raw_fd(), &raw mut ioc_port_stat)
+        })?;
+        Ok(())
+    }
+
+    pub fn port_disable(&self, port: Port) -> Result<()> {
+        self.port_update(PortUpdate::new(port).enable(false))
+    }
+
+    /// Reports that `port` has entered suspend.
+    ///
+    /// The suspend handshake goes as follows:
+    ///
+    /// 1. The host suspends the port. The next [`IocPortStat`] work
+    ///    item for it has [`PortStatus::SUSPEND`] set.
+    /// 2. We answer with this method, which sets the SUSPEND status
+    ///    bit together with its change bit. The device should stop
+    ///    completing interrupt URBs until it is resumed.
+    /// 3. The host resumes the port. The next work item for it has
+    ///    [`PortFlag::RESUMING`] set while SUSPEND is still set.
+    /// 4. We answer with [`Remote::port_resumed`], which clears the
+    ///    SUSPEND status bit and sets its change bit.
+    ///
+    /// [`IocPortStat`]: ioctl::IocPortStat
+    /// [`PortFlag::RESUMING`]: crate::PortFlag::RESUMING
+    pub fn port_suspended(&self, port: Port) -> Result<()> {
+        self.port_update(PortUpdate::new(port).suspend(true))
+    }
+
+    /// Reports that `port` has finished resuming. See
+    /// [`Remote::port_suspended`] for the full handshake.
+    pub fn port_resumed(&self, port: Port) -> Result<()> {
+        self.port_update(PortUpdate::new(port).suspend(false))
+    }
+
+    pub fn port_overcurrent(&self, port: Port, set: bool) -> Result<()> {
+        self.port_update(PortUpdate::new(port).overcurrent(set))
+    }
+
+    pub fn port_reset_done(&self, port: Port, enable: bool) -> Result<()> {
+        self.port_update(PortUpdate::new(port).reset_done(enable))
+    }
+}
+
+/// A handle that can only move URB data, i.e. what a device's
+/// transfer handlers need. See [`Remote`] for the other handles.
+///
+/// ```compile_fail
+/// # use usb_vhci::{GivebackHandle, Port};
+/// fn disable(handle: &GivebackHandle, port: Port) {
+///     handle.port_disable(port);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct GivebackHandle(Remote);
+
+impl GivebackHandle {
+    /// See [`Remote::fetch_data`].
+    pub fn fetch_data(
+        &self,
+        urb: impl Urb + IsoPacketDataMut + TransferMut,
+    ) -> Result<FetchOutcome> {
+        self.0.fetch_data(urb)
+    }
+
+    /// See [`Remote::giveback`].
+    pub fn giveback(
+        &self,
+        urb: impl Urb + IsoPacketGivebackMut + TransferMut,
+    ) -> Result<GivebackOutcome> {
+        self.0.giveback(urb)
+    }
+}
+
+/// A handle that can only answer the host's port requests. It
+/// cannot disable ports or touch URBs. See [`Remote`] for the other
+/// handles.
+///
+/// ```compile_fail
+/// # use usb_vhci::{PortSignaler, UrbWithData};
+/// fn complete(signaler: &PortSignaler, urb: &mut UrbWithData) {
+///     signaler.giveback(urb);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PortSignaler(Remote);
+
+impl PortSignaler {
+    /// See [`Remote::port_reset_done`].
+    pub fn port_reset_done(&self, port: Port, enable: bool) -> Result<()> {
+        self.0.port_reset_done(port, enable)
+    }
+
+    /// See [`Remote::port_suspended`].
+    pub fn port_suspended(&self, port: Port) -> Result<()> {
+        self.0.port_suspended(port)
+    }
+
+    /// See [`Remote::port_resumed`].
+    pub fn port_resumed(&self, port: Port) -> Result<()> {
+        self.0.port_resumed(port)
+    }
+
+    /// See [`Remote::port_overcurrent`].
+    pub fn port_overcurrent(&self, port: Port, set: bool) -> Result<()> {
+        self.0.port_overcurrent(port, set)
+    }
+}
+
+/// A port set aside by [`Controller::reserve_port`].
+///
+/// While it exists, the port counts as used and no other caller
+/// can connect to it. Dropping the reservation releases the port.
+#[derive(Debug)]
+pub struct PortReservation {
+    port: Port,
+    reserved: Arc<AtomicU32>,
+}
+
+impl PortReservation {
+    pub const fn port(&self) -> Port {
+        self.port
+    }
+
+    /// Connects a device to the reserved port. See
+    /// [`Controller::port_connect_reserved`].
+    pub fn connect(self, controller: &mut Controller, data_rate: DataRate) -> Result<Port> {
+        controller.port_connect_reserved(self, data_rate)
+    }
+
+    const fn mask(port: Port) -> u32 {
+        1 << (port.get() - 1)
+    }
+}
+
+impl Drop for PortReservation {
+    fn drop(&mut self) {
+        self.reserved
+            .fetch_and(!Self::mask(self.port), Ordering::AcqRel);
+    }
+}
+
+/// Point in a port's lifecycle that [`Controller::wait_for`]
+/// can wait for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortMilestone {
+    /// The host has powered the port.
+    Powered,
+
+    /// The host has reset the port and we have completed the reset.
+    ResetComplete,
+
+    /// The port is connected, enabled, and not being reset.
+    Enabled,
+
+    /// The device on the port was given an address. Only SET_ADDRESS
+    /// requests answered through [`Controller::wait_for_answering`]
+    /// or reported with [`Controller::note_completed_urb`] count.
+    Addressed,
+}
+
+#[derive(Debug)]
+pub enum WaitError {
+    /// The milestone was not reached before the timeout.
+    TimedOut,
+
+    /// The device was disconnected while waiting.
+    Disconnected,
+
+    Controller(Error),
+}
+
+impl std::fmt::Display for WaitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitError::TimedOut => f.write_str("timed out waiting for port milestone"),
+            WaitError::Disconnected => f.write_str("port was disconnected while waiting"),
+            WaitError::Controller(err) => write!(f, "failed to drive the port: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for WaitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WaitError::Controller(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for WaitError {
+    fn from(value: Error) -> Self {
+        Self::Controller(value)
+    }
+}
+
+/// A virtual host controller registered with the kernel.
+///
+/// # Event loops
+///
+/// The device fd is available through [`AsFd`] and [`AsRawFd`], on
+/// the controller as well as on its [`Remote`]s and
+/// [`WorkReceiver`]s, e.g. to add it to an epoll set next to
+/// sockets. `usb-vhci-iocifc` has no `poll` support, so the kernel
+/// reports the fd as readable at all times. Readable only means
+/// that a fetch won't block, which is true for
+/// [`Controller::try_fetch_work`] anyway. It doesn't mean that work
+/// is queued. Drain the work after every wakeup, and wake up on a
+/// timer as well, so work that arrives while the other fds are
+/// quiet is picked up:
+///
+/// ```no_run
+/// # use std::os::fd::AsFd;
+/// # use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+/// # use usb_vhci::{utils::BoundedU8, Controller};
+/// # fn handle_socket() {}
+/// # let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+/// let vhci = Controller::open(BoundedU8::new(1).unwrap())?;
+/// loop {
+///     let mut fds = [PollFd::new(socket.as_fd(), PollFlags::POLLIN)];
+///     poll(&mut fds, PollTimeout::from(10u8))?;
+///     if fds[0].any() == Some(true) {
+///         handle_socket();
+///     }
+///     while let Some(work) = vhci.try_fetch_work()? {
+///         println!("{:?}", work.get());
+///     }
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct Controller {
+    dev: Arc<Device>,
+    open_ports: BitVec,
+    /// Rate each connected port was connected at.
+    port_rates: Vec<Option<DataRate>>,
+    controller_id: i32,
+    usb_busnum: i32,
+    tagger: WorkTagger,
+    bus_id: Box<str>,
+    work_recv_split: AtomicBool,
+    reserved_ports: Arc<AtomicU32>,
+    port_tracker: PortStateTracker,
+    addresses: DeviceAddressMap,
+    buffered_work: VecDeque<ioctl::IocWork>,
+}
+
+impl Controller {
+    /// Registers a controller with `num_ports` ports through
+    /// `/dev/usb-vhci`, see [`Controller::open_path`].
+    pub fn open(num_ports: BoundedU8<1, 32>) -> Result<Self> {
+        Self::open_path(USB_VHCI_DEVICE_FILE, num_ports)
+    }
+
+    /// Like [`Controller::open`], for a device node at another
+    /// path, e.g. one renamed by udev or bind-mounted into a
+    /// container.
+    ///
+    /// Fails with [`Error::DeviceNotFound`] if there is nothing at
+    /// `path`, with [`Error::PermissionDenied`] if it can't be
+    /// opened, and with [`Error::NotVhciDevice`] if it is something
+    /// else.
+    pub fn open_path(path: impl AsRef<Path>, num_ports: BoundedU8<1, 32>) -> Result<Self> {
+        let path = path.as_ref();
+        let device = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            // std already sets O_CLOEXEC, spelled out so the fd
+            // never leaks into child processes by accident.
+            .custom_flags(nix::libc::O_NONBLOCK | nix::libc::O_CLOEXEC)
+            .open(path)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => Error::DeviceNotFound(path.to_path_buf()),
+                io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.to_path_buf()),
+                _ => err.into(),
+            })?;
+
+        Self::register(device, num_ports).map_err(|err| match err {
+            // Files and other devices don't know the ioctl.
+            Error::Ioctl(Errno::ENOTTY) => Error::NotVhciDevice(path.to_path_buf()),
+            err => err,
+        })
+    }
+
+    /// Registers a controller through `fd`, an already open
+    /// `/dev/usb-vhci`, e.g. one received from a privileged process.
+    /// The controller takes ownership of `fd` and makes it
+    /// nonblocking.
+    ///
+    /// Fails with [`Error::Ioctl`] and the errno of the register
+    /// ioctl if `fd` is not a vhci device or was already registered.
+    pub fn from_fd(fd: OwnedFd, num_ports: BoundedU8<1, 32>) -> Result<Self> {
+        let device = std::fs::File::from(fd);
+        // SAFETY: F_GETFL takes no argument and the fd is valid.
+        let flags = unsafe { nix::libc::fcntl(device.as_raw_fd(), nix::libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if flags & nix::libc::O_NONBLOCK == 0 {
+            let flags = flags | nix::libc::O_NONBLOCK;
+            // SAFETY: F_SETFL takes an int argument and the fd is valid.
+            if unsafe { nix::libc::fcntl(device.as_raw_fd(), nix::libc::F_SETFL, flags) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Self::register(device, num_ports)
+    }
+
+    fn register(device: std::fs::File, num_ports: BoundedU8<1, 32>) -> Result<Self> {
+        let mut ioc_register = ioctl::IocRegister::new(num_ports.get());
+
+        // SAFETY: We are using a valid file descriptor that we
+        //         are sure will last for the entire duration of this
+        //         ioctl. We also pass in a valid pointer for this
+        //         ioctl's return type.
+        unsafe { ioctl::usb_vhci_register(device.as_raw_fd(), &raw mut ioc_register)? };
+
+        Ok(Self {
+            dev: Arc::new(OwnedFd::from(device).into()),
+            open_ports: BitVec::from_elem(num_ports.get() as usize, false),
+            port_rates: vec![None; num_ports.get() as usize],
+            controller_id: ioc_register.id,
+            usb_busnum: ioc_register.usb_busnum,
+            tagger: WorkTagger::new(ioc_register.id, ioc_register.usb_busnum),
+            bus_id: ioc_register
+                .bus_id()
+                .to_str()
+                .map(|s| s.trim_end_matches('\0'))
+                .map(Box::from)
+                .unwrap(),
+            work_recv_split: AtomicBool::new(false),
+            reserved_ports: Arc::new(AtomicU32::new(0)),
+            port_tracker: PortStateTracker::new(),
+            addresses: DeviceAddressMap::new(),
+            buffered_work: VecDeque::new(),
+        })
+    }
+
+    /// Name of the controller's platform device, e.g. `vhci_hcd.0`,
+    /// as found under `/sys/bus/platform/devices`.
+    pub fn bus_id(&self) -> &str {
+        &self.bus_id
+    }
+
+    /// Id the kernel assigned to the controller.
+    pub const fn controller_id(&self) -> i32 {
+        self.controller_id
+    }
+
+    /// Number of the USB bus the controller's root hub is on, as in
+    /// `/sys/bus/usb/devices/usb<N>`.
+    pub const fn usb_busnum(&self) -> i32 {
+        self.usb_busnum
+    }
+
+    /// Number of ports the controller was registered with.
+    pub fn num_ports(&self) -> BoundedU8<1, 32> {
+        BoundedU8::new(self.open_ports.len() as u8).unwrap()
+    }
+
+    /// Whether the device fd is inherited by child processes. It is
+    /// not by default.
+    pub fn is_inheritable(&self) -> Result<bool> {
+        // SAFETY: F_GETFD takes no argument and the fd is valid.
+        let flags = unsafe { nix::libc::fcntl(self.dev.as_raw_fd(), nix::libc::F_GETFD) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(flags & nix::libc::FD_CLOEXEC == 0)
+    }
+
+    /// Lets child processes inherit the device fd, or stops them
+    /// from doing so. The fd is shared by every [`Remote`] and
+    /// [`WorkReceiver`] of this controller.
+    pub fn set_inheritable(&self, inheritable: bool) -> Result<()> {
+        let flags = if inheritable {
+            0
+        } else {
+            nix::libc::FD_CLOEXEC
+        };
+        // SAFETY: F_SETFD takes an int argument and the fd is valid.
+        if unsafe { nix::libc::fcntl(self.dev.as_raw_fd(), nix::libc::F_SETFD, flags) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Whether ioctls interrupted by a signal (`EINTR`) are retried.
+    /// They are by default.
+    pub fn retries_on_eintr(&self) -> bool {
+        self.dev.retries_on_eintr()
+    }
+
+    /// Retries ioctls interrupted by a signal, or lets them fail with
+    /// [`Error::Ioctl`] and [`Errno::EINTR`], e.g. to stop waiting for
+    /// work on a signal. Fetches are retried with the time left of
+    /// their timeout. Applies to every [`Remote`] and
+    /// [`WorkReceiver`] of this controller.
+    pub fn set_retry_on_eintr(&self, retry: bool) {
+        self.dev.retry_on_eintr.store(retry, Ordering::Relaxed);
+    }
+
+    /// Number of ports that are neither connected nor reserved.
+    pub fn free_ports(&self) -> u64 {
+        unused_ports(&self.open_ports, &self.reserved_ports).count() as u64
+    }
+
+    /// Whether [`Controller::free_ports`] is not 0, i.e. whether
+    /// [`Controller::port_connect_any`] can find a port.
+    pub fn has_free_port(&self) -> bool {
+        unused_ports(&self.open_ports, &self.reserved_ports)
+            .next()
+            .is_some()
+    }
+
+    /// Sets aside a free port without telling the kernel, so that
+    /// it can be connected later with [`PortReservation::connect`].
+    /// Returns `None` if every port is connected or reserved.
+    pub fn reserve_port(&mut self) -> Option<PortReservation> {
+        let port = unused_ports(&self.open_ports, &self.reserved_ports).next()?;
+        self.reserved_ports
+            .fetch_or(PortReservation::mask(port), Ordering::AcqRel);
+        Some(PortReservation {
+            port,
+            reserved: Arc::clone(&self.reserved_ports),
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.open_ports.none()
+    }
+
+    /// Connected, reserved and total ports, read together.
+    pub fn port_usage(&self) -> PortUsage {
+        PortUsage::new(&self.open_ports, &self.reserved_ports)
+    }
+
+    /// A handle with less capabilities than the main controller. It
+    /// shares the device fd with the controller instead of
+    /// duplicating it and keeps it open after the controller is
+    /// dropped, see [`Remote`].
+    pub fn remote(&self) -> Remote {
+        Remote::new(Arc::clone(&self.dev))
+    }
+
+    /// See [`Remote::giveback_handle`].
+    pub fn giveback_handle(&self) -> GivebackHandle {
+        self.remote().giveback_handle()
+    }
+
+    /// See [`Remote::port_signaler`].
+    pub fn port_signaler(&self) -> PortSignaler {
+        self.remote().port_signaler()
+    }
+
+    /// Splits off the receiving end of the work queue, e.g. to
+    /// fetch work on another thread. Returns `None` while a receiver
+    /// is already out, even if several threads ask at once.
+    ///
+    /// While split, [`Controller::fetch_work`] and
+    /// [`Controller::fetch_work_timeout`] fail with
+    /// [`Error::WorkReceiverOut`] instead of racing the receiver for work,
+    /// until the receiver is given to
+    /// [`Controller::return_work_receiver`].
+    pub fn work_receiver(&self) -> Option<WorkReceiver> {
+        self.work_recv_split
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| WorkReceiver::new(Arc::clone(&self.dev), self.tagger.clone()))
+    }
+
+    /// Splits the controller for the duration of a borrow, e.g. to
+    /// fetch work on a scoped thread while connecting ports on this
+    /// one. Unlike [`Controller::work_receiver`], the borrow checker
+    /// keeps the controller from fetching work meanwhile, and there
+    /// is nothing to return.
+    ///
+    /// Returns `None` while an owned [`WorkReceiver`] is out.
+    pub fn work_receiver_scoped(&mut self) -> Option<(WorkReceiverRef<'_>, PortControl<'_>)> {
+        if *self.work_recv_split.get_mut() {
+            return None;
+        }
+        let dev = &self.dev;
+        Some((
+            WorkReceiverRef {
+                dev,
+                tagger: &self.tagger,
+            },
+            PortControl {
+                dev,
+                open_ports: &mut self.open_ports,
+                port_rates: &mut self.port_rates,
+                reserved_ports: &self.reserved_ports,
+            },
+        ))
+    }
+
+    /// Takes back the receiver split off with
+    /// [`Controller::work_receiver`], so this controller fetches
+    /// work again. A receiver of another controller is handed back
+    /// in the `Err`.
+    pub fn return_work_receiver(&self, recv: WorkReceiver) -> Result<(), WorkReceiver> {
+        // Receivers aren't Clone and only `work_receiver` lets one
+        // out, so sharing our fd makes it the one that is out.
+        if !Arc::ptr_eq(&recv.dev, &self.dev) {
+            return Err(recv);
+        }
+        self.work_recv_split.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// See [`WorkReceiver::fetch_work`]. Fails like
+    /// [`Controller::fetch_work_timeout`].
+    pub fn fetch_work(&self) -> Result<ioctl::IocWork> {
+        const DEFAULT_TIMEOUT: TimeoutMillis = TimeoutMillis::Time(BoundedI16::new(100).unwrap());
+        self.fetch_work_timeout(DEFAULT_TIMEOUT)
+    }
+
+    /// See [`WorkReceiver::fetch_checked_work`]. Fails like
+    /// [`Controller::fetch_work_timeout`].
+    pub fn fetch_checked_work(&self) -> Result<ioctl::Work> {
+        self.fetch_work().and_then(checked)
+    }
+
+    pub fn fetch_work_timeout(&self, timeout: TimeoutMillis) -> Result<ioctl::IocWork> {
+        if self.work_recv_split.load(Ordering::Acquire) {
+            Err(Error::WorkReceiverOut)
+        } else {
+            self.receiver().fetch_work_timeout(timeout)
+        }
+    }
+
+    /// See [`WorkReceiver::fetch_tagged_work_timeout`]. Fails like
+    /// [`Controller::fetch_work_timeout`].
+    pub fn fetch_tagged_work_timeout(&self, timeout: TimeoutMillis) -> Result<TaggedWork> {
+        self.fetch_work_timeout(timeout)
+            .map(|work| self.tagger.tag(work))
+    }
+
+    /// See [`WorkReceiver::fetch_work_for`]. Fails like
+    /// [`Controller::fetch_work_timeout`].
+    pub fn fetch_work_for(&self, timeout: Duration) -> Result<ioctl::IocWork> {
+        if self.work_recv_split.load(Ordering::Acquire) {
+            Err(Error::WorkReceiverOut)
+        } else {
+            self.receiver().fetch_work_for(timeout)
+        }
+    }
+
+    /// See [`WorkReceiver::try_fetch_work`]. Fails like
+    /// [`Controller::fetch_work_timeout`].
+    pub fn try_fetch_work(&self) -> Result<Option<ioctl::IocWork>> {
+        if self.work_recv_split.load(Ordering::Acquire) {
+            Err(Error::WorkReceiverOut)
+        } else {
+            self.receiver().try_fetch_work()
+        }
+    }
+
+    fn receiver(&self) -> WorkReceiver {
+        WorkReceiver::new(Arc::clone(&self.dev), self.tagger.clone())
+    }
+
+    /// Drives the fetch loop until `port` reaches `milestone`,
+    /// completing any resets and resumes the host requests along the
+    /// way.
+    ///
+    /// Port-stat work for `port` is consumed. All other work is
+    /// kept and can be replayed with [`Controller::pop_buffered_work`]
+    /// or [`Controller::drain_buffered_work`] once this returns; the
+    /// fetch methods don't return it. Port stats of other ports are
+    /// recorded with [`Controller::note_port_stat`] and their resets
+    /// and resumes answered before they are kept, so waiting for
+    /// another port afterwards sees them. Only the latest stat of
+    /// each port is kept, and [`Controller::port_stat_events`]
+    /// reports nothing new for it when it is replayed.
+    ///
+    /// The URBs are buffered as well, so nobody answers SET_ADDRESS
+    /// and [`PortMilestone::Addressed`] is only reached if the device
+    /// was addressed before. See [`Controller::wait_for_answering`].
+    pub fn wait_for(
+        &mut self,
+        port: Port,
+        milestone: PortMilestone,
+        timeout: Duration,
+    ) -> Result<(), WaitError> {
+        self.wait_until(port, milestone, timeout, None)
+    }
+
+    /// Like [`Controller::wait_for`], but hands the URBs fetched while
+    /// waiting to `answer` and gives them back, e.g. to let a device
+    /// enumerate until it is [`PortMilestone::Addressed`]. The data of
+    /// OUT URBs is fetched before `answer` is called. The URBs of
+    /// every port go to `answer`; only `CancelUrb` work is buffered.
+    ///
+    /// SET_ADDRESS requests to the device in the default state are
+    /// answered here and don't reach `answer`, see
+    /// [`DeviceAddressMap::answer_set_address`].
+    pub fn wait_for_answering(
+        &mut self,
+        port: Port,
+        milestone: PortMilestone,
+        timeout: Duration,
+        mut answer: impl FnMut(&mut UrbWithData),
+    ) -> Result<(), WaitError> {
+        self.wait_until(port, milestone, timeout, Some(&mut answer))
+    }
+
+    fn wait_until(
+        &mut self,
+        port: Port,
+        milestone: PortMilestone,
+        timeout: Duration,
+        mut answer: Option<&mut dyn FnMut(&mut UrbWithData)>,
+    ) -> Result<(), WaitError> {
+        const MAX_STEP: Duration = Duration::from_millis(999);
+
+        if milestone != PortMilestone::ResetComplete && self.has_reached(port, milestone) {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(WaitError::TimedOut);
+            }
+
+            let step = TimeoutMillis::from_duration(remaining.min(MAX_STEP)).unwrap();
+            let work = match self.fetch_work_timeout(step) {
+                Ok(work) => work,
+                Err(err) if err.is_timeout() => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            let stat = match (work.get(), &mut answer) {
+                (ioctl::WorkRef::PortStat(stat), _) if stat.try_index().is_ok() => stat,
+                (ioctl::WorkRef::ProcessUrb((&urb, handle)), Some(answer)) => {
+                    let mut urb = UrbWithData::try_from_ioctl(urb, handle)
+                        .map_err(|_| Error::InvalidUrb("urb too large"))?;
+                    if urb.needs_fetch_data()
+                        && self.fetch_data(&mut urb)? == FetchOutcome::Canceled
                     {
-                        vhci.port_resumed(next.index()).unwrap();
+                        continue;
+                    }
+                    if !self.addresses.answer_set_address(&mut urb) {
+                        answer(&mut urb);
+                    }
+                    if self.giveback(&mut urb)? == GivebackOutcome::Completed {
+                        self.note_completed_urb(&urb);
+                    }
+                    if self.has_reached(port, milestone) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                _ => {
+                    self.buffered_work.push_back(work);
+                    continue;
+                }
+            };
+
+            // Stats of other ports are recorded too, so waiting for
+            // them later sees how far they got.
+            let ours = stat.index == port.get();
+            let events = self.note_port_stat(stat).expect("index was checked above");
+            if !ours {
+                // Replaying an older stat after the newer one was
+                // recorded would report its transitions backwards.
+                self.buffered_work.retain(|kept| {
+                    !matches!(kept.get(), ioctl::WorkRef::PortStat(kept) if kept.index == stat.index)
+                });
+                self.buffered_work.push_back(work);
+            }
+            for event in events {
+                match event {
+                    PortEvent::ResetRequested(port) => self.port_reset_done(port, true)?,
+                    PortEvent::ResumeRequested(port) => self.port_resumed(port)?,
+                    PortEvent::ConnectionChanged {
+                        connected: false, ..
+                    } if ours && milestone != PortMilestone::Powered => {
+                        return Err(WaitError::Disconnected)
+                    }
+                    _ => (),
+                }
+            }
+
+            if ours && Self::reached(&stat, milestone) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Records a port stat fetched as work and returns the
+    /// transitions it represents, see [`PortStateTracker::observe`].
+    ///
+    /// A port the host reports as disconnected after it was connected
+    /// is free again for [`Controller::port_connect_any`]. A stat
+    /// that was already stale when the port was connected, e.g. the
+    /// one from powering it on, doesn't count as a disconnect.
+    ///
+    /// [`Controller::wait_for`] records the stats it fetches itself.
+    pub fn note_port_stat(
+        &mut self,
+        stat: ioctl::IocPortStat,
+    ) -> Result<Vec<PortEvent>, ioctl::DecodeError> {
+        let events = self.port_tracker.try_observe(stat)?;
+        for event in &events {
+            match *event {
+                PortEvent::ConnectionChanged {
+                    port,
+                    connected: true,
+                } => self.addresses.invalidate(port),
+                PortEvent::ConnectionChanged {
+                    port,
+                    connected: false,
+                } => {
+                    self.addresses.invalidate(port);
+                    let idx = usize::from(port.get() - 1);
+                    if let Some(mut open) = self.open_ports.get_mut(idx) {
+                        *open = false;
+                        self.port_rates[idx] = None;
                     }
-                    prev = next;
                 }
+                PortEvent::PoweredOff(port) => self.addresses.invalidate(port),
+                PortEvent::ResetRequested(port) => self.addresses.reset(port),
+                _ => (),
+            }
+        }
+        Ok(events)
+    }
+
+    /// The events [`Controller::note_port_stat`] would report for
+    /// `stat`, without recording it, e.g. to answer them first.
+    pub fn port_stat_events(
+        &self,
+        stat: ioctl::IocPortStat,
+    ) -> Result<Vec<PortEvent>, ioctl::DecodeError> {
+        self.port_tracker.transitions(stat)
+    }
+
+    /// Records an URB that was given back, to see the SET_ADDRESS
+    /// requests [`PortMilestone::Addressed`] waits for. The port is
+    /// looked up by the URB's address, see
+    /// [`Controller::device_addresses`].
+    ///
+    /// This also ends the status stage of a SET_ADDRESS answered with
+    /// [`DeviceAddressMap::answer_set_address`], so only pass URBs
+    /// the host did not cancel. [`Controller::wait_for_answering`]
+    /// records the URBs it gives back itself.
+    pub fn note_completed_urb(&mut self, urb: &UrbWithData) {
+        if self.addresses.status_stage_done(urb.handle()).is_some() {
+            return;
+        }
+        if let Some(port) = self.addresses.resolve(urb.address()) {
+            self.addresses.observe(port, urb);
+        }
+    }
+
+    /// Answers SET_ADDRESS for the device in the default state, see
+    /// [`DeviceAddressMap::answer_set_address`]. Returns whether
+    /// `urb` was such a request. Pass `urb` to
+    /// [`Controller::note_completed_urb`] once it was given back for
+    /// the address to apply.
+    pub fn answer_set_address(&mut self, urb: &mut UrbWithData) -> bool {
+        self.addresses.answer_set_address(urb)
+    }
+
+    /// The addresses of the devices, as far as the recorded port stats
+    /// and URBs tell, see [`Controller::note_port_stat`] and
+    /// [`Controller::note_completed_urb`].
+    pub const fn device_addresses(&self) -> &DeviceAddressMap {
+        &self.addresses
+    }
+
+    /// Every port of the controller, in order.
+    ///
+    /// ```no_run
+    /// # use usb_vhci::{utils::BoundedU8, Controller, DataRate};
+    /// let mut vhci = Controller::open(BoundedU8::new(4).unwrap())?;
+    /// vhci.port_connect_any(DataRate::High)?;
+    /// vhci.port_connect_any(DataRate::Low)?;
+    /// for info in vhci.ports() {
+    ///     match info.data_rate {
+    ///         Some(rate) => println!("port {}: {rate}", info.port.get()),
+    ///         None => println!("port {}: free", info.port.get()),
+    ///     }
+    /// }
+    /// # Ok::<(), usb_vhci::Error>(())
+    /// ```
+    pub fn ports(&self) -> impl Iterator<Item = PortInfo> + '_ {
+        self.open_ports
+            .iter()
+            .zip(&self.port_rates)
+            .enumerate()
+            .map(|(idx, (connected, &data_rate))| {
+                let port = Port::new(idx as u8 + 1).unwrap();
+                PortInfo {
+                    port,
+                    connected,
+                    data_rate,
+                    status: self.port_tracker.get(port).map(|stat| stat.status()),
+                }
+            })
+    }
+
+    /// Status, change and flag bits of the last stat recorded for
+    /// `port`, or `None` if there was none.
+    pub fn port_status(&self, port: Port) -> Option<(PortStatus, PortChange, PortFlag)> {
+        self.port_tracker
+            .get(port)
+            .map(|stat| (stat.status(), stat.change(), stat.flags()))
+    }
+
+    /// Whether the last stat recorded for `port` has a device
+    /// connected, see [`Controller::note_port_stat`].
+    pub fn is_port_connected(&self, port: Port) -> bool {
+        self.port_tracker
+            .get(port)
+            .is_some_and(|stat| stat.status().is_connected())
+    }
+
+    fn has_reached(&self, port: Port, milestone: PortMilestone) -> bool {
+        match milestone {
+            PortMilestone::Addressed => self.addresses.address(port).is_some(),
+            _ => self
+                .port_tracker
+                .get(port)
+                .is_some_and(|stat| Self::reached(stat, milestone)),
+        }
+    }
+
+    fn reached(stat: &ioctl::IocPortStat, milestone: PortMilestone) -> bool {
+        let status = stat.status();
+        match milestone {
+            PortMilestone::Addressed => false,
+            PortMilestone::Powered => status.is_powered(),
+            PortMilestone::ResetComplete => stat.change().has_reset_changed() && !status.in_reset(),
+            PortMilestone::Enabled => {
+                status.is_connected() && status.is_enabled() && !status.in_reset()
+            }
+        }
+    }
+
+    /// Returns the work that [`Controller::wait_for`] fetched
+    /// but did not handle, oldest first.
+    pub fn drain_buffered_work(&mut self) -> impl Iterator<Item = ioctl::IocWork> + '_ {
+        self.buffered_work.drain(..)
+    }
+
+    /// Takes the oldest work that [`Controller::wait_for`] fetched
+    /// but did not handle, e.g. to handle it before fetching more.
+    pub fn pop_buffered_work(&mut self) -> Option<ioctl::IocWork> {
+        self.buffered_work.pop_front()
+    }
+
+    pub fn fetch_data(
+        &self,
+        urb: impl Urb + TransferMut + IsoPacketDataMut,
+    ) -> Result<FetchOutcome> {
+        Remote::new(Arc::clone(&self.dev)).fetch_data(urb)
+    }
+
+    pub fn giveback(
+        &self,
+        urb: impl Urb + TransferMut + IsoPacketGivebackMut,
+    ) -> Result<GivebackOutcome> {
+        Remote::new(Arc::clone(&self.dev)).giveback(urb)
+    }
+
+    fn port_control(&mut self) -> PortControl<'_> {
+        PortControl {
+            dev: &self.dev,
+            open_ports: &mut self.open_ports,
+            port_rates: &mut self.port_rates,
+            reserved_ports: &self.reserved_ports,
+        }
+    }
+
+    /// Connects a device to the first free port. Fails with
+    /// [`Error::NoFreePorts`] if every port is connected or reserved.
+    pub fn port_connect_any(&mut self, data_rate: DataRate) -> Result<Port> {
+        self.port_control().port_connect_any(data_rate)
+    }
+
+    /// Connects a device to a free port if `predicate` accepts the
+    /// number of free ports, e.g. to keep some ports for later.
+    ///
+    /// The count and the connect happen under the same `&mut self`,
+    /// so no other connect can slip in between. Returns `Ok(None)`
+    /// without calling `predicate` if no port is free, and without
+    /// connecting if `predicate` returns `false`.
+    pub fn try_connect_if(
+        &mut self,
+        data_rate: DataRate,
+        predicate: impl FnOnce(u64) -> bool,
+    ) -> Result<Option<Port>> {
+        self.port_control().try_connect_if(data_rate, predicate)
+    }
+
+    /// Connects a device to `port`. Fails with [`Error::PortInUse`]
+    /// if the port is connected already, with [`Error::InvalidPort`]
+    /// if the controller has no such port, and with
+    /// [`Error::PortReserved`] if the port is reserved; use
+    /// [`Controller::port_connect_reserved`] for those.
+    pub fn port_connect(&mut self, port: Port, data_rate: DataRate) -> Result<()> {
+        self.port_control().port_connect(port, data_rate)
+    }
+
+    /// Connects a device to a port reserved by this controller.
+    /// The reservation is used up even if connecting fails.
+    pub fn port_connect_reserved(
+        &mut self,
+        reservation: PortReservation,
+        data_rate: DataRate,
+    ) -> Result<Port> {
+        self.port_control()
+            .port_connect_reserved(reservation, data_rate)
+    }
+
+    /// Disconnects the device on `port`. Fails with
+    /// [`Error::PortNotConnected`] if there is none, and with
+    /// [`Error::InvalidPort`] if the controller has no such port.
+    pub fn port_disconnect(&mut self, port: Port) -> Result<()> {
+        self.port_control().port_disconnect(port)
+    }
+
+    /// See [`Remote::port_update`].
+    pub fn port_update(&self, update: PortUpdate) -> Result<()> {
+        Remote::new(Arc::clone(&self.dev)).port_update(update)
+    }
+
+    pub fn port_disable(&self, port: Port) -> Result<()> {
+        Remote::new(Arc::clone(&self.dev)).port_disable(port)
+    }
+
+    pub fn port_suspended(&self, port: Port) -> Result<()> {
+        Remote::new(Arc::clone(&self.dev)).port_suspended(port)
+    }
+
+    pub fn port_resumed(&self, port: Port) -> Result<()> {
+        Remote::new(Arc::clone(&self.dev)).port_resumed(port)
+    }
+
+    pub fn port_overcurrent(&self, port: Port, set: bool) -> Result<()> {
+        Remote::new(Arc::clone(&self.dev)).port_overcurrent(port, set)
+    }
+
+    pub fn port_reset_done(&self, port: Port, enable: bool) -> Result<()> {
+        Remote::new(Arc::clone(&self.dev)).port_reset_done(port, enable)
+    }
+}
+
+/// E.g. `vhci_hcd.0 (bus 5, 4 ports)`.
+impl std::fmt::Display for Controller {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (bus {}, {} ports)",
+            self.bus_id,
+            self.usb_busnum,
+            self.open_ports.len()
+        )
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{ioctl::UrbHandle, Status};
+
+    /// An URB whose parts can disagree with each other.
+    #[derive(Default)]
+    struct BrokenUrb {
+        buffer: Vec<u8>,
+        transferred: usize,
+        packets: Vec<ioctl::IocIsoPacketData>,
+        giveback: Vec<ioctl::IocIsoPacketGiveback>,
+    }
+
+    impl Urb for BrokenUrb {
+        fn kind(&self) -> ioctl::UrbType {
+            ioctl::UrbType::Iso
+        }
+
+        fn handle(&self) -> UrbHandle {
+            UrbHandle(1)
+        }
+
+        fn status(&self) -> Status {
+            Status::Success
+        }
+
+        fn dir(&self) -> Dir {
+            Dir::In
+        }
+
+        fn bytes_transferred(&self) -> usize {
+            self.transferred
+        }
+    }
+
+    impl TransferMut for BrokenUrb {
+        fn transfer_mut(&mut self) -> &mut [u8] {
+            &mut self.buffer
+        }
+    }
+
+    impl IsoPacketDataMut for BrokenUrb {
+        fn iso_packet_data_mut(&mut self) -> &mut [ioctl::IocIsoPacketData] {
+            &mut self.packets
+        }
+    }
+
+    impl IsoPacketGivebackMut for BrokenUrb {
+        fn iso_packet_giveback_mut(&mut self) -> &mut [ioctl::IocIsoPacketGiveback] {
+            &mut self.giveback
+        }
+
+        fn error_count(&self) -> u16 {
+            0
+        }
+    }
+
+    fn invalid_reason(err: Error) -> &'static str {
+        match err {
+            Error::InvalidUrb(reason) => reason,
+            err => panic!("not an invalid urb: {err:?}"),
+        }
+    }
+
+    #[test]
+    fn broken_urbs_are_errors() {
+        // Never reaches the kernel, so no device is needed.
+        let remote = Remote::new(null_fd());
+
+        let urb = BrokenUrb {
+            packets: vec![Default::default(); MAX_ISO_PACKETS + 1],
+            ..Default::default()
+        };
+        let err = remote.fetch_data(urb).unwrap_err();
+        assert_eq!(invalid_reason(err), "too many iso packets");
+
+        let urb = BrokenUrb {
+            giveback: vec![Default::default(); MAX_ISO_PACKETS + 1],
+            ..Default::default()
+        };
+        let err = remote.giveback(urb).unwrap_err();
+        assert_eq!(invalid_reason(err), "too many iso packets");
+
+        let urb = BrokenUrb {
+            buffer: vec![0; 8],
+            transferred: 4,
+            ..Default::default()
+        };
+        let err = remote.giveback(urb).unwrap_err();
+        assert_eq!(
+            invalid_reason(err),
+            "transfer length differs from bytes transferred"
+        );
+    }
+
+    #[test]
+    fn gives_back_transfers_over_64k() {
+        let remote = Remote::new(null_fd());
+        let mut urb = UrbWithData::builder()
+            .bulk(ioctl::Endpoint(0x81), &[0; 70000])
+            .build();
+        assert_eq!(urb.write_transfer(&[7; 70000]), 70000);
+        assert_eq!(urb.bytes_transferred(), 70000);
+        urb.ack();
+
+        // Passes the checks and only fails in the ioctl /dev/null
+        // does not know.
+        let err = remote.giveback(&mut urb).unwrap_err();
+        assert!(matches!(err, Error::Ioctl(Errno::ENOTTY)), "{err:?}");
+    }
+
+    #[test]
+    fn waiter_thread_wakes_pending_polls() {
+        struct CountingWaker(AtomicU32);
+
+        impl std::task::Wake for CountingWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let vhci = fake_controller(1);
+        let recv = vhci.work_receiver().unwrap();
+        let counter = Arc::new(CountingWaker(AtomicU32::new(0)));
+        let waker = Waker::from(Arc::clone(&counter));
+        let mut cx = Context::from_waker(&waker);
+        let mut work = ioctl::IocWork::default();
+
+        // As if an earlier poll found no work and left a waiter
+        // thread fetching.
+        recv.dev.waiter().running = true;
+        assert!(recv.poll_fetch_work(&mut cx, &mut work).is_pending());
+        assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+
+        // The fetch on /dev/null fails right away, which ends the
+        // thread and wakes the poll.
+        recv.dev.spawn_waiter(&mut recv.dev.waiter()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while counter.0.load(Ordering::Relaxed) == 0 {
+            assert!(Instant::now() < deadline, "never woken");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!recv.dev.waiter().running);
+        match recv.poll_fetch_work(&mut cx, &mut work) {
+            Poll::Ready(Err(Error::Ioctl(Errno::ENOTTY))) => (),
+            poll => panic!("unexpected {poll:?}"),
+        }
+
+        // Work the thread fetched goes to the next fetch of any kind.
+        recv.dev.waiter().ready = Some(Ok(ioctl::IocWork {
+            timeout: 7,
+            ..Default::default()
+        }));
+        assert_eq!(recv.try_fetch_work().unwrap().unwrap().timeout, 7);
+        assert!(recv.dev.take_ready().is_none());
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn open_errors() {
+        const NUM_PORTS: BoundedU8<1, 32> = BoundedU8::new(1).unwrap();
+        let missing = Controller::open_path("/nonexistent/usb-vhci", NUM_PORTS).unwrap_err();
+        assert!(
+            matches!(&missing, Error::DeviceNotFound(path) if path == Path::new("/nonexistent/usb-vhci"))
+        );
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+
+        let null = Controller::open_path("/dev/null", NUM_PORTS).unwrap_err();
+        assert_eq!(null.kind(), io::ErrorKind::Unsupported);
+        assert!(matches!(&null, Error::NotVhciDevice(path) if path == Path::new("/dev/null")));
+
+        // Without a path there is just the errno.
+        let fd = OwnedFd::from(std::fs::File::open("/dev/null").unwrap());
+        let from_fd = Controller::from_fd(fd, NUM_PORTS).unwrap_err();
+        assert_eq!(from_fd.raw_os_error(), Some(nix::libc::ENOTTY));
+    }
+
+    /// `/dev/null` fails every ioctl with `ENOTTY`.
+    fn null_fd() -> Arc<Device> {
+        Arc::new(OwnedFd::from(std::fs::File::open("/dev/null").unwrap()).into())
+    }
+
+    /// Queues `work` for the next fetch, as if
+    /// [`Controller::wait_for`] had put it aside.
+    pub(crate) fn buffer_work(vhci: &mut Controller, work: ioctl::IocWork) {
+        vhci.buffered_work.push_back(work);
+    }
+
+    /// Lets polls of `recv` wait as if an earlier poll found no work
+    /// and left a waiter thread fetching.
+    pub(crate) fn pretend_waiting(recv: &WorkReceiver) {
+        recv.dev.waiter().running = true;
+    }
+
+    /// Starts the waiter thread [`pretend_waiting`] pretended. Its
+    /// fetch on `/dev/null` fails right away and wakes the poll.
+    pub(crate) fn finish_waiting(recv: &WorkReceiver) {
+        recv.dev.spawn_waiter(&mut recv.dev.waiter()).unwrap();
+    }
+
+    /// A controller on `/dev/null`, for everything that doesn't
+    /// reach the kernel.
+    pub(crate) fn fake_controller(num_ports: usize) -> Controller {
+        Controller {
+            dev: null_fd(),
+            open_ports: BitVec::from_elem(num_ports, false),
+            port_rates: vec![None; num_ports],
+            controller_id: 0,
+            usb_busnum: 1,
+            tagger: WorkTagger::new(0, 1),
+            bus_id: "vhci_hcd.0".into(),
+            work_recv_split: AtomicBool::new(false),
+            reserved_ports: Arc::new(AtomicU32::new(0)),
+            port_tracker: PortStateTracker::new(),
+            addresses: DeviceAddressMap::new(),
+            buffered_work: VecDeque::new(),
+        }
+    }
+
+    fn stat(port: u8, status: PortStatus, change: PortChange) -> ioctl::IocPortStat {
+        ioctl::IocPortStat {
+            status: status.bits(),
+            change: change.bits(),
+            index: port,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tracks_reported_port_state() {
+        let mut vhci = fake_controller(2);
+        let port = Port::new(1).unwrap();
+        assert!(vhci.port_status(port).is_none());
+        assert!(!vhci.is_port_connected(port));
+
+        // As if port_connect had succeeded before the power-on stat
+        // was fetched.
+        vhci.open_ports.set(0, true);
+        vhci.port_rates[0] = Some(DataRate::High);
+        vhci.note_port_stat(stat(1, PortStatus::POWER, PortChange::empty()))
+            .unwrap();
+        assert_eq!(vhci.free_ports(), 1);
+
+        let connected = PortStatus::POWER | PortStatus::CONNECTION;
+        vhci.note_port_stat(stat(1, connected, PortChange::CONNECTION))
+            .unwrap();
+        assert!(vhci.is_port_connected(port));
+        let infos: Vec<_> = vhci
+            .ports()
+            .map(|info| (info.port.get(), info.connected, info.data_rate))
+            .collect();
+        assert_eq!(infos, [(1, true, Some(DataRate::High)), (2, false, None)]);
+        assert!(vhci.ports().nth(1).unwrap().status.is_none());
+
+        let (status, change, flags) = vhci.port_status(port).unwrap();
+        assert_eq!(
+            (status.bits(), change.bits(), flags.bits()),
+            (connected.bits(), PortChange::CONNECTION.bits(), 0)
+        );
+
+        let events = vhci
+            .note_port_stat(stat(1, PortStatus::POWER, PortChange::CONNECTION))
+            .unwrap();
+        assert!(events.contains(&PortEvent::ConnectionChanged {
+            port,
+            connected: false
+        }));
+        assert!(!vhci.is_port_connected(port));
+        assert_eq!(vhci.free_ports(), 2);
+        assert_eq!(vhci.port_rates, [None, None]);
+
+        assert!(vhci
+            .note_port_stat(stat(0, connected, PortChange::empty()))
+            .is_err());
+    }
+
+    #[test]
+    fn tracks_device_addresses() {
+        let mut vhci = fake_controller(1);
+        let port = Port::new(1).unwrap();
+        let addressed = || PortMilestone::Addressed;
+        let set_address = |addr| {
+            let mut urb = UrbWithData::builder()
+                .control(
+                    crate::usbfs::Request::STANDARD_DEVICE_SET_ADDRESS
+                        .setup(addr, 0, 0)
+                        .unwrap(),
+                )
+                .build();
+            crate::ControlTransaction::new(&mut urb)
+                .unwrap()
+                .complete(Status::Success);
+            urb
+        };
+
+        let connected = PortStatus::POWER | PortStatus::CONNECTION;
+        vhci.note_port_stat(stat(1, connected, PortChange::CONNECTION))
+            .unwrap();
+        // Without a reset, no port answers to address 0.
+        vhci.note_completed_urb(&set_address(3));
+        assert!(!vhci.has_reached(port, addressed()));
+
+        vhci.note_port_stat(stat(1, connected | PortStatus::RESET, PortChange::empty()))
+            .unwrap();
+        assert_eq!(vhci.device_addresses().default_port(), Some(port));
+        vhci.note_completed_urb(&set_address(3));
+        assert!(vhci.has_reached(port, addressed()));
+        // Reached before anything is fetched from the fake device.
+        assert!(vhci.wait_for(port, addressed(), Duration::ZERO).is_ok());
+
+        vhci.note_port_stat(stat(1, PortStatus::POWER, PortChange::CONNECTION))
+            .unwrap();
+        assert!(!vhci.has_reached(port, addressed()));
+    }
+
+    #[test]
+    fn rejects_bad_connects_without_device() {
+        let mut vhci = fake_controller(2);
+        let (first, last) = (Port::new(1).unwrap(), Port::new(2).unwrap());
+        let kind = |result: Result<()>| result.unwrap_err().kind();
+
+        let unregistered = Port::new(3).unwrap();
+        assert_eq!(
+            kind(vhci.port_connect(unregistered, DataRate::Full)),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            kind(vhci.port_disconnect(unregistered)),
+            io::ErrorKind::InvalidInput
+        );
+
+        assert_eq!(
+            kind(vhci.port_disconnect(last)),
+            io::ErrorKind::NotConnected
+        );
+        vhci.open_ports.set(0, true);
+        assert_eq!(
+            kind(vhci.port_connect(first, DataRate::Full)),
+            io::ErrorKind::AddrInUse
+        );
+        assert!(vhci.open_ports[0]);
+    }
+
+    #[test]
+    fn port_quota_without_device() {
+        // Not a vhci device, so connecting fails after the checks.
+        let null = null_fd();
+        let mut open_ports = BitVec::from_elem(4, false);
+        open_ports.set(0, true);
+        let reserved = Arc::new(AtomicU32::new(0b0100));
+        let mut port_rates = [None; 4];
+        let mut ports = PortControl {
+            dev: &null,
+            open_ports: &mut open_ports,
+            port_rates: &mut port_rates,
+            reserved_ports: &reserved,
+        };
+
+        let usage = ports.port_usage();
+        assert_eq!(
+            usage,
+            PortUsage {
+                total: 4,
+                connected: 1,
+                reserved: 1,
+            }
+        );
+        assert_eq!(usage.free(), 2);
+        assert!(ports.has_free_port());
+
+        // Keep at least two ports free for other tenants.
+        let mut seen = None;
+        let port = ports
+            .try_connect_if(DataRate::Full, |free| {
+                seen = Some(free);
+                free > 2
+            })
+            .unwrap();
+        assert_eq!((port, seen), (None, Some(2)));
+
+        let err = ports.try_connect_if(DataRate::Full, |_| true).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+        assert_eq!(ports.port_usage(), usage);
+
+        ports.open_ports.set(1, true);
+        ports.open_ports.set(3, true);
+        let port = ports
+            .try_connect_if(DataRate::Full, |_| unreachable!())
+            .unwrap();
+        assert_eq!(port, None);
+
+        assert!(!ports.has_free_port());
+        assert_eq!(ports.free_ports(), 0);
+        let err = ports.port_connect_any(DataRate::Full).unwrap_err();
+        assert!(matches!(err, Error::NoFreePorts));
+    }
+
+    #[test]
+    fn receivers_return_to_their_controller() {
+        let vhci = fake_controller(1);
+        let other = fake_controller(1);
+        let recv = vhci.work_receiver().unwrap();
+        assert!(vhci.work_receiver().is_none());
+
+        let err = vhci.fetch_work().unwrap_err();
+        assert!(matches!(err, Error::WorkReceiverOut));
+
+        let err = vhci.fetch_work_for(Duration::from_secs(5)).unwrap_err();
+        assert!(matches!(err, Error::WorkReceiverOut));
+        let err = vhci.try_fetch_work().unwrap_err();
+        assert!(matches!(err, Error::WorkReceiverOut));
+
+        let recv = other.return_work_receiver(recv).unwrap_err();
+        assert!(vhci
+            .fetch_work()
+            .is_err_and(|err| err.raw_os_error().is_none()));
+        vhci.return_work_receiver(recv).unwrap();
+        let err = vhci.fetch_work().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+        // Errors other than timeouts end long waits right away.
+        let err = vhci.fetch_work_for(Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+    }
+
+    #[test]
+    fn interrupted_fetches_retry_with_time_left() {
+        let mut timeouts = Vec::new();
+        fetch_retrying(500, true, |millis| {
+            timeouts.push(millis);
+            if timeouts.len() < 3 {
+                std::thread::sleep(Duration::from_millis(50));
+                Err(Errno::EINTR)
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap();
+        assert_eq!(timeouts.len(), 3);
+        assert_eq!(timeouts[0], 500);
+        assert!(timeouts[1] <= 450 && timeouts[2] <= 400);
+
+        // No time left to retry with.
+        let mut calls = 0;
+        let err = fetch_retrying(10, true, |_| {
+            calls += 1;
+            std::thread::sleep(Duration::from_millis(20));
+            Err(Errno::EINTR)
+        })
+        .unwrap_err();
+        assert_eq!((err.kind(), calls), (io::ErrorKind::TimedOut, 1));
+
+        // Immediate fetches retry immediately.
+        let mut timeouts = Vec::new();
+        fetch_retrying(0, true, |millis| {
+            timeouts.push(millis);
+            match timeouts.len() {
+                1 => Err(Errno::EINTR),
+                _ => Ok(()),
             }
+        })
+        .unwrap();
+        assert_eq!(timeouts, [0, 0]);
+
+        // Unless retrying is turned off.
+        let err = fetch_retrying(500, false, |_| Err(Errno::EINTR)).unwrap_err();
+        assert!(matches!(err, Error::Ioctl(Errno::EINTR)));
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+
+        let dev = null_fd();
+        let mut calls = 0;
+        let interrupted = || {
+            calls += 1;
+            match calls {
+                1 => Err(Errno::EINTR),
+                _ => Ok(calls),
+            }
+        };
+        assert_eq!(dev.retrying(interrupted), Ok(2));
+        dev.retry_on_eintr.store(false, Ordering::Relaxed);
+        assert_eq!(
+            dev.retrying(|| Err::<(), _>(Errno::EINTR)),
+            Err(Errno::EINTR)
+        );
+    }
+
+    #[test]
+    fn fetch_errors_tell_timeouts_from_not_ready() {
+        let kind = |millis, errno| {
+            fetch_retrying(millis, true, |_| Err(errno))
+                .unwrap_err()
+                .kind()
+        };
+        assert_eq!(kind(0, Errno::EAGAIN), io::ErrorKind::WouldBlock);
+        assert_eq!(kind(0, Errno::ETIMEDOUT), io::ErrorKind::WouldBlock);
+        assert_eq!(kind(100, Errno::EAGAIN), io::ErrorKind::TimedOut);
+        assert_eq!(kind(100, Errno::ETIMEDOUT), io::ErrorKind::TimedOut);
+
+        // Other errors pass through, e.g. from a pipe that is no
+        // vhci device.
+        let (read, _write) = nix::unistd::pipe().unwrap();
+        let recv = WorkReceiver::new(Arc::new(read.into()), WorkTagger::new(0, 1));
+        let err = recv
+            .fetch_work_timeout(TimeoutMillis::IMMEDIATE)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+        let err = recv.try_fetch_work().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+
+        // Fatal errors end the iterator.
+        let mut iter = recv.iter(TimeoutMillis::MAX);
+        let err = iter.next().unwrap().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn handles_share_the_fd() {
+        let vhci = fake_controller(1);
+        let fd = vhci.as_raw_fd();
+        assert_eq!(vhci.remote().as_raw_fd(), fd);
+        assert_eq!(vhci.as_fd().as_raw_fd(), fd);
+        let recv = vhci.work_receiver().unwrap();
+        assert_eq!(recv.as_fd().as_raw_fd(), fd);
+    }
+
+    #[test]
+    fn tags_count_across_receivers() {
+        let tagger = WorkTagger::new(3, 5);
+        let recv = WorkReceiver::new(null_fd(), tagger.clone());
+        let first = tagger.tag(ioctl::IocWork::default()).tag;
+        let second = recv.tagger.tag(ioctl::IocWork::default()).tag;
+        assert_eq!(
+            (first.controller_id, first.usb_busnum, first.seq),
+            (3, 5, 0)
+        );
+        assert_eq!(second.seq, 1);
+        assert_eq!(WorkTagger::new(4, 6).tag(Default::default()).tag.seq, 0);
+
+        let err = recv
+            .fetch_tagged_work_timeout(TimeoutMillis::IMMEDIATE)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+        assert_eq!(tagger.tag(Default::default()).tag.seq, 2);
+    }
+
+    #[test]
+    fn removed_controller_errors() {
+        let err = urb_error(Errno::ENODEV);
+        assert!(matches!(err, Error::ControllerGone));
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(urb_error(Errno::EIO).raw_os_error(), Some(nix::libc::EIO));
+    }
+
+    #[test]
+    fn callback_survives_failed_giveback() {
+        let mut callbacks = crate::CompletionCallbacks::new();
+        let urb = crate::UrbWithData::builder()
+            .bulk(ioctl::Endpoint(0x81), &[0; 4])
+            .build();
+        let handle = urb.handle();
+        assert!(callbacks.on_complete(handle, |_| ()).is_ok());
+
+        let err = callbacks
+            .giveback(&Remote::new(null_fd()), urb)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+        assert_eq!(callbacks.len(), 1);
+        assert!(callbacks.canceled(handle));
+    }
+
+    #[test]
+    fn narrow_handles_forward() {
+        let remote = Remote::new(null_fd());
+
+        let urb = BrokenUrb {
+            packets: vec![Default::default(); MAX_ISO_PACKETS + 1],
+            ..Default::default()
         };
+        let err = remote.giveback_handle().fetch_data(urb).unwrap_err();
+        assert_eq!(invalid_reason(err), "too many iso packets");
+
+        let port = Port::new(1).unwrap();
+        let err = remote.port_signaler().port_resumed(port).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
     }
 }
@@ -0,0 +1,343 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use nohash_hasher::IntMap;
+
+use crate::{ioctl::UrbHandle, usbfs::Dir, Remote, Result, Status, Urb, UrbWithData};
+
+/// An URB owned by its token until it is completed, canceled or
+/// expired, whichever comes first.
+type Slot = Arc<Mutex<Option<UrbWithData>>>;
+
+fn take(slot: &Slot) -> Option<UrbWithData> {
+    slot.lock().unwrap_or_else(PoisonError::into_inner).take()
+}
+
+/// URBs handed to [`DeferredCompletion`] tokens to be completed
+/// outside of the loop that fetches work, e.g. on a thread waiting
+/// for a hardware backend.
+///
+/// Completed URBs are queued in the order their tokens were
+/// completed and given back by the loop through
+/// [`DeferredCompletions::giveback_completed`], so they interleave
+/// with URBs the loop completes itself. The registry keeps track of
+/// every deferred URB until then, so a `CancelUrb` work item or a
+/// timeout can still take it away from its token.
+#[derive(Debug)]
+pub struct DeferredCompletions {
+    pending: IntMap<UrbHandle, Slot>,
+    sender: Sender<UrbWithData>,
+    receiver: Receiver<UrbWithData>,
+    fallback: Status,
+}
+
+impl Default for DeferredCompletions {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            pending: IntMap::default(),
+            sender,
+            receiver,
+            fallback: Status::Stall,
+        }
+    }
+}
+
+impl DeferredCompletions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Status of URBs whose token is dropped without completing
+    /// them, [`Status::Stall`] by default.
+    pub fn fallback(mut self, status: Status) -> Self {
+        self.fallback = status;
+        self
+    }
+
+    /// Takes `urb` out of the loop. It is given back once the
+    /// returned token is completed or dropped.
+    pub fn defer(&mut self, urb: UrbWithData) -> DeferredCompletion {
+        let handle = urb.handle();
+        let slot = Arc::new(Mutex::new(Some(urb)));
+        self.pending.insert(handle, Arc::clone(&slot));
+        DeferredCompletion {
+            handle,
+            slot,
+            sender: self.sender.clone(),
+            fallback: self.fallback,
+        }
+    }
+
+    /// Number of deferred URBs that have not been given back yet.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn is_pending(&self, handle: UrbHandle) -> bool {
+        self.pending.contains_key(&handle)
+    }
+
+    /// Reports a `CancelUrb` work item for `handle`. The URB is
+    /// dropped, even if its token was already completed, and the
+    /// token's [`DeferredCompletion::complete`] returns `false`.
+    /// Returns whether `handle` was deferred.
+    pub fn cancel(&mut self, handle: UrbHandle) -> bool {
+        match self.pending.remove(&handle) {
+            Some(slot) => {
+                take(&slot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the URB of `handle` away from its token and completes
+    /// it with [`Status::TimedOut`], to be given back by the caller.
+    /// Returns `None` if the URB is not deferred or its token was
+    /// already completed, in which case the completion wins.
+    pub fn expire(&mut self, handle: UrbHandle) -> Option<UrbWithData> {
+        let mut urb = take(self.pending.get(&handle)?)?;
+        self.pending.remove(&handle);
+        urb.set_transferred(0);
+        urb.set_status(Status::TimedOut);
+        Some(urb)
+    }
+
+    /// The next URB whose token was completed, if any.
+    pub fn try_completed(&mut self) -> Option<UrbWithData> {
+        while let Ok(urb) = self.receiver.try_recv() {
+            if self.pending.remove(&urb.handle()).is_some() {
+                return Some(urb);
+            }
+        }
+        None
+    }
+
+    /// Like [`DeferredCompletions::try_completed`], but waits up to
+    /// `timeout` for a token to be completed.
+    pub fn completed_timeout(&mut self, timeout: Duration) -> Option<UrbWithData> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(remaining) {
+                Ok(urb) if self.pending.remove(&urb.handle()).is_some() => return Some(urb),
+                // Canceled while the completion was queued.
+                Ok(_) => continue,
+                // The registry holds a sender, so it never disconnects.
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    /// Gives back every URB completed so far and returns how many
+    /// there were. Stops at the first failed giveback; that URB is
+    /// dropped.
+    pub fn giveback_completed(&mut self, remote: &Remote) -> Result<usize> {
+        let mut count = 0;
+        while let Some(urb) = self.try_completed() {
+            // An URB the host canceled meanwhile is just not delivered.
+            let _ = remote.giveback(urb)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// The right to complete a deferred URB, see
+/// [`DeferredCompletions::defer`]. Tokens can be moved to and
+/// completed on any thread.
+///
+/// Dropping a token without completing it completes the URB with the
+/// registry's [`DeferredCompletions::fallback`] status.
+#[derive(Debug)]
+pub struct DeferredCompletion {
+    handle: UrbHandle,
+    slot: Slot,
+    sender: Sender<UrbWithData>,
+    fallback: Status,
+}
+
+impl DeferredCompletion {
+    pub const fn handle(&self) -> UrbHandle {
+        self.handle
+    }
+
+    /// Calls `f` with the URB, e.g. to look at its setup packet, or
+    /// returns `None` if it was canceled or expired.
+    pub fn with_urb<R>(&self, f: impl FnOnce(&UrbWithData) -> R) -> Option<R> {
+        self.slot
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map(f)
+    }
+
+    /// Completes the URB with `status`. An IN URB replies with as
+    /// much of `data` as fits its buffer, a successful OUT URB
+    /// consumed all of its data. Returns `false` if the URB was
+    /// canceled or expired meanwhile.
+    pub fn complete(self, status: Status, data: &[u8]) -> bool {
+        self.complete_with(|urb| {
+            let transferred = match (status, urb.dir()) {
+                (_, Dir::In) => {
+                    let len = data.len().min(urb.buffer_length());
+                    urb.buffer_mut()[..len].copy_from_slice(&data[..len]);
+                    len
+                }
+                (Status::Success, Dir::Out) => urb.buffer_length(),
+                (_, Dir::Out) => 0,
+            };
+            urb.set_transferred(transferred);
+            urb.set_status(status);
+        })
+    }
+
+    /// Completes the URB however `f` fills it in. Returns `false`
+    /// without calling `f` if the URB was canceled or expired.
+    pub fn complete_with(self, f: impl FnOnce(&mut UrbWithData)) -> bool {
+        let Some(mut urb) = take(&self.slot) else {
+            return false;
+        };
+        f(&mut urb);
+        // The registry is gone if this fails, and the URB with it.
+        let _ = self.sender.send(urb);
+        true
+    }
+}
+
+impl Drop for DeferredCompletion {
+    fn drop(&mut self) {
+        if let Some(mut urb) = take(&self.slot) {
+            urb.set_transferred(0);
+            urb.set_status(self.fallback);
+            let _ = self.sender.send(urb);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{ioctl::Endpoint, usbfs::Request, Transfer};
+
+    fn get_descriptor(handle: u64) -> UrbWithData {
+        UrbWithData::builder()
+            .control(
+                Request::STANDARD_DEVICE_GET_DESCRIPTOR
+                    .setup(0x0100, 0, 18)
+                    .unwrap(),
+            )
+            .handle(UrbHandle(handle))
+            .build()
+    }
+
+    #[test]
+    fn completes_from_another_thread() {
+        let mut deferred = DeferredCompletions::new();
+        let token = deferred.defer(get_descriptor(1));
+        assert!(deferred.is_pending(UrbHandle(1)));
+
+        let (go, wait) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            let length = token.with_urb(|urb| urb.buffer_length()).unwrap();
+            assert_eq!(length, 18);
+            wait.recv().unwrap();
+            assert!(token.complete(Status::Success, &[18, 1, 0, 2]));
+        });
+
+        // The loop answers an URB synchronously while the worker is
+        // still busy, and the deferred URB can't overtake it.
+        let mut sync = get_descriptor(1000);
+        sync.reply_with(&[18]).unwrap();
+        let mut given_back = vec![sync.handle().0];
+        assert!(deferred.try_completed().is_none());
+        go.send(()).unwrap();
+        let urb = deferred.completed_timeout(Duration::from_secs(5)).unwrap();
+        worker.join().unwrap();
+        given_back.push(urb.handle().0);
+        assert_eq!(urb.status(), Status::Success);
+        assert_eq!(urb.transfer(), [18, 1, 0, 2]);
+        assert!(deferred.is_empty());
+        assert_eq!(given_back, [1000, 1]);
+    }
+
+    #[test]
+    fn keeps_completion_order() {
+        let mut deferred = DeferredCompletions::new();
+        let tokens: Vec<_> = (1..=4).map(|h| deferred.defer(get_descriptor(h))).collect();
+
+        // Complete in reverse order on separate threads, one at a
+        // time, with the loop giving back synchronous URBs between.
+        let mut given_back = Vec::new();
+        for (sync, token) in (100..).zip(tokens.into_iter().rev()) {
+            thread::spawn(move || token.complete(Status::Success, &[]))
+                .join()
+                .unwrap();
+            given_back.push(sync);
+            given_back.extend(deferred.try_completed().map(|urb| urb.handle().0));
+        }
+        assert_eq!(given_back, [100, 4, 101, 3, 102, 2, 103, 1]);
+        assert!(deferred.try_completed().is_none());
+    }
+
+    #[test]
+    fn dropped_tokens_fall_back() {
+        let mut deferred = DeferredCompletions::new().fallback(Status::TimedOut);
+        drop(deferred.defer(get_descriptor(1)));
+        let urb = deferred.try_completed().unwrap();
+        assert_eq!(urb.status(), Status::TimedOut);
+        assert!(urb.transfer().is_empty());
+
+        let mut stalling = DeferredCompletions::new();
+        drop(stalling.defer(get_descriptor(1)));
+        assert_eq!(stalling.try_completed().unwrap().status(), Status::Stall);
+    }
+
+    #[test]
+    fn cancel_and_expire() {
+        let mut deferred = DeferredCompletions::new();
+        let canceled = deferred.defer(get_descriptor(1));
+        let expired = deferred.defer(get_descriptor(2));
+        let raced = deferred.defer(get_descriptor(3));
+        let late = deferred.defer(
+            UrbWithData::builder()
+                .bulk(Endpoint(0x02), &[1, 2, 3])
+                .handle(UrbHandle(4))
+                .build(),
+        );
+
+        assert!(deferred.cancel(UrbHandle(1)));
+        assert!(!deferred.cancel(UrbHandle(1)));
+        assert!(canceled.with_urb(|_| ()).is_none());
+        assert!(!canceled.complete(Status::Success, &[]));
+
+        let urb = deferred.expire(UrbHandle(2)).unwrap();
+        assert_eq!(urb.status(), Status::TimedOut);
+        assert!(!expired.complete(Status::Success, &[]));
+
+        // Completed before the timeout fired.
+        assert!(raced.complete(Status::Success, &[]));
+        assert!(deferred.expire(UrbHandle(3)).is_none());
+
+        // Canceled while its completion was queued.
+        assert!(late.complete(Status::Success, &[]));
+        assert!(deferred.cancel(UrbHandle(4)));
+
+        let urb = deferred.try_completed().unwrap();
+        assert_eq!(urb.handle(), UrbHandle(3));
+        assert!(deferred.try_completed().is_none());
+        assert!(deferred.is_empty());
+    }
+}
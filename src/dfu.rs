@@ -0,0 +1,433 @@
+//! Device Firmware Upgrade (DFU 1.1) class requests.
+//!
+//! [`DfuDevice`] answers the DFU requests of one interface in the
+//! style of [`HaltState`](crate::HaltState): call
+//! [`DfuDevice::intercept`] on every URB before handing it to the
+//! rest of the device.
+
+use crate::{ioctl::UrbType, usbfs::Dir, ControlTransaction, Status, Urb, UrbWithData};
+
+pub const DFU_DETACH: u8 = 0;
+pub const DFU_DNLOAD: u8 = 1;
+pub const DFU_UPLOAD: u8 = 2;
+pub const DFU_GETSTATUS: u8 = 3;
+pub const DFU_CLRSTATUS: u8 = 4;
+pub const DFU_GETSTATE: u8 = 5;
+pub const DFU_ABORT: u8 = 6;
+
+/// Descriptor type of the DFU functional descriptor.
+pub const DFU_FUNCTIONAL_DESCRIPTOR: u8 = 0x21;
+
+/// `bState` of the DFU specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DfuState {
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DnloadSync = 3,
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    ManifestWaitReset = 8,
+    UploadIdle = 9,
+    Error = 10,
+}
+
+/// `bStatus` of the DFU specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DfuStatus {
+    Ok = 0x00,
+    ErrTarget = 0x01,
+    ErrFile = 0x02,
+    ErrWrite = 0x03,
+    ErrErase = 0x04,
+    ErrCheckErased = 0x05,
+    ErrProg = 0x06,
+    ErrVerify = 0x07,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrFirmware = 0x0A,
+    ErrVendor = 0x0B,
+    ErrUsbr = 0x0C,
+    ErrPor = 0x0D,
+    ErrUnknown = 0x0E,
+    ErrStalledPkt = 0x0F,
+}
+
+/// A failure to simulate when a download block arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuFault {
+    /// Stall the DFU_DNLOAD request itself.
+    Stall,
+
+    /// Accept the block, then report the status from the following
+    /// DFU_GETSTATUS and enter [`DfuState::Error`].
+    Status(DfuStatus),
+}
+
+/// The DFU interface of a device, either in run-time mode (starting
+/// in [`DfuState::AppIdle`]) or in DFU mode (starting in
+/// [`DfuState::DfuIdle`]).
+///
+/// Downloaded blocks are appended to an image that can be taken
+/// with [`DfuDevice::take_image`] once the host has finished the
+/// download. The device is manifestation tolerant, i.e. it goes
+/// back to [`DfuState::DfuIdle`] after manifesting.
+#[derive(Debug, Clone)]
+pub struct DfuDevice {
+    interface: u8,
+    transfer_size: u16,
+    poll_timeout_ms: u32,
+    state: DfuState,
+    status: DfuStatus,
+    pending_fault: Option<DfuStatus>,
+    faults: Vec<(u16, DfuFault)>,
+    image: Vec<u8>,
+    complete: Option<Vec<u8>>,
+    upload: Vec<u8>,
+    upload_offset: usize,
+}
+
+impl DfuDevice {
+    /// A device in run-time mode that has to be detached first.
+    pub fn runtime(interface: u8, transfer_size: u16) -> Self {
+        Self::with_state(interface, transfer_size, DfuState::AppIdle)
+    }
+
+    /// A device already in DFU mode.
+    pub fn dfu_mode(interface: u8, transfer_size: u16) -> Self {
+        Self::with_state(interface, transfer_size, DfuState::DfuIdle)
+    }
+
+    fn with_state(interface: u8, transfer_size: u16, state: DfuState) -> Self {
+        Self {
+            interface,
+            transfer_size,
+            poll_timeout_ms: 0,
+            state,
+            status: DfuStatus::Ok,
+            pending_fault: None,
+            faults: Vec::new(),
+            image: Vec::new(),
+            complete: None,
+            upload: Vec::new(),
+            upload_offset: 0,
+        }
+    }
+
+    pub const fn state(&self) -> DfuState {
+        self.state
+    }
+
+    pub const fn status(&self) -> DfuStatus {
+        self.status
+    }
+
+    /// `bwPollTimeout` reported by DFU_GETSTATUS. Only the low
+    /// 24 bits are sent.
+    pub fn set_poll_timeout(&mut self, millis: u32) {
+        self.poll_timeout_ms = millis & 0x00FF_FFFF;
+    }
+
+    /// Firmware returned by DFU_UPLOAD.
+    pub fn set_upload(&mut self, firmware: Vec<u8>) {
+        self.upload = firmware;
+    }
+
+    /// Simulates `fault` when the download block numbered `block`
+    /// arrives.
+    pub fn inject_fault(&mut self, block: u16, fault: DfuFault) {
+        self.faults.push((block, fault));
+    }
+
+    /// The image of the last finished download.
+    pub fn take_image(&mut self) -> Option<Vec<u8>> {
+        self.complete.take()
+    }
+
+    /// The DFU functional descriptor for this interface, with
+    /// download and upload support.
+    pub const fn functional_descriptor(&self) -> [u8; 9] {
+        // bitCanDnload | bitCanUpload | bitManifestationTolerant
+        const ATTRIBUTES: u8 = 0x01 | 0x02 | 0x04;
+        const DETACH_TIMEOUT_MS: u16 = 1000;
+        let [detach_lo, detach_hi] = DETACH_TIMEOUT_MS.to_le_bytes();
+        let [size_lo, size_hi] = self.transfer_size.to_le_bytes();
+        [
+            9,
+            DFU_FUNCTIONAL_DESCRIPTOR,
+            ATTRIBUTES,
+            detach_lo,
+            detach_hi,
+            size_lo,
+            size_hi,
+            0x10,
+            0x01,
+        ]
+    }
+
+    /// Completes the bus reset the host issues after DFU_DETACH,
+    /// switching a detached device to DFU mode. A reset during a
+    /// download drops what was received so far.
+    pub fn bus_reset(&mut self) {
+        match self.state {
+            DfuState::AppIdle => (),
+            _ => self.enter_idle(),
+        }
+    }
+
+    fn enter_idle(&mut self) {
+        self.state = DfuState::DfuIdle;
+        self.status = DfuStatus::Ok;
+        self.pending_fault = None;
+        self.image.clear();
+        self.upload_offset = 0;
+    }
+
+    fn fail(&mut self, status: DfuStatus) {
+        self.state = DfuState::Error;
+        self.status = status;
+    }
+
+    /// Answers `urb` if it is a DFU request for this interface,
+    /// returning the state the device is in afterwards.
+    pub fn intercept(&mut self, urb: &mut UrbWithData) -> Option<DfuState> {
+        if UrbType::Ctrl != urb.kind() {
+            return None;
+        }
+        let mut ctrl = ControlTransaction::new(urb)?;
+        let setup = *ctrl.request();
+        // Class request to an interface.
+        if setup.bm_request_type & 0x7F != 0x21 || setup.index() != self.interface.into() {
+            return None;
+        }
+
+        let status = match (ctrl.dir(), setup.b_request) {
+            (Dir::Out, DFU_DETACH) => self.detach(),
+            (Dir::Out, DFU_DNLOAD) => self.dnload(setup.value(), ctrl.read_data().unwrap()),
+            (Dir::In, DFU_UPLOAD) => self.upload(&mut ctrl),
+            (Dir::In, DFU_GETSTATUS) => self.get_status(&mut ctrl),
+            (Dir::Out, DFU_CLRSTATUS) => self.clear_status(),
+            (Dir::In, DFU_GETSTATE) => {
+                ctrl.write_reply(&[self.state as u8]).ok();
+                Status::Success
+            }
+            (Dir::Out, DFU_ABORT) => self.abort(),
+            _ => self.stall(),
+        };
+
+        ctrl.complete(status);
+        Some(self.state)
+    }
+
+    /// Stalls an unexpected request. In DFU mode this is an error
+    /// the host has to clear.
+    fn stall(&mut self) -> Status {
+        if !matches!(self.state, DfuState::AppIdle | DfuState::AppDetach) {
+            self.fail(DfuStatus::ErrStalledPkt);
+        }
+        Status::Stall
+    }
+
+    fn detach(&mut self) -> Status {
+        match self.state {
+            DfuState::AppIdle => {
+                self.state = DfuState::AppDetach;
+                Status::Success
+            }
+            _ => self.stall(),
+        }
+    }
+
+    fn dnload(&mut self, block: u16, data: &[u8]) -> Status {
+        match (self.state, data.is_empty()) {
+            (DfuState::DfuIdle | DfuState::DnloadIdle, false) => (),
+            (DfuState::DnloadIdle, true) => {
+                self.state = DfuState::ManifestSync;
+                return Status::Success;
+            }
+            _ => return self.stall(),
+        }
+
+        match self.faults.iter().find(|(b, _)| *b == block) {
+            Some((_, DfuFault::Stall)) => return self.stall(),
+            Some(&(_, DfuFault::Status(status))) => self.pending_fault = Some(status),
+            None => (),
+        }
+        self.image.extend_from_slice(data);
+        self.state = DfuState::DnloadSync;
+        Status::Success
+    }
+
+    fn upload(&mut self, ctrl: &mut ControlTransaction<'_>) -> Status {
+        match self.state {
+            DfuState::DfuIdle => self.state = DfuState::UploadIdle,
+            DfuState::UploadIdle => (),
+            _ => return self.stall(),
+        }
+
+        let remaining = &self.upload[self.upload_offset..];
+        let sent = ctrl.write_reply(remaining).unwrap_or(0);
+        self.upload_offset += sent;
+        // A short packet ends the upload.
+        if sent < ctrl.request().length().into() {
+            self.state = DfuState::DfuIdle;
+            self.upload_offset = 0;
+        }
+        Status::Success
+    }
+
+    fn get_status(&mut self, ctrl: &mut ControlTransaction<'_>) -> Status {
+        match self.state {
+            DfuState::DnloadSync => match self.pending_fault.take() {
+                Some(status) => self.fail(status),
+                None => self.state = DfuState::DnloadIdle,
+            },
+            DfuState::ManifestSync => {
+                self.complete = Some(std::mem::take(&mut self.image));
+                self.state = DfuState::DfuIdle;
+            }
+            _ => (),
+        }
+
+        let [t0, t1, t2, _] = self.poll_timeout_ms.to_le_bytes();
+        let reply = [self.status as u8, t0, t1, t2, self.state as u8, 0];
+        ctrl.write_reply(&reply).ok();
+        Status::Success
+    }
+
+    fn clear_status(&mut self) -> Status {
+        match self.state {
+            DfuState::Error => {
+                self.enter_idle();
+                Status::Success
+            }
+            _ => self.stall(),
+        }
+    }
+
+    fn abort(&mut self) -> Status {
+        match self.state {
+            DfuState::DfuIdle
+            | DfuState::DnloadSync
+            | DfuState::DnloadIdle
+            | DfuState::ManifestSync
+            | DfuState::UploadIdle => {
+                self.enter_idle();
+                Status::Success
+            }
+            _ => self.stall(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ioctl::IocSetupPacket, Transfer};
+
+    const INTERFACE: u8 = 0;
+
+    fn request(dir: Dir, b_request: u8, w_value: u16, data: &[u8]) -> UrbWithData {
+        let setup = IocSetupPacket {
+            bm_request_type: 0x21 | ((dir as u8) << 7),
+            b_request,
+            w_value,
+            w_index: INTERFACE.into(),
+            w_length: data.len() as u16,
+        };
+        UrbWithData::builder().control(setup).data(data).build()
+    }
+
+    fn send(dfu: &mut DfuDevice, b_request: u8, w_value: u16, data: &[u8]) -> Status {
+        let mut urb = request(Dir::Out, b_request, w_value, data);
+        assert!(dfu.intercept(&mut urb).is_some());
+        urb.status()
+    }
+
+    fn get_status(dfu: &mut DfuDevice) -> (u8, u8) {
+        let mut urb = request(Dir::In, DFU_GETSTATUS, 0, &[0; 6]);
+        dfu.intercept(&mut urb).unwrap();
+        let reply = urb.transfer();
+        (reply[0], reply[4])
+    }
+
+    #[test]
+    fn detach_and_download() {
+        let mut dfu = DfuDevice::runtime(INTERFACE, 4);
+        assert_eq!(send(&mut dfu, DFU_DETACH, 1000, &[]), Status::Success);
+        assert_eq!(dfu.state(), DfuState::AppDetach);
+        dfu.bus_reset();
+        assert_eq!(dfu.state(), DfuState::DfuIdle);
+
+        for (block, data) in [[1, 2, 3, 4], [5, 6, 7, 8]].iter().enumerate() {
+            assert_eq!(
+                send(&mut dfu, DFU_DNLOAD, block as u16, data),
+                Status::Success
+            );
+            assert_eq!(dfu.state(), DfuState::DnloadSync);
+            assert_eq!(get_status(&mut dfu), (0, DfuState::DnloadIdle as u8));
+        }
+        assert_eq!(dfu.take_image(), None);
+        assert_eq!(send(&mut dfu, DFU_DNLOAD, 2, &[]), Status::Success);
+        assert_eq!(get_status(&mut dfu), (0, DfuState::DfuIdle as u8));
+        assert_eq!(dfu.take_image(), Some(vec![1, 2, 3, 4, 5, 6, 7, 8]));
+    }
+
+    #[test]
+    fn injected_faults() {
+        let mut dfu = DfuDevice::dfu_mode(INTERFACE, 4);
+        dfu.inject_fault(1, DfuFault::Status(DfuStatus::ErrVerify));
+        dfu.inject_fault(3, DfuFault::Stall);
+
+        send(&mut dfu, DFU_DNLOAD, 0, &[0; 4]);
+        get_status(&mut dfu);
+        send(&mut dfu, DFU_DNLOAD, 1, &[0; 4]);
+        assert_eq!(
+            get_status(&mut dfu),
+            (DfuStatus::ErrVerify as u8, DfuState::Error as u8)
+        );
+        // Everything but GETSTATUS/GETSTATE/CLRSTATUS is refused.
+        assert_eq!(send(&mut dfu, DFU_DNLOAD, 2, &[0; 4]), Status::Stall);
+        assert_eq!(send(&mut dfu, DFU_CLRSTATUS, 0, &[]), Status::Success);
+        assert_eq!(dfu.state(), DfuState::DfuIdle);
+
+        assert_eq!(send(&mut dfu, DFU_DNLOAD, 3, &[0; 4]), Status::Stall);
+        assert_eq!(dfu.status(), DfuStatus::ErrStalledPkt);
+    }
+
+    #[test]
+    fn upload_ends_with_short_packet() {
+        let mut dfu = DfuDevice::dfu_mode(INTERFACE, 4);
+        dfu.set_upload(vec![1, 2, 3, 4, 5, 6]);
+        let mut replies = Vec::new();
+        loop {
+            let mut urb = request(Dir::In, DFU_UPLOAD, replies.len() as u16, &[0; 4]);
+            dfu.intercept(&mut urb).unwrap();
+            replies.push(urb.transfer().to_vec());
+            if dfu.state() == DfuState::DfuIdle {
+                break;
+            }
+        }
+        assert_eq!(replies, [vec![1, 2, 3, 4], vec![5, 6]]);
+    }
+
+    #[test]
+    fn functional_descriptor() {
+        let dfu = DfuDevice::dfu_mode(INTERFACE, 1024);
+        assert_eq!(
+            dfu.functional_descriptor(),
+            [9, 0x21, 0x07, 0xE8, 0x03, 0x00, 0x04, 0x10, 0x01]
+        );
+    }
+
+    #[test]
+    fn ignores_other_interfaces() {
+        let mut dfu = DfuDevice::dfu_mode(1, 64);
+        let mut urb = request(Dir::In, DFU_GETSTATE, 0, &[0]);
+        assert_eq!(dfu.intercept(&mut urb), None);
+    }
+}
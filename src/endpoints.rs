@@ -0,0 +1,164 @@
+use crate::{
+    ioctl::{Endpoint, UrbType},
+    usbfs::Dir,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointError {
+    /// All 15 endpoint numbers of the direction are in use.
+    Exhausted(Dir),
+
+    /// The endpoint is already in use.
+    Taken(Endpoint),
+
+    /// Endpoint 0 or an address with reserved bits set.
+    Invalid(Endpoint),
+}
+
+impl std::fmt::Display for EndpointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EndpointError::Exhausted(dir) => write!(f, "no free {dir:?} endpoint left"),
+            EndpointError::Taken(ep) => write!(f, "endpoint {:#04x} is already in use", ep.0),
+            EndpointError::Invalid(ep) => write!(f, "{:#04x} is not a valid endpoint", ep.0),
+        }
+    }
+}
+
+impl std::error::Error for EndpointError {}
+
+/// Hands out endpoint addresses for the interfaces of one device.
+///
+/// IN and OUT endpoints are numbered separately, so `0x81` and `0x01`
+/// can both be used. Endpoint 0 is the default control pipe and is
+/// never handed out.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EndpointAllocator {
+    /// Transfer type of endpoints 1 to 15, OUT then IN.
+    used: [[Option<UrbType>; 15]; 2],
+}
+
+impl EndpointAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Position of `ep` in `used`.
+    fn index(ep: Endpoint) -> Result<(usize, usize), EndpointError> {
+        let number = ep.0 & 0x7F;
+        if ep.is_control_zero() || number > 15 {
+            return Err(EndpointError::Invalid(ep));
+        }
+        Ok((ep.direction() as usize, usize::from(number) - 1))
+    }
+
+    fn slot(&mut self, ep: Endpoint) -> Result<&mut Option<UrbType>, EndpointError> {
+        let (dir, number) = Self::index(ep)?;
+        Ok(&mut self.used[dir][number])
+    }
+
+    /// Allocates the lowest free endpoint number in `dir` for an
+    /// endpoint of type `kind`.
+    pub fn alloc(&mut self, dir: Dir, kind: UrbType) -> Result<Endpoint, EndpointError> {
+        let free = self.used[dir as usize]
+            .iter()
+            .position(Option::is_none)
+            .ok_or(EndpointError::Exhausted(dir))?;
+        self.used[dir as usize][free] = Some(kind);
+        Ok(Endpoint((dir as u8) << 7 | (free as u8 + 1)))
+    }
+
+    /// Claims a specific endpoint address, e.g. one a class
+    /// specification or existing firmware fixes.
+    pub fn reserve(&mut self, ep: Endpoint, kind: UrbType) -> Result<(), EndpointError> {
+        let slot = self.slot(ep)?;
+        if slot.is_some() {
+            return Err(EndpointError::Taken(ep));
+        }
+        *slot = Some(kind);
+        Ok(())
+    }
+
+    pub fn release(&mut self, ep: Endpoint) {
+        if let Ok(slot) = self.slot(ep) {
+            *slot = None;
+        }
+    }
+
+    /// Transfer type of `ep`, if it has been allocated.
+    pub fn kind(&self, ep: Endpoint) -> Option<UrbType> {
+        let (dir, number) = Self::index(ep).ok()?;
+        self.used[dir][number]
+    }
+
+    /// All allocated endpoints in address order, OUT before IN.
+    pub fn endpoints(&self) -> impl Iterator<Item = (Endpoint, UrbType)> + '_ {
+        self.used.iter().enumerate().flat_map(|(dir, numbers)| {
+            numbers.iter().enumerate().filter_map(move |(i, kind)| {
+                kind.map(|kind| (Endpoint((dir as u8) << 7 | (i as u8 + 1)), kind))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cdc_hid_composite() {
+        let mut alloc = EndpointAllocator::new();
+
+        // CDC-ACM: notification, then the data interface.
+        let notify = alloc.alloc(Dir::In, UrbType::Int).unwrap();
+        let data_in = alloc.alloc(Dir::In, UrbType::Bulk).unwrap();
+        let data_out = alloc.alloc(Dir::Out, UrbType::Bulk).unwrap();
+        // HID keyboard with a fixed report endpoint.
+        alloc.reserve(Endpoint(0x84), UrbType::Int).unwrap();
+        let hid_out = alloc.alloc(Dir::Out, UrbType::Int).unwrap();
+
+        assert_eq!(notify, Endpoint(0x81));
+        assert_eq!(data_in, Endpoint(0x82));
+        assert_eq!(data_out, Endpoint(0x01));
+        assert_eq!(hid_out, Endpoint(0x02));
+        assert_eq!(alloc.alloc(Dir::In, UrbType::Bulk), Ok(Endpoint(0x83)));
+        assert_eq!(alloc.alloc(Dir::In, UrbType::Bulk), Ok(Endpoint(0x85)));
+        assert_eq!(alloc.kind(Endpoint(0x84)), Some(UrbType::Int));
+
+        let addresses: Vec<_> = alloc.endpoints().map(|(ep, _)| ep.0).collect();
+        assert_eq!(addresses, [0x01, 0x02, 0x81, 0x82, 0x83, 0x84, 0x85]);
+    }
+
+    #[test]
+    fn reserve_conflicts() {
+        let mut alloc = EndpointAllocator::new();
+        let ep = alloc.alloc(Dir::Out, UrbType::Bulk).unwrap();
+        assert_eq!(
+            alloc.reserve(ep, UrbType::Bulk),
+            Err(EndpointError::Taken(ep))
+        );
+        assert_eq!(
+            alloc.reserve(Endpoint(0x80), UrbType::Ctrl),
+            Err(EndpointError::Invalid(Endpoint(0x80)))
+        );
+        assert_eq!(
+            alloc.reserve(Endpoint(0x10), UrbType::Bulk),
+            Err(EndpointError::Invalid(Endpoint(0x10)))
+        );
+        alloc.release(ep);
+        assert_eq!(alloc.reserve(ep, UrbType::Iso), Ok(()));
+    }
+
+    #[test]
+    fn exhausts_per_direction() {
+        let mut alloc = EndpointAllocator::new();
+        for _ in 0..15 {
+            alloc.alloc(Dir::In, UrbType::Bulk).unwrap();
+        }
+        assert_eq!(
+            alloc.alloc(Dir::In, UrbType::Bulk),
+            Err(EndpointError::Exhausted(Dir::In))
+        );
+        assert_eq!(alloc.alloc(Dir::Out, UrbType::Bulk), Ok(Endpoint(0x01)));
+    }
+}
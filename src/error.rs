@@ -0,0 +1,240 @@
+use std::{io, path::PathBuf};
+
+use nix::errno::Errno;
+
+use crate::{ioctl::DecodeError, Port, PortUpdateError};
+
+/// `Result` of the controller and its handles.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Why the [`Controller`] or one of its handles failed.
+///
+/// Converts into an [`io::Error`] of [`Error::kind`] for code that
+/// works with those. Errors that carry an errno keep it as the
+/// [`io::Error::raw_os_error`], the others are wrapped and can be
+/// taken back out with [`io::Error::into_inner`].
+///
+/// [`Controller`]: crate::Controller
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// There is no device node at the path, usually because the
+    /// `usb-vhci-iocifc` module is not loaded.
+    DeviceNotFound(PathBuf),
+
+    /// The device node at the path can't be opened for reading and
+    /// writing by this process.
+    PermissionDenied(PathBuf),
+
+    /// The file at the path is not a `usb-vhci-iocifc` device.
+    NotVhciDevice(PathBuf),
+
+    /// The kernel tore the controller down, e.g. because the
+    /// `usb-vhci-hcd` module was unloaded.
+    ControllerGone,
+
+    /// Every port of the controller is connected or reserved.
+    NoFreePorts,
+
+    /// The controller was registered with fewer ports.
+    InvalidPort(Port),
+
+    /// A device is connected to the port already.
+    PortInUse(Port),
+
+    /// The port is reserved, see [`Controller::port_connect_reserved`].
+    ///
+    /// [`Controller::port_connect_reserved`]: crate::Controller::port_connect_reserved
+    PortReserved(Port),
+
+    /// No device is connected to the port.
+    PortNotConnected(Port),
+
+    /// The [`PortReservation`] belongs to another controller.
+    ///
+    /// [`PortReservation`]: crate::PortReservation
+    ForeignReservation,
+
+    /// Work can't be fetched from the controller while a
+    /// [`WorkReceiver`] is split off.
+    ///
+    /// [`WorkReceiver`]: crate::WorkReceiver
+    WorkReceiverOut,
+
+    /// An [`Urb`] implementation broke one of the invariants
+    /// documented on the URB traits.
+    ///
+    /// [`Urb`]: crate::Urb
+    InvalidUrb(&'static str),
+
+    /// [`PortUpdate::try_build`] refused the update.
+    ///
+    /// [`PortUpdate::try_build`]: crate::PortUpdate::try_build
+    InvalidPortUpdate(PortUpdateError),
+
+    /// The kernel handed out work this crate can't make sense of.
+    InvalidWork(DecodeError),
+
+    /// No work arrived before the timeout.
+    TimedOut,
+
+    /// No work was queued for a fetch that doesn't wait.
+    WouldBlock,
+
+    /// An ioctl failed with an errno not covered by the other
+    /// variants.
+    Ioctl(Errno),
+
+    Io(io::Error),
+}
+
+impl Error {
+    /// The [`io::ErrorKind`] the error converts into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::DeviceNotFound(_) | Error::ControllerGone => io::ErrorKind::NotFound,
+            Error::PermissionDenied(_) => io::ErrorKind::PermissionDenied,
+            Error::NotVhciDevice(_) => io::ErrorKind::Unsupported,
+            Error::NoFreePorts | Error::PortReserved(_) | Error::WorkReceiverOut => {
+                io::ErrorKind::ResourceBusy
+            }
+            Error::InvalidPort(_)
+            | Error::ForeignReservation
+            | Error::InvalidUrb(_)
+            | Error::InvalidPortUpdate(_) => io::ErrorKind::InvalidInput,
+            Error::PortInUse(_) => io::ErrorKind::AddrInUse,
+            Error::PortNotConnected(_) => io::ErrorKind::NotConnected,
+            Error::InvalidWork(_) => io::ErrorKind::InvalidData,
+            Error::TimedOut => io::ErrorKind::TimedOut,
+            Error::WouldBlock => io::ErrorKind::WouldBlock,
+            Error::Ioctl(errno) => io::Error::from(*errno).kind(),
+            Error::Io(err) => err.kind(),
+        }
+    }
+
+    /// The errno the error carries, if any.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::Ioctl(errno) => Some(*errno as i32),
+            Error::Io(err) => err.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// Whether no work arrived in time, see [`Error::TimedOut`] and
+    /// [`Error::WouldBlock`].
+    pub const fn is_timeout(&self) -> bool {
+        matches!(self, Error::TimedOut | Error::WouldBlock)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DeviceNotFound(path) => write!(f, "{} does not exist", path.display()),
+            Error::PermissionDenied(path) => {
+                write!(f, "no permission to open {}", path.display())
+            }
+            Error::NotVhciDevice(path) => {
+                write!(f, "{} is not a usb-vhci device", path.display())
+            }
+            Error::ControllerGone => f.write_str("controller removed"),
+            Error::NoFreePorts => f.write_str("no free ports"),
+            Error::InvalidPort(port) => {
+                write!(f, "port {} not registered with the controller", port.get())
+            }
+            Error::PortInUse(port) => write!(f, "port {} is connected already", port.get()),
+            Error::PortReserved(port) => write!(f, "port {} is reserved", port.get()),
+            Error::PortNotConnected(port) => write!(f, "port {} is not connected", port.get()),
+            Error::ForeignReservation => f.write_str("port reserved by a different controller"),
+            Error::WorkReceiverOut => f.write_str("work is fetched by a split off receiver"),
+            Error::InvalidUrb(reason) => write!(f, "invalid urb: {reason}"),
+            Error::InvalidPortUpdate(err) => write!(f, "invalid port update: {err}"),
+            Error::InvalidWork(err) => write!(f, "invalid work: {err}"),
+            Error::TimedOut => f.write_str("timed out waiting for work"),
+            Error::WouldBlock => f.write_str("no work queued"),
+            Error::Ioctl(errno) => write!(f, "ioctl failed: {}", errno.desc()),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidPortUpdate(err) => Some(err),
+            Error::InvalidWork(err) => Some(err),
+            Error::Ioctl(errno) => Some(errno),
+            Error::Io(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<Errno> for Error {
+    fn from(value: Errno) -> Self {
+        Self::Ioctl(value)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<PortUpdateError> for Error {
+    fn from(value: PortUpdateError) -> Self {
+        Self::InvalidPortUpdate(value)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(value: DecodeError) -> Self {
+        Self::InvalidWork(value)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Ioctl(errno) => errno.into(),
+            Error::Io(err) => err,
+            Error::TimedOut | Error::WouldBlock => value.kind().into(),
+            value => io::Error::new(value.kind(), value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_into_io_errors() {
+        let err = io::Error::from(Error::Ioctl(Errno::ENOTTY));
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOTTY));
+        assert_eq!(
+            Error::Ioctl(Errno::EBUSY).kind(),
+            io::ErrorKind::ResourceBusy
+        );
+        assert_eq!(
+            Error::Ioctl(Errno::EIO).raw_os_error(),
+            Some(nix::libc::EIO)
+        );
+
+        let port = Port::new(2).unwrap();
+        let err = io::Error::from(Error::PortInUse(port));
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        let inner = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*inner, Error::PortInUse(p) if p == port));
+
+        let err = io::Error::from(Error::TimedOut);
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.get_ref().is_none());
+
+        let err = Error::from(io::Error::from_raw_os_error(nix::libc::EACCES));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(nix::libc::EACCES));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{ioctl::Address, DeviceAddressMap, Port, Status, UrbWithData};
+
+/// The device an URB is for, see [`UrbFilter::classify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrbTarget {
+    /// The device that was given the URB's address.
+    Ours(Port),
+
+    /// The device in the default state, which answers to address 0
+    /// until it is given its own.
+    DefaultAddress(Port),
+
+    /// No device has the URB's address, e.g. because it was just
+    /// disconnected.
+    Foreign,
+}
+
+/// What [`UrbFilter::intercept`] does with URBs no device is there
+/// for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForeignPolicy {
+    /// Completes them with [`Status::DeviceDisconnected`], like the
+    /// host completes URBs of a device that went away.
+    #[default]
+    DeviceDisconnected,
+
+    /// Completes them with [`Status::NoResponse`], like a device that
+    /// doesn't answer.
+    NoResponse,
+
+    /// Leaves them alone. They are leaked unless the application
+    /// gives them back itself.
+    Ignore,
+}
+
+/// Sorts URBs by the device they are for, and completes those no
+/// device is there for instead of leaking them.
+///
+/// Every URB has to be given back. One that is dropped stays in
+/// flight on the host: the driver that submitted it waits until its
+/// own timeout runs out, which is several seconds for control
+/// requests and never for most bulk and interrupt URBs, and the
+/// endpoint's queue is stuck behind it until then. By default the
+/// filter completes such URBs, so they can't be forgotten.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UrbFilter {
+    foreign: ForeignPolicy,
+}
+
+impl UrbFilter {
+    pub const fn new(foreign: ForeignPolicy) -> Self {
+        Self { foreign }
+    }
+
+    pub const fn policy(&self) -> ForeignPolicy {
+        self.foreign
+    }
+
+    /// The device on the bus `map` describes that an URB for
+    /// `address` is for.
+    pub fn classify(&self, map: &DeviceAddressMap, address: Address) -> UrbTarget {
+        match map.resolve(address) {
+            Some(port) if address.is_for_unassigned() => UrbTarget::DefaultAddress(port),
+            Some(port) => UrbTarget::Ours(port),
+            None => UrbTarget::Foreign,
+        }
+    }
+
+    /// Classifies `urb` and completes it if it is
+    /// [`UrbTarget::Foreign`], unless the policy is
+    /// [`ForeignPolicy::Ignore`]. A completed URB must be given back
+    /// without reaching a device.
+    pub fn intercept(&self, map: &DeviceAddressMap, urb: &mut UrbWithData) -> UrbTarget {
+        let target = self.classify(map, urb.address());
+        let status = match (target, self.foreign) {
+            (UrbTarget::Foreign, ForeignPolicy::DeviceDisconnected) => Status::DeviceDisconnected,
+            (UrbTarget::Foreign, ForeignPolicy::NoResponse) => Status::NoResponse,
+            _ => return target,
+        };
+        urb.set_transferred(0);
+        urb.set_status(status);
+        target
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ioctl::Endpoint, Urb};
+
+    fn addr(addr: u8) -> Address {
+        Address::new(addr).unwrap()
+    }
+
+    fn bulk_for(addr: Address) -> UrbWithData {
+        UrbWithData::builder()
+            .bulk(Endpoint(0x81), &[0; 8])
+            .address(addr)
+            .build()
+    }
+
+    #[test]
+    fn classifies_by_address() {
+        let filter = UrbFilter::default();
+        let mut map = DeviceAddressMap::new();
+        let (one, two) = (Port::new(1).unwrap(), Port::new(2).unwrap());
+        assert_eq!(filter.classify(&map, addr(0)), UrbTarget::Foreign);
+
+        map.reset(one);
+        map.assign(one, addr(3));
+        map.reset(two);
+        assert_eq!(filter.classify(&map, addr(3)), UrbTarget::Ours(one));
+        assert_eq!(
+            filter.classify(&map, addr(0)),
+            UrbTarget::DefaultAddress(two)
+        );
+        assert_eq!(filter.classify(&map, addr(4)), UrbTarget::Foreign);
+    }
+
+    #[test]
+    fn completes_foreign_urbs_by_policy() {
+        let mut map = DeviceAddressMap::new();
+        let port = Port::new(1).unwrap();
+        map.reset(port);
+        map.assign(port, addr(3));
+
+        let mut ours = bulk_for(addr(3));
+        ours.write_transfer(&[1; 8]);
+        assert_eq!(
+            UrbFilter::default().intercept(&map, &mut ours),
+            UrbTarget::Ours(port)
+        );
+        assert_eq!(
+            (ours.status(), ours.bytes_transferred()),
+            (Status::Success, 8)
+        );
+
+        let policies = [
+            (
+                ForeignPolicy::DeviceDisconnected,
+                Status::DeviceDisconnected,
+            ),
+            (ForeignPolicy::NoResponse, Status::NoResponse),
+            (ForeignPolicy::Ignore, Status::Success),
+        ];
+        for (policy, status) in policies {
+            let mut foreign = bulk_for(addr(5));
+            foreign.write_transfer(&[1; 8]);
+            let target = UrbFilter::new(policy).intercept(&map, &mut foreign);
+            assert_eq!((target, foreign.status()), (UrbTarget::Foreign, status));
+            assert_eq!(
+                foreign.bytes_transferred() == 0,
+                policy != ForeignPolicy::Ignore
+            );
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use crate::{
+    ioctl::{Endpoint, UrbType},
+    usbfs::Request,
+    ControlTransaction, Status, Urb, UrbWithData,
+};
+
+/// Feature selector of `ENDPOINT_HALT` for SET/CLEAR_FEATURE.
+pub const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+/// What [`HaltState::intercept`] did with an URB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltAction {
+    /// The URB targets a halted endpoint and was stalled.
+    Stalled(Endpoint),
+
+    /// GET_STATUS for an endpoint was answered.
+    StatusReported(Endpoint),
+
+    /// SET_FEATURE(ENDPOINT_HALT) halted the endpoint.
+    Halted(Endpoint),
+
+    /// CLEAR_FEATURE(ENDPOINT_HALT) cleared the halt. The device
+    /// should reset its data toggle and state for the endpoint.
+    Cleared(Endpoint),
+}
+
+/// Tracks halted (stalled) endpoints of a device.
+///
+/// Call [`HaltState::intercept`] before handing an URB to the
+/// device and [`HaltState::observe`] after the device completed it.
+/// URBs that `intercept` handled are already completed and must
+/// not be passed to the device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HaltState {
+    /// Bit `number` for OUT endpoints, bit `16 + number` for IN.
+    halted: u32,
+}
+
+impl HaltState {
+    pub const fn new() -> Self {
+        Self { halted: 0 }
+    }
+
+    const fn mask(ep: Endpoint) -> u32 {
+        1 << ((ep.0 & 0x0F) + if ep.0 & 0x80 != 0 { 16 } else { 0 })
+    }
+
+    pub const fn is_halted(&self, ep: Endpoint) -> bool {
+        self.halted & Self::mask(ep) != 0
+    }
+
+    pub fn halt(&mut self, ep: Endpoint) {
+        self.halted |= Self::mask(ep);
+    }
+
+    pub fn clear(&mut self, ep: Endpoint) {
+        self.halted &= !Self::mask(ep);
+    }
+
+    /// Completes `urb` on behalf of the device if it targets a
+    /// halted endpoint or is a halt related standard request.
+    pub fn intercept(&mut self, urb: &mut UrbWithData) -> Option<HaltAction> {
+        if UrbType::Ctrl != urb.kind() {
+            let ep = urb.endpoint();
+            if self.is_halted(ep) {
+                urb.set_status(Status::Stall);
+                return Some(HaltAction::Stalled(ep));
+            }
+            return None;
+        }
+
+        let mut ctrl = ControlTransaction::new(urb)?;
+        let setup = *ctrl.request();
+        // The endpoint address lives in the low byte of wIndex.
+        let ep = Endpoint(setup.index() as u8);
+        let is_halt = setup.value() == FEATURE_ENDPOINT_HALT;
+        let req = setup.req();
+
+        let action = if req == Request::STANDARD_ENDPOINT_GET_STATUS {
+            let status = u16::from(self.is_halted(ep)).to_le_bytes();
+            ctrl.write_reply(&status).ok()?;
+            HaltAction::StatusReported(ep)
+        } else if req == Request::STANDARD_ENDPOINT_SET_FEATURE && is_halt {
+            self.halt(ep);
+            HaltAction::Halted(ep)
+        } else if req == Request::STANDARD_ENDPOINT_CLEAR_FEATURE && is_halt {
+            self.clear(ep);
+            HaltAction::Cleared(ep)
+        } else {
+            return None;
+        };
+
+        ctrl.complete(Status::Success);
+        Some(action)
+    }
+
+    /// Records a halt when the device stalled a non-control URB.
+    pub fn observe(&mut self, urb: &UrbWithData) {
+        if UrbType::Ctrl != urb.kind() && Status::Stall == urb.status() {
+            self.halt(urb.endpoint());
+        }
+    }
+
+    /// The halted endpoints, OUT before IN, e.g. to carry them over
+    /// to a restarted device.
+    pub fn halted_endpoints(&self) -> impl Iterator<Item = Endpoint> {
+        let halted = self.halted;
+        (0..32)
+            .filter(move |bit| halted & (1 << bit) != 0)
+            .map(|bit| Endpoint(if bit >= 16 { 0x80 | (bit - 16) } else { bit }))
+    }
+}
+
+/// Serialized as the list of halted endpoint addresses.
+#[cfg(feature = "serde")]
+impl serde::Serialize for HaltState {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.halted_endpoints().map(|ep| ep.0))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for HaltState {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut state = Self::new();
+        for ep in Vec::<u8>::deserialize(deserializer)? {
+            state.halt(Endpoint(ep));
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ioctl::IocSetupPacket, Transfer};
+
+    const BULK_IN: Endpoint = Endpoint(0x81);
+
+    fn bulk_in() -> UrbWithData {
+        UrbWithData::builder().bulk(BULK_IN, &[0; 64]).build()
+    }
+
+    fn endpoint_request(req: Request, w_value: u16, w_length: u16) -> UrbWithData {
+        let setup = IocSetupPacket {
+            bm_request_type: req.bm_request_type,
+            b_request: req.b_request,
+            w_value,
+            w_index: BULK_IN.0.into(),
+            w_length,
+        };
+        UrbWithData::builder().control(setup).build()
+    }
+
+    fn get_status(state: &mut HaltState) -> [u8; 2] {
+        let mut urb = endpoint_request(Request::STANDARD_ENDPOINT_GET_STATUS, 0, 2);
+        assert_eq!(
+            state.intercept(&mut urb),
+            Some(HaltAction::StatusReported(BULK_IN))
+        );
+        assert_eq!(urb.status(), Status::Success);
+        urb.transfer().try_into().unwrap()
+    }
+
+    #[test]
+    fn stalls_until_cleared() {
+        let mut state = HaltState::new();
+
+        let mut urb = bulk_in();
+        assert_eq!(state.intercept(&mut urb), None);
+        urb.set_status(Status::Stall);
+        state.observe(&urb);
+        assert!(state.is_halted(BULK_IN));
+        assert!(!state.is_halted(Endpoint(0x01)));
+
+        let mut urb = bulk_in();
+        assert_eq!(
+            state.intercept(&mut urb),
+            Some(HaltAction::Stalled(BULK_IN))
+        );
+        assert_eq!(urb.status(), Status::Stall);
+
+        let mut clear = endpoint_request(
+            Request::STANDARD_ENDPOINT_CLEAR_FEATURE,
+            FEATURE_ENDPOINT_HALT,
+            0,
+        );
+        assert_eq!(
+            state.intercept(&mut clear),
+            Some(HaltAction::Cleared(BULK_IN))
+        );
+        assert_eq!(clear.status(), Status::Success);
+
+        let mut urb = bulk_in();
+        assert_eq!(state.intercept(&mut urb), None);
+    }
+
+    #[test]
+    fn get_status_reports_halt_bit() {
+        let mut state = HaltState::new();
+        assert_eq!(get_status(&mut state), [0, 0]);
+
+        let mut set = endpoint_request(
+            Request::STANDARD_ENDPOINT_SET_FEATURE,
+            FEATURE_ENDPOINT_HALT,
+            0,
+        );
+        assert_eq!(state.intercept(&mut set), Some(HaltAction::Halted(BULK_IN)));
+        assert_eq!(get_status(&mut state), [1, 0]);
+    }
+
+    #[test]
+    fn lists_halted_endpoints() {
+        let mut state = HaltState::new();
+        state.halt(BULK_IN);
+        state.halt(Endpoint(0x02));
+        state.halt(Endpoint(0x8F));
+        let halted: Vec<_> = state.halted_endpoints().map(|ep| ep.0).collect();
+        assert_eq!(halted, [0x02, 0x81, 0x8F]);
+
+        let mut copy = HaltState::new();
+        state.halted_endpoints().for_each(|ep| copy.halt(ep));
+        assert_eq!(copy, state);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserializes_halted_endpoints() {
+        use serde::{
+            de::{value::Error, IntoDeserializer},
+            Deserialize,
+        };
+
+        let parsed: Result<HaltState, Error> =
+            HaltState::deserialize(vec![0x81_u8, 0x02].into_deserializer());
+        let parsed = parsed.unwrap();
+        assert!(parsed.is_halted(BULK_IN));
+        assert!(parsed.is_halted(Endpoint(0x02)));
+        assert!(!parsed.is_halted(Endpoint(0x01)));
+    }
+
+    #[test]
+    fn ignores_other_requests() {
+        let mut state = HaltState::new();
+        let mut urb = endpoint_request(Request::STANDARD_ENDPOINT_CLEAR_FEATURE, 1, 0);
+        assert_eq!(state.intercept(&mut urb), None);
+        let mut urb = endpoint_request(Request::STANDARD_DEVICE_GET_STATUS, 0, 2);
+        assert_eq!(state.intercept(&mut urb), None);
+    }
+}
@@ -9,9 +9,9 @@ use nix::{ioctl_readwrite, ioctl_write_ptr};
 use zerocopy_derive::*;
 
 use crate::{
-    usbfs::{CtrlType, Dir, Recipient, Req, Request},
+    usbfs::{CtrlType, Dir, Recipient, Request, RequestClass, StandardRequest},
     utils::BoundedU8,
-    Port, PortChange, PortFlag, PortStatus,
+    Port, PortChange, PortFlag, PortStatus, UrbFlags,
 };
 
 pub const USB_VHCI_HCD_IOC_MAGIC: u8 = 138;
@@ -53,6 +53,48 @@ pub const URB_RQ_GET_INTERFACE: u8 = 0x0A;
 pub const URB_RQ_SET_INTERFACE: u8 = 0x0B;
 pub const URB_RQ_SYNCH_FRAME: u8 = 0x0C;
 
+/// A field from the kernel or the bus holds a value this crate
+/// has no meaning for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A port index outside of 1 to 31.
+    PortIndex(u8),
+
+    /// A `bmRequestType` of the reserved type 3.
+    RequestType(u8),
+
+    /// A `bmRequestType` with one of the reserved recipients.
+    Recipient(u8),
+
+    /// A work type other than those of `USB_VHCI_WORK_TYPE_*`.
+    WorkType(u8),
+
+    /// An URB type other than those of `USB_VHCI_URB_TYPE_*`.
+    UrbType(u8),
+
+    /// A negative `buffer_length`.
+    BufferLength(i32),
+
+    /// A negative `packet_count`.
+    PacketCount(i32),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::PortIndex(index) => write!(f, "invalid port index {index}"),
+            DecodeError::RequestType(bits) => write!(f, "reserved request type in {bits:#04x}"),
+            DecodeError::Recipient(bits) => write!(f, "reserved recipient in {bits:#04x}"),
+            DecodeError::WorkType(typ) => write!(f, "unknown work type {typ}"),
+            DecodeError::UrbType(typ) => write!(f, "unknown urb type {typ}"),
+            DecodeError::BufferLength(len) => write!(f, "negative buffer length {len}"),
+            DecodeError::PacketCount(count) => write!(f, "negative iso packet count {count}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
 #[cfg_attr(
     feature = "zerocopy",
     derive(Immutable, KnownLayout, FromBytes, IntoBytes)
@@ -106,16 +148,40 @@ pub struct IocPortStat {
 }
 
 impl IocPortStat {
+    /// Bits without a name in [`PortStatus`] are kept.
     pub const fn status(&self) -> PortStatus {
         PortStatus::from_bits_retain(self.status)
     }
 
+    /// Bits without a name in [`PortChange`] are kept.
     pub const fn change(&self) -> PortChange {
         PortChange::from_bits_retain(self.change)
     }
 
+    pub const fn status_raw(&self) -> u16 {
+        self.status
+    }
+
+    pub const fn change_raw(&self) -> u16 {
+        self.change
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the index is 0 or above 31, see
+    /// [`IocPortStat::try_index`].
     pub const fn index(&self) -> Port {
-        Port::new(self.index).unwrap()
+        match self.try_index() {
+            Ok(port) => port,
+            Err(_) => panic!("invalid port index"),
+        }
+    }
+
+    pub const fn try_index(&self) -> Result<Port, DecodeError> {
+        match Port::new(self.index) {
+            Some(port) => Ok(port),
+            None => Err(DecodeError::PortIndex(self.index)),
+        }
     }
 
     pub const fn flags(&self) -> PortFlag {
@@ -128,7 +194,7 @@ impl std::fmt::Debug for IocPortStat {
         f.debug_struct("IocPortStat")
             .field("status", &self.status())
             .field("change", &self.change())
-            .field("index", &self.index())
+            .field("index", &self.index)
             .field("flags", &self.flags())
             .finish()
     }
@@ -145,7 +211,7 @@ ioctl_write_ptr!(
     feature = "zerocopy",
     derive(IntoBytes, FromBytes, Immutable, KnownLayout)
 )]
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
 #[repr(C)]
 pub struct IocSetupPacket {
     pub bm_request_type: u8,
@@ -164,6 +230,47 @@ impl IocSetupPacket {
         }
     }
 
+    /// Same as [`IocSetupPacket::req`], named for comparing
+    /// against the [`Request`] constants.
+    #[inline(always)]
+    pub const fn request_id(&self) -> Request {
+        self.req()
+    }
+
+    /// Same as [`IocSetupPacket::req`], the reverse of
+    /// [`Request::setup`].
+    #[inline(always)]
+    pub const fn as_request(&self) -> Request {
+        self.req()
+    }
+
+    /// See [`Request::try_ctrl_type`].
+    pub const fn try_control_type(&self) -> Result<CtrlType, DecodeError> {
+        self.req().try_ctrl_type()
+    }
+
+    /// See [`Request::try_recipient`].
+    pub const fn try_recipient(&self) -> Result<Recipient, DecodeError> {
+        self.req().try_recipient()
+    }
+
+    /// The standard request this packet carries, or `None` for
+    /// class and vendor requests.
+    pub const fn standard_request(&self) -> Option<StandardRequest> {
+        StandardRequest::from_setup(self)
+    }
+
+    /// Sorts the packet into a standard, class or vendor request.
+    /// Fails for reserved request types and recipients.
+    pub const fn classify(&self) -> Result<RequestClass, DecodeError> {
+        RequestClass::from_setup(self)
+    }
+
+    /// Whether this packet carries `request`, see [`Request::matches`].
+    pub const fn is(&self, request: Request) -> bool {
+        request.matches(self)
+    }
+
     #[inline(always)]
     pub const fn value(&self) -> u16 {
         self.w_value
@@ -235,6 +342,16 @@ impl Address {
         }
     }
 
+    /// Decodes the `wValue` of a SET_ADDRESS request, which must
+    /// hold a 7-bit device address and nothing else.
+    pub const fn from_set_address_value(w_value: u16) -> Option<Self> {
+        if w_value > 0x7F {
+            None
+        } else {
+            Self::new(w_value as u8)
+        }
+    }
+
     pub const fn get(&self) -> u8 {
         self.0
     }
@@ -253,22 +370,99 @@ impl Address {
 pub struct Endpoint(pub u8);
 
 impl Endpoint {
+    /// The endpoint address of endpoint `number` in direction `dir`,
+    /// or `None` if `number` is 16 or larger.
+    pub const fn new(number: u8, dir: Dir) -> Option<Self> {
+        if number < 16 {
+            Some(Self((dir as u8) << 7 | number))
+        } else {
+            None
+        }
+    }
+
     pub const fn direction(&self) -> Dir {
         Dir::from_u8((self.0 & 0x80) >> 7).unwrap()
     }
 
-    /// Returns whether the endpoint should be
-    /// sent to all devices.
-    pub const fn is_anycast(&self) -> bool {
+    /// The endpoint number without the direction bit.
+    pub const fn number(&self) -> u8 {
+        self.0 & 0x0f
+    }
+
+    /// Returns whether this is endpoint 0, the default control
+    /// endpoint every device has, in either direction.
+    pub const fn is_control_zero(&self) -> bool {
         self.0 & 0x7f == 0
     }
+
+    #[deprecated(note = "renamed to `is_control_zero`")]
+    pub const fn is_anycast(&self) -> bool {
+        self.is_control_zero()
+    }
+
+    /// The number and direction, without the reserved bits.
+    pub const fn key(&self) -> EndpointKey {
+        EndpointKey(self.0 & 0x8f)
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.key().fmt(f)
+    }
+}
+
+/// An endpoint by number and direction, e.g. to keep a queue per
+/// endpoint of a device. Unlike [`Endpoint`], keys of addresses that
+/// only differ in the reserved bits compare equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EndpointKey(u8);
+
+impl nohash_hasher::IsEnabled for EndpointKey {}
+
+impl EndpointKey {
+    /// See [`Endpoint::new`].
+    pub const fn new(number: u8, dir: Dir) -> Option<Self> {
+        match Endpoint::new(number, dir) {
+            Some(endpoint) => Some(endpoint.key()),
+            None => None,
+        }
+    }
+
+    pub const fn number(&self) -> u8 {
+        self.0 & 0x0f
+    }
+
+    pub const fn dir(&self) -> Dir {
+        Dir::from_u8(self.0 >> 7).unwrap()
+    }
+
+    pub const fn endpoint(&self) -> Endpoint {
+        Endpoint(self.0)
+    }
+}
+
+impl From<Endpoint> for EndpointKey {
+    fn from(value: Endpoint) -> Self {
+        value.key()
+    }
+}
+
+impl std::fmt::Display for EndpointKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let dir = match self.dir() {
+            Dir::In => "IN",
+            Dir::Out => "OUT",
+        };
+        write!(f, "EP{} {dir}", self.number())
+    }
 }
 
 #[cfg_attr(
     feature = "zerocopy",
     derive(IntoBytes, FromZeros, Immutable, KnownLayout)
 )]
-#[derive(Debug, Clone, Default, Copy)]
+#[derive(Debug, Clone, Default, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub struct IocUrb {
     pub setup_packet: IocSetupPacket,
@@ -282,6 +476,14 @@ pub struct IocUrb {
     pub _reserved: [u8; 3],
 }
 
+impl IocUrb {
+    /// See [`UrbFlags::from_raw`].
+    pub const fn flags(&self) -> UrbFlags {
+        UrbFlags::from_raw(self.flags)
+    }
+}
+
+#[cfg_attr(feature = "zerocopy", derive(FromZeros, Immutable, KnownLayout))]
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub union IocWorkUnion {
@@ -290,10 +492,11 @@ pub union IocWorkUnion {
 }
 
 impl Default for IocWorkUnion {
+    /// All zeros. Initializing only `port` would leave the rest of
+    /// the union undefined when it is handed to the kernel.
     fn default() -> Self {
-        Self {
-            port: Default::default(),
-        }
+        // SAFETY: All zeros is a valid `IocUrb` and `IocPortStat`.
+        unsafe { std::mem::zeroed() }
     }
 }
 
@@ -313,6 +516,7 @@ impl UrbHandle {
 
 impl nohash_hasher::IsEnabled for UrbHandle {}
 
+#[derive(Debug)]
 pub enum WorkRef<'a> {
     PortStat(IocPortStat),
     ProcessUrb((&'a IocUrb, UrbHandle)),
@@ -330,6 +534,7 @@ pub enum Work {
     feature = "zerocopy",
     derive(IntoBytes, FromZeros, Immutable, KnownLayout, Unaligned)
 )]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum WorkType {
@@ -339,6 +544,7 @@ pub enum WorkType {
     CancelUrb = USB_VHCI_WORK_TYPE_CANCEL_URB,
 }
 
+#[cfg_attr(feature = "zerocopy", derive(FromZeros, Immutable, KnownLayout))]
 #[derive(Clone, Default)]
 #[repr(C)]
 pub struct IocWork {
@@ -374,7 +580,7 @@ impl IocWork {
     ///
     /// If this work item was returned from an ioctl call, then
     /// the above will always be true.
-    pub const fn get(&self) -> WorkRef {
+    pub const fn get(&self) -> WorkRef<'_> {
         // SAFETY: Caller upholds safety contract in function description.
         match self.typ {
             WorkType::PortStat => WorkRef::PortStat(unsafe { self.work.port }),
@@ -386,11 +592,118 @@ impl IocWork {
     }
 }
 
+impl IocWork {
+    /// Like [`IocWork::get`], but checks the lengths of an URB
+    /// first, so it is fine for work that did not come from the
+    /// kernel. The types were checked by [`RawIocWork::decode`].
+    pub fn work(&self) -> Result<WorkRef<'_>, DecodeError> {
+        let work = self.get();
+        if let WorkRef::ProcessUrb((urb, _)) = work {
+            if urb.buffer_length < 0 {
+                return Err(DecodeError::BufferLength(urb.buffer_length));
+            }
+            if urb.packet_count < 0 {
+                return Err(DecodeError::PacketCount(urb.packet_count));
+            }
+        }
+        Ok(work)
+    }
+}
+
+/// The bytes of an [`IocWork`] as the kernel writes them. Until
+/// [`RawIocWork::decode`] checked the work and URB types, any bit
+/// pattern may be in them, which an [`IocWork`] must never hold.
+#[derive(Clone)]
+#[repr(C, align(8))]
+pub struct RawIocWork([u8; size_of::<IocWork>()]);
+
+impl Default for RawIocWork {
+    fn default() -> Self {
+        Self([0; size_of::<IocWork>()])
+    }
+}
+
+impl RawIocWork {
+    pub const fn from_bytes(bytes: [u8; size_of::<IocWork>()]) -> Self {
+        Self(bytes)
+    }
+
+    /// All zeros, except for the `timeout` the fetch waits.
+    pub fn with_timeout(timeout: i16) -> Self {
+        let mut raw = Self::default();
+        let offset = std::mem::offset_of!(IocWork, timeout);
+        raw.0[offset..offset + 2].copy_from_slice(&timeout.to_ne_bytes());
+        raw
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; size_of::<IocWork>()] {
+        &self.0
+    }
+
+    /// Checks the work and URB types and only then reads the bytes
+    /// as an [`IocWork`]. The lengths are left to [`IocWork::work`].
+    pub fn decode(&self) -> Result<IocWork, DecodeError> {
+        let typ = self.0[std::mem::offset_of!(IocWork, typ)];
+        match typ {
+            USB_VHCI_WORK_TYPE_PORT_STAT | USB_VHCI_WORK_TYPE_CANCEL_URB => (),
+            USB_VHCI_WORK_TYPE_PROCESS_URB => {
+                let urb_typ =
+                    self.0[std::mem::offset_of!(IocWork, work) + std::mem::offset_of!(IocUrb, typ)];
+                if !matches!(
+                    urb_typ,
+                    USB_VHCI_URB_TYPE_ISO
+                        | USB_VHCI_URB_TYPE_INT
+                        | USB_VHCI_URB_TYPE_CONTROL
+                        | USB_VHCI_URB_TYPE_BULK
+                ) {
+                    return Err(DecodeError::UrbType(urb_typ));
+                }
+            }
+            typ => return Err(DecodeError::WorkType(typ)),
+        }
+        // SAFETY: The buffer is as large and aligned as an `IocWork`.
+        //         Both enums in it were checked above, and every bit
+        //         pattern is fine for the other fields.
+        Ok(unsafe { self.0.as_ptr().cast::<IocWork>().read() })
+    }
+}
+
+impl std::fmt::Debug for RawIocWork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.decode() {
+            Ok(work) => work.fmt(f),
+            Err(err) => f.debug_tuple("RawIocWork").field(&err).finish(),
+        }
+    }
+}
+
+impl TryFrom<IocWork> for Work {
+    type Error = DecodeError;
+
+    /// Checks the work like [`IocWork::work`].
+    fn try_from(value: IocWork) -> Result<Self, Self::Error> {
+        Ok(match value.work()? {
+            WorkRef::PortStat(port) => Work::PortStat(port),
+            WorkRef::ProcessUrb((urb, handle)) => Work::ProcessUrb((*urb, handle)),
+            WorkRef::CancelUrb(handle) => Work::CancelUrb(handle),
+        })
+    }
+}
+
+impl std::fmt::Debug for IocWork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IocWork")
+            .field("work", &self.get())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
 ioctl_readwrite!(
     usb_vhci_fetchwork,
     USB_VHCI_HCD_IOC_MAGIC,
     USB_VHCI_HCD_IOCFETCHWORK,
-    IocWork
+    RawIocWork
 );
 
 #[cfg_attr(
@@ -404,6 +717,7 @@ pub struct IocIsoPacketData {
     pub packet_length: u32,
 }
 
+#[cfg_attr(feature = "zerocopy", derive(FromZeros, Immutable, KnownLayout))]
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct IocUrbData {
@@ -444,6 +758,7 @@ pub struct IocIsoPacketGiveback {
     pub status: i32,
 }
 
+#[cfg_attr(feature = "zerocopy", derive(FromZeros, Immutable, KnownLayout))]
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct IocGiveback {
@@ -476,3 +791,328 @@ ioctl_write_ptr!(
     USB_VHCI_HCD_IOCGIVEBACK,
     IocGiveback
 );
+
+/// `as_bytes` and `read_from_bytes` without spelling out the
+/// zerocopy traits, e.g. for structs captured from the C side.
+#[cfg(feature = "zerocopy")]
+macro_rules! byte_conversions {
+    ($($ty:ty),* $(,)?) => {$(
+        impl $ty {
+            /// The struct as the kernel sees it.
+            pub fn as_bytes(&self) -> &[u8] {
+                zerocopy::IntoBytes::as_bytes(self)
+            }
+
+            /// Reads the struct from exactly `size_of::<Self>()` bytes.
+            /// Returns `None` for any other length or for bytes that
+            /// are not a valid value.
+            pub fn read_from_bytes(bytes: &[u8]) -> Option<Self> {
+                zerocopy::TryFromBytes::try_read_from_bytes(bytes).ok()
+            }
+        }
+    )*};
+}
+
+#[cfg(feature = "zerocopy")]
+byte_conversions!(
+    IocRegister,
+    IocPortStat,
+    IocSetupPacket,
+    IocUrb,
+    IocIsoPacketData,
+    IocIsoPacketGiveback,
+);
+
+// The ioctl arguments have no implicit padding, every byte is a
+// named field and thereby initialized before it reaches the kernel.
+const _: () = {
+    use std::mem::size_of;
+    assert!(size_of::<IocRegister>() == 4 + 4 + 20 + 1 + 3);
+    assert!(size_of::<IocPortStat>() == 2 + 2 + 4);
+    assert!(size_of::<IocUrb>() == 8 + 3 * 4 + 2 + 3 + 3);
+    assert!(size_of::<IocWork>() == 8 + size_of::<IocUrb>() + 2 + 1 + 1);
+    assert!(size_of::<RawIocWork>() == size_of::<IocWork>());
+    assert!(align_of::<RawIocWork>() >= align_of::<IocWork>());
+    assert!(size_of::<IocUrbData>() == 8 + 2 * size_of::<usize>() + 2 * 4);
+    assert!(size_of::<IocGiveback>() == 8 + 2 * size_of::<usize>() + 4 * 4);
+};
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::strategies::work_strategy;
+
+    #[test]
+    fn endpoint_keys() {
+        let ep3_in = Endpoint::new(3, Dir::In).unwrap();
+        assert_eq!(ep3_in, Endpoint(0x83));
+        assert_eq!((ep3_in.number(), ep3_in.direction()), (3, Dir::In));
+        assert_eq!(ep3_in.to_string(), "EP3 IN");
+        assert_eq!(Endpoint(0x00).to_string(), "EP0 OUT");
+        assert_eq!(Endpoint::new(16, Dir::Out), None);
+        assert_eq!(
+            EndpointKey::new(15, Dir::Out).unwrap().endpoint(),
+            Endpoint(0x0f)
+        );
+
+        assert!(Endpoint(0x80).is_control_zero() && Endpoint(0x00).is_control_zero());
+        assert!(!Endpoint(0x81).is_control_zero());
+
+        // EP0 OUT and IN are separate keys, reserved bits are dropped.
+        let mut queues = nohash_hasher::IntMap::default();
+        queues.insert(Endpoint(0x00).key(), "out");
+        queues.insert(Endpoint(0x80).key(), "in");
+        queues.insert(EndpointKey::from(Endpoint(0xf3)), "ep3");
+        assert_eq!(queues.len(), 3);
+        assert_eq!(queues[&ep3_in.key()], "ep3");
+        assert_eq!(ep3_in.key().to_string(), "EP3 IN");
+    }
+
+    #[test]
+    fn set_address_value() {
+        assert_eq!(Address::from_set_address_value(0), Address::new(0));
+        assert_eq!(Address::from_set_address_value(5), Address::new(5));
+        assert_eq!(Address::from_set_address_value(127), Address::new(127));
+        assert_eq!(Address::from_set_address_value(128), None);
+        assert_eq!(Address::from_set_address_value(0x0105), None);
+    }
+
+    #[test]
+    fn reserved_request_types_decode() {
+        // Reserved type 3 and recipient 31.
+        let setup = IocSetupPacket {
+            bm_request_type: 0x7F,
+            b_request: 6,
+            ..Default::default()
+        };
+        assert_eq!(
+            setup.try_control_type(),
+            Err(DecodeError::RequestType(0x7F))
+        );
+        assert_eq!(setup.try_recipient(), Err(DecodeError::Recipient(0x7F)));
+        assert_eq!(setup.req().req(), crate::usbfs::Req::Other(6));
+        assert_eq!(
+            setup.req().to_string(),
+            "[Out | Reserved | Reserved] Other(6)"
+        );
+        let _ = setup.to_string();
+    }
+
+    #[test]
+    fn default_work_is_all_zeros() {
+        let work = IocWork::default();
+        // SAFETY: `IocWork` has no implicit padding, and the union
+        //         is zeroed as a whole by its `Default`.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                (&raw const work).cast::<u8>(),
+                std::mem::size_of::<IocWork>(),
+            )
+        };
+        assert!(bytes.iter().all(|&b| b == 0));
+    }
+
+    #[cfg(feature = "zerocopy")]
+    #[test]
+    fn default_arguments_are_all_zeros() {
+        assert!(IocRegister::new(0).as_bytes().iter().all(|&b| b == 0));
+        assert!(IocPortStat::default().as_bytes().iter().all(|&b| b == 0));
+        assert!(IocUrb::default().as_bytes().iter().all(|&b| b == 0));
+    }
+
+    #[cfg(feature = "zerocopy")]
+    #[test]
+    fn byte_round_trips() {
+        // Laid out like the `struct usb_vhci_ioc_*` of the C headers
+        // on a little endian machine.
+        let register = [
+            1, 0, 0, 0, 3, 0, 0, 0, b'u', b's', b'b', b'_', b'v', b'h', b'c', b'i', b'_', b'h',
+            b'c', b'd', b'.', b'0', 0, 0, 0, 0, 0, 0, 2, 0, 0, 0,
+        ];
+        let stat = [0x03, 0x01, 0x01, 0x00, 2, 0, 0, 0];
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let urb = [
+            0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00, // setup_packet
+            0x12, 0, 0, 0, // buffer_length
+            0, 0, 0, 0, // interval
+            0, 0, 0, 0, // packet_count
+            0x01, 0x00, // flags
+            5, 0x80, 2, // address, endpoint, type
+            0, 0, 0,
+        ];
+        let iso_data = [0x40, 0, 0, 0, 0x10, 0, 0, 0];
+        let iso_giveback = [0x10, 0, 0, 0, 0xee, 0xff, 0xff, 0xff];
+
+        let parsed = IocRegister::read_from_bytes(&register).unwrap();
+        assert_eq!((parsed.id, parsed.usb_busnum, parsed.port_count), (1, 3, 2));
+        assert_eq!(
+            parsed.bus_id().to_str().unwrap().trim_end_matches('\0'),
+            "usb_vhci_hcd.0"
+        );
+        assert_eq!(parsed.as_bytes(), register);
+
+        let parsed = IocPortStat::read_from_bytes(&stat).unwrap();
+        assert_eq!(parsed.status_raw(), 0x0103);
+        assert_eq!(parsed.index, 2);
+        assert_eq!(parsed.as_bytes(), stat);
+
+        let parsed = IocSetupPacket::read_from_bytes(&setup).unwrap();
+        assert_eq!(parsed.value(), 0x0100);
+        assert_eq!(parsed.length(), 18);
+        assert_eq!(parsed.as_bytes(), setup);
+
+        let parsed = IocUrb::read_from_bytes(&urb).unwrap();
+        assert_eq!(
+            parsed.setup_packet,
+            IocSetupPacket::read_from_bytes(&setup).unwrap()
+        );
+        assert_eq!(parsed.buffer_length, 18);
+        assert_eq!(parsed.flags().bits(), 1);
+        assert_eq!(parsed.address, Address::new(5).unwrap());
+        assert_eq!(parsed.endpoint, Endpoint(0x80));
+        assert_eq!(parsed.typ, UrbType::Ctrl);
+        assert_eq!(parsed.as_bytes(), urb);
+
+        let parsed = IocIsoPacketData::read_from_bytes(&iso_data).unwrap();
+        assert_eq!((parsed.offset, parsed.packet_length), (64, 16));
+        assert_eq!(parsed.as_bytes(), iso_data);
+
+        let parsed = IocIsoPacketGiveback::read_from_bytes(&iso_giveback).unwrap();
+        assert_eq!((parsed.packet_actual, parsed.status), (16, -18));
+        assert_eq!(parsed.as_bytes(), iso_giveback);
+    }
+
+    #[cfg(feature = "zerocopy")]
+    #[test]
+    fn rejects_invalid_bytes() {
+        assert_eq!(IocPortStat::read_from_bytes(&[0; 7]), None);
+        let mut urb = [0; 28];
+        urb[24] = 7;
+        assert_eq!(IocUrb::read_from_bytes(&urb), None);
+    }
+
+    #[test]
+    fn port_stat_keeps_unknown_bits() {
+        let stat = IocPortStat {
+            status: 0x8103,
+            change: 0x8001,
+            index: 1,
+            ..Default::default()
+        };
+        assert_eq!(stat.status().bits(), 0x8103);
+        assert!(stat.status().is_connected());
+        assert!(stat.status().is_powered());
+        assert_eq!(stat.status_raw(), 0x8103);
+        assert!(stat.change().has_connection_changed());
+        assert_eq!(stat.change_raw(), 0x8001);
+        assert!(format!("{stat:?}").contains("0x8000"));
+    }
+
+    #[test]
+    fn checked_work_rejects_bad_urbs() {
+        let work = |urb| IocWork {
+            work: IocWorkUnion { urb },
+            typ: WorkType::ProcessUrb,
+            ..Default::default()
+        };
+        let urb = IocUrb {
+            buffer_length: 8,
+            typ: UrbType::Bulk,
+            ..Default::default()
+        };
+        assert!(matches!(work(urb).work(), Ok(WorkRef::ProcessUrb(_))));
+
+        let negative = IocUrb {
+            buffer_length: -1,
+            ..urb
+        };
+        assert_eq!(
+            Work::try_from(work(negative)).unwrap_err(),
+            DecodeError::BufferLength(-1)
+        );
+        let negative = IocUrb {
+            packet_count: -3,
+            typ: UrbType::Iso,
+            ..urb
+        };
+        assert_eq!(
+            work(negative).work().unwrap_err(),
+            DecodeError::PacketCount(-3)
+        );
+    }
+
+    #[test]
+    fn raw_work_checks_types() {
+        let typ = std::mem::offset_of!(IocWork, typ);
+        let urb_typ = std::mem::offset_of!(IocWork, work) + std::mem::offset_of!(IocUrb, typ);
+
+        let mut bytes = *RawIocWork::with_timeout(100).as_bytes();
+        let work = RawIocWork::from_bytes(bytes).decode().unwrap();
+        assert_eq!((work.typ, work.timeout), (WorkType::PortStat, 100));
+
+        // As the kernel would write them, with every bit pattern
+        // possible.
+        bytes[typ] = 7;
+        assert_eq!(
+            RawIocWork::from_bytes(bytes).decode().unwrap_err(),
+            DecodeError::WorkType(7)
+        );
+        bytes[typ] = USB_VHCI_WORK_TYPE_PROCESS_URB;
+        bytes[urb_typ] = 9;
+        assert_eq!(
+            RawIocWork::from_bytes(bytes).decode().unwrap_err(),
+            DecodeError::UrbType(9)
+        );
+        bytes[urb_typ] = USB_VHCI_URB_TYPE_BULK;
+        let work = RawIocWork::from_bytes(bytes).decode().unwrap();
+        assert!(matches!(
+            work.work(),
+            Ok(WorkRef::ProcessUrb((
+                IocUrb {
+                    typ: UrbType::Bulk,
+                    ..
+                },
+                _
+            )))
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn work_decodes_consistently(ioc_work in work_strategy()) {
+            let typ = ioc_work.typ;
+            let handle = UrbHandle(ioc_work.handle);
+            let borrowed = match ioc_work.get() {
+                WorkRef::PortStat(port) => Work::PortStat(port),
+                WorkRef::ProcessUrb((urb, handle)) => Work::ProcessUrb((*urb, handle)),
+                WorkRef::CancelUrb(handle) => Work::CancelUrb(handle),
+            };
+            prop_assert!(ioc_work.work().is_ok());
+            let checked = Work::try_from(ioc_work.clone()).unwrap();
+            prop_assert_eq!(format!("{checked:?}"), format!("{borrowed:?}"));
+            // SAFETY: The strategy always sets `typ` to match the union.
+            let owned = unsafe { ioc_work.into_inner() };
+            match (typ, borrowed, owned) {
+                (WorkType::PortStat, Work::PortStat(a), Work::PortStat(b)) => {
+                    prop_assert!(a == b);
+                    prop_assert!(a.index().get() >= 1);
+                }
+                (WorkType::ProcessUrb, Work::ProcessUrb((a, ha)), Work::ProcessUrb((b, hb))) => {
+                    prop_assert_eq!(a, b);
+                    prop_assert_eq!(ha, handle);
+                    prop_assert_eq!(hb, handle);
+                    // Decoding the setup packet must never panic.
+                    let _ = a.setup_packet.req().req();
+                    let _ = a.setup_packet.to_string();
+                }
+                (WorkType::CancelUrb, Work::CancelUrb(a), Work::CancelUrb(b)) => {
+                    prop_assert_eq!(a, handle);
+                    prop_assert_eq!(b, handle);
+                }
+                (typ, a, b) => prop_assert!(false, "{typ:?} decoded as {a:?} / {b:?}"),
+            }
+        }
+    }
+}
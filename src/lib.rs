@@ -1,21 +1,113 @@
+//! Userspace side of the `usb-vhci-hcd` virtual host controller.
+//!
+//! [`Controller`] registers a controller with the kernel and fetches
+//! its work: port status changes and URBs for the emulated devices.
+//! Most programs only need [`prelude`].
+
 use bitflags::bitflags;
 use utils::BoundedU8;
 
 #[cfg(feature = "zerocopy")]
 use zerocopy_derive::*;
 
+pub use addresses::DeviceAddressMap;
+#[cfg(feature = "controller")]
+pub use async_work::{AsyncController, AsyncWorkReceiver, FetchWork, WorkReadiness};
+#[cfg(feature = "controller")]
+pub use automaton::{AttachPolicy, AutomatonEvent, PortAutomaton};
+pub use builder::UrbBuilder;
+pub use bulk::{BulkReader, BulkWriter};
+#[cfg(feature = "controller")]
+pub use callbacks::{CompletionCallbacks, UrbCompletion};
+#[cfg(feature = "controller")]
+pub use controller::{
+    Controller, FetchOutcome, GivebackHandle, GivebackOutcome, PortControl, PortInfo,
+    PortMilestone, PortReservation, PortSignaler, PortUsage, Remote, TaggedWork, WaitError,
+    WorkIter, WorkReceiver, WorkReceiverRef, WorkTag,
+};
 #[cfg(feature = "controller")]
-pub use controller::{Controller, Remote, WorkReceiver};
+pub use deferred::{DeferredCompletion, DeferredCompletions};
+pub use endpoints::{EndpointAllocator, EndpointError};
+#[cfg(feature = "controller")]
+pub use error::{Error, Result};
+pub use filter::{ForeignPolicy, UrbFilter, UrbTarget};
+pub use halt::{HaltAction, HaltState, FEATURE_ENDPOINT_HALT};
 pub use nix::libc;
-
+pub use observer::{EnumEvent, EnumerationObserver, RecordingObserver};
+pub use pending::PendingUrbs;
+pub use pool::BufferPool;
+pub use port::{PortEvent, PortStateTracker, PortUpdate, PortUpdateError};
+pub use preconfig::{PreConfigAction, PreConfigGate, PreConfigUrbPolicy};
+pub use quarantine::AddressQuarantine;
+pub use registry::{DescriptorSummary, DeviceInfo, DeviceMetrics, DeviceRegistry, DeviceState};
+#[cfg(feature = "controller")]
+pub use runner::{RunError, RunSummary, Runner, UrbTypeCounts};
+#[cfg(feature = "controller")]
+pub use select::ControllerSet;
+pub use session::SessionSnapshot;
+pub use urb::{
+    effective_max_packet, is_short_terminated, packet_count_for, Chunk, ChunkMut, ControlError,
+    ControlTransaction, IsoPacketError, IsoPacketMut, TransferAssembler, UrbDecodeError,
+    UrbWithData,
+};
+
+mod addresses;
+#[cfg(feature = "controller")]
+mod async_work;
+#[cfg(feature = "controller")]
+mod automaton;
+pub mod bandwidth;
+mod builder;
+mod bulk;
+#[cfg(feature = "controller")]
+mod callbacks;
 #[cfg(feature = "controller")]
 mod controller;
+#[cfg(feature = "controller")]
+mod deferred;
+#[cfg(feature = "dfu")]
+pub mod dfu;
+mod endpoints;
+#[cfg(feature = "controller")]
+mod error;
+mod filter;
+mod halt;
 pub mod ioctl;
+#[cfg(feature = "midi")]
+pub mod midi;
+mod observer;
+mod pending;
+mod pool;
+mod port;
+mod preconfig;
+pub mod prelude;
+mod quarantine;
+mod registry;
+#[cfg(feature = "controller")]
+mod runner;
+#[cfg(feature = "controller")]
+mod select;
+#[cfg(feature = "serde")]
+mod serde_flags;
+mod session;
+pub mod speed;
+#[cfg(any(test, feature = "proptest"))]
+pub mod strategies;
+mod urb;
 pub mod usbfs;
 pub mod utils;
 
 pub const MAX_ISO_PACKETS: usize = 64;
 
+/// Largest transfer buffer [`UrbWithData::try_from_ioctl`] accepts,
+/// the default usbfs memory limit of Linux. An IN URB can be filled
+/// up to this length and given back, see [`Urb::bytes_transferred`].
+pub const MAX_BUFFER_LENGTH: usize = 16 * 1024 * 1024;
+
+#[cfg_attr(
+    feature = "zerocopy",
+    derive(KnownLayout, Immutable, IntoBytes, Unaligned)
+)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct Port(BoundedU8<1, 32>);
@@ -36,6 +128,27 @@ impl Port {
     }
 }
 
+/// Serialized as the port number.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Port {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.get())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Port {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let port = u8::deserialize(deserializer)?;
+        Self::new(port).ok_or_else(|| {
+            serde::de::Error::invalid_value(
+                serde::de::Unexpected::Unsigned(port.into()),
+                &"a port number from 1 to 32",
+            )
+        })
+    }
+}
+
 #[cfg_attr(
     feature = "zerocopy",
     derive(KnownLayout, Immutable, IntoBytes, FromZeros)
@@ -107,6 +220,76 @@ impl Status {
             _ => Status::Error,
         }
     }
+
+    /// The status as an [`std::io::Error`] carrying its errno, see
+    /// [`Status::to_errno_raw`]. Meant for statuses other than
+    /// [`Status::Success`].
+    pub fn to_io_error(&self, is_iso: bool) -> std::io::Error {
+        std::io::Error::from_raw_os_error(-self.to_errno_raw(is_iso))
+    }
+
+    /// The status for a failed I/O operation, e.g. of a backend
+    /// behind an endpoint. The errno is used when there is one, the
+    /// kind otherwise:
+    ///
+    /// | [`std::io::ErrorKind`] | [`Status`] |
+    /// |---|---|
+    /// | `TimedOut` | `TimedOut` |
+    /// | `BrokenPipe` | `Stall` |
+    /// | `ConnectionReset`, `Interrupted` | `Canceled` |
+    /// | `ConnectionAborted` | `DeviceDisabled` |
+    /// | `NotConnected` | `DeviceDisconnected` |
+    /// | `UnexpectedEof` | `ShortPacket` |
+    /// | `InvalidData` | `Crc` |
+    /// | anything else | `Error` |
+    pub fn from_io_error(err: &std::io::Error, is_iso: bool) -> Self {
+        use std::io::ErrorKind;
+        if let Some(errno) = err.raw_os_error() {
+            return Self::from_errno_raw(-errno, is_iso);
+        }
+        match err.kind() {
+            ErrorKind::TimedOut => Status::TimedOut,
+            ErrorKind::BrokenPipe => Status::Stall,
+            ErrorKind::ConnectionReset | ErrorKind::Interrupted => Status::Canceled,
+            ErrorKind::ConnectionAborted => Status::DeviceDisabled,
+            ErrorKind::NotConnected => Status::DeviceDisconnected,
+            ErrorKind::UnexpectedEof => Status::ShortPacket,
+            ErrorKind::InvalidData => Status::Crc,
+            _ => Status::Error,
+        }
+    }
+}
+
+/// | [`Status`] | [`std::io::ErrorKind`] |
+/// |---|---|
+/// | `Pending` | `WouldBlock` |
+/// | `ShortPacket` | `UnexpectedEof` |
+/// | `Canceled` | `ConnectionReset` |
+/// | `TimedOut`, `NoResponse` | `TimedOut` |
+/// | `DeviceDisabled` | `ConnectionAborted` |
+/// | `DeviceDisconnected` | `NotConnected` |
+/// | `BitStuff`, `Crc`, `Babble` | `InvalidData` |
+/// | `Stall` | `BrokenPipe` |
+/// | `Success`, `Error`, buffer over- and underruns, failed iso packets | `Other` |
+impl From<Status> for std::io::ErrorKind {
+    fn from(value: Status) -> Self {
+        use std::io::ErrorKind;
+        match value {
+            Status::Pending => ErrorKind::WouldBlock,
+            Status::ShortPacket => ErrorKind::UnexpectedEof,
+            Status::Canceled => ErrorKind::ConnectionReset,
+            Status::TimedOut | Status::NoResponse => ErrorKind::TimedOut,
+            Status::DeviceDisabled => ErrorKind::ConnectionAborted,
+            Status::DeviceDisconnected => ErrorKind::NotConnected,
+            Status::BitStuff | Status::Crc | Status::Babble => ErrorKind::InvalidData,
+            Status::Stall => ErrorKind::BrokenPipe,
+            Status::Success
+            | Status::Error
+            | Status::BufferOverrun
+            | Status::BufferUnderrun
+            | Status::AllIsoPacketsFailed => ErrorKind::Other,
+        }
+    }
 }
 
 bitflags! {
@@ -120,12 +303,39 @@ bitflags! {
     }
 }
 
+impl UrbFlags {
+    /// Decodes the flags of an URB, keeping bits that have no name
+    /// here so they still show up in [`UrbFlags::unknown_bits`]
+    /// and the `Display` output.
+    pub const fn from_raw(bits: u16) -> Self {
+        Self::from_bits_retain(bits)
+    }
+
+    /// Bits without a name in this crate.
+    pub const fn unknown_bits(&self) -> u16 {
+        self.bits()
+            & !(Self::SHORT_NOT_OK.bits() | Self::ISO_ASAP.bits() | Self::ZERO_PACKET.bits())
+    }
+}
+
+/// An URB that can be handed to [`Remote::fetch_data`] and
+/// [`Remote::giveback`].
+///
+/// For IN URBs, a non-zero [`Urb::bytes_transferred`] must equal the
+/// length of [`TransferMut::transfer_mut`], and the transfer must fit
+/// into an `i32`. Isochronous URBs have at most [`MAX_ISO_PACKETS`]
+/// packets. URBs that break this are rejected with
+/// [`Error::InvalidUrb`].
+///
+/// [`Remote::fetch_data`]: crate::Remote::fetch_data
+/// [`Remote::giveback`]: crate::Remote::giveback
+/// [`Error::InvalidUrb`]: crate::Error::InvalidUrb
 pub trait Urb {
     fn kind(&self) -> ioctl::UrbType;
     fn handle(&self) -> ioctl::UrbHandle;
     fn status(&self) -> Status;
     fn dir(&self) -> usbfs::Dir;
-    fn bytes_transferred(&self) -> u16;
+    fn bytes_transferred(&self) -> usize;
 }
 
 pub trait Transfer {
@@ -174,7 +384,7 @@ where
         T::dir(self)
     }
 
-    fn bytes_transferred(&self) -> u16 {
+    fn bytes_transferred(&self) -> usize {
         T::bytes_transferred(self)
     }
 }
@@ -275,9 +485,337 @@ bitflags::bitflags! {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl PortStatus {
+    /// Speed of the attached device. Mirrors the Linux hub driver,
+    /// which checks HIGH_SPEED before LOW_SPEED, so a (bogus) status
+    /// with both bits set reports [`DataRate::High`].
+    pub const fn speed(&self) -> DataRate {
+        DataRate::from_port_status(*self)
+    }
+
+    pub const fn is_connected(&self) -> bool {
+        self.contains(PortStatus::CONNECTION)
+    }
+
+    pub const fn is_enabled(&self) -> bool {
+        self.contains(PortStatus::ENABLE)
+    }
+
+    pub const fn is_powered(&self) -> bool {
+        self.contains(PortStatus::POWER)
+    }
+
+    pub const fn is_suspended(&self) -> bool {
+        self.contains(PortStatus::SUSPEND)
+    }
+
+    pub const fn in_reset(&self) -> bool {
+        self.contains(PortStatus::RESET)
+    }
+}
+
+impl PortChange {
+    pub const fn has_connection_changed(&self) -> bool {
+        self.contains(PortChange::CONNECTION)
+    }
+
+    pub const fn has_enable_changed(&self) -> bool {
+        self.contains(PortChange::ENABLE)
+    }
+
+    pub const fn has_suspend_changed(&self) -> bool {
+        self.contains(PortChange::SUSPEND)
+    }
+
+    pub const fn has_overcurrent_changed(&self) -> bool {
+        self.contains(PortChange::OVERCURRENT)
+    }
+
+    pub const fn has_reset_changed(&self) -> bool {
+        self.contains(PortChange::RESET)
+    }
+}
+
+/// `names` and `unknown_bits` for the port bitflags.
+macro_rules! named_flags {
+    ($($ty:ident: $bits:ty),* $(,)?) => {$(
+        impl $ty {
+            /// Names of the set flags, e.g. for metrics labels. Bits
+            /// without a name are left out, see `unknown_bits`.
+            pub fn names(&self) -> impl Iterator<Item = &'static str> {
+                self.iter_names().map(|(name, _)| name)
+            }
+
+            /// Bits without a name in this crate.
+            pub fn unknown_bits(&self) -> $bits {
+                let mut names = self.iter_names();
+                names.by_ref().for_each(drop);
+                names.remaining().bits()
+            }
+        }
+    )*};
+}
+
+named_flags!(PortStatus: u16, PortChange: u16, PortFlag: u8);
+
+/// Writes the names of the set flags separated by `|`, followed by
+/// any bits without a name in hex, e.g. `CONNECTION|POWER (+0x8000)`.
+fn fmt_flags<F>(flags: &F, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+where
+    F: bitflags::Flags,
+    F::Bits: std::fmt::LowerHex,
+{
+    let mut residual = flags.bits();
+    let mut empty = true;
+    for (name, flag) in flags.iter_names() {
+        if !empty {
+            f.write_str("|")?;
+        }
+        f.write_str(name)?;
+        residual = residual & !flag.bits();
+        empty = false;
+    }
+
+    match (empty, residual == <F::Bits as bitflags::Bits>::EMPTY) {
+        (true, true) => f.write_str("(empty)"),
+        (true, false) => write!(f, "{residual:#x}"),
+        (false, false) => write!(f, " (+{residual:#x})"),
+        (false, true) => Ok(()),
+    }
+}
+
+impl std::fmt::Display for UrbFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_flags(self, f)
+    }
+}
+
+impl std::fmt::Display for PortStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_flags(self, f)
+    }
+}
+
+impl std::fmt::Display for PortChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_flags(self, f)
+    }
+}
+
+impl std::fmt::Display for PortFlag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_flags(self, f)
+    }
+}
+
+/// Connection speed of a port.
+///
+/// `usb-vhci-hcd` registers as a USB 2.0 host controller, so its
+/// port status word has no SuperSpeed encoding and there is no
+/// variant for it here.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "zerocopy",
+    derive(KnownLayout, Immutable, IntoBytes, FromZeros, Unaligned)
+)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, num_enum::TryFromPrimitive, num_enum::IntoPrimitive,
+)]
+#[repr(u8)]
 pub enum DataRate {
     Full = 0,
     Low = 1,
     High = 2,
 }
+
+impl DataRate {
+    /// See [`PortStatus::speed`].
+    pub const fn from_port_status(status: PortStatus) -> Self {
+        if status.contains(PortStatus::HIGH_SPEED) {
+            DataRate::High
+        } else if status.contains(PortStatus::LOW_SPEED) {
+            DataRate::Low
+        } else {
+            DataRate::Full
+        }
+    }
+}
+
+impl From<PortStatus> for DataRate {
+    fn from(status: PortStatus) -> Self {
+        Self::from_port_status(status)
+    }
+}
+
+impl std::fmt::Display for DataRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DataRate::Full => "full-speed",
+            DataRate::Low => "low-speed",
+            DataRate::High => "high-speed",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::{
+        strategies::any_status, DataRate, PortChange, PortFlag, PortStatus, Status, UrbFlags,
+    };
+
+    #[test]
+    fn status_io_error_kinds() {
+        use std::io::ErrorKind;
+
+        let table = [
+            (Status::Success, ErrorKind::Other),
+            (Status::Pending, ErrorKind::WouldBlock),
+            (Status::ShortPacket, ErrorKind::UnexpectedEof),
+            (Status::Error, ErrorKind::Other),
+            (Status::Canceled, ErrorKind::ConnectionReset),
+            (Status::TimedOut, ErrorKind::TimedOut),
+            (Status::DeviceDisabled, ErrorKind::ConnectionAborted),
+            (Status::DeviceDisconnected, ErrorKind::NotConnected),
+            (Status::BitStuff, ErrorKind::InvalidData),
+            (Status::Crc, ErrorKind::InvalidData),
+            (Status::NoResponse, ErrorKind::TimedOut),
+            (Status::Babble, ErrorKind::InvalidData),
+            (Status::Stall, ErrorKind::BrokenPipe),
+            (Status::BufferOverrun, ErrorKind::Other),
+            (Status::BufferUnderrun, ErrorKind::Other),
+            (Status::AllIsoPacketsFailed, ErrorKind::Other),
+        ];
+        for (status, kind) in table {
+            assert_eq!(ErrorKind::from(status), kind, "{status:?}");
+        }
+
+        let table = [
+            (ErrorKind::TimedOut, Status::TimedOut),
+            (ErrorKind::BrokenPipe, Status::Stall),
+            (ErrorKind::ConnectionReset, Status::Canceled),
+            (ErrorKind::Interrupted, Status::Canceled),
+            (ErrorKind::ConnectionAborted, Status::DeviceDisabled),
+            (ErrorKind::NotConnected, Status::DeviceDisconnected),
+            (ErrorKind::UnexpectedEof, Status::ShortPacket),
+            (ErrorKind::InvalidData, Status::Crc),
+            (ErrorKind::PermissionDenied, Status::Error),
+        ];
+        for (kind, status) in table {
+            let err = std::io::Error::from(kind);
+            assert_eq!(Status::from_io_error(&err, false), status, "{kind:?}");
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn status_io_error_round_trip(status in any_status(), is_iso: bool) {
+            let err = status.to_io_error(is_iso);
+            prop_assert_eq!(err.raw_os_error(), Some(-status.to_errno_raw(is_iso)));
+            prop_assert_eq!(
+                Status::from_io_error(&err, is_iso),
+                Status::from_errno_raw(status.to_errno_raw(is_iso), is_iso)
+            );
+        }
+    }
+
+    #[test]
+    fn port_flag_names() {
+        let status = PortStatus::from_bits_retain(0x8103);
+        assert_eq!(
+            status.names().collect::<Vec<_>>(),
+            ["CONNECTION", "ENABLE", "POWER"]
+        );
+        assert_eq!(status.unknown_bits(), 0x8000);
+        assert_eq!(PortChange::RESET.names().collect::<Vec<_>>(), ["RESET"]);
+        assert_eq!(PortChange::RESET.unknown_bits(), 0);
+        assert_eq!(PortFlag::from_bits_retain(0x81).unknown_bits(), 0x80);
+        assert_eq!(PortFlag::empty().names().count(), 0);
+    }
+
+    #[test]
+    fn speed_from_port_status() {
+        let table = [
+            (PortStatus::empty(), DataRate::Full),
+            (PortStatus::LOW_SPEED, DataRate::Low),
+            (PortStatus::HIGH_SPEED, DataRate::High),
+            (
+                PortStatus::LOW_SPEED | PortStatus::HIGH_SPEED,
+                DataRate::High,
+            ),
+            (PortStatus::CONNECTION | PortStatus::POWER, DataRate::Full),
+            (
+                PortStatus::CONNECTION | PortStatus::LOW_SPEED,
+                DataRate::Low,
+            ),
+        ];
+        for (status, speed) in table {
+            assert_eq!(status.speed(), speed, "{status:?}");
+        }
+    }
+
+    #[test]
+    fn port_flags_display() {
+        let status = PortStatus::CONNECTION | PortStatus::ENABLE | PortStatus::POWER;
+        assert_eq!(status.to_string(), "CONNECTION|ENABLE|POWER");
+        assert_eq!(
+            PortStatus::from_bits_retain(0x8103).to_string(),
+            "CONNECTION|ENABLE|POWER (+0x8000)"
+        );
+        assert_eq!(PortStatus::from_bits_retain(0x0800).to_string(), "0x800");
+        assert_eq!(PortStatus::empty().to_string(), "(empty)");
+        assert_eq!(
+            (PortChange::CONNECTION | PortChange::RESET).to_string(),
+            "CONNECTION|RESET"
+        );
+        assert_eq!(
+            PortFlag::from_bits_retain(0x81).to_string(),
+            "RESUMING (+0x80)"
+        );
+    }
+
+    #[test]
+    fn urb_flags_keep_unknown_bits() {
+        let flags = UrbFlags::from_raw(0x0041);
+        assert_eq!(flags.unknown_bits(), 0);
+        assert_eq!(flags.to_string(), "SHORT_NOT_OK|ZERO_PACKET");
+
+        let flags = UrbFlags::from_raw(0x0203);
+        assert_eq!(flags.unknown_bits(), 0x0200);
+        assert_eq!(flags.bits(), 0x0203);
+        assert_eq!(flags.to_string(), "SHORT_NOT_OK|ISO_ASAP (+0x200)");
+        assert_eq!(UrbFlags::from_raw(0x0004).to_string(), "0x4");
+    }
+
+    #[test]
+    fn data_rate_conversions() {
+        for rate in [DataRate::Full, DataRate::Low, DataRate::High] {
+            let raw: u8 = rate.into();
+            assert_eq!(DataRate::try_from(raw).unwrap(), rate);
+        }
+        assert!(DataRate::try_from(3).is_err());
+        assert_eq!(DataRate::High.to_string(), "high-speed");
+    }
+
+    proptest! {
+        #[test]
+        fn errno_round_trips(status in any_status(), is_iso in any::<bool>()) {
+            let decoded = Status::from_errno_raw(status.to_errno_raw(is_iso), is_iso);
+            match status {
+                // Both share EPROTO with BitStuff outside of iso transfers.
+                Status::Error | Status::AllIsoPacketsFailed if !is_iso => {
+                    prop_assert_eq!(decoded, Status::BitStuff)
+                }
+                // EXDEV has no dedicated decoding.
+                Status::Error => prop_assert_eq!(decoded, Status::Error),
+                _ => prop_assert_eq!(decoded, status),
+            }
+        }
+
+        #[test]
+        fn unknown_errno_decodes_to_error(errno in 1000i32..i32::MAX, is_iso in any::<bool>()) {
+            prop_assert_eq!(Status::from_errno_raw(-errno, is_iso), Status::Error);
+        }
+    }
+}
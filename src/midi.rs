@@ -0,0 +1,204 @@
+//! USB-MIDI 1.0 event packets, the 4-byte units carried by the bulk
+//! endpoints of a MIDIStreaming interface.
+
+/// One complete MIDI message other than system exclusive, always
+/// starting with its status byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MidiMessage {
+    bytes: [u8; 3],
+    len: u8,
+}
+
+impl MidiMessage {
+    /// Returns `None` unless `bytes` is exactly one message with an
+    /// explicit status byte. Running status is not accepted, every
+    /// event packet carries its own status.
+    pub const fn new(bytes: &[u8]) -> Option<Self> {
+        let status = match bytes.first() {
+            Some(&status) => status,
+            None => return None,
+        };
+        let len = match Self::len_for(status) {
+            Some(len) => len,
+            None => return None,
+        };
+        if bytes.len() != len {
+            return None;
+        }
+
+        let mut message = Self {
+            bytes: [0; 3],
+            len: len as u8,
+        };
+        let mut i = 1;
+        while i < len {
+            if bytes[i] & 0x80 != 0 {
+                return None;
+            }
+            message.bytes[i] = bytes[i];
+            i += 1;
+        }
+        message.bytes[0] = status;
+        Some(message)
+    }
+
+    /// Length of a message with `status`, or `None` for data bytes
+    /// and the system exclusive bytes.
+    const fn len_for(status: u8) -> Option<usize> {
+        match status {
+            0x80..=0xBF | 0xE0..=0xEF | 0xF2 => Some(3),
+            0xC0..=0xDF | 0xF1 | 0xF3 => Some(2),
+            0xF6 | 0xF8..=0xFF => Some(1),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len.into()]
+    }
+
+    const fn cin(&self) -> u8 {
+        match self.bytes[0] {
+            status @ 0x80..=0xEF => status >> 4,
+            0xF1 | 0xF3 => 0x2,
+            0xF2 => 0x3,
+            0xF6 => 0x5,
+            _ => 0xF,
+        }
+    }
+}
+
+/// A USB-MIDI event packet: cable number and Code Index Number in
+/// the first byte, followed by up to three MIDI bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MidiPacket(pub [u8; 4]);
+
+impl MidiPacket {
+    /// # Panics
+    ///
+    /// Panics if `cable` does not fit into 4 bits.
+    pub const fn new(cable: u8, message: MidiMessage) -> Self {
+        assert!(cable < 16, "cable number out of range");
+        let [a, b, c] = message.bytes;
+        Self([cable << 4 | message.cin(), a, b, c])
+    }
+
+    pub const fn cable(&self) -> u8 {
+        self.0[0] >> 4
+    }
+
+    /// Code Index Number, the kind of the packet.
+    pub const fn cin(&self) -> u8 {
+        self.0[0] & 0x0F
+    }
+
+    /// The MIDI bytes of the packet. Reserved CINs carry none.
+    pub fn data(&self) -> &[u8] {
+        let len = match self.cin() {
+            0x5 | 0xF => 1,
+            0x2 | 0x6 | 0xC | 0xD => 2,
+            0x3 | 0x4 | 0x7..=0xB | 0xE => 3,
+            _ => 0,
+        };
+        &self.0[1..1 + len]
+    }
+
+    /// The message in this packet, or `None` for packets that are
+    /// part of a system exclusive message.
+    pub fn message(&self) -> Option<MidiMessage> {
+        match self.cin() {
+            0x4 | 0x6 | 0x7 => None,
+            _ => MidiMessage::new(self.data()),
+        }
+    }
+
+    /// Splits a system exclusive message, including its `F0` and
+    /// `F7` bytes, into packets of three bytes each.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cable` does not fit into 4 bits.
+    pub fn sysex(cable: u8, sysex: &[u8]) -> impl Iterator<Item = MidiPacket> + '_ {
+        assert!(cable < 16, "cable number out of range");
+        let last = sysex.len().div_ceil(3).saturating_sub(1);
+        sysex.chunks(3).enumerate().map(move |(i, chunk)| {
+            let cin = match (i == last, chunk.len()) {
+                (false, _) => 0x4,
+                (true, 1) => 0x5,
+                (true, 2) => 0x6,
+                (true, _) => 0x7,
+            };
+            let mut packet = [cable << 4 | cin, 0, 0, 0];
+            packet[1..1 + chunk.len()].copy_from_slice(chunk);
+            MidiPacket(packet)
+        })
+    }
+
+    /// Splits the data of a bulk transfer into packets. Trailing
+    /// bytes that do not make up a whole packet are dropped.
+    pub fn parse(transfer: &[u8]) -> impl Iterator<Item = MidiPacket> + '_ {
+        transfer
+            .chunks_exact(4)
+            .map(|chunk| MidiPacket(chunk.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_on_off() {
+        let on = MidiPacket::new(0, MidiMessage::new(&[0x90, 60, 100]).unwrap());
+        assert_eq!(on.0, [0x09, 0x90, 60, 100]);
+        let off = MidiPacket::new(3, MidiMessage::new(&[0x85, 60, 0]).unwrap());
+        assert_eq!(off.0, [0x38, 0x85, 60, 0]);
+        assert_eq!(off.cable(), 3);
+        assert_eq!(off.message().unwrap().as_bytes(), [0x85, 60, 0]);
+
+        let pc = MidiPacket::new(0, MidiMessage::new(&[0xC2, 5]).unwrap());
+        assert_eq!(pc.0, [0x0C, 0xC2, 5, 0]);
+        assert_eq!(pc.data(), [0xC2, 5]);
+        let clock = MidiPacket::new(1, MidiMessage::new(&[0xF8]).unwrap());
+        assert_eq!(clock.0, [0x1F, 0xF8, 0, 0]);
+    }
+
+    #[test]
+    fn no_running_status() {
+        // A second note without its status byte.
+        assert_eq!(MidiMessage::new(&[62, 100]), None);
+        assert_eq!(MidiMessage::new(&[0x90, 62]), None);
+        assert_eq!(MidiMessage::new(&[0x90, 62, 100, 64]), None);
+        assert_eq!(MidiMessage::new(&[0x90, 0x90, 100]), None);
+        assert_eq!(MidiMessage::new(&[0xF0, 0x7E, 0xF7]), None);
+    }
+
+    #[test]
+    fn sysex_fragments() {
+        let sysex = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+        let packets: Vec<_> = MidiPacket::sysex(2, &sysex).map(|p| p.0).collect();
+        assert_eq!(
+            packets,
+            [[0x24, 0xF0, 0x7E, 0x7F], [0x27, 0x06, 0x01, 0xF7]]
+        );
+
+        let sysex = [0xF0, 0x01, 0x02, 0xF7];
+        let packets: Vec<_> = MidiPacket::sysex(0, &sysex).collect();
+        assert_eq!(packets[1].0, [0x05, 0xF7, 0, 0]);
+        assert_eq!(packets[1].data(), [0xF7]);
+        assert!(packets.iter().all(|p| p.message().is_none()));
+
+        let sysex = [0xF0, 0x01, 0x02, 0x03, 0xF7];
+        let packets: Vec<_> = MidiPacket::sysex(0, &sysex).collect();
+        assert_eq!(packets[1].0, [0x06, 0x03, 0xF7, 0]);
+
+        let mut transfer = Vec::new();
+        for packet in MidiPacket::sysex(0, &sysex) {
+            transfer.extend_from_slice(&packet.0);
+        }
+        let joined: Vec<u8> = MidiPacket::parse(&transfer)
+            .flat_map(|p| p.data().to_vec())
+            .collect();
+        assert_eq!(joined, sysex);
+    }
+}
@@ -0,0 +1,252 @@
+use std::{fmt::Write, time::Duration};
+
+use crate::{
+    ioctl::IocSetupPacket,
+    usbfs::Dir,
+    utils::{Clock, SystemClock},
+    PortEvent, Status, Transfer, Urb, UrbWithData,
+};
+
+/// Gets told about every step of an enumeration. All methods do
+/// nothing by default.
+///
+/// When enumeration fails, attach a [`RecordingObserver`] and send
+/// its [`RecordingObserver::report`] along with the bug report.
+pub trait EnumerationObserver {
+    fn on_port_event(&mut self, _event: &PortEvent) {}
+
+    fn on_request(&mut self, _setup: &IocSetupPacket) {}
+
+    /// The request was completed with `data`, which is empty for
+    /// requests without an IN data stage.
+    fn on_reply(&mut self, _setup: &IocSetupPacket, _data: &[u8]) {}
+
+    fn on_stall(&mut self, _setup: &IocSetupPacket) {}
+
+    /// Reports a completed control URB as a request followed by
+    /// its reply or stall. Other URBs are ignored.
+    fn on_control_completed(&mut self, urb: &UrbWithData) {
+        let Some(setup) = urb.control_packet() else {
+            return;
+        };
+        self.on_request(setup);
+        match urb.status() {
+            Status::Stall => self.on_stall(setup),
+            _ if Dir::In == urb.dir() => self.on_reply(setup, urb.transfer()),
+            _ => self.on_reply(setup, &[]),
+        }
+    }
+}
+
+/// A step recorded by [`RecordingObserver`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnumEvent {
+    Port(PortEvent),
+    Request(IocSetupPacket),
+    Reply {
+        setup: IocSetupPacket,
+        data: Vec<u8>,
+    },
+    Stall(IocSetupPacket),
+}
+
+/// Records every step together with the time since the observer
+/// was created.
+#[derive(Debug, Clone)]
+pub struct RecordingObserver<C = SystemClock> {
+    clock: C,
+    start: std::time::Instant,
+    events: Vec<(Duration, EnumEvent)>,
+}
+
+impl RecordingObserver {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl Default for RecordingObserver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> RecordingObserver<C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            start: clock.now(),
+            clock,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn events(&self) -> &[(Duration, EnumEvent)] {
+        &self.events
+    }
+
+    fn push(&mut self, event: EnumEvent) {
+        let at = self.clock.now() - self.start;
+        self.events.push((at, event));
+    }
+
+    /// One line per event, e.g.
+    ///
+    /// ```text
+    /// 0.012 port 1 reset requested
+    /// 0.015 [In | Standard | Device] GetDescriptor value=0x0100 index=0x0000 length=64
+    /// 0.015   -> 18 bytes: 12 01 00 02 00 00 00 40 ...
+    /// ```
+    pub fn report(&self) -> String {
+        /// Reply bytes shown before the rest is elided.
+        const SHOWN: usize = 8;
+
+        let mut report = String::new();
+        for (at, event) in &self.events {
+            let _ = write!(report, "{}.{:03} ", at.as_secs(), at.subsec_millis());
+            let _ = match event {
+                EnumEvent::Port(event) => write!(report, "{event}"),
+                EnumEvent::Request(setup) => write!(
+                    report,
+                    "{} value={:#06x} index={:#06x} length={}",
+                    setup.req(),
+                    setup.value(),
+                    setup.index(),
+                    setup.length()
+                ),
+                EnumEvent::Reply { data, .. } if data.is_empty() => report.write_str("  -> ok"),
+                EnumEvent::Reply { data, .. } => {
+                    let _ = write!(report, "  -> {} bytes:", data.len());
+                    for byte in data.iter().take(SHOWN) {
+                        let _ = write!(report, " {byte:02x}");
+                    }
+                    if data.len() > SHOWN {
+                        report.push_str(" ...");
+                    }
+                    Ok(())
+                }
+                EnumEvent::Stall(_) => report.write_str("  -> STALL"),
+            };
+            report.push('\n');
+        }
+        report
+    }
+}
+
+impl<C: Clock> EnumerationObserver for RecordingObserver<C> {
+    fn on_port_event(&mut self, event: &PortEvent) {
+        self.push(EnumEvent::Port(*event));
+    }
+
+    fn on_request(&mut self, setup: &IocSetupPacket) {
+        self.push(EnumEvent::Request(*setup));
+    }
+
+    fn on_reply(&mut self, setup: &IocSetupPacket, data: &[u8]) {
+        self.push(EnumEvent::Reply {
+            setup: *setup,
+            data: data.to_vec(),
+        });
+    }
+
+    fn on_stall(&mut self, setup: &IocSetupPacket) {
+        self.push(EnumEvent::Stall(*setup));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{usbfs::Request, utils::ManualClock, ControlTransaction, Port};
+
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x09, 0x12, 0x01, 0x00, 0x00, 0x01, 0x01,
+        0x02, 0x00, 0x01,
+    ];
+
+    fn control(
+        observer: &mut impl EnumerationObserver,
+        request: Request,
+        value: u16,
+        length: u16,
+        reply: Option<&[u8]>,
+    ) {
+        let setup = request.setup(value, 0, length).unwrap();
+        let mut urb = UrbWithData::builder().control(setup).build();
+        let mut ctrl = ControlTransaction::new(&mut urb).unwrap();
+        match reply {
+            Some(data) => {
+                if !data.is_empty() {
+                    ctrl.write_reply(data).unwrap();
+                }
+                ctrl.complete(Status::Success);
+            }
+            None => ctrl.complete(Status::Stall),
+        }
+        observer.on_control_completed(&urb);
+    }
+
+    #[test]
+    fn golden_enumeration_report() {
+        let clock = ManualClock::new();
+        let mut observer = RecordingObserver::with_clock(clock.clone());
+        let port = Port::new(1).unwrap();
+        let ms = |n| clock.advance(Duration::from_millis(n));
+
+        observer.on_port_event(&PortEvent::PoweredOn(port));
+        ms(2);
+        observer.on_port_event(&PortEvent::ConnectionChanged {
+            port,
+            connected: true,
+        });
+        ms(100);
+        observer.on_port_event(&PortEvent::ResetRequested(port));
+        ms(12);
+        control(
+            &mut observer,
+            Request::STANDARD_DEVICE_GET_DESCRIPTOR,
+            0x0100,
+            64,
+            Some(&DEVICE_DESCRIPTOR),
+        );
+        ms(3);
+        control(
+            &mut observer,
+            Request::STANDARD_DEVICE_SET_ADDRESS,
+            5,
+            0,
+            Some(&[]),
+        );
+        ms(5);
+        control(
+            &mut observer,
+            Request::STANDARD_DEVICE_GET_DESCRIPTOR,
+            0x0600,
+            10,
+            None,
+        );
+
+        let expected = "\
+0.000 port 1 powered on
+0.002 port 1 connected
+0.102 port 1 reset requested
+0.114 [In | Standard | Device] GetDescriptor value=0x0100 index=0x0000 length=64
+0.114   -> 18 bytes: 12 01 00 02 00 00 00 40 ...
+0.117 [Out | Standard | Device] SetAddress value=0x0005 index=0x0000 length=0
+0.117   -> ok
+0.122 [In | Standard | Device] GetDescriptor value=0x0600 index=0x0000 length=10
+0.122   -> STALL
+";
+        assert_eq!(observer.report(), expected);
+        assert_eq!(observer.events().len(), 9);
+    }
+
+    #[test]
+    fn ignores_non_control_urbs() {
+        let mut observer = RecordingObserver::new();
+        let urb = UrbWithData::builder()
+            .bulk(crate::ioctl::Endpoint(0x81), &[0; 4])
+            .build();
+        observer.on_control_completed(&urb);
+        assert!(observer.events().is_empty());
+    }
+}
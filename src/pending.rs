@@ -0,0 +1,171 @@
+use std::time::{Duration, Instant};
+
+use nohash_hasher::IntMap;
+
+use crate::{
+    ioctl::UrbHandle,
+    utils::{Clock, SystemClock},
+};
+
+/// The URBs fetched but not given back yet, e.g. to match a
+/// `CancelUrb` work item with the URB it cancels.
+///
+/// [`PendingUrbs::insert`] an URB when it is fetched and
+/// [`PendingUrbs::take`] it when it is given back. When a
+/// `CancelUrb` work item arrives, [`PendingUrbs::cancel`] drops the
+/// URB without giving it back, as the kernel already completed it.
+/// URBs the device never answered can be picked up with
+/// [`PendingUrbs::drain_stale`].
+#[derive(Debug)]
+pub struct PendingUrbs<T, C = SystemClock> {
+    clock: C,
+    urbs: IntMap<UrbHandle, (Instant, T)>,
+}
+
+impl<T> PendingUrbs<T> {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl<T> Default for PendingUrbs<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, C: Clock> PendingUrbs<T, C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            clock,
+            urbs: IntMap::default(),
+        }
+    }
+
+    /// Starts tracking `urb`. Returns the URB that was pending with
+    /// the same handle, which the kernel never does.
+    pub fn insert(&mut self, handle: UrbHandle, urb: T) -> Option<T> {
+        self.urbs
+            .insert(handle, (self.clock.now(), urb))
+            .map(|(_, urb)| urb)
+    }
+
+    /// Stops tracking the URB `handle` to give it back.
+    pub fn take(&mut self, handle: UrbHandle) -> Option<T> {
+        self.urbs.remove(&handle).map(|(_, urb)| urb)
+    }
+
+    /// Stops tracking the URB `handle` after a `CancelUrb` work item.
+    /// Returns `None` if it was already given back.
+    pub fn cancel(&mut self, handle: UrbHandle) -> Option<T> {
+        self.take(handle)
+    }
+
+    pub fn get(&self, handle: UrbHandle) -> Option<&T> {
+        self.urbs.get(&handle).map(|(_, urb)| urb)
+    }
+
+    pub fn contains(&self, handle: UrbHandle) -> bool {
+        self.urbs.contains_key(&handle)
+    }
+
+    pub fn len(&self) -> usize {
+        self.urbs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.urbs.is_empty()
+    }
+
+    /// Handles of the pending URBs, in no particular order.
+    pub fn handles(&self) -> impl Iterator<Item = UrbHandle> + '_ {
+        self.urbs.keys().copied()
+    }
+
+    /// Stops tracking the URBs `f` picks, e.g. those of a device that
+    /// went away. They are returned oldest first.
+    pub fn drain_where(&mut self, mut f: impl FnMut(&T) -> bool) -> Vec<(UrbHandle, T)> {
+        self.drain_by(|_, urb| f(urb))
+    }
+
+    /// Stops tracking the URBs pending for `max_age` or longer, e.g.
+    /// to give them back with [`Status::TimedOut`]. They are
+    /// returned oldest first.
+    ///
+    /// [`Status::TimedOut`]: crate::Status::TimedOut
+    pub fn drain_stale(&mut self, max_age: Duration) -> Vec<(UrbHandle, T)> {
+        let now = self.clock.now();
+        self.drain_by(|since, _| now - since >= max_age)
+    }
+
+    fn drain_by(&mut self, mut f: impl FnMut(Instant, &T) -> bool) -> Vec<(UrbHandle, T)> {
+        let mut picked: Vec<_> = self
+            .urbs
+            .iter()
+            .filter(|(_, (since, urb))| f(*since, urb))
+            .map(|(&handle, &(since, _))| (since, handle.get()))
+            .collect();
+        picked.sort_unstable();
+        picked
+            .into_iter()
+            .filter_map(|(_, handle)| {
+                let handle = UrbHandle(handle);
+                self.take(handle).map(|urb| (handle, urb))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ioctl::Endpoint, utils::ManualClock, Urb, UrbWithData};
+
+    fn bulk_in(handle: u64) -> UrbWithData {
+        UrbWithData::builder()
+            .bulk(Endpoint(0x81), &[0; 8])
+            .handle(UrbHandle(handle))
+            .build()
+    }
+
+    #[test]
+    fn cancel_and_giveback() {
+        let mut pending = PendingUrbs::new();
+        for handle in 1..=3 {
+            assert!(pending.insert(UrbHandle(handle), bulk_in(handle)).is_none());
+        }
+        assert_eq!(pending.len(), 3);
+
+        // Canceled before the device answered.
+        let canceled = pending.cancel(UrbHandle(2)).unwrap();
+        assert_eq!(canceled.handle(), UrbHandle(2));
+        assert!(pending.take(UrbHandle(2)).is_none());
+
+        // Given back before the cancel arrived.
+        assert!(pending.take(UrbHandle(1)).is_some());
+        assert!(pending.cancel(UrbHandle(1)).is_none());
+
+        let handles: Vec<_> = pending.handles().collect();
+        assert_eq!(handles, [UrbHandle(3)]);
+        assert!(pending.contains(UrbHandle(3)));
+        assert_eq!(pending.get(UrbHandle(3)).unwrap().buffer_length(), 8);
+    }
+
+    #[test]
+    fn drains_stale_urbs_oldest_first() {
+        let clock = ManualClock::new();
+        let mut pending = PendingUrbs::with_clock(clock.clone());
+        pending.insert(UrbHandle(7), bulk_in(7));
+        clock.advance(Duration::from_millis(10));
+        pending.insert(UrbHandle(3), bulk_in(3));
+        clock.advance(Duration::from_millis(10));
+        pending.insert(UrbHandle(5), bulk_in(5));
+
+        clock.advance(Duration::from_millis(500));
+        let stale = pending.drain_stale(Duration::from_millis(505));
+        let handles: Vec<_> = stale.iter().map(|(handle, _)| handle.get()).collect();
+        assert_eq!(handles, [7, 3]);
+        assert_eq!(pending.len(), 1);
+        assert!(pending.drain_stale(Duration::from_secs(1)).is_empty());
+    }
+}
@@ -0,0 +1,45 @@
+/// Transfer buffers kept around for the next URBs, so a busy device
+/// doesn't allocate one per URB.
+///
+/// ```
+/// # use usb_vhci::{ioctl::{Endpoint, IocUrb, UrbHandle, UrbType}, BufferPool, UrbWithData};
+/// # let urb = IocUrb { buffer_length: 64, endpoint: Endpoint(0x81), typ: UrbType::Bulk, ..Default::default() };
+/// let mut pool = BufferPool::new();
+/// let urb = UrbWithData::from_ioctl_with_buffer(urb, UrbHandle(1), pool.get());
+/// // ... complete and give back the URB ...
+/// pool.put(urb.into_buffer());
+/// assert_eq!(pool.len(), 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A buffer from the pool, or a new empty one if the pool ran
+    /// dry.
+    pub fn get(&mut self) -> Vec<u8> {
+        self.buffers.pop().unwrap_or_default()
+    }
+
+    /// Keeps `buf` for a later [`BufferPool::get`]. Buffers that
+    /// never allocated are dropped.
+    pub fn put(&mut self, buf: Vec<u8>) {
+        if buf.capacity() > 0 {
+            self.buffers.push(buf);
+        }
+    }
+
+    /// Number of buffers in the pool.
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+}
@@ -0,0 +1,507 @@
+use nohash_hasher::IntMap;
+
+use crate::{
+    ioctl::{DecodeError, IocPortStat},
+    Port, PortChange, PortFlag, PortStatus,
+};
+
+/// High level port transition, derived from two consecutive
+/// [`IocPortStat`] work items for the same port.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortEvent {
+    /// The host powered the port; a device can now be connected.
+    PoweredOn(Port),
+
+    /// The host removed power from the port.
+    PoweredOff(Port),
+
+    /// The host started resetting a connected port and waits
+    /// for the reset to be completed.
+    ResetRequested(Port),
+
+    /// The host started resuming a connected port and waits
+    /// for the resume to be completed.
+    ResumeRequested(Port),
+
+    /// The host suspended the port.
+    SuspendRequested(Port),
+
+    /// The connection state of the port changed.
+    ConnectionChanged { port: Port, connected: bool },
+}
+
+impl PortEvent {
+    pub const fn port(&self) -> Port {
+        match self {
+            PortEvent::PoweredOn(port)
+            | PortEvent::PoweredOff(port)
+            | PortEvent::ResetRequested(port)
+            | PortEvent::ResumeRequested(port)
+            | PortEvent::SuspendRequested(port)
+            | PortEvent::ConnectionChanged { port, .. } => *port,
+        }
+    }
+}
+
+/// E.g. `port 1 reset requested`.
+impl std::fmt::Display for PortEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let what = match self {
+            PortEvent::PoweredOn(_) => "powered on",
+            PortEvent::PoweredOff(_) => "powered off",
+            PortEvent::ResetRequested(_) => "reset requested",
+            PortEvent::ResumeRequested(_) => "resume requested",
+            PortEvent::SuspendRequested(_) => "suspend requested",
+            PortEvent::ConnectionChanged {
+                connected: true, ..
+            } => "connected",
+            PortEvent::ConnectionChanged {
+                connected: false, ..
+            } => "disconnected",
+        };
+        write!(f, "port {} {what}", self.port().get())
+    }
+}
+
+/// Why [`PortUpdate::try_build`] refused an update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortUpdateError {
+    /// The update changes nothing.
+    Empty,
+
+    /// The enable state set with [`PortUpdate::enable`] differs from
+    /// the one [`PortUpdate::reset_done`] leaves the port in.
+    EnableConflict,
+
+    /// The port is suspended and disabled at the same time.
+    SuspendWhileDisabled,
+}
+
+impl std::fmt::Display for PortUpdateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortUpdateError::Empty => f.write_str("port update changes nothing"),
+            PortUpdateError::EnableConflict => {
+                f.write_str("port update both enables and disables the port")
+            }
+            PortUpdateError::SuspendWhileDisabled => {
+                f.write_str("port update suspends a port it disables")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PortUpdateError {}
+
+/// A port status update for [`Remote::port_update`].
+///
+/// Every setter sets a change bit together with the status it
+/// changes to, so a change never comes with a stale status bit. Each
+/// setter can be called once; calling it again replaces the earlier
+/// decision. Connecting and disconnecting go through the controller,
+/// which keeps track of the connected ports.
+///
+/// [`Remote::port_update`]: crate::Remote::port_update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortUpdate {
+    port: Port,
+    enable: Option<bool>,
+    suspend: Option<bool>,
+    overcurrent: Option<bool>,
+
+    /// Whether the port is enabled after its reset.
+    reset: Option<bool>,
+}
+
+impl PortUpdate {
+    pub const fn new(port: Port) -> Self {
+        Self {
+            port,
+            enable: None,
+            suspend: None,
+            overcurrent: None,
+            reset: None,
+        }
+    }
+
+    pub const fn port(&self) -> Port {
+        self.port
+    }
+
+    /// Enables or disables the port.
+    pub const fn enable(mut self, enable: bool) -> Self {
+        self.enable = Some(enable);
+        self
+    }
+
+    /// Reports that the port entered or left suspend, see
+    /// [`Remote::port_suspended`].
+    ///
+    /// [`Remote::port_suspended`]: crate::Remote::port_suspended
+    pub const fn suspend(mut self, suspend: bool) -> Self {
+        self.suspend = Some(suspend);
+        self
+    }
+
+    pub const fn overcurrent(mut self, overcurrent: bool) -> Self {
+        self.overcurrent = Some(overcurrent);
+        self
+    }
+
+    /// Completes a reset the host requested, clearing the RESET
+    /// status. The port ends up enabled if `enable` is `true`.
+    pub const fn reset_done(mut self, enable: bool) -> Self {
+        self.reset = Some(enable);
+        self
+    }
+
+    /// The `IocPortStat` for the update.
+    pub const fn try_build(&self) -> Result<IocPortStat, PortUpdateError> {
+        let enabled = match (self.enable, self.reset) {
+            (Some(a), Some(b)) if a != b => return Err(PortUpdateError::EnableConflict),
+            (Some(enabled), _) | (None, Some(enabled)) => Some(enabled),
+            (None, None) => None,
+        };
+        if let (Some(true), Some(false)) = (self.suspend, enabled) {
+            return Err(PortUpdateError::SuspendWhileDisabled);
+        }
+
+        let mut status = PortStatus::empty();
+        let mut change = PortChange::empty();
+        if let Some(enable) = self.enable {
+            change = change.union(PortChange::ENABLE);
+            if enable {
+                status = status.union(PortStatus::ENABLE);
+            }
+        }
+        if let Some(suspend) = self.suspend {
+            change = change.union(PortChange::SUSPEND);
+            if suspend {
+                status = status.union(PortStatus::SUSPEND);
+            }
+        }
+        if let Some(overcurrent) = self.overcurrent {
+            change = change.union(PortChange::OVERCURRENT);
+            if overcurrent {
+                status = status.union(PortStatus::OVERCURRENT);
+            }
+        }
+        match self.reset {
+            // A successful reset enables the port without an ENABLE
+            // change, like the hub driver expects.
+            Some(true) => {
+                change = change.union(PortChange::RESET);
+                status = status.union(PortStatus::ENABLE);
+            }
+            Some(false) => change = change.union(PortChange::RESET.union(PortChange::ENABLE)),
+            None => (),
+        }
+
+        if change.is_empty() {
+            return Err(PortUpdateError::Empty);
+        }
+        Ok(IocPortStat {
+            status: status.bits(),
+            change: change.bits(),
+            index: self.port.get(),
+            flags: 0,
+            _reserved1: 0,
+            _reserved2: 0,
+        })
+    }
+}
+
+/// Remembers the last [`IocPortStat`] seen for every port and
+/// turns new ones into [`PortEvent`]s.
+///
+/// Ports that have not been observed yet are assumed to be
+/// unpowered and disconnected.
+#[derive(Debug, Default, Clone)]
+pub struct PortStateTracker {
+    ports: IntMap<Port, IocPortStat>,
+}
+
+impl PortStateTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Last observed state of `port`.
+    pub fn get(&self, port: Port) -> Option<&IocPortStat> {
+        self.ports.get(&port)
+    }
+
+    /// Records `stat` and returns the transitions it represents,
+    /// in the order they should be acted upon.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stat` has an invalid port index, see
+    /// [`PortStateTracker::try_observe`].
+    pub fn observe(&mut self, stat: IocPortStat) -> Vec<PortEvent> {
+        self.try_observe(stat).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like [`PortStateTracker::observe`], but a stat with an invalid
+    /// port index is rejected and leaves the tracker unchanged.
+    pub fn try_observe(&mut self, stat: IocPortStat) -> Result<Vec<PortEvent>, DecodeError> {
+        let events = self.transitions(stat)?;
+        self.ports.insert(stat.try_index()?, stat);
+        Ok(events)
+    }
+
+    /// The events [`PortStateTracker::try_observe`] would report for
+    /// `stat`, without recording it.
+    pub fn transitions(&self, stat: IocPortStat) -> Result<Vec<PortEvent>, DecodeError> {
+        let port = stat.try_index()?;
+        let prev = self.ports.get(&port).copied().unwrap_or_default();
+        let (prev_status, next_status) = (prev.status(), stat.status());
+        let mut events = Vec::new();
+
+        if !prev_status.is_powered() && next_status.is_powered() {
+            events.push(PortEvent::PoweredOn(port));
+        } else if prev_status.is_powered() && !next_status.is_powered() {
+            events.push(PortEvent::PoweredOff(port));
+        }
+
+        if prev_status.is_connected() != next_status.is_connected() {
+            events.push(PortEvent::ConnectionChanged {
+                port,
+                connected: next_status.is_connected(),
+            });
+        }
+
+        if !next_status.is_connected() {
+            return Ok(events);
+        }
+
+        if !prev_status.in_reset() && next_status.in_reset() {
+            events.push(PortEvent::ResetRequested(port));
+        }
+
+        if !prev_status.is_suspended() && next_status.is_suspended() {
+            events.push(PortEvent::SuspendRequested(port));
+        }
+
+        if !prev.flags().contains(PortFlag::RESUMING) && stat.flags().contains(PortFlag::RESUMING) {
+            events.push(PortEvent::ResumeRequested(port));
+        }
+
+        Ok(events)
+    }
+
+    /// Forgets everything known about `port`.
+    pub fn clear(&mut self, port: Port) {
+        self.ports.remove(&port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PortStatus;
+
+    const PORT: Port = Port::new(1).unwrap();
+
+    fn stat(status: PortStatus, flags: PortFlag) -> IocPortStat {
+        IocPortStat {
+            status: status.bits(),
+            index: PORT.get(),
+            flags: flags.bits(),
+            ..Default::default()
+        }
+    }
+
+    fn replay(seq: &[(PortStatus, PortFlag)]) -> Vec<Vec<PortEvent>> {
+        let mut tracker = PortStateTracker::new();
+        seq.iter()
+            .map(|&(status, flags)| tracker.observe(stat(status, flags)))
+            .collect()
+    }
+
+    #[test]
+    fn power_cycle() {
+        let on = PortStatus::POWER;
+        let connected = on | PortStatus::CONNECTION;
+        let events = replay(&[
+            (on, PortFlag::empty()),
+            (connected, PortFlag::empty()),
+            (PortStatus::empty(), PortFlag::empty()),
+            (on, PortFlag::empty()),
+        ]);
+        assert_eq!(
+            events,
+            [
+                vec![PortEvent::PoweredOn(PORT)],
+                vec![PortEvent::ConnectionChanged {
+                    port: PORT,
+                    connected: true
+                }],
+                vec![
+                    PortEvent::PoweredOff(PORT),
+                    PortEvent::ConnectionChanged {
+                        port: PORT,
+                        connected: false
+                    }
+                ],
+                vec![PortEvent::PoweredOn(PORT)],
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_during_suspend() {
+        let enabled = PortStatus::POWER | PortStatus::CONNECTION | PortStatus::ENABLE;
+        let events = replay(&[
+            (enabled, PortFlag::empty()),
+            (enabled | PortStatus::SUSPEND, PortFlag::empty()),
+            (
+                enabled | PortStatus::SUSPEND | PortStatus::RESET,
+                PortFlag::empty(),
+            ),
+            (enabled, PortFlag::empty()),
+            (enabled | PortStatus::SUSPEND, PortFlag::empty()),
+            (enabled | PortStatus::SUSPEND, PortFlag::RESUMING),
+        ]);
+        assert_eq!(
+            &events[1..],
+            [
+                vec![PortEvent::SuspendRequested(PORT)],
+                vec![PortEvent::ResetRequested(PORT)],
+                vec![],
+                vec![PortEvent::SuspendRequested(PORT)],
+                vec![PortEvent::ResumeRequested(PORT)],
+            ]
+        );
+    }
+
+    #[test]
+    fn disconnect_mid_reset() {
+        let connected = PortStatus::POWER | PortStatus::CONNECTION;
+        let events = replay(&[
+            (connected, PortFlag::empty()),
+            (connected | PortStatus::RESET, PortFlag::empty()),
+            (PortStatus::POWER | PortStatus::RESET, PortFlag::empty()),
+            (connected | PortStatus::RESET, PortFlag::empty()),
+        ]);
+        assert_eq!(
+            &events[1..],
+            [
+                vec![PortEvent::ResetRequested(PORT)],
+                vec![PortEvent::ConnectionChanged {
+                    port: PORT,
+                    connected: false
+                }],
+                // Reconnecting while the reset bit is still set does
+                // not count as a new reset request.
+                vec![PortEvent::ConnectionChanged {
+                    port: PORT,
+                    connected: true
+                }],
+            ]
+        );
+    }
+
+    #[test]
+    fn ports_are_tracked_separately() {
+        let mut tracker = PortStateTracker::new();
+        let other = Port::new(2).unwrap();
+        let mut powered = stat(PortStatus::POWER, PortFlag::empty());
+        assert_eq!(tracker.observe(powered), [PortEvent::PoweredOn(PORT)]);
+        powered.index = other.get();
+        assert_eq!(tracker.observe(powered), [PortEvent::PoweredOn(other)]);
+        tracker.clear(PORT);
+        assert!(tracker.get(PORT).is_none());
+        assert!(tracker.get(other).is_some());
+    }
+
+    #[test]
+    fn rejects_bogus_port_index() {
+        let mut tracker = PortStateTracker::new();
+        for index in [0, 32, 255] {
+            let mut bogus = stat(PortStatus::POWER, PortFlag::empty());
+            bogus.index = index;
+            assert_eq!(
+                tracker.try_observe(bogus),
+                Err(DecodeError::PortIndex(index))
+            );
+            assert!(format!("{bogus:?}").contains(&format!("index: {index}")));
+        }
+        assert!(tracker.ports.is_empty());
+    }
+
+    fn built(update: PortUpdate) -> (PortStatus, PortChange) {
+        let stat = update.try_build().unwrap();
+        assert_eq!(stat.index, PORT.get());
+        (stat.status(), stat.change())
+    }
+
+    #[test]
+    fn updates_match_helpers() {
+        let update = PortUpdate::new(PORT);
+        let cases = [
+            // port_disable
+            (update.enable(false), 0, PortChange::ENABLE),
+            // port_suspended and port_resumed
+            (update.suspend(true), 0x0004, PortChange::SUSPEND),
+            (update.suspend(false), 0, PortChange::SUSPEND),
+            // port_overcurrent
+            (update.overcurrent(true), 0x0008, PortChange::OVERCURRENT),
+            // port_reset_done
+            (update.reset_done(true), 0x0002, PortChange::RESET),
+            (
+                update.reset_done(false),
+                0,
+                PortChange::RESET | PortChange::ENABLE,
+            ),
+        ];
+        for (update, status, change) in cases {
+            let (built_status, built_change) = built(update);
+            assert_eq!(built_status.bits(), status, "{update:?}");
+            assert_eq!(built_change.bits(), change.bits(), "{update:?}");
+        }
+    }
+
+    #[test]
+    fn accepts_combined_updates() {
+        // Recovering from an error: out of suspend and disabled.
+        let (status, change) = built(PortUpdate::new(PORT).suspend(false).enable(false));
+        assert_eq!(status.bits(), 0);
+        assert_eq!(
+            change.bits(),
+            (PortChange::SUSPEND | PortChange::ENABLE).bits()
+        );
+
+        let (status, change) = built(PortUpdate::new(PORT).overcurrent(true).enable(false));
+        assert_eq!(status.bits(), PortStatus::OVERCURRENT.bits());
+        assert_eq!(
+            change.bits(),
+            (PortChange::OVERCURRENT | PortChange::ENABLE).bits()
+        );
+
+        let (status, _) = built(PortUpdate::new(PORT).reset_done(true).enable(true));
+        assert!(!status.in_reset());
+        assert_eq!(status.bits(), PortStatus::ENABLE.bits());
+    }
+
+    #[test]
+    fn rejects_contradictions() {
+        let update = PortUpdate::new(PORT);
+        assert_eq!(update.try_build(), Err(PortUpdateError::Empty));
+        assert_eq!(
+            update.reset_done(true).enable(false).try_build(),
+            Err(PortUpdateError::EnableConflict)
+        );
+        assert_eq!(
+            update.suspend(true).enable(false).try_build(),
+            Err(PortUpdateError::SuspendWhileDisabled)
+        );
+        assert_eq!(
+            update.suspend(true).reset_done(false).try_build(),
+            Err(PortUpdateError::SuspendWhileDisabled)
+        );
+        // A later call replaces the earlier decision.
+        assert!(update.enable(false).enable(true).try_build().is_ok());
+    }
+}
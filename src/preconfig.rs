@@ -0,0 +1,317 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ioctl::{UrbHandle, UrbType},
+    usbfs::Request,
+    utils::{Clock, SystemClock},
+    Status, Urb, UrbWithData,
+};
+
+/// How [`PreConfigGate`] handles non-control URBs that arrive while
+/// the device is not configured.
+///
+/// A real device does not answer on endpoints other than 0 before
+/// SET_CONFIGURATION, but some host stacks submit interrupt URBs
+/// early anyway, typically class drivers that start polling as soon
+/// as they have seen the descriptors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PreConfigUrbPolicy {
+    /// Completes them with [`Status::Stall`]. Strict, and what a
+    /// device with an unconfigured endpoint would do, but drivers
+    /// that don't clear the halt lose the endpoint for good.
+    #[default]
+    Stall,
+
+    /// Holds them, like a device answering NAK, and releases them
+    /// once the device is configured. The most forgiving choice for
+    /// drivers, at the cost of URBs held until the timeout when the
+    /// device never configures.
+    Nak,
+
+    /// Completes them with [`Status::Error`], which the host sees as
+    /// `-EPROTO`, for testing how drivers recover from failed early
+    /// polls.
+    Error,
+}
+
+/// What [`PreConfigGate::intercept`] did with an URB.
+#[derive(Debug)]
+#[must_use]
+pub enum PreConfigAction {
+    /// Hand the URB to the device.
+    Pass(UrbWithData),
+
+    /// The URB was completed as the policy says and must be given
+    /// back without reaching the device.
+    Completed(UrbWithData),
+
+    /// The URB is held until the device is configured.
+    Held,
+}
+
+/// Applies a [`PreConfigUrbPolicy`] to URBs between enumeration and
+/// SET_CONFIGURATION.
+///
+/// Call [`PreConfigGate::intercept`] before handing an URB to the
+/// device and [`PreConfigGate::observe`] after the device completed
+/// it, so the gate sees the device being configured. Held URBs are
+/// picked up with [`PreConfigGate::take_released`] once it is, or
+/// with [`PreConfigGate::take_expired`] if it never is.
+#[derive(Debug)]
+pub struct PreConfigGate<C = SystemClock> {
+    clock: C,
+    policy: PreConfigUrbPolicy,
+    timeout: Duration,
+    configured: bool,
+    held: VecDeque<(Instant, UrbWithData)>,
+}
+
+impl PreConfigGate {
+    /// Held URBs are completed as canceled after `timeout`.
+    pub fn new(policy: PreConfigUrbPolicy, timeout: Duration) -> Self {
+        Self::with_clock(policy, timeout, SystemClock)
+    }
+}
+
+impl<C: Clock> PreConfigGate<C> {
+    pub fn with_clock(policy: PreConfigUrbPolicy, timeout: Duration, clock: C) -> Self {
+        Self {
+            clock,
+            policy,
+            timeout,
+            configured: false,
+            held: VecDeque::new(),
+        }
+    }
+
+    pub const fn policy(&self) -> PreConfigUrbPolicy {
+        self.policy
+    }
+
+    pub const fn is_configured(&self) -> bool {
+        self.configured
+    }
+
+    /// Number of held URBs.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn intercept(&mut self, mut urb: UrbWithData) -> PreConfigAction {
+        if self.configured || UrbType::Ctrl == urb.kind() {
+            return PreConfigAction::Pass(urb);
+        }
+        let status = match self.policy {
+            PreConfigUrbPolicy::Stall => Status::Stall,
+            PreConfigUrbPolicy::Error => Status::Error,
+            PreConfigUrbPolicy::Nak => {
+                self.held.push_back((self.clock.now(), urb));
+                return PreConfigAction::Held;
+            }
+        };
+        urb.set_transferred(0);
+        urb.set_status(status);
+        PreConfigAction::Completed(urb)
+    }
+
+    /// Tracks SET_CONFIGURATION requests the device completed
+    /// successfully. Configuration 0 puts it back into the address
+    /// state.
+    pub fn observe(&mut self, urb: &UrbWithData) {
+        let Some(setup) = urb.control_packet() else {
+            return;
+        };
+        if setup.req() == Request::STANDARD_DEVICE_SET_CONFIGURATION
+            && Status::Success == urb.status()
+        {
+            self.configured = setup.value() & 0xFF != 0;
+        }
+    }
+
+    /// Forgets the configuration, e.g. after a port reset or
+    /// disconnect. Held URBs are returned completed as canceled.
+    pub fn reset(&mut self) -> Vec<UrbWithData> {
+        self.configured = false;
+        self.held.drain(..).map(|(_, urb)| canceled(urb)).collect()
+    }
+
+    /// Drops the held URB `handle` after a `CancelUrb` work item.
+    /// Returns whether it was held.
+    pub fn cancel(&mut self, handle: UrbHandle) -> bool {
+        let len = self.held.len();
+        self.held.retain(|(_, urb)| urb.handle() != handle);
+        len != self.held.len()
+    }
+
+    /// Held URBs to hand to the device, in the order they arrived,
+    /// once it is configured.
+    pub fn take_released(&mut self) -> Vec<UrbWithData> {
+        if !self.configured {
+            return Vec::new();
+        }
+        self.held.drain(..).map(|(_, urb)| urb).collect()
+    }
+
+    /// Held URBs whose timeout ran out, completed as canceled to be
+    /// given back.
+    pub fn take_expired(&mut self) -> Vec<UrbWithData> {
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        while let Some((since, _)) = self.held.front() {
+            if now - *since < self.timeout {
+                break;
+            }
+            let (_, urb) = self.held.pop_front().unwrap();
+            expired.push(canceled(urb));
+        }
+        expired
+    }
+}
+
+fn canceled(mut urb: UrbWithData) -> UrbWithData {
+    urb.set_transferred(0);
+    urb.set_status(Status::Canceled);
+    urb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ioctl::Endpoint, utils::ManualClock, ControlTransaction};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// A host whose HID driver polls the interrupt endpoint between
+    /// SET_ADDRESS and SET_CONFIGURATION.
+    struct FakeHost {
+        next_handle: u64,
+    }
+
+    impl FakeHost {
+        fn control(&mut self, request: Request, value: u16) -> UrbWithData {
+            self.next_handle += 1;
+            UrbWithData::builder()
+                .control(request.setup(value, 0, 0).unwrap())
+                .handle(UrbHandle(self.next_handle))
+                .build()
+        }
+
+        fn poll(&mut self) -> UrbWithData {
+            self.next_handle += 1;
+            UrbWithData::builder()
+                .interrupt(Endpoint(0x81), 10, 8)
+                .handle(UrbHandle(self.next_handle))
+                .build()
+        }
+    }
+
+    /// Runs an enumeration with two early polls through `gate`,
+    /// completing everything that reaches the device successfully,
+    /// and returns the URBs the gate completed itself.
+    fn enumerate<C: Clock>(gate: &mut PreConfigGate<C>) -> Vec<UrbWithData> {
+        let mut host = FakeHost { next_handle: 0 };
+        let mut completed = Vec::new();
+
+        let urbs = [
+            host.control(Request::STANDARD_DEVICE_SET_ADDRESS, 5),
+            host.poll(),
+            host.poll(),
+            host.control(Request::STANDARD_DEVICE_SET_CONFIGURATION, 1),
+        ];
+        for urb in urbs {
+            match gate.intercept(urb) {
+                PreConfigAction::Pass(mut urb) => {
+                    if let Some(ctrl) = ControlTransaction::new(&mut urb) {
+                        ctrl.complete(Status::Success);
+                    }
+                    gate.observe(&urb);
+                }
+                PreConfigAction::Completed(urb) => completed.push(urb),
+                PreConfigAction::Held => (),
+            }
+        }
+        completed
+    }
+
+    #[test]
+    fn stall_policy() {
+        let mut gate = PreConfigGate::new(PreConfigUrbPolicy::Stall, TIMEOUT);
+        let completed = enumerate(&mut gate);
+        assert!(gate.is_configured());
+        let statuses: Vec<_> = completed.iter().map(|urb| urb.status()).collect();
+        assert_eq!(statuses, [Status::Stall, Status::Stall]);
+
+        // Configured devices get every URB.
+        let mut host = FakeHost { next_handle: 10 };
+        assert!(matches!(
+            gate.intercept(host.poll()),
+            PreConfigAction::Pass(_)
+        ));
+    }
+
+    #[test]
+    fn error_policy() {
+        let mut gate = PreConfigGate::new(PreConfigUrbPolicy::Error, TIMEOUT);
+        let completed = enumerate(&mut gate);
+        assert_eq!(completed.len(), 2);
+        assert!(completed.iter().all(|urb| Status::Error == urb.status()));
+    }
+
+    #[test]
+    fn nak_policy_releases_after_configuration() {
+        let mut gate = PreConfigGate::new(PreConfigUrbPolicy::Nak, TIMEOUT);
+        assert!(enumerate(&mut gate).is_empty());
+        assert_eq!(gate.held(), 2);
+        let released: Vec<_> = gate
+            .take_released()
+            .iter()
+            .map(|urb| urb.handle().0)
+            .collect();
+        assert_eq!(released, [2, 3]);
+        assert_eq!(gate.held(), 0);
+    }
+
+    #[test]
+    fn nak_policy_times_out() {
+        let clock = ManualClock::new();
+        let mut gate = PreConfigGate::with_clock(PreConfigUrbPolicy::Nak, TIMEOUT, clock.clone());
+        let mut host = FakeHost { next_handle: 0 };
+        assert!(matches!(gate.intercept(host.poll()), PreConfigAction::Held));
+        clock.advance(TIMEOUT / 2);
+        assert!(matches!(gate.intercept(host.poll()), PreConfigAction::Held));
+        assert!(matches!(gate.intercept(host.poll()), PreConfigAction::Held));
+        assert!(gate.cancel(UrbHandle(3)));
+        assert!(!gate.cancel(UrbHandle(3)));
+        // Never configured.
+        assert!(gate.take_released().is_empty());
+
+        clock.advance(TIMEOUT / 2);
+        let expired = gate.take_expired();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].handle(), UrbHandle(1));
+        assert_eq!(expired[0].status(), Status::Canceled);
+
+        let reset = gate.reset();
+        assert_eq!(reset.len(), 1);
+        assert_eq!(reset[0].status(), Status::Canceled);
+        assert_eq!(gate.held(), 0);
+    }
+
+    #[test]
+    fn deconfiguring_gates_again() {
+        let mut gate = PreConfigGate::new(PreConfigUrbPolicy::Stall, TIMEOUT);
+        let _ = enumerate(&mut gate);
+        let mut host = FakeHost { next_handle: 10 };
+        let mut urb = host.control(Request::STANDARD_DEVICE_SET_CONFIGURATION, 0);
+        urb.set_status(Status::Success);
+        gate.observe(&urb);
+        assert!(!gate.is_configured());
+        assert!(matches!(
+            gate.intercept(host.poll()),
+            PreConfigAction::Completed(_)
+        ));
+    }
+}
@@ -0,0 +1,22 @@
+//! The types needed by most users of the crate.
+//!
+//! ```
+//! use usb_vhci::prelude::*;
+//! ```
+
+pub use crate::{
+    ioctl::{Endpoint, EndpointKey, IocSetupPacket, IocWork, UrbHandle, UrbType, Work, WorkRef},
+    usbfs::{
+        ClassRequest, CtrlType, Dir, Recipient, Req, Request, RequestClass, StandardRequest,
+        VendorRequest,
+    },
+    utils::TimeoutMillis,
+    ControlTransaction, DataRate, IsoPacketData, IsoPacketDataMut, IsoPacketGiveback,
+    IsoPacketGivebackMut, Port, PortChange, PortEvent, PortFlag, PortStateTracker, PortStatus,
+    Status, Transfer, TransferMut, Urb, UrbFlags, UrbWithData,
+};
+#[cfg(feature = "controller")]
+pub use crate::{
+    Controller, Error, FetchOutcome, GivebackHandle, GivebackOutcome, PortMilestone, PortSignaler,
+    Remote, RunSummary, Runner, WorkReceiver,
+};
@@ -0,0 +1,141 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    ioctl::Address,
+    utils::{Clock, SystemClock},
+    Status, UrbWithData,
+};
+
+/// Keeps URBs for a device that just went away from reaching the
+/// next device the host gives the same address.
+///
+/// After a quick disconnect and reconnect the host may still have
+/// URBs in flight for the old device. Call
+/// [`AddressQuarantine::release`] with the old device's address when
+/// it disconnects and pass every URB through
+/// [`AddressQuarantine::intercept`] until the quarantine ends, either
+/// after the grace period or when [`AddressQuarantine::lift`] is
+/// called, e.g. once the old device's URBs have all been given back.
+#[derive(Debug, Clone)]
+pub struct AddressQuarantine<C = SystemClock> {
+    clock: C,
+    grace: Option<Duration>,
+
+    /// When the quarantine of each address started.
+    since: [Option<Instant>; 128],
+}
+
+impl AddressQuarantine {
+    /// Quarantines addresses for `grace`, or until they are lifted
+    /// if `grace` is `None`.
+    pub fn new(grace: Option<Duration>) -> Self {
+        Self::with_clock(grace, SystemClock)
+    }
+}
+
+impl<C: Clock> AddressQuarantine<C> {
+    pub fn with_clock(grace: Option<Duration>, clock: C) -> Self {
+        Self {
+            clock,
+            grace,
+            since: [None; 128],
+        }
+    }
+
+    /// Index of `addr` in `since`. Addresses from the kernel are
+    /// not checked, so the reserved top bit is ignored like in
+    /// [`Address::is_for_unassigned`].
+    const fn slot(addr: Address) -> usize {
+        (addr.get() & 0x7F) as usize
+    }
+
+    /// Starts quarantining `addr`. The default address 0 is used by
+    /// every device during enumeration and is never quarantined.
+    pub fn release(&mut self, addr: Address) {
+        if !addr.is_for_unassigned() {
+            self.since[Self::slot(addr)] = Some(self.clock.now());
+        }
+    }
+
+    /// Ends the quarantine of `addr` early.
+    pub fn lift(&mut self, addr: Address) {
+        self.since[Self::slot(addr)] = None;
+    }
+
+    pub fn is_quarantined(&mut self, addr: Address) -> bool {
+        let slot = &mut self.since[Self::slot(addr)];
+        match (*slot, self.grace) {
+            (Some(since), Some(grace)) if self.clock.now() - since >= grace => {
+                *slot = None;
+                false
+            }
+            (since, _) => since.is_some(),
+        }
+    }
+
+    /// Completes `urb` with [`Status::DeviceDisconnected`] if it is
+    /// for a quarantined address. Returns whether it did; such URBs
+    /// must be given back without reaching a device.
+    pub fn intercept(&mut self, urb: &mut UrbWithData) -> bool {
+        let quarantined = self.is_quarantined(urb.ioc_urb().address);
+        if quarantined {
+            urb.set_status(Status::DeviceDisconnected);
+        }
+        quarantined
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ioctl::Endpoint, utils::ManualClock, Urb};
+
+    const OLD: Address = Address::new(5).unwrap();
+
+    fn bulk_for(addr: Address) -> UrbWithData {
+        UrbWithData::builder()
+            .bulk(Endpoint(0x81), &[0; 8])
+            .address(addr)
+            .build()
+    }
+
+    #[test]
+    fn rapid_reconnect() {
+        let clock = ManualClock::new();
+        let mut quarantine =
+            AddressQuarantine::with_clock(Some(Duration::from_millis(50)), clock.clone());
+
+        quarantine.release(OLD);
+        // The host still had an URB in flight for the old device.
+        let mut stale = bulk_for(OLD);
+        assert!(quarantine.intercept(&mut stale));
+        assert_eq!(stale.status(), Status::DeviceDisconnected);
+
+        // The new device enumerates at address 0 meanwhile.
+        let mut setup = bulk_for(Address::new(0).unwrap());
+        assert!(!quarantine.intercept(&mut setup));
+        let mut other = bulk_for(Address::new(6).unwrap());
+        assert!(!quarantine.intercept(&mut other));
+
+        clock.advance(Duration::from_millis(49));
+        assert!(quarantine.is_quarantined(OLD));
+        clock.advance(Duration::from_millis(1));
+        let mut fresh = bulk_for(OLD);
+        assert!(!quarantine.intercept(&mut fresh));
+        assert_eq!(fresh.status(), Status::Success);
+    }
+
+    #[test]
+    fn lasts_until_lifted() {
+        let clock = ManualClock::new();
+        let mut quarantine = AddressQuarantine::with_clock(None, clock.clone());
+        quarantine.release(Address::new(0).unwrap());
+        assert!(!quarantine.is_quarantined(Address::new(0).unwrap()));
+
+        quarantine.release(OLD);
+        clock.advance(Duration::from_secs(3600));
+        assert!(quarantine.is_quarantined(OLD));
+        quarantine.lift(OLD);
+        assert!(!quarantine.is_quarantined(OLD));
+    }
+}
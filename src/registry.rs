@@ -0,0 +1,410 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+use nohash_hasher::IntMap;
+
+use crate::{
+    usbfs::{Dir, Request},
+    DataRate, Port, Status, Transfer, Urb, UrbWithData,
+};
+
+const DESCRIPTOR_TYPE_DEVICE: u8 = 1;
+const DESCRIPTOR_TYPE_STRING: u8 = 3;
+
+/// Where a device is in enumeration, as far as the requests it
+/// completed tell.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeviceState {
+    /// Connected and answering at address 0.
+    #[default]
+    Default,
+    Addressed,
+    Configured,
+}
+
+/// The identifying parts of a device descriptor and its strings.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DescriptorSummary {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+
+    /// `iManufacturer`, `iProduct` and `iSerialNumber`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    string_indices: [u8; 3],
+}
+
+impl DescriptorSummary {
+    /// Summarizes a device descriptor, or returns `None` if `desc`
+    /// is not a complete one.
+    pub fn from_device_descriptor(desc: &[u8]) -> Option<Self> {
+        if desc.len() < 18 || desc[1] != DESCRIPTOR_TYPE_DEVICE {
+            return None;
+        }
+        Some(Self {
+            vendor_id: u16::from_le_bytes([desc[8], desc[9]]),
+            product_id: u16::from_le_bytes([desc[10], desc[11]]),
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+            string_indices: [desc[14], desc[15], desc[16]],
+        })
+    }
+
+    /// Fills in the string with descriptor index `index` from a
+    /// string descriptor.
+    fn set_string(&mut self, index: u8, desc: &[u8]) {
+        if index == 0 || desc.len() < 2 || desc[1] != DESCRIPTOR_TYPE_STRING {
+            return;
+        }
+        let len = usize::from(desc[0]).min(desc.len());
+        let units: Vec<u16> = desc[2..len]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        let string = String::from_utf16_lossy(&units);
+        let [manufacturer, product, serial_number] = self.string_indices;
+        if index == manufacturer {
+            self.manufacturer = Some(string.clone());
+        }
+        if index == product {
+            self.product = Some(string.clone());
+        }
+        if index == serial_number {
+            self.serial_number = Some(string);
+        }
+    }
+}
+
+/// Traffic of a device since it was attached.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceMetrics {
+    /// Completed URBs, including failed ones.
+    pub urbs: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub stalls: u64,
+
+    /// URBs completed with a status other than success, short
+    /// packet or stall.
+    pub errors: u64,
+}
+
+/// A snapshot of an attached device, see [`DeviceRegistry::list`].
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub port: Port,
+    pub rate: DataRate,
+
+    /// Address assigned by SET_ADDRESS, 0 before that.
+    pub address: u8,
+    pub descriptor: Option<DescriptorSummary>,
+    pub state: DeviceState,
+
+    /// Value of the last SET_CONFIGURATION, 0 if unconfigured.
+    pub configuration: u8,
+
+    /// Alternate settings selected with SET_INTERFACE as
+    /// `(interface, setting)`, sorted by interface. Interfaces not
+    /// listed use setting 0.
+    pub alt_settings: Vec<(u8, u8)>,
+
+    /// URBs handed to the device that it has not completed yet.
+    pub pending_urbs: usize,
+    pub metrics: DeviceMetrics,
+}
+
+impl DeviceInfo {
+    fn new(port: Port, rate: DataRate) -> Self {
+        Self {
+            port,
+            rate,
+            address: 0,
+            descriptor: None,
+            state: DeviceState::Default,
+            configuration: 0,
+            alt_settings: Vec::new(),
+            pending_urbs: 0,
+            metrics: DeviceMetrics::default(),
+        }
+    }
+
+    /// Updates the metrics, and the state from successful standard
+    /// requests.
+    fn observe(&mut self, urb: &UrbWithData) {
+        let metrics = &mut self.metrics;
+        metrics.urbs += 1;
+        let bytes = urb.transfer().len() as u64;
+        match urb.dir() {
+            Dir::In => metrics.bytes_in += bytes,
+            Dir::Out => metrics.bytes_out += bytes,
+        }
+        match urb.status() {
+            Status::Success => (),
+            Status::ShortPacket => return,
+            Status::Stall => {
+                metrics.stalls += 1;
+                return;
+            }
+            _ => {
+                metrics.errors += 1;
+                return;
+            }
+        }
+
+        let Some(setup) = urb.control_packet() else {
+            return;
+        };
+        let req = setup.req();
+        let value = setup.value();
+        if req == Request::STANDARD_DEVICE_SET_ADDRESS {
+            self.address = (value & 0x7F) as u8;
+            self.state = match self.address {
+                0 => DeviceState::Default,
+                _ => DeviceState::Addressed,
+            };
+        } else if req == Request::STANDARD_DEVICE_SET_CONFIGURATION {
+            self.configuration = value as u8;
+            self.alt_settings.clear();
+            self.state = match (self.configuration, self.address) {
+                (0, 0) => DeviceState::Default,
+                (0, _) => DeviceState::Addressed,
+                _ => DeviceState::Configured,
+            };
+        } else if req == Request::STANDARD_INTERFACE_SET_INTERFACE {
+            let interface = setup.index() as u8;
+            let setting = value as u8;
+            self.alt_settings.retain(|&(i, _)| i != interface);
+            if setting != 0 {
+                self.alt_settings.push((interface, setting));
+                self.alt_settings.sort_unstable();
+            }
+        } else if req == Request::STANDARD_DEVICE_GET_DESCRIPTOR {
+            let [index, kind] = value.to_le_bytes();
+            match kind {
+                DESCRIPTOR_TYPE_DEVICE => {
+                    if let Some(summary) = DescriptorSummary::from_device_descriptor(urb.transfer())
+                    {
+                        self.descriptor = Some(summary);
+                    }
+                }
+                DESCRIPTOR_TYPE_STRING => {
+                    if let Some(summary) = &mut self.descriptor {
+                        summary.set_string(index, urb.transfer());
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// The devices attached to a controller, for showing to an operator.
+///
+/// The registry is fed by whatever drives the devices: it is told
+/// when a device is attached and detached, when an URB is handed to
+/// it, and when the device completed one. Clones share the same
+/// devices, so one clone can be listed from another thread while the
+/// work loop updates another. Every call takes the registry's lock,
+/// so [`DeviceRegistry::list`] is a consistent snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceRegistry {
+    devices: Arc<Mutex<IntMap<Port, DeviceInfo>>>,
+}
+
+impl DeviceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, IntMap<Port, DeviceInfo>> {
+        self.devices.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts tracking a device connected to `port`, replacing the
+    /// previous one.
+    pub fn attach(&self, port: Port, rate: DataRate) {
+        self.lock().insert(port, DeviceInfo::new(port, rate));
+    }
+
+    /// Stops tracking the device on `port` and returns its last
+    /// snapshot.
+    pub fn detach(&self, port: Port) -> Option<DeviceInfo> {
+        self.lock().remove(&port)
+    }
+
+    /// Records that an URB was handed to the device on `port`.
+    /// Returns whether a device is attached there.
+    pub fn submitted(&self, port: Port) -> bool {
+        match self.lock().get_mut(&port) {
+            Some(info) => {
+                info.pending_urbs += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records that the device on `port` completed `urb`. Returns
+    /// whether a device is attached there.
+    pub fn completed(&self, port: Port, urb: &UrbWithData) -> bool {
+        match self.lock().get_mut(&port) {
+            Some(info) => {
+                info.pending_urbs = info.pending_urbs.saturating_sub(1);
+                info.observe(urb);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, port: Port) -> Option<DeviceInfo> {
+        self.lock().get(&port).cloned()
+    }
+
+    /// The attached devices, by port.
+    pub fn list(&self) -> Vec<DeviceInfo> {
+        let mut devices: Vec<_> = self.lock().values().cloned().collect();
+        devices.sort_unstable_by_key(|info| info.port);
+        devices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{ioctl::Endpoint, ControlTransaction};
+
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x09, 0x12, 0x01, 0x00, 0x00, 0x01, 0x01,
+        0x02, 0x00, 0x01,
+    ];
+
+    fn request(registry: &DeviceRegistry, port: Port, req: Request, value: u16, reply: &[u8]) {
+        let length = reply.len() as u16;
+        let mut urb = UrbWithData::builder()
+            .control(req.setup(value, 0, length).unwrap())
+            .build();
+        let mut ctrl = ControlTransaction::new(&mut urb).unwrap();
+        if !reply.is_empty() {
+            ctrl.write_reply(reply).unwrap();
+        }
+        ctrl.complete(Status::Success);
+        assert!(registry.submitted(port));
+        assert!(registry.completed(port, &urb));
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut desc = vec![0, DESCRIPTOR_TYPE_STRING];
+        desc.extend(s.encode_utf16().flat_map(u16::to_le_bytes));
+        desc[0] = desc.len() as u8;
+        desc
+    }
+
+    #[test]
+    fn follows_enumeration() {
+        let registry = DeviceRegistry::new();
+        let port = Port::new(2).unwrap();
+        registry.attach(port, DataRate::Full);
+        assert_eq!(registry.get(port).unwrap().state, DeviceState::Default);
+
+        let get_descriptor = Request::STANDARD_DEVICE_GET_DESCRIPTOR;
+        request(&registry, port, get_descriptor, 0x0100, &DEVICE_DESCRIPTOR);
+        request(
+            &registry,
+            port,
+            Request::STANDARD_DEVICE_SET_ADDRESS,
+            7,
+            &[],
+        );
+        assert_eq!(registry.get(port).unwrap().state, DeviceState::Addressed);
+        request(&registry, port, get_descriptor, 0x0301, &string("Acme"));
+        request(&registry, port, get_descriptor, 0x0302, &string("Gadget"));
+        request(
+            &registry,
+            port,
+            Request::STANDARD_DEVICE_SET_CONFIGURATION,
+            1,
+            &[],
+        );
+        let set_interface = Request::STANDARD_INTERFACE_SET_INTERFACE;
+        let mut urb = UrbWithData::builder()
+            .control(set_interface.setup(2, 1, 0).unwrap())
+            .build();
+        ControlTransaction::new(&mut urb)
+            .unwrap()
+            .complete(Status::Success);
+        registry.completed(port, &urb);
+
+        let mut stalled = UrbWithData::builder().bulk(Endpoint(0x02), &[0; 4]).build();
+        stalled.set_status(Status::Stall);
+        registry.submitted(port);
+        registry.submitted(port);
+        registry.completed(port, &stalled);
+
+        let info = registry.get(port).unwrap();
+        let descriptor = info.descriptor.unwrap();
+        assert_eq!(
+            (descriptor.vendor_id, descriptor.product_id),
+            (0x1209, 0x0001)
+        );
+        assert_eq!(descriptor.manufacturer.as_deref(), Some("Acme"));
+        assert_eq!(descriptor.product.as_deref(), Some("Gadget"));
+        assert_eq!(descriptor.serial_number, None);
+        assert_eq!(info.address, 7);
+        assert_eq!(info.state, DeviceState::Configured);
+        assert_eq!(info.configuration, 1);
+        assert_eq!(info.alt_settings, [(1, 2)]);
+        assert_eq!(info.pending_urbs, 1);
+        assert_eq!(
+            info.metrics,
+            DeviceMetrics {
+                urbs: 7,
+                bytes_in: 18 + 10 + 14,
+                bytes_out: 4,
+                stalls: 1,
+                errors: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn lists_consistent_snapshots() {
+        let registry = DeviceRegistry::new();
+        let ports = [3, 1].map(|p| Port::new(p).unwrap());
+        for port in ports {
+            registry.attach(port, DataRate::High);
+        }
+
+        let feeder = registry.clone();
+        let worker = thread::spawn(move || {
+            let urb = UrbWithData::builder().bulk(Endpoint(0x02), &[0; 8]).build();
+            for _ in 0..1000 {
+                for port in ports {
+                    feeder.submitted(port);
+                    feeder.completed(port, &urb);
+                }
+            }
+        });
+        for _ in 0..100 {
+            let list = registry.list();
+            assert_eq!(list.len(), 2);
+            assert_eq!(list[0].port.get(), 1);
+            assert!(list.iter().all(|info| info.pending_urbs <= 1));
+        }
+        worker.join().unwrap();
+
+        let list = registry.list();
+        assert!(list.iter().all(|info| info.metrics.bytes_out == 8000));
+        assert_eq!(registry.detach(ports[0]).unwrap().port, ports[0]);
+        assert_eq!(registry.list().len(), 1);
+        assert!(!registry.submitted(ports[0]));
+    }
+}
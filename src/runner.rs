@@ -0,0 +1,492 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ioctl::{IocUrb, IocWork, UrbHandle, UrbType, WorkRef, WorkType},
+    utils::{Clock, SystemClock, TimeoutMillis},
+    Controller, Error, ForeignPolicy, PortEvent, PortStateTracker, Result, UrbFilter, UrbTarget,
+    UrbWithData,
+};
+
+/// What a [`Runner`] went through until it stopped.
+///
+/// The runner only sees the work it hands out, not what the handler
+/// gives back, so URBs are counted by type but not by status.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    pub port_stats: u64,
+    pub urbs: u64,
+    pub cancels: u64,
+
+    /// Work items the handler returned an error for.
+    pub errors: u64,
+
+    /// URBs the [`Runner::filter`] kept from the handler. They are
+    /// counted in [`RunSummary::urbs`] too.
+    pub foreign: u64,
+
+    pub duration: Duration,
+
+    pub urb_types: UrbTypeCounts,
+
+    /// Port transitions in the order the port stats arrived.
+    pub port_events: Vec<PortEvent>,
+
+    /// The first [`RunSummary::MAX_ERRORS`] handler errors.
+    pub first_errors: Vec<RunError>,
+}
+
+impl RunSummary {
+    /// Handler errors kept in [`RunSummary::first_errors`].
+    pub const MAX_ERRORS: usize = 8;
+
+    /// Number of work items handed to the handler.
+    pub const fn work(&self) -> u64 {
+        self.port_stats + self.urbs + self.cancels
+    }
+
+    fn record(&mut self, work: &IocWork, tracker: &mut PortStateTracker) {
+        match work.get() {
+            WorkRef::PortStat(stat) => {
+                self.port_stats += 1;
+                // A stat with a bogus index is counted but has no
+                // transitions.
+                if let Ok(events) = tracker.try_observe(stat) {
+                    self.port_events.extend(events);
+                }
+            }
+            WorkRef::ProcessUrb((urb, _)) => {
+                self.urbs += 1;
+                let count = match urb.typ {
+                    UrbType::Iso => &mut self.urb_types.iso,
+                    UrbType::Int => &mut self.urb_types.int,
+                    UrbType::Ctrl => &mut self.urb_types.ctrl,
+                    UrbType::Bulk => &mut self.urb_types.bulk,
+                };
+                *count += 1;
+            }
+            WorkRef::CancelUrb(_) => self.cancels += 1,
+        }
+    }
+
+    /// Records that the handler failed on the last recorded work
+    /// item, which was of type `work`.
+    fn record_error(&mut self, work: WorkType, err: &Error) {
+        self.errors += 1;
+        if self.first_errors.len() < Self::MAX_ERRORS {
+            self.first_errors.push(RunError {
+                index: self.work() - 1,
+                work,
+                message: err.to_string(),
+            });
+        }
+    }
+}
+
+/// A short report, e.g.
+///
+/// ```text
+/// 12 work items in 1.2s: 5 port stats, 7 URBs (7 control), 0 cancels, 1 errors
+///   port 1 powered on
+///   port 1 connected
+///   port 1 reset requested
+///   error in work item 11 (ProcessUrb): Broken pipe (os error 32)
+/// ```
+impl std::fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} work items in {:.1?}: {} port stats, {} URBs",
+            self.work(),
+            self.duration,
+            self.port_stats,
+            self.urbs
+        )?;
+        let types = [
+            (self.urb_types.ctrl, "control"),
+            (self.urb_types.bulk, "bulk"),
+            (self.urb_types.int, "interrupt"),
+            (self.urb_types.iso, "iso"),
+        ];
+        let mut sep = " (";
+        for (count, name) in types {
+            if count != 0 {
+                write!(f, "{sep}{count} {name}")?;
+                sep = ", ";
+            }
+        }
+        if sep == ", " {
+            f.write_str(")")?;
+        }
+        writeln!(f, ", {} cancels, {} errors", self.cancels, self.errors)?;
+
+        for event in &self.port_events {
+            writeln!(f, "  {event}")?;
+        }
+        for err in &self.first_errors {
+            writeln!(f, "  {err}")?;
+        }
+        Ok(())
+    }
+}
+
+/// URBs of a [`RunSummary`] by transfer type.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UrbTypeCounts {
+    pub ctrl: u64,
+    pub bulk: u64,
+    pub int: u64,
+    pub iso: u64,
+}
+
+/// A handler error kept by [`RunSummary`].
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunError {
+    /// Position of the work item in the run, starting at 0.
+    pub index: u64,
+
+    pub work: WorkType,
+    pub message: String,
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "error in work item {} ({:?}): {}",
+            self.index, self.work, self.message
+        )
+    }
+}
+
+type StopPredicate<'a> = Box<dyn FnMut(&RunSummary) -> bool + 'a>;
+
+/// Drives the fetch loop of a [`Controller`] until one of its stop
+/// conditions is met.
+///
+/// The runner only borrows the controller, which can be used again
+/// once [`Runner::run`] returns. Without any stop condition the
+/// runner keeps going until fetching fails.
+pub struct Runner<'a> {
+    controller: &'a mut Controller,
+    deadline: Option<Instant>,
+    flag: Option<Arc<AtomicBool>>,
+    predicate: Option<StopPredicate<'a>>,
+    clock: Box<dyn Clock + 'a>,
+    filter: Option<UrbFilter>,
+}
+
+impl<'a> Runner<'a> {
+    /// How long a single fetch waits, which bounds how late a stop
+    /// condition is noticed.
+    const STEP: Duration = Duration::from_millis(100);
+
+    pub fn new(controller: &'a mut Controller) -> Self {
+        Self {
+            controller,
+            deadline: None,
+            flag: None,
+            predicate: None,
+            clock: Box::new(SystemClock),
+            filter: None,
+        }
+    }
+
+    /// Reads the time for [`Runner::until`] and
+    /// [`RunSummary::duration`] from `clock` instead of the system
+    /// clock. Fetching still waits in real time.
+    pub fn clock(mut self, clock: impl Clock + 'a) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Stops once `deadline` has passed.
+    pub fn until(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Stops once `flag` is set, e.g. from a signal handler or
+    /// another thread.
+    pub fn until_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.flag = Some(flag);
+        self
+    }
+
+    /// Stops once `predicate` returns `true`. It is called after
+    /// every handled work item.
+    pub fn until_stats(mut self, predicate: impl FnMut(&RunSummary) -> bool + 'a) -> Self {
+        self.predicate = Some(Box::new(predicate));
+        self
+    }
+
+    /// Keeps URBs for addresses no device has in
+    /// [`Controller::device_addresses`] from the handler and
+    /// completes them with `filter`, see [`UrbFilter`]. A failed
+    /// giveback counts as a handler error.
+    ///
+    /// The controller only knows the addresses it was told about, so
+    /// the handler has to pass the port stats to
+    /// [`Controller::note_port_stat`], e.g. through
+    /// [`PortAutomaton::handle`], and the URBs it gave back to
+    /// [`Controller::note_completed_urb`]. Otherwise every URB looks
+    /// foreign.
+    ///
+    /// [`PortAutomaton::handle`]: crate::PortAutomaton::handle
+    pub fn filter(mut self, filter: UrbFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Completes an URB `filter` found foreign.
+    fn complete_foreign(&self, filter: UrbFilter, urb: IocUrb, handle: UrbHandle) -> Result<()> {
+        if filter.policy() == ForeignPolicy::Ignore {
+            return Ok(());
+        }
+        let mut urb = UrbWithData::try_from_ioctl(urb, handle)
+            .map_err(|_| Error::InvalidUrb("urb too large"))?;
+        filter.intercept(self.controller.device_addresses(), &mut urb);
+        // An URB the host canceled meanwhile is just not delivered.
+        let _ = self.controller.giveback(&mut urb)?;
+        Ok(())
+    }
+
+    fn should_stop(&mut self, summary: &RunSummary) -> bool {
+        self.deadline
+            .is_some_and(|deadline| self.clock.now() >= deadline)
+            || self
+                .flag
+                .as_ref()
+                .is_some_and(|flag| flag.load(Ordering::Acquire))
+            || self
+                .predicate
+                .as_mut()
+                .is_some_and(|predicate| predicate(summary))
+    }
+
+    /// Fetches work and passes it to `handler` until a stop
+    /// condition is met. Work buffered by [`Controller::wait_for`]
+    /// is handled first.
+    ///
+    /// Errors from `handler` are counted in the summary and do not
+    /// stop the runner. Failing to fetch work does, and is returned.
+    pub fn run(
+        mut self,
+        mut handler: impl FnMut(&mut Controller, IocWork) -> Result<()>,
+    ) -> Result<RunSummary> {
+        let start = self.clock.now();
+        let mut summary = RunSummary::default();
+        let mut tracker = PortStateTracker::new();
+
+        while !self.should_stop(&summary) {
+            let work = match self.controller.pop_buffered_work() {
+                Some(work) => work,
+                None => {
+                    let step = self.deadline.map_or(Self::STEP, |deadline| {
+                        deadline
+                            .saturating_duration_since(self.clock.now())
+                            .min(Self::STEP)
+                    });
+                    let timeout = TimeoutMillis::from_duration(step).unwrap();
+                    match self.controller.fetch_work_timeout(timeout) {
+                        Ok(work) => work,
+                        Err(err) if err.is_timeout() => continue,
+                        Err(err) => return Err(err),
+                    }
+                }
+            };
+
+            summary.record(&work, &mut tracker);
+            let typ = work.typ;
+            let foreign = match (self.filter, work.get()) {
+                (Some(filter), WorkRef::ProcessUrb((&urb, handle)))
+                    if filter.classify(self.controller.device_addresses(), urb.address)
+                        == UrbTarget::Foreign =>
+                {
+                    Some((filter, urb, handle))
+                }
+                _ => None,
+            };
+            let result = match foreign {
+                Some((filter, urb, handle)) => {
+                    summary.foreign += 1;
+                    self.complete_foreign(filter, urb, handle)
+                }
+                None => handler(self.controller, work),
+            };
+            if let Err(err) = result {
+                summary.record_error(typ, &err);
+            }
+            summary.duration = self.clock.now() - start;
+        }
+
+        summary.duration = self.clock.now() - start;
+        Ok(summary)
+    }
+}
+
+impl std::fmt::Debug for Runner<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Runner")
+            .field("controller", &self.controller)
+            .field("deadline", &self.deadline)
+            .field("flag", &self.flag)
+            .field("predicate", &self.predicate.is_some())
+            .field("filter", &self.filter)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ioctl::{Address, Endpoint, IocPortStat, IocUrb, IocWorkUnion},
+        Port, PortStatus,
+    };
+
+    fn port_stat(status: PortStatus) -> IocWork {
+        IocWork {
+            work: IocWorkUnion {
+                port: IocPortStat {
+                    status: status.bits(),
+                    index: 1,
+                    ..Default::default()
+                },
+            },
+            typ: WorkType::PortStat,
+            ..Default::default()
+        }
+    }
+
+    fn urb(typ: UrbType) -> IocWork {
+        IocWork {
+            work: IocWorkUnion {
+                urb: IocUrb {
+                    typ,
+                    endpoint: Endpoint(0x81),
+                    ..Default::default()
+                },
+            },
+            typ: WorkType::ProcessUrb,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn categorizes_work() {
+        let mut summary = RunSummary::default();
+        let mut tracker = PortStateTracker::new();
+        let powered = PortStatus::POWER;
+        let work = [
+            port_stat(powered),
+            port_stat(powered | PortStatus::CONNECTION),
+            urb(UrbType::Ctrl),
+            urb(UrbType::Ctrl),
+            urb(UrbType::Bulk),
+            IocWork {
+                typ: WorkType::CancelUrb,
+                ..Default::default()
+            },
+        ];
+        for work in &work {
+            summary.record(work, &mut tracker);
+        }
+        summary.record_error(
+            WorkType::CancelUrb,
+            &Error::Io(std::io::ErrorKind::NotFound.into()),
+        );
+
+        let port = Port::new(1).unwrap();
+        assert_eq!(
+            summary.port_events,
+            [
+                PortEvent::PoweredOn(port),
+                PortEvent::ConnectionChanged {
+                    port,
+                    connected: true
+                },
+            ]
+        );
+        assert_eq!(
+            summary.urb_types,
+            UrbTypeCounts {
+                ctrl: 2,
+                bulk: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!((summary.work(), summary.errors), (6, 1));
+        assert_eq!(summary.first_errors[0].index, 5);
+
+        let expected = "\
+6 work items in 0.0ns: 2 port stats, 3 URBs (2 control, 1 bulk), 1 cancels, 1 errors
+  port 1 powered on
+  port 1 connected
+  error in work item 5 (CancelUrb): entity not found
+";
+        assert_eq!(summary.to_string(), expected);
+    }
+
+    #[test]
+    fn filters_foreign_urbs() {
+        let mut vhci = crate::controller::tests::fake_controller(1);
+        let connected = PortStatus::POWER | PortStatus::CONNECTION;
+        let reset = IocPortStat {
+            status: (connected | PortStatus::RESET).bits(),
+            index: 1,
+            ..Default::default()
+        };
+        vhci.note_port_stat(reset).unwrap();
+        let urb_for = |addr| {
+            let mut work = urb(UrbType::Bulk);
+            work.work.urb.address = Address::new(addr).unwrap();
+            work
+        };
+        for policy in [ForeignPolicy::NoResponse, ForeignPolicy::Ignore] {
+            crate::controller::tests::buffer_work(&mut vhci, urb_for(0));
+            crate::controller::tests::buffer_work(&mut vhci, urb_for(5));
+
+            let mut handled = Vec::new();
+            let summary = Runner::new(&mut vhci)
+                .filter(UrbFilter::new(policy))
+                .until_stats(|summary| summary.work() == 2)
+                .run(|_, work| {
+                    if let WorkRef::ProcessUrb((urb, _)) = work.get() {
+                        handled.push(urb.address.get());
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            // Only the device in the default state is there.
+            assert_eq!(handled, [0]);
+            assert_eq!((summary.urbs, summary.foreign), (2, 1));
+            // Giving back fails on /dev/null.
+            assert_eq!(summary.errors, u64::from(policy != ForeignPolicy::Ignore));
+        }
+    }
+
+    #[test]
+    fn keeps_first_errors() {
+        let mut summary = RunSummary::default();
+        let mut tracker = PortStateTracker::new();
+        for _ in 0..RunSummary::MAX_ERRORS + 2 {
+            summary.record(&urb(UrbType::Int), &mut tracker);
+            summary.record_error(
+                WorkType::ProcessUrb,
+                &Error::Io(std::io::ErrorKind::BrokenPipe.into()),
+            );
+        }
+        assert_eq!(summary.errors, RunSummary::MAX_ERRORS as u64 + 2);
+        assert_eq!(summary.first_errors.len(), RunSummary::MAX_ERRORS);
+        assert_eq!(summary.first_errors.last().unwrap().index, 7);
+    }
+}
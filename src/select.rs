@@ -0,0 +1,167 @@
+use std::time::{Duration, Instant};
+
+use crate::{ioctl::IocWork, Result, WorkReceiver};
+
+/// Fetches work from several controllers on one thread, e.g. one
+/// controller per tenant.
+///
+/// `usb-vhci-iocifc` has no `poll` support, so there is nothing to
+/// wait on for several fds at once. [`ControllerSet::select`] takes
+/// turns fetching from each receiver without waiting instead, and
+/// sleeps for the [`ControllerSet::poll_interval`] between rounds
+/// that found no work. Work that arrives while it sleeps waits in the
+/// kernel, which adds up to the interval, 2 ms by default, to the
+/// latency of every URB. A shorter interval costs more CPU time
+/// while the controllers are idle.
+///
+/// A receiver whose fetch fails, e.g. with [`Error::ControllerGone`]
+/// after its controller was removed, is reported once and then left
+/// out. The others keep going.
+///
+/// [`Error::ControllerGone`]: crate::Error::ControllerGone
+#[derive(Debug)]
+pub struct ControllerSet {
+    receivers: Vec<WorkReceiver>,
+    failed: Vec<bool>,
+    poll_interval: Duration,
+
+    /// Index the next round starts at, so a busy controller can't
+    /// starve the ones after it.
+    next: usize,
+}
+
+impl ControllerSet {
+    /// The default [`ControllerSet::poll_interval`].
+    pub const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+    pub fn new(receivers: Vec<WorkReceiver>) -> Self {
+        Self {
+            failed: vec![false; receivers.len()],
+            receivers,
+            poll_interval: Self::POLL_INTERVAL,
+            next: 0,
+        }
+    }
+
+    /// Sleeps for `interval` between rounds that found no work, which
+    /// is the longest time work waits in the kernel before
+    /// [`ControllerSet::select`] picks it up.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Adds a receiver and returns its index.
+    pub fn push(&mut self, recv: WorkReceiver) -> usize {
+        self.receivers.push(recv);
+        self.failed.push(false);
+        self.receivers.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.receivers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receivers.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&WorkReceiver> {
+        self.receivers.get(index)
+    }
+
+    /// Whether the receiver at `index` failed and is left out.
+    pub fn is_failed(&self, index: usize) -> bool {
+        self.failed.get(index).copied().unwrap_or(false)
+    }
+
+    /// Takes the receivers back, e.g. to give them to
+    /// [`Controller::return_work_receiver`].
+    ///
+    /// [`Controller::return_work_receiver`]: crate::Controller::return_work_receiver
+    pub fn into_inner(self) -> Vec<WorkReceiver> {
+        self.receivers
+    }
+
+    /// Waits up to `timeout` for work from any of the receivers and
+    /// returns it with the index of its receiver. A failed fetch is
+    /// returned with the index of the receiver that failed.
+    ///
+    /// Returns `None` if no work arrived in time, or right away if
+    /// every receiver has failed.
+    pub fn select(&mut self, timeout: Duration) -> Option<(usize, Result<IocWork>)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let count = self.receivers.len();
+            for index in (0..count).map(|offset| (self.next + offset) % count) {
+                if self.failed[index] {
+                    continue;
+                }
+                match self.receivers[index].try_fetch_work() {
+                    Ok(None) => continue,
+                    Ok(Some(work)) => {
+                        self.next = (index + 1) % count;
+                        return Some((index, Ok(work)));
+                    }
+                    Err(err) => {
+                        self.failed[index] = true;
+                        self.next = (index + 1) % count;
+                        return Some((index, Err(err)));
+                    }
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || self.failed.iter().all(|&failed| failed) {
+                return None;
+            }
+            std::thread::sleep(remaining.min(self.poll_interval));
+        }
+    }
+}
+
+impl Default for ControllerSet {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::tests::fake_controller;
+
+    #[test]
+    fn failed_receivers_are_left_out() {
+        // Fetching from /dev/null fails right away.
+        let controllers = [fake_controller(1), fake_controller(1)];
+        let mut set = ControllerSet::new(
+            controllers
+                .iter()
+                .map(|vhci| vhci.work_receiver().unwrap())
+                .collect(),
+        );
+        assert_eq!(set.len(), 2);
+
+        let (index, result) = set.select(Duration::from_secs(5)).unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(nix::libc::ENOTTY));
+        assert!(set.is_failed(0) && !set.is_failed(1));
+
+        let (index, _) = set.select(Duration::from_secs(5)).unwrap();
+        assert_eq!(index, 1);
+
+        let start = Instant::now();
+        assert!(set.select(Duration::from_secs(5)).is_none());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let set = set.poll_interval(Duration::from_micros(100));
+        assert_eq!(set.poll_interval, Duration::from_micros(100));
+
+        let empty = ControllerSet::default();
+        assert!(empty.is_empty() && !empty.is_failed(0));
+        assert_eq!(empty.poll_interval, ControllerSet::POLL_INTERVAL);
+        for (vhci, recv) in controllers.iter().zip(set.into_inner()) {
+            vhci.return_work_receiver(recv).unwrap();
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! The port bitflags serialize as the names of their set flags
+//! together with the raw bits, so bits without a name survive:
+//! `{ "names": ["CONNECTION", "POWER"], "raw": 33025 }`.
+//!
+//! Both that form and a plain integer deserialize. When `raw` is
+//! present it wins over `names`.
+
+use bitflags::Flags;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{PortChange, PortFlag, PortStatus};
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct NamedRepr<B> {
+    names: Vec<&'static str>,
+    raw: B,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnyRepr<B> {
+    Raw(B),
+    Named {
+        #[serde(default)]
+        names: Vec<String>,
+        raw: Option<B>,
+    },
+}
+
+fn named<F: Flags>(flags: &F) -> NamedRepr<F::Bits> {
+    NamedRepr {
+        names: flags.iter_names().map(|(name, _)| name).collect(),
+        raw: flags.bits(),
+    }
+}
+
+fn from_any<F: Flags, E: Error>(repr: AnyRepr<F::Bits>) -> Result<F, E> {
+    match repr {
+        AnyRepr::Raw(bits)
+        | AnyRepr::Named {
+            raw: Some(bits), ..
+        } => Ok(F::from_bits_retain(bits)),
+        AnyRepr::Named { names, raw: None } => names.iter().try_fold(F::empty(), |flags, name| {
+            F::from_name(name)
+                .map(|flag| flags.union(flag))
+                .ok_or_else(|| E::custom(format_args!("unknown flag {name}")))
+        }),
+    }
+}
+
+macro_rules! serde_flags {
+    ($($ty:ty),*) => {$(
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                named(self).serialize(serializer)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                from_any(AnyRepr::deserialize(deserializer)?)
+            }
+        }
+    )*};
+}
+
+serde_flags!(PortStatus, PortChange, PortFlag);
+
+#[cfg(test)]
+mod tests {
+    use serde::de::{
+        value::{Error, MapDeserializer},
+        IntoDeserializer,
+    };
+
+    use super::*;
+
+    fn from_names(names: &[&str]) -> Result<PortStatus, Error> {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        PortStatus::deserialize(MapDeserializer::new([("names", names)].into_iter()))
+    }
+
+    #[test]
+    fn keeps_unknown_bits() {
+        let status = PortStatus::from_bits_retain(0x8103);
+        assert_eq!(
+            named(&status),
+            NamedRepr {
+                names: vec!["CONNECTION", "ENABLE", "POWER"],
+                raw: 0x8103,
+            }
+        );
+
+        let raw = MapDeserializer::new([("raw", 0x8103_u16)].into_iter());
+        let parsed: Result<PortStatus, Error> = PortStatus::deserialize(raw);
+        assert_eq!(parsed.unwrap().bits(), 0x8103);
+    }
+
+    #[test]
+    fn deserializes_both_forms() {
+        let parsed: Result<PortChange, Error> =
+            PortChange::deserialize(0x0011_u16.into_deserializer());
+        assert_eq!(parsed.unwrap().bits(), 0x0011);
+
+        let status = PortStatus::ENABLE | PortStatus::POWER;
+        assert_eq!(from_names(&named(&status).names).unwrap().bits(), 0x0102);
+        assert!(from_names(&["ENABLE", "TURBO"]).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+#[cfg(feature = "controller")]
+use crate::{Controller, DeviceRegistry, Result};
+use crate::{DataRate, DescriptorSummary, DeviceInfo, HaltState, Port};
+
+/// The state of an emulated device, for bringing it back after the
+/// program driving it restarted.
+///
+/// Take it with [`SessionSnapshot::capture`] before shutting down,
+/// from what the [`DeviceRegistry`] and the device's [`HaltState`]
+/// tracked, and pass it to [`SessionSnapshot::restore`] after
+/// starting again.
+///
+/// A restart is not transparent to the host. The port goes away with
+/// the controller that connected it, so the host sees the device
+/// disconnect, and after the restore a new device that it enumerates
+/// from the start: it resets the port, reads the descriptors, assigns
+/// an address, which need not be the old one, and selects a
+/// configuration and alternate settings. What stays the same is what
+/// the device answers: the same rate, the same descriptors and so
+/// the same vendor, product and serial number for udev to match on.
+/// [`SessionSnapshot::is_restored`] tells when the host brought the
+/// device back to the configuration it had.
+///
+/// [`DeviceRegistry`]: crate::DeviceRegistry
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionSnapshot {
+    pub port: Port,
+    pub rate: DataRate,
+
+    /// What the host read of the device descriptor, `None` if it
+    /// never asked.
+    pub descriptor: Option<DescriptorSummary>,
+
+    /// Address the host had assigned, 0 if none.
+    pub address: u8,
+
+    /// Value of the last SET_CONFIGURATION, 0 if unconfigured.
+    pub configuration: u8,
+
+    /// As [`DeviceInfo::alt_settings`].
+    pub alt_settings: Vec<(u8, u8)>,
+
+    /// Endpoints halted at the time of the snapshot. A device that
+    /// follows the specification clears them when the host sets the
+    /// configuration again.
+    pub halts: HaltState,
+}
+
+impl SessionSnapshot {
+    pub fn capture(info: &DeviceInfo, halts: HaltState) -> Self {
+        Self {
+            port: info.port,
+            rate: info.rate,
+            descriptor: info.descriptor.clone(),
+            address: info.address,
+            configuration: info.configuration,
+            alt_settings: info.alt_settings.clone(),
+            halts,
+        }
+    }
+
+    /// The alternate setting `interface` was in.
+    pub fn alt_setting(&self, interface: u8) -> u8 {
+        self.alt_settings
+            .iter()
+            .find(|&&(i, _)| i == interface)
+            .map_or(0, |&(_, setting)| setting)
+    }
+
+    /// Connects the device to its old port at its old rate, and
+    /// starts tracking it in `registry`.
+    ///
+    /// The device has to answer enumeration with the descriptors it
+    /// had, and SET_CONFIGURATION and SET_INTERFACE by switching to
+    /// what the host asks for, as for any new device.
+    #[cfg(feature = "controller")]
+    pub fn restore(&self, ctrl: &mut Controller, registry: &DeviceRegistry) -> Result<()> {
+        ctrl.port_connect(self.port, self.rate)?;
+        registry.attach(self.port, self.rate);
+        Ok(())
+    }
+
+    /// Whether `info` shows the device back where the snapshot was
+    /// taken: the host read the same device descriptor and selected
+    /// the same configuration and alternate settings. The address is
+    /// not compared, the host picks it anew.
+    pub fn is_restored(&self, info: &DeviceInfo) -> bool {
+        let same_descriptor = match (&self.descriptor, &info.descriptor) {
+            (Some(old), Some(new)) => {
+                old.vendor_id == new.vendor_id
+                    && old.product_id == new.product_id
+                    && old.manufacturer == new.manufacturer
+                    && old.product == new.product
+                    && old.serial_number == new.serial_number
+            }
+            (None, _) => true,
+            (Some(_), None) => false,
+        };
+        info.port == self.port
+            && info.rate == self.rate
+            && same_descriptor
+            && info.configuration == self.configuration
+            && info.alt_settings == self.alt_settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ioctl::Endpoint, usbfs::Request, ControlTransaction, DeviceRegistry, HaltAction, Status,
+        UrbWithData,
+    };
+
+    const BULK_IN: Endpoint = Endpoint(0x81);
+
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x09, 0x12, 0x01, 0x00, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x01,
+    ];
+
+    fn request(registry: &DeviceRegistry, port: Port, req: Request, value: u16, index: u16) {
+        let reply: &[u8] = match req == Request::STANDARD_DEVICE_GET_DESCRIPTOR {
+            true => &DEVICE_DESCRIPTOR,
+            false => &[],
+        };
+        let mut urb = UrbWithData::builder()
+            .control(req.setup(value, index, reply.len() as u16).unwrap())
+            .build();
+        let mut ctrl = ControlTransaction::new(&mut urb).unwrap();
+        if !reply.is_empty() {
+            ctrl.write_reply(reply).unwrap();
+        }
+        ctrl.complete(Status::Success);
+        assert!(registry.submitted(port));
+        assert!(registry.completed(port, &urb));
+    }
+
+    /// Runs the host's side of enumeration up to interface 1 in
+    /// alternate setting 2.
+    fn enumerate(registry: &DeviceRegistry, port: Port, address: u16) {
+        request(
+            registry,
+            port,
+            Request::STANDARD_DEVICE_GET_DESCRIPTOR,
+            0x0100,
+            0,
+        );
+        request(
+            registry,
+            port,
+            Request::STANDARD_DEVICE_SET_ADDRESS,
+            address,
+            0,
+        );
+        request(
+            registry,
+            port,
+            Request::STANDARD_DEVICE_SET_CONFIGURATION,
+            1,
+            0,
+        );
+        request(
+            registry,
+            port,
+            Request::STANDARD_INTERFACE_SET_INTERFACE,
+            2,
+            1,
+        );
+    }
+
+    fn snapshot(port: Port) -> SessionSnapshot {
+        let registry = DeviceRegistry::new();
+        registry.attach(port, DataRate::High);
+        enumerate(&registry, port, 5);
+        let mut halts = HaltState::new();
+        halts.halt(BULK_IN);
+        SessionSnapshot::capture(&registry.detach(port).unwrap(), halts)
+    }
+
+    #[test]
+    fn captures_tracked_state() {
+        let port = Port::new(3).unwrap();
+        let snapshot = snapshot(port);
+        assert_eq!(snapshot.rate, DataRate::High);
+        assert_eq!(snapshot.descriptor.as_ref().unwrap().vendor_id, 0x1209);
+        assert_eq!(snapshot.address, 5);
+        assert_eq!(snapshot.configuration, 1);
+        assert_eq!(snapshot.alt_setting(1), 2);
+        assert_eq!(snapshot.alt_setting(0), 0);
+        assert!(snapshot.halts.is_halted(BULK_IN));
+    }
+
+    #[test]
+    fn is_restored_after_enumeration() {
+        let port = Port::new(3).unwrap();
+        let snapshot = snapshot(port);
+
+        let registry = DeviceRegistry::new();
+        registry.attach(port, DataRate::High);
+        assert!(!snapshot.is_restored(&registry.get(port).unwrap()));
+        enumerate(&registry, port, 9);
+        let info = registry.get(port).unwrap();
+        assert_ne!(info.address, snapshot.address);
+        assert!(snapshot.is_restored(&info));
+
+        // Halts carried over keep stalling until cleared.
+        let mut halts = snapshot.halts;
+        let mut urb = UrbWithData::builder().bulk(BULK_IN, &[0; 64]).build();
+        assert_eq!(
+            halts.intercept(&mut urb),
+            Some(HaltAction::Stalled(BULK_IN))
+        );
+
+        request(
+            &registry,
+            port,
+            Request::STANDARD_INTERFACE_SET_INTERFACE,
+            0,
+            1,
+        );
+        assert!(!snapshot.is_restored(&registry.get(port).unwrap()));
+    }
+
+    #[cfg(feature = "controller")]
+    #[test]
+    fn restore_tracks_only_connected_devices() {
+        use crate::{controller::tests::fake_controller, Error};
+
+        let port = Port::new(2).unwrap();
+        let snapshot = snapshot(port);
+        let mut ctrl = fake_controller(2);
+        let registry = DeviceRegistry::new();
+        let err = snapshot.restore(&mut ctrl, &registry).unwrap_err();
+        assert!(matches!(err, Error::Ioctl(_)), "{err:?}");
+        assert_eq!(registry.get(port), None);
+    }
+}
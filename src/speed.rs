@@ -0,0 +1,237 @@
+//! Fitting the endpoints of a configuration descriptor to the speed
+//! a port is connected at.
+//!
+//! A device connected at a lower speed than its descriptors were
+//! written for is a handy driver robustness test. These helpers find
+//! the endpoints that break the rules of the slower speed, and
+//! rewrite a high-speed configuration into a legal full-speed one.
+
+use crate::{
+    ioctl::{Endpoint, UrbType},
+    DataRate,
+};
+
+const DESCRIPTOR_TYPE_ENDPOINT: u8 = 5;
+
+/// An endpoint whose `wMaxPacketSize` is too large for the speed of
+/// the port, see [`speed_mismatches`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpeedMismatch {
+    pub endpoint: Endpoint,
+    pub kind: UrbType,
+    pub w_max_packet_size: u16,
+
+    /// Largest packet the endpoint may have at the speed, 0 for
+    /// transfer types the speed does not allow at all.
+    pub limit: u16,
+}
+
+impl std::fmt::Display for SpeedMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} endpoint {:#04x} has wMaxPacketSize {:#06x}, ",
+            self.kind, self.endpoint.0, self.w_max_packet_size
+        )?;
+        match self.limit {
+            0 => f.write_str("which is not allowed at this speed"),
+            limit => write!(f, "more than the {limit} bytes allowed at this speed"),
+        }
+    }
+}
+
+/// Largest `wMaxPacketSize` of a `kind` endpoint at `rate`.
+const fn max_packet_limit(kind: UrbType, rate: DataRate) -> u16 {
+    match (rate, kind) {
+        (DataRate::Low, UrbType::Ctrl | UrbType::Int) => 8,
+        (DataRate::Low, UrbType::Bulk | UrbType::Iso) => 0,
+        (DataRate::Full, UrbType::Ctrl | UrbType::Bulk | UrbType::Int) => 64,
+        (DataRate::Full, UrbType::Iso) => 1023,
+        (DataRate::High, UrbType::Ctrl) => 64,
+        (DataRate::High, UrbType::Bulk) => 512,
+        // Up to three 1024 byte transactions per microframe.
+        (DataRate::High, UrbType::Int | UrbType::Iso) => 3 * 1024,
+    }
+}
+
+pub(crate) const fn urb_type(bm_attributes: u8) -> UrbType {
+    match bm_attributes & 0x3 {
+        0 => UrbType::Ctrl,
+        1 => UrbType::Iso,
+        2 => UrbType::Bulk,
+        _ => UrbType::Int,
+    }
+}
+
+/// Calls `f` with every descriptor in `config`, a configuration
+/// descriptor with everything that follows it. Stops at the first
+/// malformed descriptor.
+pub(crate) fn for_each_descriptor(config: &mut [u8], mut f: impl FnMut(&mut [u8])) {
+    let mut rest = config;
+    while let [len, _, ..] = *rest {
+        let len = usize::from(len);
+        if len < 2 || len > rest.len() {
+            break;
+        }
+        let (desc, tail) = rest.split_at_mut(len);
+        f(desc);
+        rest = tail;
+    }
+}
+
+/// Like [`for_each_descriptor`], but only for endpoint descriptors.
+fn for_each_endpoint(config: &mut [u8], mut f: impl FnMut(&mut [u8])) {
+    for_each_descriptor(config, |desc| {
+        if is_endpoint(desc) {
+            f(desc);
+        }
+    });
+}
+
+pub(crate) fn is_endpoint(desc: &[u8]) -> bool {
+    desc[1] == DESCRIPTOR_TYPE_ENDPOINT && desc.len() >= 7
+}
+
+/// Endpoints of `config` whose packets are too large for a port
+/// connected at `rate`. A mismatch is not an error: the host may
+/// still enumerate the device, which is the point of testing it.
+pub fn speed_mismatches(config: &[u8], rate: DataRate) -> Vec<SpeedMismatch> {
+    let mut config = config.to_vec();
+    let mut mismatches = Vec::new();
+    for_each_endpoint(&mut config, |desc| {
+        let kind = urb_type(desc[3]);
+        let w_max_packet_size = u16::from_le_bytes([desc[4], desc[5]]);
+        let limit = max_packet_limit(kind, rate);
+        let size = match rate {
+            DataRate::High => crate::effective_max_packet(w_max_packet_size).unwrap_or(u16::MAX),
+            // Only high speed has additional transactions.
+            DataRate::Full | DataRate::Low => w_max_packet_size,
+        };
+        if size > limit {
+            mismatches.push(SpeedMismatch {
+                endpoint: Endpoint(desc[2]),
+                kind,
+                w_max_packet_size,
+                limit,
+            });
+        }
+    });
+    mismatches
+}
+
+/// Rewrites the endpoints of a high-speed `config` in place so they
+/// are legal at full speed, and returns how many were changed.
+///
+/// Packet sizes are capped at the full-speed limits, with bulk
+/// endpoints rounded down to 8, 16, 32 or 64 bytes, and additional
+/// transactions are dropped. Intervals are converted from
+/// microframes to frames: interrupt endpoints poll at least once a
+/// frame, and isochronous exponents are lowered by 3.
+pub fn downgrade_to_full_speed(config: &mut [u8]) -> usize {
+    let mut changed = 0;
+    for_each_endpoint(config, |desc| {
+        let kind = urb_type(desc[3]);
+        let old = [desc[4], desc[5], desc[6]];
+        let size = u16::from_le_bytes([desc[4], desc[5]]) & 0x07FF;
+        let limit = max_packet_limit(kind, DataRate::Full);
+        let size = match kind {
+            UrbType::Bulk => match size.min(limit) {
+                0..=15 => 8,
+                size => 1 << size.ilog2(),
+            },
+            _ => size.min(limit),
+        };
+        [desc[4], desc[5]] = size.to_le_bytes();
+
+        let b_interval = desc[6].clamp(1, 16);
+        desc[6] = match kind {
+            // 2^(bInterval - 1) microframes, as whole frames.
+            UrbType::Int => ((1u16 << (b_interval - 1)) / 8).clamp(1, 255) as u8,
+            UrbType::Iso => b_interval.saturating_sub(3).max(1),
+            UrbType::Ctrl | UrbType::Bulk => 0,
+        };
+
+        if old != [desc[4], desc[5], desc[6]] {
+            changed += 1;
+        }
+    });
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A high-speed configuration with one interface: bulk IN and
+    /// OUT, an interrupt IN polling every 8 microframes, and a
+    /// high-bandwidth isochronous IN.
+    fn high_speed_config() -> Vec<u8> {
+        let mut config = vec![9, 2, 0, 0, 1, 1, 0, 0x80, 50];
+        config.extend([9, 4, 0, 0, 4, 0xFF, 0, 0, 0]);
+        config.extend([7, 5, 0x81, 2, 0x00, 0x02, 0]);
+        config.extend([7, 5, 0x02, 2, 0x00, 0x02, 0]);
+        config.extend([7, 5, 0x83, 3, 0x40, 0x00, 4]);
+        config.extend([7, 5, 0x84, 1, 0x00, 0x14, 1]);
+        let len = config.len() as u16;
+        [config[2], config[3]] = len.to_le_bytes();
+        config
+    }
+
+    fn endpoints(config: &[u8]) -> Vec<[u8; 7]> {
+        config[18..]
+            .chunks(7)
+            .map(|desc| desc.try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn finds_full_speed_mismatches() {
+        let config = high_speed_config();
+        assert!(speed_mismatches(&config, DataRate::High).is_empty());
+
+        let mismatches = speed_mismatches(&config, DataRate::Full);
+        let found: Vec<_> = mismatches.iter().map(|m| (m.endpoint.0, m.limit)).collect();
+        assert_eq!(found, [(0x81, 64), (0x02, 64), (0x84, 1023)]);
+        assert_eq!(
+            mismatches[0].to_string(),
+            "Bulk endpoint 0x81 has wMaxPacketSize 0x0200, \
+             more than the 64 bytes allowed at this speed"
+        );
+
+        let low = speed_mismatches(&config, DataRate::Low);
+        assert_eq!(low.len(), 4);
+        assert_eq!(low[0].limit, 0);
+    }
+
+    #[test]
+    fn downgrades_to_legal_full_speed() {
+        let mut config = high_speed_config();
+        assert_eq!(downgrade_to_full_speed(&mut config), 4);
+        assert!(speed_mismatches(&config, DataRate::Full).is_empty());
+        assert_eq!(
+            endpoints(&config),
+            [
+                [7, 5, 0x81, 2, 64, 0, 0],
+                [7, 5, 0x02, 2, 64, 0, 0],
+                // 8 microframes are one frame.
+                [7, 5, 0x83, 3, 64, 0, 1],
+                // One 1023 byte packet every frame.
+                [7, 5, 0x84, 1, 0xFF, 0x03, 1],
+            ]
+        );
+        // The rest of the configuration is untouched.
+        assert_eq!(config[..18], high_speed_config()[..18]);
+        assert_eq!(downgrade_to_full_speed(&mut config), 0);
+    }
+
+    #[test]
+    fn stops_at_malformed_descriptors() {
+        let mut config = high_speed_config();
+        config[18] = 0;
+        assert_eq!(downgrade_to_full_speed(&mut config), 0);
+
+        let mut truncated = high_speed_config();
+        truncated.truncate(30);
+        assert_eq!(downgrade_to_full_speed(&mut truncated), 1);
+    }
+}
@@ -0,0 +1,209 @@
+//! [`mod@proptest`] strategies for the crate's plain-data types.
+//!
+//! Every strategy here only produces values that the kernel
+//! module could plausibly hand us, so the decoding accessors
+//! on the generated values never panic.
+
+use proptest::prelude::*;
+
+use crate::{
+    ioctl::{
+        Address, Endpoint, IocPortStat, IocSetupPacket, IocUrb, IocWork, IocWorkUnion, UrbHandle,
+        UrbType, WorkType,
+    },
+    Port, Status, UrbFlags, MAX_ISO_PACKETS,
+};
+
+/// Upper bound on generated transfer buffers. Large enough to cover
+/// multi-packet transfers without making the strategies slow.
+pub const MAX_STRATEGY_BUFFER: i32 = 4096;
+
+const ALL_STATUSES: [Status; 16] = [
+    Status::Success,
+    Status::Pending,
+    Status::ShortPacket,
+    Status::Error,
+    Status::Canceled,
+    Status::TimedOut,
+    Status::DeviceDisabled,
+    Status::DeviceDisconnected,
+    Status::BitStuff,
+    Status::Crc,
+    Status::NoResponse,
+    Status::Babble,
+    Status::Stall,
+    Status::BufferOverrun,
+    Status::BufferUnderrun,
+    Status::AllIsoPacketsFailed,
+];
+
+pub fn any_port() -> impl Strategy<Value = Port> {
+    (1u8..32).prop_map(|num| Port::new(num).unwrap())
+}
+
+pub fn any_status() -> impl Strategy<Value = Status> {
+    proptest::sample::select(&ALL_STATUSES[..])
+}
+
+pub fn any_urb_type() -> impl Strategy<Value = UrbType> {
+    prop_oneof![
+        Just(UrbType::Iso),
+        Just(UrbType::Int),
+        Just(UrbType::Ctrl),
+        Just(UrbType::Bulk),
+    ]
+}
+
+pub fn any_address() -> impl Strategy<Value = Address> {
+    (0u8..128).prop_map(|addr| Address::new(addr).unwrap())
+}
+
+pub fn any_handle() -> impl Strategy<Value = UrbHandle> {
+    any::<u64>().prop_map(UrbHandle)
+}
+
+/// Setup packets whose `bmRequestType` only uses the defined
+/// type and recipient encodings.
+pub fn setup_packet_strategy() -> impl Strategy<Value = IocSetupPacket> {
+    (
+        0u8..2,
+        0u8..3,
+        0u8..4,
+        any::<u8>(),
+        any::<u16>(),
+        any::<u16>(),
+        any::<u16>(),
+    )
+        .prop_map(
+            |(dir, ctrl_type, recipient, b_request, w_value, w_index, w_length)| IocSetupPacket {
+                bm_request_type: (dir << 7) | (ctrl_type << 5) | recipient,
+                b_request,
+                w_value,
+                w_index,
+                w_length,
+            },
+        )
+}
+
+/// URBs of the given `kind` whose fields agree with each other:
+/// control URBs have a buffer exactly as long as `wLength` and an
+/// endpoint direction matching the setup packet, only isochronous
+/// URBs carry packets, and only periodic URBs have an interval.
+pub fn urb_strategy(kind: UrbType) -> BoxedStrategy<IocUrb> {
+    match kind {
+        UrbType::Ctrl => (
+            setup_packet_strategy(),
+            0u16..=MAX_STRATEGY_BUFFER as u16,
+            any_address(),
+        )
+            .prop_map(|(mut setup_packet, length, address)| {
+                setup_packet.w_length = length;
+                IocUrb {
+                    setup_packet,
+                    buffer_length: length.into(),
+                    address,
+                    endpoint: Endpoint(setup_packet.bm_request_type & 0x80),
+                    typ: UrbType::Ctrl,
+                    ..Default::default()
+                }
+            })
+            .boxed(),
+        UrbType::Bulk | UrbType::Int => (
+            0..=MAX_STRATEGY_BUFFER,
+            1i32..=255,
+            any_address(),
+            endpoint_strategy(),
+            any::<bool>(),
+        )
+            .prop_map(
+                move |(buffer_length, interval, address, endpoint, short_not_ok)| {
+                    let mut flags = UrbFlags::empty();
+                    if short_not_ok {
+                        flags |= UrbFlags::SHORT_NOT_OK;
+                    }
+                    IocUrb {
+                        buffer_length,
+                        interval: if UrbType::Int == kind { interval } else { 0 },
+                        flags: flags.bits(),
+                        address,
+                        endpoint,
+                        typ: kind,
+                        ..Default::default()
+                    }
+                },
+            )
+            .boxed(),
+        UrbType::Iso => (
+            1..=MAX_ISO_PACKETS as i32,
+            0i32..=1024,
+            1i32..=16,
+            any_address(),
+            endpoint_strategy(),
+            any::<bool>(),
+        )
+            .prop_map(
+                |(packet_count, packet_length, interval, address, endpoint, asap)| {
+                    let flags = if asap {
+                        UrbFlags::ISO_ASAP
+                    } else {
+                        UrbFlags::empty()
+                    };
+                    IocUrb {
+                        buffer_length: packet_count * packet_length,
+                        interval,
+                        packet_count,
+                        flags: flags.bits(),
+                        address,
+                        endpoint,
+                        typ: UrbType::Iso,
+                        ..Default::default()
+                    }
+                },
+            )
+            .boxed(),
+    }
+}
+
+/// URBs of any kind, see [`urb_strategy`].
+pub fn any_urb() -> impl Strategy<Value = IocUrb> {
+    any_urb_type().prop_flat_map(urb_strategy)
+}
+
+pub fn port_stat_strategy() -> impl Strategy<Value = IocPortStat> {
+    (any::<u16>(), any::<u16>(), any_port(), any::<u8>()).prop_map(
+        |(status, change, port, flags)| IocPortStat {
+            status,
+            change,
+            index: port.get(),
+            flags,
+            ..Default::default()
+        },
+    )
+}
+
+/// Work items as returned from the fetchwork ioctl, with
+/// `typ` always matching the active union field.
+pub fn work_strategy() -> impl Strategy<Value = IocWork> {
+    prop_oneof![
+        port_stat_strategy().prop_map(|port| IocWork {
+            work: IocWorkUnion { port },
+            typ: WorkType::PortStat,
+            ..Default::default()
+        }),
+        (any_urb(), any_handle()).prop_map(|(urb, handle)| IocWork {
+            handle: handle.get(),
+            work: IocWorkUnion { urb },
+            typ: WorkType::ProcessUrb,
+            ..Default::default()
+        }),
+        any_handle().prop_map(|handle| IocWork {
+            handle: handle.get(),
+            typ: WorkType::CancelUrb,
+            ..Default::default()
+        }),
+    ]
+}
+
+fn endpoint_strategy() -> impl Strategy<Value = Endpoint> {
+    (1u8..16, any::<bool>()).prop_map(|(num, is_in)| Endpoint(num | if is_in { 0x80 } else { 0 }))
+}
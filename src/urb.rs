@@ -0,0 +1,1292 @@
+use crate::{
+    ioctl::{
+        Address, Endpoint, IocIsoPacketData, IocIsoPacketGiveback, IocSetupPacket, IocUrb,
+        UrbHandle, UrbType,
+    },
+    usbfs::Dir,
+    IsoPacketData, IsoPacketDataMut, IsoPacketGiveback, IsoPacketGivebackMut, Status, Transfer,
+    TransferMut, Urb, UrbBuilder, UrbFlags, MAX_BUFFER_LENGTH, MAX_ISO_PACKETS,
+};
+
+/// A fetched [`IocUrb`] that [`UrbWithData::try_from_ioctl`] refuses
+/// to allocate buffers for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrbDecodeError {
+    /// `buffer_length` is negative or larger than [`MAX_BUFFER_LENGTH`].
+    BufferLength(i32),
+
+    /// `packet_count` of an isochronous URB is negative or larger
+    /// than [`MAX_ISO_PACKETS`].
+    PacketCount(i32),
+}
+
+impl std::fmt::Display for UrbDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UrbDecodeError::BufferLength(len) => write!(f, "invalid buffer length {len}"),
+            UrbDecodeError::PacketCount(count) => write!(f, "invalid iso packet count {count}"),
+        }
+    }
+}
+
+impl std::error::Error for UrbDecodeError {}
+
+/// An URB fetched from the kernel together with its transfer
+/// buffer and, for isochronous URBs, its packet descriptors.
+///
+/// For OUT and isochronous URBs the transfer is the whole buffer,
+/// which [`Remote::fetch_data`] fills in. For other IN URBs the
+/// transfer is the part of the buffer written so far, which is what
+/// [`Remote::giveback`] sends back to the host.
+///
+/// [`Remote::fetch_data`]: crate::Remote::fetch_data
+/// [`Remote::giveback`]: crate::Remote::giveback
+#[derive(Debug, Clone)]
+pub struct UrbWithData {
+    urb: IocUrb,
+    handle: UrbHandle,
+    buffer: Vec<u8>,
+    transferred: usize,
+    status: Status,
+    iso_packets: Vec<IocIsoPacketData>,
+    iso_giveback: Vec<IocIsoPacketGiveback>,
+}
+
+impl UrbWithData {
+    /// # Panics
+    ///
+    /// Panics if the lengths of `urb` are out of range, see
+    /// [`UrbWithData::try_from_ioctl`].
+    pub fn from_ioctl(urb: IocUrb, handle: UrbHandle) -> Self {
+        Self::try_from_ioctl(urb, handle).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Allocates the buffers for `urb`, checking the lengths the
+    /// kernel reported before anything is allocated. The
+    /// `packet_count` of non-isochronous URBs is ignored.
+    pub fn try_from_ioctl(urb: IocUrb, handle: UrbHandle) -> Result<Self, UrbDecodeError> {
+        Self::try_from_ioctl_with_buffer(urb, handle, Vec::new())
+    }
+
+    /// Like [`UrbWithData::from_ioctl`], but the transfer buffer
+    /// reuses the allocation of `buf`, see [`BufferPool`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lengths of `urb` are out of range, see
+    /// [`UrbWithData::try_from_ioctl`].
+    ///
+    /// [`BufferPool`]: crate::BufferPool
+    pub fn from_ioctl_with_buffer(urb: IocUrb, handle: UrbHandle, buf: Vec<u8>) -> Self {
+        Self::try_from_ioctl_with_buffer(urb, handle, buf).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like [`UrbWithData::try_from_ioctl`], but the transfer buffer
+    /// reuses the allocation of `buf`. The old contents are zeroed,
+    /// so nothing leaks from one URB into the next.
+    pub fn try_from_ioctl_with_buffer(
+        urb: IocUrb,
+        handle: UrbHandle,
+        mut buf: Vec<u8>,
+    ) -> Result<Self, UrbDecodeError> {
+        let buffer_length = usize::try_from(urb.buffer_length)
+            .ok()
+            .filter(|&len| len <= MAX_BUFFER_LENGTH)
+            .ok_or(UrbDecodeError::BufferLength(urb.buffer_length))?;
+        let packet_count = if UrbType::Iso == urb.typ {
+            usize::try_from(urb.packet_count)
+                .ok()
+                .filter(|&count| count <= MAX_ISO_PACKETS)
+                .ok_or(UrbDecodeError::PacketCount(urb.packet_count))?
+        } else {
+            0
+        };
+
+        buf.clear();
+        buf.resize(buffer_length, 0);
+        Ok(Self {
+            urb,
+            handle,
+            buffer: buf,
+            transferred: 0,
+            status: Status::Success,
+            iso_packets: vec![IocIsoPacketData::default(); packet_count],
+            iso_giveback: vec![IocIsoPacketGiveback::default(); packet_count],
+        })
+    }
+
+    /// Fabricates an URB without the kernel, see [`UrbBuilder`].
+    pub fn builder() -> UrbBuilder {
+        UrbBuilder::new()
+    }
+
+    pub const fn ioc_urb(&self) -> &IocUrb {
+        &self.urb
+    }
+
+    /// The setup packet, if this is a control URB.
+    pub const fn control_packet(&self) -> Option<&IocSetupPacket> {
+        match self.urb.typ {
+            UrbType::Ctrl => Some(&self.urb.setup_packet),
+            _ => None,
+        }
+    }
+
+    pub const fn endpoint(&self) -> Endpoint {
+        self.urb.endpoint
+    }
+
+    pub const fn flags(&self) -> UrbFlags {
+        self.urb.flags()
+    }
+
+    /// Polling interval of interrupt and isochronous URBs, in
+    /// frames for full- and low-speed devices and in microframes
+    /// for high-speed devices.
+    pub const fn interval(&self) -> i32 {
+        self.urb.interval
+    }
+
+    /// Address of the device the URB is for.
+    pub const fn address(&self) -> Address {
+        self.urb.address
+    }
+
+    /// Direction of the transfer. For control URBs this comes
+    /// from the setup packet.
+    pub const fn dir(&self) -> Dir {
+        match self.urb.typ {
+            UrbType::Ctrl => self.urb.setup_packet.req().dir(),
+            _ => self.urb.endpoint.direction(),
+        }
+    }
+
+    /// Full size of the transfer buffer requested by the host.
+    pub fn buffer_length(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Whether [`Remote::fetch_data`] has to be called before
+    /// the URB can be processed: OUT URBs with data, and all
+    /// isochronous URBs since their packet layout is fetched too.
+    ///
+    /// [`Remote::fetch_data`]: crate::Remote::fetch_data
+    pub fn needs_fetch_data(&self) -> bool {
+        UrbType::Iso == self.urb.typ || (Dir::Out == self.dir() && !self.buffer.is_empty())
+    }
+
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    /// The whole transfer buffer, regardless of how much of it
+    /// has been written.
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    /// Takes the transfer buffer back after the giveback, to be
+    /// reused by [`UrbWithData::from_ioctl_with_buffer`].
+    pub fn into_buffer(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    /// Sets how many bytes of the buffer were transferred.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` is larger than [`UrbWithData::buffer_length`].
+    pub fn set_transferred(&mut self, len: usize) {
+        assert!(len <= self.buffer.len(), "transferred more than the buffer");
+        self.transferred = len;
+    }
+
+    /// Appends as much of `data` to the reply of an IN URB as fits,
+    /// and returns how many bytes were used. Control URBs take at
+    /// most `wLength` bytes. OUT and isochronous URBs take nothing,
+    /// see [`UrbWithData::fill_transfer_with`].
+    pub fn write_transfer(&mut self, data: &[u8]) -> usize {
+        self.fill_transfer_with(|space| {
+            let len = data.len().min(space.len());
+            space[..len].copy_from_slice(&data[..len]);
+            len
+        })
+    }
+
+    /// Lets `fill` write to the part of an IN URB's reply that is not
+    /// written yet, and appends as many bytes as it returns, at most
+    /// the length of the part it was given.
+    ///
+    /// `fill` is not called for OUT and isochronous URBs, whose
+    /// transfer is the whole buffer.
+    pub fn fill_transfer_with(&mut self, fill: impl FnOnce(&mut [u8]) -> usize) -> usize {
+        if self.transfers_whole_buffer() {
+            return 0;
+        }
+        let end = self.reply_capacity();
+        let start = self.transferred.min(end);
+        let written = fill(&mut self.buffer[start..end]).min(end - start);
+        self.transferred = start + written;
+        written
+    }
+
+    /// Whether the reply of an IN URB is shorter than the host asked
+    /// for. With [`UrbFlags::SHORT_NOT_OK`] set, the host treats
+    /// such a reply as an error.
+    pub fn is_short(&self) -> bool {
+        !self.transfers_whole_buffer() && self.transferred < self.reply_capacity()
+    }
+
+    /// Answers a control IN request with `data`, cut to `wLength`,
+    /// and completes it. The status is [`Status::Success`], or
+    /// [`Status::ShortPacket`] if the reply is short and the URB has
+    /// [`UrbFlags::SHORT_NOT_OK`] set.
+    ///
+    /// Fails without changing the URB if it is not a control URB,
+    /// and like [`ControlTransaction::write_reply`] otherwise.
+    pub fn reply_with(&mut self, data: &[u8]) -> Result<(), ControlError> {
+        ControlTransaction::new(self)
+            .ok_or(ControlError::NotControl)?
+            .write_reply(data)?;
+        let status = if self.flags().contains(UrbFlags::SHORT_NOT_OK) && self.is_short() {
+            Status::ShortPacket
+        } else {
+            Status::Success
+        };
+        self.set_status(status);
+        Ok(())
+    }
+
+    /// Completes the URB successfully: an OUT URB consumed all of its
+    /// data, an IN URB keeps what was written to it, usually nothing.
+    pub fn ack(&mut self) {
+        self.complete(Status::Success);
+    }
+
+    /// Completes the URB with [`Status::Stall`] and nothing
+    /// transferred, e.g. for an unsupported control request.
+    pub fn stall(&mut self) {
+        self.complete(Status::Stall);
+    }
+
+    /// See [`ControlTransaction::complete`], which this is for all
+    /// URB types.
+    fn complete(&mut self, status: Status) {
+        self.transferred = match (status, self.dir()) {
+            (Status::Success, Dir::Out) => self.buffer.len(),
+            (Status::Success, Dir::In) => self.transferred,
+            _ => 0,
+        };
+        self.status = status;
+    }
+
+    /// The most an IN URB can reply with.
+    fn reply_capacity(&self) -> usize {
+        match self.control_packet() {
+            Some(setup) => self.buffer.len().min(setup.length().into()),
+            None => self.buffer.len(),
+        }
+    }
+
+    /// The `index`th packet of an isochronous URB, or `None` if
+    /// there is no such packet.
+    pub fn iso_packet_mut(&mut self, index: usize) -> Option<IsoPacketMut<'_>> {
+        let layout = *self.iso_packets.get(index)?;
+        let (offset, len) = (layout.offset as usize, layout.packet_length as usize);
+        Some(IsoPacketMut {
+            layout,
+            slot: self.buffer.get_mut(offset..offset + len),
+            giveback: &mut self.iso_giveback[index],
+        })
+    }
+
+    /// The packets of an isochronous URB in order, e.g. to complete
+    /// all of them in one pass. The layout is only known after
+    /// [`Remote::fetch_data`].
+    ///
+    /// A packet whose slot overlaps the slot of an earlier packet
+    /// can't be handed out next to it, and is treated like one
+    /// outside of the buffer. The kernel never lays packets out like
+    /// that.
+    ///
+    /// [`Remote::fetch_data`]: crate::Remote::fetch_data
+    pub fn iso_packets_mut(&mut self) -> impl Iterator<Item = IsoPacketMut<'_>> {
+        let mut rest = &mut self.buffer[..];
+        let mut rest_offset = 0;
+        self.iso_packets
+            .iter()
+            .zip(&mut self.iso_giveback)
+            .map(move |(&layout, giveback)| {
+                let (offset, len) = (layout.offset as usize, layout.packet_length as usize);
+                let slot = offset
+                    .checked_sub(rest_offset)
+                    .filter(|&skip| skip + len <= rest.len())
+                    .map(|skip| {
+                        let (_, tail) = std::mem::take(&mut rest).split_at_mut(skip);
+                        let (slot, tail) = tail.split_at_mut(len);
+                        rest = tail;
+                        rest_offset = offset + len;
+                        slot
+                    });
+                IsoPacketMut {
+                    layout,
+                    slot,
+                    giveback,
+                }
+            })
+    }
+
+    const fn transfers_whole_buffer(&self) -> bool {
+        matches!(self.urb.typ, UrbType::Iso) || matches!(self.dir(), Dir::Out)
+    }
+
+    fn transfer_len(&self) -> usize {
+        if self.transfers_whole_buffer() {
+            self.buffer.len()
+        } else {
+            self.transferred
+        }
+    }
+
+    /// Splits the transfer into the packets it occupies on the bus.
+    /// An empty transfer is a single zero-length packet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_packet` is zero.
+    pub fn packets(&self, max_packet: u16) -> impl Iterator<Item = &[u8]> {
+        assert_ne!(max_packet, 0, "max packet size must not be zero");
+        let transfer = self.transfer();
+        let zlp = transfer.is_empty().then_some(transfer);
+        transfer.chunks(max_packet.into()).chain(zlp)
+    }
+
+    /// Splits the transfer into pieces of `max_packet` bytes. Unlike
+    /// [`UrbWithData::packets`], an empty transfer has no pieces.
+    /// Only the part of an IN buffer written so far is covered.
+    ///
+    /// Returns `None` if `max_packet` is zero.
+    pub fn chunks(&self, max_packet: u16) -> Option<impl Iterator<Item = Chunk<'_>>> {
+        let max_packet = usize::from(max_packet);
+        (max_packet != 0).then(|| {
+            self.transfer().chunks(max_packet).map(move |data| Chunk {
+                short: data.len() < max_packet,
+                data,
+            })
+        })
+    }
+
+    /// Same as [`UrbWithData::chunks`], with writable pieces.
+    pub fn chunks_mut(&mut self, max_packet: u16) -> Option<impl Iterator<Item = ChunkMut<'_>>> {
+        let max_packet = usize::from(max_packet);
+        (max_packet != 0).then(|| {
+            self.transfer_mut()
+                .chunks_mut(max_packet)
+                .map(move |data| ChunkMut {
+                    short: data.len() < max_packet,
+                    data,
+                })
+        })
+    }
+}
+
+/// A max-packet-sized piece of a transfer, see [`UrbWithData::chunks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    pub data: &'a [u8],
+
+    /// Shorter than the max packet size, which only the last
+    /// piece can be.
+    pub short: bool,
+}
+
+/// See [`UrbWithData::chunks_mut`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChunkMut<'a> {
+    pub data: &'a mut [u8],
+    pub short: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsoPacketError {
+    /// The data does not fit into the packet's slot.
+    TooLong,
+
+    /// The packet's slot lies outside of the transfer buffer.
+    OutOfBounds,
+}
+
+impl std::fmt::Display for IsoPacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IsoPacketError::TooLong => f.write_str("data does not fit into the iso packet"),
+            IsoPacketError::OutOfBounds => f.write_str("iso packet lies outside of the buffer"),
+        }
+    }
+}
+
+impl std::error::Error for IsoPacketError {}
+
+/// One packet of an isochronous URB, see [`UrbWithData::iso_packet_mut`]
+/// and [`UrbWithData::iso_packets_mut`].
+///
+/// Every packet has a fixed slot in the transfer buffer given by
+/// its offset and length. Writing a short packet only changes its
+/// actual length, the slots of the other packets stay where the
+/// host put them.
+#[derive(Debug)]
+pub struct IsoPacketMut<'a> {
+    layout: IocIsoPacketData,
+
+    /// `None` if the slot is outside of the buffer.
+    slot: Option<&'a mut [u8]>,
+    giveback: &'a mut IocIsoPacketGiveback,
+}
+
+impl IsoPacketMut<'_> {
+    pub const fn offset(&self) -> usize {
+        self.layout.offset as usize
+    }
+
+    /// Size of the packet's slot.
+    pub const fn capacity(&self) -> usize {
+        self.layout.packet_length as usize
+    }
+
+    fn slot(&mut self) -> Result<&mut [u8], IsoPacketError> {
+        self.slot.as_deref_mut().ok_or(IsoPacketError::OutOfBounds)
+    }
+
+    /// The data of an OUT packet.
+    pub fn data(&mut self) -> Result<&[u8], IsoPacketError> {
+        self.slot().map(|slot| &*slot)
+    }
+
+    /// Copies `data` to the start of the packet's slot and records
+    /// its length as the packet's actual length.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, IsoPacketError> {
+        let slot = self.slot()?;
+        let dst = slot.get_mut(..data.len()).ok_or(IsoPacketError::TooLong)?;
+        dst.copy_from_slice(data);
+        self.set_actual(data.len());
+        Ok(data.len())
+    }
+
+    /// Records `len` as the packet's actual length, e.g. after
+    /// consuming an OUT packet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` is larger than [`IsoPacketMut::capacity`].
+    pub fn set_actual(&mut self, len: usize) {
+        assert!(len <= self.capacity(), "actual length exceeds the packet");
+        self.giveback.packet_actual = len as u32;
+    }
+
+    pub fn set_status(&mut self, status: Status) {
+        self.giveback.status = status.to_errno_raw(true);
+    }
+}
+
+/// Number of packets needed to move `len` bytes with packets of
+/// `max_packet` bytes. Zero bytes still take one zero-length packet.
+///
+/// # Panics
+///
+/// Panics if `max_packet` is zero.
+pub const fn packet_count_for(len: usize, max_packet: u16) -> usize {
+    assert!(max_packet != 0, "max packet size must not be zero");
+    if len == 0 {
+        1
+    } else {
+        len.div_ceil(max_packet as usize)
+    }
+}
+
+/// Whether a transfer of `len` bytes ends in a short or zero-length
+/// packet, which terminates a message. See [`TransferAssembler`].
+///
+/// # Panics
+///
+/// Panics if `max_packet` is zero.
+pub const fn is_short_terminated(len: usize, max_packet: u16) -> bool {
+    assert!(max_packet != 0, "max packet size must not be zero");
+    len == 0 || !len.is_multiple_of(max_packet as usize)
+}
+
+/// Bytes an endpoint can move per (micro)frame, decoded from its
+/// `wMaxPacketSize`. Bits 10..0 are the packet size and bits 12..11
+/// the number of additional transactions of high-bandwidth periodic
+/// endpoints, so the result is up to 3 packets' worth of data.
+///
+/// Returns `None` for the reserved additional-transaction value 3.
+pub const fn effective_max_packet(w_max_packet_size: u16) -> Option<u16> {
+    let size = w_max_packet_size & 0x07FF;
+    match (w_max_packet_size >> 11) & 0x3 {
+        3 => None,
+        additional => Some(size * (additional + 1)),
+    }
+}
+
+/// Collects the data of consecutive OUT transfers on one endpoint
+/// into messages.
+///
+/// Following the usual USB convention, a message ends with the first
+/// transfer that is not a non-zero multiple of the max packet size,
+/// i.e. one that ends in a short or zero-length packet. A message
+/// whose length is an exact multiple of the max packet size
+/// therefore needs a trailing zero-length transfer.
+#[derive(Debug, Clone)]
+pub struct TransferAssembler {
+    max_packet: u16,
+    pending: Vec<u8>,
+}
+
+impl TransferAssembler {
+    /// # Panics
+    ///
+    /// Panics if `max_packet` is zero.
+    pub fn new(max_packet: u16) -> Self {
+        assert_ne!(max_packet, 0, "max packet size must not be zero");
+        Self {
+            max_packet,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Adds the data of one transfer, returning the completed
+    /// message if this transfer terminated it.
+    pub fn push(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+        if is_short_terminated(data.len(), self.max_packet) {
+            Some(std::mem::take(&mut self.pending))
+        } else {
+            None
+        }
+    }
+
+    /// Adds the data of an OUT URB, see [`TransferAssembler::push`].
+    pub fn push_urb(&mut self, urb: &UrbWithData) -> Option<Vec<u8>> {
+        self.push(urb.transfer())
+    }
+
+    /// Data received so far for the unfinished message.
+    pub fn pending(&self) -> &[u8] {
+        &self.pending
+    }
+
+    /// Drops the unfinished message, e.g. after the endpoint
+    /// was reset.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+impl Urb for UrbWithData {
+    fn kind(&self) -> UrbType {
+        self.urb.typ
+    }
+
+    fn handle(&self) -> UrbHandle {
+        self.handle
+    }
+
+    fn status(&self) -> Status {
+        self.status
+    }
+
+    fn dir(&self) -> Dir {
+        UrbWithData::dir(self)
+    }
+
+    /// For isochronous URBs, the whole buffer, which holds the
+    /// packets at their offsets. The packets tell how much of each
+    /// slot was used.
+    fn bytes_transferred(&self) -> usize {
+        match self.urb.typ {
+            UrbType::Iso => self.buffer.len(),
+            _ => self.transferred,
+        }
+    }
+}
+
+impl Transfer for UrbWithData {
+    fn transfer(&self) -> &[u8] {
+        &self.buffer[..self.transfer_len()]
+    }
+}
+
+impl TransferMut for UrbWithData {
+    fn transfer_mut(&mut self) -> &mut [u8] {
+        let len = self.transfer_len();
+        &mut self.buffer[..len]
+    }
+}
+
+impl IsoPacketData for UrbWithData {
+    fn iso_packet_data(&self) -> &[IocIsoPacketData] {
+        &self.iso_packets
+    }
+}
+
+impl IsoPacketDataMut for UrbWithData {
+    fn iso_packet_data_mut(&mut self) -> &mut [IocIsoPacketData] {
+        &mut self.iso_packets
+    }
+}
+
+impl IsoPacketGiveback for UrbWithData {
+    fn iso_packet_giveback(&self) -> &[IocIsoPacketGiveback] {
+        &self.iso_giveback
+    }
+
+    fn error_count(&self) -> u16 {
+        iso_error_count(&self.iso_giveback)
+    }
+}
+
+impl IsoPacketGivebackMut for UrbWithData {
+    fn iso_packet_giveback_mut(&mut self) -> &mut [IocIsoPacketGiveback] {
+        &mut self.iso_giveback
+    }
+
+    fn error_count(&self) -> u16 {
+        iso_error_count(&self.iso_giveback)
+    }
+}
+
+fn iso_error_count(packets: &[IocIsoPacketGiveback]) -> u16 {
+    packets.iter().filter(|packet| packet.status != 0).count() as u16
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlError {
+    /// Data was written to an OUT request.
+    WrongDirection,
+
+    /// Data was written to a request with a `wLength` of zero.
+    NoDataStage,
+
+    /// The URB is not a control URB.
+    NotControl,
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlError::WrongDirection => f.write_str("request has no IN data stage"),
+            ControlError::NoDataStage => f.write_str("request has no data stage"),
+            ControlError::NotControl => f.write_str("not a control urb"),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+/// The stages of a control transfer on top of an [`UrbWithData`].
+///
+/// The setup stage is [`ControlTransaction::request`], the data
+/// stage is either [`ControlTransaction::read_data`] or
+/// [`ControlTransaction::write_reply`] depending on the direction,
+/// and the status stage is handled by [`ControlTransaction::complete`].
+#[derive(Debug)]
+pub struct ControlTransaction<'a> {
+    urb: &'a mut UrbWithData,
+}
+
+impl<'a> ControlTransaction<'a> {
+    /// Returns `None` if `urb` is not a control URB.
+    pub fn new(urb: &'a mut UrbWithData) -> Option<Self> {
+        if UrbType::Ctrl == urb.kind() {
+            Some(Self { urb })
+        } else {
+            None
+        }
+    }
+
+    pub const fn request(&self) -> &IocSetupPacket {
+        &self.urb.urb.setup_packet
+    }
+
+    pub const fn dir(&self) -> Dir {
+        UrbWithData::dir(self.urb)
+    }
+
+    /// Whether the request has a data stage at all.
+    pub const fn has_data_stage(&self) -> bool {
+        self.request().length() != 0
+    }
+
+    /// Data sent by the host for an OUT request, or `None` for
+    /// an IN request. Requests without a data stage have nothing
+    /// to fetch and always read as empty.
+    pub fn read_data(&self) -> Option<&[u8]> {
+        match self.dir() {
+            Dir::Out => {
+                let len = self
+                    .urb
+                    .transfer()
+                    .len()
+                    .min(self.request().length().into());
+                Some(&self.urb.transfer()[..len])
+            }
+            Dir::In => None,
+        }
+    }
+
+    /// Copies as much of `data` as the host asked for into the
+    /// reply of an IN request, returning how many bytes were
+    /// used. Replying with less than `wLength` bytes is allowed,
+    /// replying with data to a request without a data stage is not.
+    pub fn write_reply(&mut self, data: &[u8]) -> Result<usize, ControlError> {
+        if Dir::Out == self.dir() {
+            return Err(ControlError::WrongDirection);
+        }
+        if !self.has_data_stage() && !data.is_empty() {
+            return Err(ControlError::NoDataStage);
+        }
+
+        let len = data
+            .len()
+            .min(self.request().length().into())
+            .min(self.urb.buffer_length());
+        self.urb.buffer_mut()[..len].copy_from_slice(&data[..len]);
+        self.urb.set_transferred(len);
+        Ok(len)
+    }
+
+    /// Finishes the status stage. A successful OUT request has
+    /// consumed all of its data, an IN request keeps the length of
+    /// its reply, and a failed request transferred nothing.
+    pub fn complete(self, status: Status) {
+        self.urb.complete(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{usbfs::Request, BufferPool};
+
+    fn control_urb(req: Request, w_length: u16) -> UrbWithData {
+        let setup = IocSetupPacket {
+            bm_request_type: req.bm_request_type,
+            b_request: req.b_request,
+            w_length,
+            ..Default::default()
+        };
+        UrbWithData::builder().control(setup).build()
+    }
+
+    #[test]
+    fn urb_accessors() {
+        let urb = IocUrb {
+            buffer_length: 64,
+            interval: 8,
+            flags: (UrbFlags::SHORT_NOT_OK | UrbFlags::ZERO_PACKET).bits(),
+            address: Address::new(12).unwrap(),
+            endpoint: Endpoint(0x83),
+            typ: UrbType::Int,
+            ..Default::default()
+        };
+        let urb = UrbWithData::from_ioctl(urb, UrbHandle(1));
+        assert_eq!(urb.interval(), 8);
+        assert_eq!(urb.address(), Address::new(12).unwrap());
+        assert_eq!(urb.flags(), UrbFlags::SHORT_NOT_OK | UrbFlags::ZERO_PACKET);
+        assert_eq!(urb.endpoint().number(), 3);
+        assert_eq!(urb.dir(), Dir::In);
+        assert_eq!(Endpoint(0x02).number(), 2);
+    }
+
+    #[test]
+    fn write_transfer_appends() {
+        let mut urb = UrbWithData::builder().bulk(Endpoint(0x81), &[0; 6]).build();
+        assert!(urb.is_short());
+        assert_eq!(urb.write_transfer(&[1, 2, 3, 4]), 4);
+        assert_eq!(urb.write_transfer(&[5, 6, 7]), 2);
+        assert_eq!(urb.write_transfer(&[8]), 0);
+        assert_eq!(urb.transfer(), [1, 2, 3, 4, 5, 6]);
+        assert!(!urb.is_short());
+
+        // A producer claiming more than it was given is cut off.
+        let mut urb = UrbWithData::builder().bulk(Endpoint(0x81), &[0; 4]).build();
+        let written = urb.fill_transfer_with(|space| {
+            space.fill(9);
+            space.len() + 10
+        });
+        assert_eq!((written, urb.transfer()), (4, &[9; 4][..]));
+
+        // Control replies stop at wLength, OUT URBs take nothing.
+        let mut urb = control_urb_with_buffer(Request::STANDARD_DEVICE_GET_DESCRIPTOR, 2, 18);
+        assert_eq!(urb.write_transfer(&[0x12, 0x01, 0x00]), 2);
+        assert!(!urb.is_short());
+        let mut out = UrbWithData::builder().bulk(Endpoint(0x01), &[0; 4]).build();
+        assert_eq!(out.fill_transfer_with(|_| unreachable!()), 0);
+        assert!(!out.is_short());
+    }
+
+    #[test]
+    fn reply_helpers() {
+        let mut urb = control_urb_with_buffer(Request::STANDARD_DEVICE_GET_DESCRIPTOR, 4, 4);
+        urb.reply_with(&[0x12, 0x01, 0x00, 0x02, 0x00]).unwrap();
+        assert_eq!(
+            (urb.transfer(), urb.status()),
+            (&[0x12, 0x01, 0x00, 0x02][..], Status::Success)
+        );
+
+        // Short replies only fail if the host asked it to.
+        let mut urb = control_urb_with_buffer(Request::STANDARD_DEVICE_GET_DESCRIPTOR, 18, 18);
+        urb.reply_with(&[0x12, 0x01]).unwrap();
+        assert_eq!(urb.status(), Status::Success);
+        urb.urb.flags = UrbFlags::SHORT_NOT_OK.bits();
+        urb.reply_with(&[0x12, 0x01]).unwrap();
+        assert_eq!(urb.status(), Status::ShortPacket);
+
+        let mut out = control_urb_with_buffer(Request::STANDARD_DEVICE_SET_ADDRESS, 0, 0);
+        assert_eq!(out.reply_with(&[1]), Err(ControlError::WrongDirection));
+        out.ack();
+        assert_eq!(out.status(), Status::Success);
+
+        let mut bulk = UrbWithData::builder()
+            .bulk(Endpoint(0x01), &[1, 2, 3])
+            .build();
+        assert_eq!(bulk.reply_with(&[]), Err(ControlError::NotControl));
+        bulk.ack();
+        assert_eq!(bulk.bytes_transferred(), 3);
+        bulk.stall();
+        assert_eq!(
+            (bulk.status(), bulk.bytes_transferred()),
+            (Status::Stall, 0)
+        );
+    }
+
+    fn control_urb_with_buffer(req: Request, w_length: u16, buffer_length: i32) -> UrbWithData {
+        let urb = IocUrb {
+            setup_packet: IocSetupPacket {
+                bm_request_type: req.bm_request_type,
+                b_request: req.b_request,
+                w_length,
+                ..Default::default()
+            },
+            buffer_length,
+            endpoint: Endpoint(req.bm_request_type & 0x80),
+            typ: UrbType::Ctrl,
+            ..Default::default()
+        };
+        UrbWithData::from_ioctl(urb, UrbHandle(1))
+    }
+
+    proptest! {
+        #[test]
+        fn decode_is_bounded(
+            typ in prop_oneof![Just(UrbType::Iso), Just(UrbType::Bulk)],
+            buffer_length in prop_oneof![Just(i32::MAX), Just(i32::MIN), Just(-1), any::<i32>()],
+            packet_count in prop_oneof![Just(i32::MAX), Just(i32::MIN), Just(-1), any::<i32>()],
+        ) {
+            let urb = IocUrb {
+                buffer_length,
+                packet_count,
+                endpoint: Endpoint(0x81),
+                typ,
+                ..Default::default()
+            };
+            match UrbWithData::try_from_ioctl(urb, UrbHandle(1)) {
+                Ok(urb) => {
+                    prop_assert!(urb.buffer_length() <= MAX_BUFFER_LENGTH);
+                    prop_assert!(urb.iso_packet_data().len() <= MAX_ISO_PACKETS);
+                }
+                Err(UrbDecodeError::BufferLength(len)) => prop_assert_eq!(len, buffer_length),
+                Err(UrbDecodeError::PacketCount(count)) => {
+                    prop_assert_eq!(typ, UrbType::Iso);
+                    prop_assert_eq!(count, packet_count);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fills_largest_buffers() {
+        let urb = IocUrb {
+            buffer_length: MAX_BUFFER_LENGTH as i32,
+            typ: UrbType::Bulk,
+            endpoint: Endpoint(0x81),
+            ..Default::default()
+        };
+        let mut urb = UrbWithData::try_from_ioctl(urb, UrbHandle(1)).unwrap();
+        assert_eq!(
+            urb.fill_transfer_with(|space| space.len()),
+            MAX_BUFFER_LENGTH
+        );
+        assert_eq!(urb.bytes_transferred(), MAX_BUFFER_LENGTH);
+
+        let urb = IocUrb {
+            buffer_length: MAX_BUFFER_LENGTH as i32 + 1,
+            ..*urb.ioc_urb()
+        };
+        assert_eq!(
+            UrbWithData::try_from_ioctl(urb, UrbHandle(1)).unwrap_err(),
+            UrbDecodeError::BufferLength(MAX_BUFFER_LENGTH as i32 + 1)
+        );
+    }
+
+    #[test]
+    fn decode_rejects_bogus_lengths() {
+        let urb = IocUrb {
+            buffer_length: 16,
+            packet_count: 65,
+            typ: UrbType::Iso,
+            ..Default::default()
+        };
+        assert_eq!(
+            UrbWithData::try_from_ioctl(urb, UrbHandle(1)).unwrap_err(),
+            UrbDecodeError::PacketCount(65)
+        );
+        let urb = IocUrb {
+            buffer_length: -1,
+            ..urb
+        };
+        assert_eq!(
+            UrbWithData::try_from_ioctl(urb, UrbHandle(1)).unwrap_err(),
+            UrbDecodeError::BufferLength(-1)
+        );
+        let urb = IocUrb {
+            buffer_length: 16,
+            packet_count: -1,
+            typ: UrbType::Bulk,
+            ..urb
+        };
+        assert_eq!(
+            UrbWithData::try_from_ioctl(urb, UrbHandle(1))
+                .unwrap()
+                .iso_packet_data()
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn chunks_follow_max_packet() {
+        let urb = UrbWithData::builder()
+            .bulk(Endpoint(0x02), &[0; 10])
+            .build();
+        assert!(urb.chunks(0).is_none());
+        let chunks: Vec<_> = urb
+            .chunks(4)
+            .unwrap()
+            .map(|chunk| (chunk.data.len(), chunk.short))
+            .collect();
+        assert_eq!(chunks, [(4, false), (4, false), (2, true)]);
+        let chunks: Vec<_> = urb.chunks(5).unwrap().map(|chunk| chunk.short).collect();
+        assert_eq!(chunks, [false, false]);
+
+        let empty = UrbWithData::builder().bulk(Endpoint(0x02), &[]).build();
+        assert_eq!(empty.chunks(64).unwrap().count(), 0);
+        assert_eq!(empty.packets(64).count(), 1);
+    }
+
+    #[test]
+    fn chunks_cover_written_part() {
+        let mut urb = UrbWithData::builder()
+            .bulk(Endpoint(0x81), &[0; 16])
+            .build();
+        assert_eq!(urb.chunks_mut(8).unwrap().count(), 0);
+
+        urb.set_transferred(11);
+        for (i, chunk) in urb.chunks_mut(8).unwrap().enumerate() {
+            chunk.data.fill(i as u8 + 1);
+        }
+        assert_eq!(urb.transfer(), [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2]);
+        assert!(urb.chunks(8).unwrap().last().unwrap().short);
+    }
+
+    #[test]
+    fn short_termination() {
+        assert!(is_short_terminated(0, 64));
+        assert!(is_short_terminated(63, 64));
+        assert!(!is_short_terminated(64, 64));
+        assert!(!is_short_terminated(128, 64));
+        assert!(is_short_terminated(129, 64));
+    }
+
+    #[test]
+    fn in_with_data() {
+        let mut urb = control_urb(Request::STANDARD_DEVICE_GET_DESCRIPTOR, 4);
+        assert!(!urb.needs_fetch_data());
+        let mut ctrl = ControlTransaction::new(&mut urb).unwrap();
+        assert!(ctrl.read_data().is_none());
+        assert_eq!(ctrl.write_reply(&[1, 2, 3, 4, 5, 6]), Ok(4));
+        ctrl.complete(Status::Success);
+        assert_eq!(urb.bytes_transferred(), 4);
+        assert_eq!(urb.transfer(), [1, 2, 3, 4]);
+
+        let mut urb = control_urb(Request::STANDARD_DEVICE_GET_DESCRIPTOR, 64);
+        let mut ctrl = ControlTransaction::new(&mut urb).unwrap();
+        assert_eq!(ctrl.write_reply(&[9; 18]), Ok(18));
+        ctrl.complete(Status::Success);
+        assert_eq!(urb.bytes_transferred(), 18);
+        assert_eq!(urb.transfer().len(), 18);
+    }
+
+    #[test]
+    fn out_with_data() {
+        let mut urb = control_urb(Request::STANDARD_DEVICE_SET_DESCRIPTOR, 3);
+        assert!(urb.needs_fetch_data());
+        urb.transfer_mut().copy_from_slice(&[7, 8, 9]);
+
+        let mut ctrl = ControlTransaction::new(&mut urb).unwrap();
+        assert_eq!(ctrl.read_data(), Some(&[7, 8, 9][..]));
+        assert_eq!(ctrl.write_reply(&[1]), Err(ControlError::WrongDirection));
+        ctrl.complete(Status::Success);
+        assert_eq!(urb.bytes_transferred(), 3);
+        assert_eq!(urb.status(), Status::Success);
+    }
+
+    #[test]
+    fn no_data() {
+        let mut urb = control_urb(Request::STANDARD_DEVICE_SET_CONFIGURATION, 0);
+        assert!(!urb.needs_fetch_data());
+        let ctrl = ControlTransaction::new(&mut urb).unwrap();
+        assert_eq!(ctrl.read_data(), Some(&[][..]));
+        ctrl.complete(Status::Success);
+        assert_eq!(urb.bytes_transferred(), 0);
+
+        let mut urb = control_urb(Request::STANDARD_DEVICE_GET_STATUS, 2);
+        let mut ctrl = ControlTransaction::new(&mut urb).unwrap();
+        ctrl.write_reply(&[0, 0]).unwrap();
+        ctrl.complete(Status::Stall);
+        assert_eq!(urb.bytes_transferred(), 0);
+        assert_eq!(urb.status(), Status::Stall);
+    }
+
+    #[test]
+    fn data_stage_matrix() {
+        const W_LENGTH: u16 = 8;
+        let data = [0x5A; W_LENGTH as usize];
+
+        // (direction, wLength, bytes supplied, expected result, transferred)
+        let table = [
+            (Dir::In, 0, 4, Err(ControlError::NoDataStage), 0),
+            (Dir::In, W_LENGTH, 4, Ok(4), 4),
+            (Dir::In, W_LENGTH, 8, Ok(8), 8),
+            (Dir::Out, 0, 0, Ok(0), 0),
+            (Dir::Out, W_LENGTH, 4, Ok(4), 4),
+            (Dir::Out, W_LENGTH, 8, Ok(8), 8),
+        ];
+
+        for (dir, w_length, supplied, expected, transferred) in table {
+            let req = match dir {
+                Dir::In => Request::STANDARD_DEVICE_GET_DESCRIPTOR,
+                Dir::Out => Request::STANDARD_DEVICE_SET_DESCRIPTOR,
+            };
+            let result = match dir {
+                Dir::In => {
+                    let mut urb = control_urb(req, w_length);
+                    let mut ctrl = ControlTransaction::new(&mut urb).unwrap();
+                    let result = ctrl.write_reply(&data[..supplied]);
+                    ctrl.complete(Status::Success);
+                    (result, urb)
+                }
+                Dir::Out => {
+                    // The kernel may deliver fewer bytes than wLength.
+                    let mut urb = control_urb_with_buffer(req, w_length, supplied as i32);
+                    urb.transfer_mut().copy_from_slice(&data[..supplied]);
+                    let ctrl = ControlTransaction::new(&mut urb).unwrap();
+                    let result = Ok(ctrl.read_data().unwrap().len());
+                    ctrl.complete(Status::Success);
+                    (result, urb)
+                }
+            };
+            let (result, urb) = result;
+            let case = (dir, w_length, supplied);
+            assert_eq!(result, expected, "{case:?}");
+            assert_eq!(urb.bytes_transferred(), transferred, "{case:?}");
+        }
+    }
+
+    #[test]
+    fn empty_reply_without_data_stage() {
+        let mut urb = control_urb(Request::STANDARD_DEVICE_GET_DESCRIPTOR, 0);
+        let mut ctrl = ControlTransaction::new(&mut urb).unwrap();
+        assert!(!ctrl.has_data_stage());
+        assert_eq!(ctrl.write_reply(&[]), Ok(0));
+    }
+
+    fn bulk_out(data: &[u8]) -> UrbWithData {
+        UrbWithData::builder().bulk(Endpoint(0x02), data).build()
+    }
+
+    #[test]
+    fn packet_boundaries() {
+        assert_eq!(packet_count_for(0, 64), 1);
+        assert_eq!(packet_count_for(1, 64), 1);
+        assert_eq!(packet_count_for(64, 64), 1);
+        assert_eq!(packet_count_for(65, 64), 2);
+        assert_eq!(packet_count_for(128, 64), 2);
+
+        let urb = bulk_out(&[0xAA; 10]);
+        let lens: Vec<_> = urb.packets(4).map(<[u8]>::len).collect();
+        assert_eq!(lens, [4, 4, 2]);
+        assert_eq!(urb.packets(4).count(), packet_count_for(10, 4));
+
+        let urb = bulk_out(&[0xAA; 8]);
+        let lens: Vec<_> = urb.packets(4).map(<[u8]>::len).collect();
+        assert_eq!(lens, [4, 4]);
+
+        let urb = bulk_out(&[]);
+        let lens: Vec<_> = urb.packets(4).map(<[u8]>::len).collect();
+        assert_eq!(lens, [0]);
+    }
+
+    #[test]
+    fn high_bandwidth_max_packet() {
+        assert_eq!(effective_max_packet(0x0400), Some(1024));
+        assert_eq!(effective_max_packet(0x0C00), Some(2048));
+        assert_eq!(effective_max_packet(0x1400), Some(3072));
+        assert_eq!(effective_max_packet(0x1C00), None);
+        assert_eq!(effective_max_packet(0x0040), Some(64));
+        // Bits above the transaction count are ignored.
+        assert_eq!(effective_max_packet(0xE0C0), Some(192));
+
+        let per_frame = effective_max_packet(0x1400).unwrap();
+        assert_eq!(packet_count_for(3 * 1024, per_frame), 1);
+        assert_eq!(packet_count_for(3 * 1024 + 1, per_frame), 2);
+    }
+
+    fn iso_in(lengths: &[u32]) -> UrbWithData {
+        UrbWithData::builder().iso(Endpoint(0x83), lengths).build()
+    }
+
+    #[test]
+    fn reuses_buffers() {
+        let bulk_in = |buffer_length| IocUrb {
+            buffer_length,
+            endpoint: Endpoint(0x81),
+            typ: UrbType::Bulk,
+            ..Default::default()
+        };
+        let mut pool = BufferPool::new();
+        let mut urb = UrbWithData::from_ioctl_with_buffer(bulk_in(512), UrbHandle(1), pool.get());
+        assert_eq!(urb.write_transfer(&[0xaa; 512]), 512);
+        let buf = urb.into_buffer();
+        let (ptr, capacity) = (buf.as_ptr(), buf.capacity());
+        pool.put(buf);
+        pool.put(Vec::new());
+        assert_eq!(pool.len(), 1);
+
+        let mut urb = UrbWithData::from_ioctl_with_buffer(bulk_in(64), UrbHandle(2), pool.get());
+        assert!(pool.is_empty());
+        assert_eq!(urb.buffer_length(), 64);
+        assert!(urb.transfer().is_empty());
+        assert!(urb.buffer_mut().iter().all(|&b| b == 0));
+        let buf = urb.into_buffer();
+        assert_eq!((buf.as_ptr(), buf.capacity()), (ptr, capacity));
+
+        assert_eq!(
+            UrbWithData::try_from_ioctl_with_buffer(bulk_in(-1), UrbHandle(3), buf).unwrap_err(),
+            UrbDecodeError::BufferLength(-1)
+        );
+    }
+
+    #[test]
+    fn short_iso_packets_keep_layout() {
+        let mut urb = iso_in(&[4, 4, 4]);
+        let writes: [&[u8]; 3] = [&[1, 2], &[3, 4, 5, 6], &[7]];
+        for (index, data) in writes.into_iter().enumerate() {
+            let mut packet = urb.iso_packet_mut(index).unwrap();
+            assert_eq!(packet.write(data), Ok(data.len()));
+        }
+        assert!(urb.iso_packet_mut(3).is_none());
+
+        assert_eq!(urb.transfer(), [1, 2, 0, 0, 3, 4, 5, 6, 7, 0, 0, 0]);
+        let actual: Vec<_> = urb
+            .iso_packet_giveback()
+            .iter()
+            .map(|packet| packet.packet_actual)
+            .collect();
+        assert_eq!(actual, [2, 4, 1]);
+
+        let mut packet = urb.iso_packet_mut(1).unwrap();
+        assert_eq!(packet.write(&[0; 5]), Err(IsoPacketError::TooLong));
+        assert_eq!(urb.iso_packet_giveback()[1].packet_actual, 4);
+
+        urb.iso_packet_data_mut()[2].offset = 10;
+        let mut packet = urb.iso_packet_mut(2).unwrap();
+        assert_eq!(packet.write(&[]), Err(IsoPacketError::OutOfBounds));
+    }
+
+    #[test]
+    fn completes_iso_urbs_packet_by_packet() {
+        let urb = IocUrb {
+            buffer_length: 24,
+            packet_count: 3,
+            endpoint: Endpoint(0x82),
+            typ: UrbType::Iso,
+            ..Default::default()
+        };
+        let mut urb = UrbWithData::from_ioctl(urb, UrbHandle(9));
+        assert_eq!(urb.iso_packet_data().len(), 3);
+        // As fetched by Remote::fetch_data.
+        for (index, layout) in urb.iso_packet_data_mut().iter_mut().enumerate() {
+            layout.offset = 8 * index as u32;
+            layout.packet_length = 8;
+        }
+
+        for (index, mut packet) in urb.iso_packets_mut().enumerate() {
+            assert_eq!((packet.offset(), packet.capacity()), (8 * index, 8));
+            match index {
+                1 => packet.set_status(Status::Crc),
+                _ => assert_eq!(packet.write(&[index as u8; 3]), Ok(3)),
+            }
+        }
+        assert_eq!(IsoPacketGiveback::error_count(&urb), 1);
+        let actual: Vec<_> = urb
+            .iso_packet_giveback()
+            .iter()
+            .map(|packet| packet.packet_actual)
+            .collect();
+        assert_eq!(actual, [3, 0, 3]);
+        assert_eq!(urb.transfer()[16..19], [2; 3]);
+        // What giveback sends along with the packets.
+        assert_eq!(urb.bytes_transferred(), 24);
+        assert_eq!(urb.transfer_mut().len(), 24);
+
+        // The overlapping packet gets no slot.
+        urb.iso_packet_data_mut()[2].offset = 4;
+        let mut packets: Vec<_> = urb.iso_packets_mut().collect();
+        assert!(packets[1].data().is_ok());
+        assert_eq!(packets[2].data(), Err(IsoPacketError::OutOfBounds));
+    }
+
+    #[test]
+    fn assembler_exact_multiple_needs_zlp() {
+        let mut asm = TransferAssembler::new(4);
+        assert_eq!(asm.push_urb(&bulk_out(&[1, 2, 3, 4])), None);
+        assert_eq!(asm.push_urb(&bulk_out(&[5, 6, 7, 8])), None);
+        assert_eq!(asm.pending(), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(
+            asm.push_urb(&bulk_out(&[])),
+            Some(vec![1, 2, 3, 4, 5, 6, 7, 8])
+        );
+        assert!(asm.pending().is_empty());
+        assert_eq!(asm.push(&[]), Some(vec![]));
+    }
+
+    #[test]
+    fn assembler_short_terminated() {
+        let mut asm = TransferAssembler::new(4);
+        assert_eq!(asm.push(&[1, 2, 3, 4, 5, 6, 7, 8]), None);
+        assert_eq!(asm.push(&[9]), Some(vec![1, 2, 3, 4, 5, 6, 7, 8, 9]));
+        assert_eq!(asm.push(&[1, 2, 3, 4, 5]), Some(vec![1, 2, 3, 4, 5]));
+        assert_eq!(asm.push(&[1, 2, 3, 4]), None);
+        asm.clear();
+        assert_eq!(asm.push(&[7, 7]), Some(vec![7, 7]));
+    }
+
+    #[test]
+    fn only_wraps_control_urbs() {
+        let mut urb = UrbWithData::builder().bulk(Endpoint(0x81), &[0; 8]).build();
+        assert!(ControlTransaction::new(&mut urb).is_none());
+    }
+}
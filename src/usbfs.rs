@@ -1,13 +1,19 @@
 use crate::ioctl::{
-    URB_RQ_CLEAR_FEATURE, URB_RQ_GET_CONFIGURATION, URB_RQ_GET_DESCRIPTOR, URB_RQ_GET_INTERFACE,
-    URB_RQ_GET_STATUS, URB_RQ_SET_ADDRESS, URB_RQ_SET_CONFIGURATION, URB_RQ_SET_DESCRIPTOR,
-    URB_RQ_SET_FEATURE, URB_RQ_SET_INTERFACE, URB_RQ_SYNCH_FRAME,
+    Address, DecodeError, IocSetupPacket, URB_RQ_CLEAR_FEATURE, URB_RQ_GET_CONFIGURATION,
+    URB_RQ_GET_DESCRIPTOR, URB_RQ_GET_INTERFACE, URB_RQ_GET_STATUS, URB_RQ_SET_ADDRESS,
+    URB_RQ_SET_CONFIGURATION, URB_RQ_SET_DESCRIPTOR, URB_RQ_SET_FEATURE, URB_RQ_SET_INTERFACE,
+    URB_RQ_SYNCH_FRAME,
 };
 
 #[cfg(feature = "zerocopy")]
 use zerocopy_derive::*;
 
+#[cfg_attr(
+    feature = "zerocopy",
+    derive(KnownLayout, Immutable, IntoBytes, FromBytes, Unaligned)
+)]
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
 pub struct Request {
     pub bm_request_type: u8,
     pub b_request: u8,
@@ -41,7 +47,7 @@ impl Request {
 
     pub const STANDARD_DEVICE_SET_DESCRIPTOR: Self = Self {
         bm_request_type: 0x00,
-        b_request: URB_RQ_GET_DESCRIPTOR,
+        b_request: URB_RQ_SET_DESCRIPTOR,
     };
 
     pub const STANDARD_DEVICE_GET_CONFIGURATION: Self = Self {
@@ -99,41 +105,113 @@ impl Request {
         b_request: URB_RQ_SYNCH_FRAME,
     };
 
+    /// # Panics
+    ///
+    /// Panics if `bmRequestType` uses a reserved type or recipient.
     pub const fn kind(&self) -> (Dir, CtrlType, Recipient) {
         (self.dir(), self.ctrl_type(), self.recipient())
     }
 
+    /// # Panics
+    ///
+    /// Panics if `bmRequestType` uses the reserved type 3, see
+    /// [`Request::try_ctrl_type`].
     pub const fn ctrl_type(&self) -> CtrlType {
-        CtrlType::from_u8((self.bm_request_type & 0x60) >> 5).unwrap()
+        match self.try_ctrl_type() {
+            Ok(ctrl_type) => ctrl_type,
+            Err(_) => panic!("reserved request type"),
+        }
+    }
+
+    pub const fn try_ctrl_type(&self) -> Result<CtrlType, DecodeError> {
+        match CtrlType::from_u8((self.bm_request_type & 0x60) >> 5) {
+            Some(ctrl_type) => Ok(ctrl_type),
+            None => Err(DecodeError::RequestType(self.bm_request_type)),
+        }
     }
 
     pub const fn dir(&self) -> Dir {
         Dir::from_u8((self.bm_request_type & 0x80) >> 7).unwrap()
     }
 
+    /// # Panics
+    ///
+    /// Panics if `bmRequestType` uses one of the reserved recipients
+    /// 4 to 31, see [`Request::try_recipient`].
     pub const fn recipient(&self) -> Recipient {
-        Recipient::from_u8(self.bm_request_type & 0x1F).unwrap()
+        match self.try_recipient() {
+            Ok(recipient) => recipient,
+            Err(_) => panic!("reserved request recipient"),
+        }
+    }
+
+    pub const fn try_recipient(&self) -> Result<Recipient, DecodeError> {
+        match Recipient::from_u8(self.bm_request_type & 0x1F) {
+            Some(recipient) => Ok(recipient),
+            None => Err(DecodeError::Recipient(self.bm_request_type)),
+        }
     }
 
+    /// The request, or [`Req::Other`] for requests of a reserved
+    /// type or recipient.
     pub const fn req(&self) -> Req {
-        match self.kind() {
-            (_, CtrlType::Standard, _) => Req::standard_from_u8(self.b_request),
-            (dir, CtrlType::Class, Recipient::Interface) => Req::class_from_u8(dir, self.b_request),
+        match (self.dir(), self.try_ctrl_type(), self.try_recipient()) {
+            (_, Ok(CtrlType::Standard), _) => Req::standard_from_u8(self.b_request),
+            (dir, Ok(CtrlType::Class), Ok(recipient)) => {
+                Req::class_from_request(dir, recipient, self.b_request)
+            }
             _ => Req::Other(self.b_request),
         }
     }
+
+    /// Builds the setup packet for this request.
+    ///
+    /// IN requests need a data stage to reply in, and the standard
+    /// OUT requests other than SET_DESCRIPTOR take no data stage.
+    pub const fn setup(
+        &self,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<IocSetupPacket, SetupError> {
+        let has_data_stage = length != 0;
+        match (self.dir(), self.try_ctrl_type(), has_data_stage) {
+            (Dir::In, _, false) => return Err(SetupError::MissingDataStage),
+            (Dir::Out, Ok(CtrlType::Standard), true)
+                if !matches!(self.req(), Req::SetDescriptor) =>
+            {
+                return Err(SetupError::UnexpectedDataStage)
+            }
+            _ => (),
+        }
+
+        Ok(IocSetupPacket {
+            bm_request_type: self.bm_request_type,
+            b_request: self.b_request,
+            w_value: value,
+            w_index: index,
+            w_length: length,
+        })
+    }
+
+    /// Whether `pkt` carries this request.
+    pub const fn matches(&self, pkt: &IocSetupPacket) -> bool {
+        self.bm_request_type == pkt.bm_request_type && self.b_request == pkt.b_request
+    }
 }
 
 impl std::fmt::Display for Request {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "[{:?} | {:?} | {:?}] {:?}",
-            self.dir(),
-            self.ctrl_type(),
-            self.recipient(),
-            self.req()
-        )
+        write!(f, "[{:?} | ", self.dir())?;
+        match self.try_ctrl_type() {
+            Ok(ctrl_type) => write!(f, "{ctrl_type:?} | ")?,
+            Err(_) => f.write_str("Reserved | ")?,
+        }
+        match self.try_recipient() {
+            Ok(recipient) => write!(f, "{recipient:?}] ")?,
+            Err(_) => f.write_str("Reserved] ")?,
+        }
+        write!(f, "{:?}", self.req())
     }
 }
 
@@ -143,6 +221,27 @@ impl std::fmt::Debug for Request {
     }
 }
 
+/// Why [`Request::setup`] refused to build a setup packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupError {
+    /// An IN request with a `wLength` of zero.
+    MissingDataStage,
+
+    /// A data stage for a request that does not take one.
+    UnexpectedDataStage,
+}
+
+impl std::fmt::Display for SetupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SetupError::MissingDataStage => f.write_str("IN request without a data stage"),
+            SetupError::UnexpectedDataStage => f.write_str("request does not take a data stage"),
+        }
+    }
+}
+
+impl std::error::Error for SetupError {}
+
 #[cfg_attr(
     feature = "zerocopy",
     derive(KnownLayout, Immutable, IntoBytes, TryFromBytes, Unaligned)
@@ -257,7 +356,7 @@ impl Recipient {
     }
 }
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Req {
     #[default]
     GetStatus,
@@ -284,6 +383,9 @@ pub enum Req {
     UacSetRes,
     UacGetRes,
     UacSetIdle,
+    GetIdle,
+    GetProtocol,
+    SetProtocol,
     GetReport,
     SetReport,
     Other(u8),
@@ -307,24 +409,661 @@ impl Req {
         }
     }
 
+    /// Names a class request. Audio, HID and mass storage reuse
+    /// the same numbers, so the direction and recipient pick the
+    /// class: endpoints only take audio requests, and on interfaces
+    /// the audio SET requests are OUT while the HID GET requests
+    /// with the same numbers are IN. Unknown requests, including
+    /// every class request to the device, are [`Req::Other`].
+    pub const fn class_from_request(dir: Dir, recipient: Recipient, b_request: u8) -> Req {
+        match (recipient, dir, b_request) {
+            (Recipient::Interface | Recipient::Endpoint, Dir::Out, 0x01) => Self::UacSetCur,
+            (Recipient::Interface | Recipient::Endpoint, Dir::Out, 0x02) => Self::UacSetMin,
+            (Recipient::Interface | Recipient::Endpoint, Dir::Out, 0x03) => Self::UacSetMax,
+            (Recipient::Interface | Recipient::Endpoint, Dir::Out, 0x04) => Self::UacSetRes,
+            (Recipient::Interface | Recipient::Endpoint, Dir::In, 0x81) => Self::UacGetCur,
+            (Recipient::Interface | Recipient::Endpoint, Dir::In, 0x82) => Self::UacGetMin,
+            (Recipient::Interface | Recipient::Endpoint, Dir::In, 0x83) => Self::UacGetMax,
+            (Recipient::Interface | Recipient::Endpoint, Dir::In, 0x84) => Self::UacGetRes,
+            (Recipient::Interface, Dir::In, 0x01) => Self::GetReport,
+            (Recipient::Interface, Dir::In, 0x02) => Self::GetIdle,
+            (Recipient::Interface, Dir::In, 0x03) => Self::GetProtocol,
+            (Recipient::Interface, Dir::Out, 0x09) => Self::SetReport,
+            (Recipient::Interface, Dir::Out, 0x0A) => Self::UacSetIdle,
+            (Recipient::Interface, Dir::Out, 0x0B) => Self::SetProtocol,
+            (Recipient::Interface, _, 0xFC) => Self::GetRequests,
+            (Recipient::Interface, _, 0xFD) => Self::PutRequests,
+            (Recipient::Interface, Dir::In, 0xFE) => Self::GetMaxLun,
+            (Recipient::Interface, Dir::Out, 0xFF) => Self::BulkOnlyMassStorageReset,
+            _ => Self::Other(b_request),
+        }
+    }
+
+    #[deprecated(note = "use `class_from_request`")]
     pub const fn class_from_u8(dir: Dir, b_request: u8) -> Req {
-        match (dir, b_request) {
-            (Dir::Out, 0x01) => Self::UacSetCur,
-            (Dir::In, 0x01) => Self::GetReport,
-            (_, 0x02) => Self::UacSetMin,
-            (_, 0x03) => Self::UacSetMax,
-            (_, 0x04) => Self::UacSetRes,
-            (_, 0x09) => Self::SetReport,
-            (_, 0x0A) => Self::UacSetIdle,
-            (_, 0x81) => Self::UacGetCur,
-            (_, 0x82) => Self::UacGetMin,
-            (_, 0x83) => Self::UacGetMax,
-            (_, 0x84) => Self::UacGetRes,
-            (_, 0xFC) => Self::GetRequests,
-            (_, 0xFD) => Self::PutRequests,
-            (_, 0xFF) => Self::BulkOnlyMassStorageReset,
-            (_, 0xFE) => Self::GetMaxLun,
-            _ => Self::standard_from_u8(b_request),
+        Self::class_from_request(dir, Recipient::Interface, b_request)
+    }
+}
+
+/// A standard request decoded from its setup packet, see
+/// [`IocSetupPacket::standard_request`].
+///
+/// A standard request is only decoded if its direction and recipient
+/// are the ones the USB specification allows and its fields are in
+/// range. Anything else, including requests not listed here like
+/// GET_CONFIGURATION, is [`StandardRequest::Other`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StandardRequest {
+    /// GET_DESCRIPTOR of the device. `lang_id` is only meaningful
+    /// for string descriptors.
+    GetDescriptor {
+        desc_type: DescriptorType,
+        index: u8,
+        lang_id: u16,
+    },
+    SetAddress(Address),
+    SetConfiguration(u8),
+
+    /// GET_STATUS of `target`, the interface or endpoint number in
+    /// `wIndex`. Always 0 for the device.
+    GetStatus {
+        recipient: Recipient,
+        target: u16,
+    },
+    ClearFeature {
+        recipient: Recipient,
+        feature: u16,
+        target: u16,
+    },
+    SetFeature {
+        recipient: Recipient,
+        feature: u16,
+        target: u16,
+    },
+    SetInterface {
+        interface: u16,
+        alt: u16,
+    },
+    SynchFrame {
+        endpoint: u16,
+    },
+    Other(IocSetupPacket),
+}
+
+impl StandardRequest {
+    /// Decodes `pkt`, or returns `None` if it is not a standard
+    /// request.
+    pub const fn from_setup(pkt: &IocSetupPacket) -> Option<Self> {
+        match pkt.req().try_ctrl_type() {
+            Ok(CtrlType::Standard) => Some(Self::decode(pkt)),
+            _ => None,
+        }
+    }
+
+    /// Decodes `pkt` as a standard request without looking at its
+    /// type, e.g. once [`RequestClass::from_setup`] found it to be
+    /// one. Requests it doesn't know are [`StandardRequest::Other`].
+    pub const fn decode(pkt: &IocSetupPacket) -> Self {
+        use Recipient::{Device, Endpoint, Interface};
+
+        let req = pkt.req();
+        let Ok(recipient) = req.try_recipient() else {
+            return Self::Other(*pkt);
+        };
+        let (value, index) = (pkt.w_value, pkt.w_index);
+        match (req.dir(), recipient, pkt.b_request) {
+            (Dir::In, Device, URB_RQ_GET_DESCRIPTOR) => {
+                match DescriptorType::from_u8((value >> 8) as u8) {
+                    Some(desc_type) => Self::GetDescriptor {
+                        desc_type,
+                        index: value as u8,
+                        lang_id: index,
+                    },
+                    None => Self::Other(*pkt),
+                }
+            }
+            (Dir::Out, Device, URB_RQ_SET_ADDRESS) => {
+                match Address::from_set_address_value(value) {
+                    Some(address) => Self::SetAddress(address),
+                    None => Self::Other(*pkt),
+                }
+            }
+            (Dir::Out, Device, URB_RQ_SET_CONFIGURATION) if value <= 0xFF => {
+                Self::SetConfiguration(value as u8)
+            }
+            (Dir::In, _, URB_RQ_GET_STATUS) => Self::GetStatus {
+                recipient,
+                target: index,
+            },
+            (Dir::Out, _, URB_RQ_CLEAR_FEATURE) => Self::ClearFeature {
+                recipient,
+                feature: value,
+                target: index,
+            },
+            (Dir::Out, _, URB_RQ_SET_FEATURE) => Self::SetFeature {
+                recipient,
+                feature: value,
+                target: index,
+            },
+            (Dir::Out, Interface, URB_RQ_SET_INTERFACE) => Self::SetInterface {
+                interface: index,
+                alt: value,
+            },
+            (Dir::In, Endpoint, URB_RQ_SYNCH_FRAME) => Self::SynchFrame { endpoint: index },
+            _ => Self::Other(*pkt),
+        }
+    }
+}
+
+/// The fields of a class or vendor setup packet, with the direction
+/// and recipient decoded. See [`IocSetupPacket::classify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassRequest {
+    pub dir: Dir,
+    pub recipient: Recipient,
+    pub b_request: u8,
+    pub w_value: u16,
+    pub w_index: u16,
+    pub w_length: u16,
+}
+
+impl ClassRequest {
+    /// The request by name, see [`Req::class_from_request`].
+    pub const fn req(&self) -> Req {
+        Req::class_from_request(self.dir, self.recipient, self.b_request)
+    }
+}
+
+/// Same as [`ClassRequest`], for requests only the vendor knows the
+/// meaning of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VendorRequest {
+    pub dir: Dir,
+    pub recipient: Recipient,
+    pub b_request: u8,
+    pub w_value: u16,
+    pub w_index: u16,
+    pub w_length: u16,
+}
+
+/// A setup packet sorted by its request type, see
+/// [`IocSetupPacket::classify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestClass {
+    Standard(StandardRequest),
+    Class(ClassRequest),
+    Vendor(VendorRequest),
+}
+
+impl RequestClass {
+    /// Sorts `pkt` by its request type.
+    pub const fn from_setup(pkt: &IocSetupPacket) -> Result<Self, DecodeError> {
+        let req = pkt.req();
+        let (ctrl_type, recipient) = match (req.try_ctrl_type(), req.try_recipient()) {
+            (Ok(ctrl_type), Ok(recipient)) => (ctrl_type, recipient),
+            (Err(err), _) | (_, Err(err)) => return Err(err),
+        };
+        Ok(match ctrl_type {
+            CtrlType::Standard => Self::Standard(StandardRequest::decode(pkt)),
+            CtrlType::Class => Self::Class(ClassRequest {
+                dir: req.dir(),
+                recipient,
+                b_request: pkt.b_request,
+                w_value: pkt.w_value,
+                w_index: pkt.w_index,
+                w_length: pkt.w_length,
+            }),
+            CtrlType::Vendor => Self::Vendor(VendorRequest {
+                dir: req.dir(),
+                recipient,
+                b_request: pkt.b_request,
+                w_value: pkt.w_value,
+                w_index: pkt.w_index,
+                w_length: pkt.w_length,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn setup(bm_request_type: u8, b_request: u8) -> IocSetupPacket {
+        IocSetupPacket {
+            bm_request_type,
+            b_request,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_setup_packets() {
+        let pkt = setup(0x80, URB_RQ_GET_DESCRIPTOR);
+        assert!(Request::STANDARD_DEVICE_GET_DESCRIPTOR.matches(&pkt));
+        assert!(!Request::STANDARD_INTERFACE_GET_INTERFACE.matches(&pkt));
+        assert_eq!(pkt.request_id(), Request::STANDARD_DEVICE_GET_DESCRIPTOR);
+
+        // Same bRequest, different recipient.
+        let pkt = setup(0x82, URB_RQ_GET_STATUS);
+        assert!(Request::STANDARD_ENDPOINT_GET_STATUS.matches(&pkt));
+        assert!(!Request::STANDARD_DEVICE_GET_STATUS.matches(&pkt));
+    }
+
+    #[test]
+    fn constants_decode_to_their_request() {
+        use CtrlType::Standard;
+        use Dir::{In, Out};
+        use Recipient::{Device, Endpoint, Interface};
+
+        let table = [
+            (
+                Request::STANDARD_DEVICE_GET_STATUS,
+                (In, Standard, Device),
+                Req::GetStatus,
+            ),
+            (
+                Request::STANDARD_DEVICE_CLEAR_FEATURE,
+                (Out, Standard, Device),
+                Req::ClearFeature,
+            ),
+            (
+                Request::STANDARD_DEVICE_SET_FEATURE,
+                (Out, Standard, Device),
+                Req::SetFeature,
+            ),
+            (
+                Request::STANDARD_DEVICE_SET_ADDRESS,
+                (Out, Standard, Device),
+                Req::SetAddress,
+            ),
+            (
+                Request::STANDARD_DEVICE_GET_DESCRIPTOR,
+                (In, Standard, Device),
+                Req::GetDescriptor,
+            ),
+            (
+                Request::STANDARD_DEVICE_SET_DESCRIPTOR,
+                (Out, Standard, Device),
+                Req::SetDescriptor,
+            ),
+            (
+                Request::STANDARD_DEVICE_GET_CONFIGURATION,
+                (In, Standard, Device),
+                Req::GetConfiguration,
+            ),
+            (
+                Request::STANDARD_DEVICE_SET_CONFIGURATION,
+                (Out, Standard, Device),
+                Req::SetConfiguration,
+            ),
+            (
+                Request::STANDARD_INTERFACE_GET_STATUS,
+                (In, Standard, Interface),
+                Req::GetStatus,
+            ),
+            (
+                Request::STANDARD_INTERFACE_CLEAR_FEATURE,
+                (Out, Standard, Interface),
+                Req::ClearFeature,
+            ),
+            (
+                Request::STANDARD_INTERFACE_SET_FEATURE,
+                (Out, Standard, Interface),
+                Req::SetFeature,
+            ),
+            (
+                Request::STANDARD_INTERFACE_GET_INTERFACE,
+                (In, Standard, Interface),
+                Req::GetInterface,
+            ),
+            (
+                Request::STANDARD_INTERFACE_SET_INTERFACE,
+                (Out, Standard, Interface),
+                Req::SetInterface,
+            ),
+            (
+                Request::STANDARD_ENDPOINT_GET_STATUS,
+                (In, Standard, Endpoint),
+                Req::GetStatus,
+            ),
+            (
+                Request::STANDARD_ENDPOINT_CLEAR_FEATURE,
+                (Out, Standard, Endpoint),
+                Req::ClearFeature,
+            ),
+            (
+                Request::STANDARD_ENDPOINT_SET_FEATURE,
+                (Out, Standard, Endpoint),
+                Req::SetFeature,
+            ),
+            (
+                Request::STANDARD_ENDPOINT_SYNCH_FRAME,
+                (In, Standard, Endpoint),
+                Req::SynchFrame,
+            ),
+        ];
+        for (request, kind, req) in table {
+            assert_eq!(request.kind(), kind, "{request}");
+            assert_eq!(request.req(), req, "{request}");
+            let pkt = setup(request.bm_request_type, request.b_request);
+            assert!(pkt.is(request), "{request}");
+        }
+    }
+
+    #[test]
+    fn setup_round_trips() {
+        let pkt = Request::STANDARD_DEVICE_GET_DESCRIPTOR
+            .setup(0x0100, 0, 18)
+            .unwrap();
+        assert_eq!(pkt.as_request(), Request::STANDARD_DEVICE_GET_DESCRIPTOR);
+        assert_eq!((pkt.value(), pkt.index(), pkt.length()), (0x0100, 0, 18));
+
+        let pkt = Request::STANDARD_DEVICE_SET_CONFIGURATION
+            .setup(1, 0, 0)
+            .unwrap();
+        assert_eq!(pkt.as_request(), Request::STANDARD_DEVICE_SET_CONFIGURATION);
+
+        let pkt = Request::STANDARD_DEVICE_SET_DESCRIPTOR
+            .setup(0x0300, 0x0409, 16)
+            .unwrap();
+        assert_eq!(pkt.as_request().req(), Req::SetDescriptor);
+
+        let pkt = Request::STANDARD_ENDPOINT_CLEAR_FEATURE
+            .setup(0, 0x81, 0)
+            .unwrap();
+        assert!(Request::STANDARD_ENDPOINT_CLEAR_FEATURE.matches(&pkt));
+
+        // HID GET_REPORT and SET_REPORT.
+        let get_report = Request {
+            bm_request_type: 0xA1,
+            b_request: 0x01,
+        };
+        let pkt = get_report.setup(0x0100, 0, 8).unwrap();
+        assert_eq!(pkt.as_request().req(), Req::GetReport);
+        let set_report = Request {
+            bm_request_type: 0x21,
+            b_request: 0x09,
+        };
+        let pkt = set_report.setup(0x0200, 0, 8).unwrap();
+        assert_eq!(pkt.as_request().req(), Req::SetReport);
+        assert!(set_report.setup(0x0200, 0, 0).is_ok());
+    }
+
+    #[test]
+    fn setup_checks_data_stage() {
+        assert_eq!(
+            Request::STANDARD_DEVICE_GET_STATUS.setup(0, 0, 0),
+            Err(SetupError::MissingDataStage)
+        );
+        assert_eq!(
+            Request::STANDARD_DEVICE_SET_ADDRESS.setup(5, 0, 2),
+            Err(SetupError::UnexpectedDataStage)
+        );
+        let class_in = Request {
+            bm_request_type: 0xA1,
+            b_request: 0x81,
+        };
+        assert_eq!(class_in.setup(0, 0, 0), Err(SetupError::MissingDataStage));
+    }
+
+    #[test]
+    fn vendor_requests_compare_by_number() {
+        let a = setup(0xC0, 0x51).request_id();
+        let b = setup(0x40, 0x51).request_id();
+        let c = setup(0xC0, 0x52).request_id();
+        assert_eq!(a.req(), Req::Other(0x51));
+        assert_eq!(a.req(), b.req());
+        assert_ne!(a.req(), c.req());
+        assert_ne!(a, b);
+
+        let mut handlers = HashMap::new();
+        handlers.insert(a.req(), "read");
+        handlers.insert(c.req(), "write");
+        assert_eq!(handlers.get(&Req::Other(0x51)), Some(&"read"));
+        assert_eq!(handlers.get(&Req::Other(0x53)), None);
+    }
+
+    #[test]
+    fn parses_standard_requests() {
+        use StandardRequest::*;
+
+        let parse = |request: Request, value, index| {
+            IocSetupPacket {
+                w_value: value,
+                w_index: index,
+                ..setup(request.bm_request_type, request.b_request)
+            }
+            .standard_request()
+            .unwrap()
+        };
+        let table = [
+            (
+                parse(Request::STANDARD_DEVICE_GET_DESCRIPTOR, 0x0302, 0x0409),
+                GetDescriptor {
+                    desc_type: DescriptorType::String,
+                    index: 2,
+                    lang_id: 0x0409,
+                },
+            ),
+            (
+                parse(Request::STANDARD_DEVICE_SET_ADDRESS, 12, 0),
+                SetAddress(Address::new(12).unwrap()),
+            ),
+            (
+                parse(Request::STANDARD_DEVICE_SET_CONFIGURATION, 1, 0),
+                SetConfiguration(1),
+            ),
+            (
+                parse(Request::STANDARD_DEVICE_GET_STATUS, 0, 0),
+                GetStatus {
+                    recipient: Recipient::Device,
+                    target: 0,
+                },
+            ),
+            (
+                parse(Request::STANDARD_INTERFACE_GET_STATUS, 0, 2),
+                GetStatus {
+                    recipient: Recipient::Interface,
+                    target: 2,
+                },
+            ),
+            (
+                parse(Request::STANDARD_ENDPOINT_GET_STATUS, 0, 0x81),
+                GetStatus {
+                    recipient: Recipient::Endpoint,
+                    target: 0x81,
+                },
+            ),
+            (
+                parse(Request::STANDARD_DEVICE_CLEAR_FEATURE, 1, 0),
+                ClearFeature {
+                    recipient: Recipient::Device,
+                    feature: 1,
+                    target: 0,
+                },
+            ),
+            (
+                parse(Request::STANDARD_ENDPOINT_CLEAR_FEATURE, 0, 0x02),
+                ClearFeature {
+                    recipient: Recipient::Endpoint,
+                    feature: 0,
+                    target: 0x02,
+                },
+            ),
+            (
+                parse(Request::STANDARD_INTERFACE_SET_FEATURE, 0, 1),
+                SetFeature {
+                    recipient: Recipient::Interface,
+                    feature: 0,
+                    target: 1,
+                },
+            ),
+            (
+                parse(Request::STANDARD_ENDPOINT_SET_FEATURE, 0, 0x83),
+                SetFeature {
+                    recipient: Recipient::Endpoint,
+                    feature: 0,
+                    target: 0x83,
+                },
+            ),
+            (
+                parse(Request::STANDARD_INTERFACE_SET_INTERFACE, 1, 3),
+                SetInterface {
+                    interface: 3,
+                    alt: 1,
+                },
+            ),
+            (
+                parse(Request::STANDARD_ENDPOINT_SYNCH_FRAME, 0, 0x84),
+                SynchFrame { endpoint: 0x84 },
+            ),
+        ];
+        for (parsed, expected) in table {
+            assert_eq!(parsed, expected);
         }
     }
+
+    #[test]
+    fn unparsed_standard_requests_are_other() {
+        let other = |bm_request_type, b_request, value| {
+            let pkt = IocSetupPacket {
+                w_value: value,
+                ..setup(bm_request_type, b_request)
+            };
+            assert_eq!(pkt.standard_request(), Some(StandardRequest::Other(pkt)));
+        };
+        // Not listed.
+        other(0x80, URB_RQ_GET_CONFIGURATION, 0);
+        other(0x00, URB_RQ_SET_DESCRIPTOR, 0x0100);
+        // Wrong recipient, e.g. SET_CONFIGURATION to an interface.
+        other(0x01, URB_RQ_SET_CONFIGURATION, 1);
+        other(0x81, URB_RQ_GET_DESCRIPTOR, 0x2200);
+        other(0x80, URB_RQ_SYNCH_FRAME, 0);
+        // Wrong direction.
+        other(0x00, URB_RQ_GET_STATUS, 0);
+        other(0x80, URB_RQ_SET_ADDRESS, 5);
+        // Out of range.
+        other(0x00, URB_RQ_SET_ADDRESS, 0x80);
+        other(0x00, URB_RQ_SET_CONFIGURATION, 0x0101);
+        other(0x80, URB_RQ_GET_DESCRIPTOR, 0x0600);
+        // Reserved recipient.
+        other(0x84, URB_RQ_GET_STATUS, 0);
+
+        // Class, vendor and reserved types are not standard requests.
+        assert_eq!(setup(0xA1, 0x01).standard_request(), None);
+        assert_eq!(setup(0xC0, URB_RQ_GET_DESCRIPTOR).standard_request(), None);
+        assert_eq!(setup(0x60, URB_RQ_SET_ADDRESS).standard_request(), None);
+        // Unless decoded as one regardless of the type.
+        assert_eq!(
+            StandardRequest::decode(&setup(0xC0, URB_RQ_GET_STATUS)),
+            StandardRequest::GetStatus {
+                recipient: Recipient::Device,
+                target: 0
+            }
+        );
+    }
+
+    #[test]
+    fn class_requests_depend_on_recipient() {
+        use Dir::{In, Out};
+        use Recipient::{Device, Endpoint, Interface};
+
+        let table = [
+            // SET_CUR and GET_REPORT share 0x01.
+            (Out, Interface, 0x01, Req::UacSetCur),
+            (Out, Endpoint, 0x01, Req::UacSetCur),
+            (In, Interface, 0x01, Req::GetReport),
+            (In, Endpoint, 0x01, Req::Other(0x01)),
+            // SET_MIN and GET_IDLE share 0x02, SET_MAX and
+            // GET_PROTOCOL share 0x03.
+            (Out, Endpoint, 0x02, Req::UacSetMin),
+            (In, Interface, 0x02, Req::GetIdle),
+            (Out, Interface, 0x03, Req::UacSetMax),
+            (In, Interface, 0x03, Req::GetProtocol),
+            (In, Endpoint, 0x81, Req::UacGetCur),
+            (In, Interface, 0x84, Req::UacGetRes),
+            (Out, Interface, 0x09, Req::SetReport),
+            (Out, Endpoint, 0x09, Req::Other(0x09)),
+            (Out, Interface, 0x0B, Req::SetProtocol),
+            (In, Interface, 0xFE, Req::GetMaxLun),
+            (Out, Interface, 0xFE, Req::Other(0xFE)),
+            (Out, Interface, 0xFF, Req::BulkOnlyMassStorageReset),
+            (Out, Device, 0x01, Req::Other(0x01)),
+            (In, Interface, 0x00, Req::Other(0x00)),
+        ];
+        for (dir, recipient, b_request, req) in table {
+            let bm_request_type = (dir as u8) << 7 | 0x20 | recipient as u8;
+            let request = Request {
+                bm_request_type,
+                b_request,
+            };
+            assert_eq!(
+                request.req(),
+                req,
+                "{bm_request_type:#04x} {b_request:#04x}"
+            );
+        }
+    }
+
+    #[test]
+    fn classifies_requests() {
+        let pkt = IocSetupPacket {
+            w_value: 0x0100,
+            w_index: 0x0409,
+            w_length: 18,
+            ..setup(0x80, URB_RQ_GET_DESCRIPTOR)
+        };
+        assert!(matches!(
+            pkt.classify(),
+            Ok(RequestClass::Standard(StandardRequest::GetDescriptor {
+                desc_type: DescriptorType::Device,
+                ..
+            }))
+        ));
+
+        let pkt = IocSetupPacket {
+            w_value: 0x0100,
+            w_index: 1,
+            w_length: 8,
+            ..setup(0xA1, 0x01)
+        };
+        let class = ClassRequest {
+            dir: Dir::In,
+            recipient: Recipient::Interface,
+            b_request: 0x01,
+            w_value: 0x0100,
+            w_index: 1,
+            w_length: 8,
+        };
+        assert_eq!(pkt.classify(), Ok(RequestClass::Class(class)));
+        assert_eq!(class.req(), Req::GetReport);
+
+        let pkt = IocSetupPacket {
+            w_length: 4,
+            ..setup(0xC2, 0x51)
+        };
+        assert_eq!(
+            pkt.classify(),
+            Ok(RequestClass::Vendor(VendorRequest {
+                dir: Dir::In,
+                recipient: Recipient::Endpoint,
+                b_request: 0x51,
+                w_value: 0,
+                w_index: 0,
+                w_length: 4,
+            }))
+        );
+
+        assert_eq!(
+            setup(0xE0, 0x01).classify(),
+            Err(DecodeError::RequestType(0xE0))
+        );
+        assert_eq!(
+            setup(0x84, URB_RQ_GET_STATUS).classify(),
+            Err(DecodeError::Recipient(0x84))
+        );
+    }
 }
@@ -1,15 +1,40 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 #[cfg(feature = "zerocopy")]
 use zerocopy_derive::*;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TimeoutMillis {
-    // Unlimited, TODO: Find out why this times out immediately?
+    /// Waits until work arrives. The device is opened in
+    /// non-blocking mode, where the kernel fails an infinite wait
+    /// right away, so this waits in steps of the longest
+    /// [`TimeoutMillis::Time`] instead.
+    Unlimited,
     Time(BoundedI16<0, 1000>),
 }
 
 impl TimeoutMillis {
+    /// A single attempt that does not wait. Fetching work with it
+    /// fails with [`std::io::ErrorKind::WouldBlock`] if none is
+    /// queued, whether or not the fd is in non-blocking mode.
+    pub const IMMEDIATE: TimeoutMillis = TimeoutMillis::Time(BoundedI16::new(0).unwrap());
+
+    /// The longest single wait the kernel is asked for.
+    pub const MAX: TimeoutMillis = TimeoutMillis::Time(BoundedI16::new(999).unwrap());
+
+    pub const fn is_immediate(&self) -> bool {
+        match self {
+            TimeoutMillis::Unlimited => false,
+            TimeoutMillis::Time(time) => time.get() == 0,
+        }
+    }
+
     pub const fn from_duration(dur: Duration) -> Option<TimeoutMillis> {
         let millis = dur.as_millis();
         if 1000 <= millis {
@@ -48,6 +73,7 @@ impl<const LOWER_INC: u8, const UPPER_EX: u8> Default for BoundedU8<LOWER_INC, U
     }
 }
 
+#[cfg_attr(feature = "zerocopy", derive(IntoBytes, Immutable, KnownLayout))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct BoundedU16<const LOWER_INC: u16, const UPPER_EX: u16>(u16);
@@ -66,13 +92,13 @@ impl<const LOWER_INC: u16, const UPPER_EX: u16> BoundedU16<LOWER_INC, UPPER_EX>
     }
 }
 
-
 impl<const LOWER_INC: u16, const UPPER_EX: u16> Default for BoundedU16<LOWER_INC, UPPER_EX> {
     fn default() -> Self {
         BoundedU16(LOWER_INC)
     }
 }
 
+#[cfg_attr(feature = "zerocopy", derive(IntoBytes, Immutable, KnownLayout))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct BoundedI16<const LOWER_INC: i16, const UPPER_EX: i16>(i16);
@@ -96,3 +122,76 @@ impl<const LOWER_INC: i16, const UPPER_EX: i16> Default for BoundedI16<LOWER_INC
         BoundedI16(LOWER_INC)
     }
 }
+
+/// Source of the current time for components with deadlines, so
+/// tests can step time with [`ManualClock`] instead of sleeping.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// [`Instant::now`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when [`ManualClock::advance`] is called.
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed_nanos: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed_nanos: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let nanos = u64::try_from(by.as_nanos()).unwrap_or(u64::MAX);
+        self.elapsed_nanos.fetch_add(nanos, Ordering::AcqRel);
+    }
+
+    /// Time passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_nanos.load(Ordering::Acquire))
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_only_moves_when_advanced() {
+        let clock = ManualClock::new();
+        let shared = clock.clone();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        shared.advance(Duration::from_millis(250));
+        assert_eq!(clock.now() - start, Duration::from_millis(250));
+        clock.advance(Duration::from_micros(1));
+        assert_eq!(shared.elapsed(), Duration::from_micros(250_001));
+    }
+}
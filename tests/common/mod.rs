@@ -0,0 +1,44 @@
+use std::io;
+
+/// Device node of `usb-vhci-iocifc`.
+pub const DEVICE_FILE: &str = "/dev/usb-vhci";
+
+/// Set to `1` to fail instead of skip when the kernel module is
+/// not usable, e.g. on CI machines that are supposed to have it.
+pub const REQUIRE_VAR: &str = "VHCI_TEST_REQUIRE";
+
+/// Whether the kernel module can be used by this process.
+///
+/// A missing device node or missing permissions are reported and
+/// make the test skip, unless [`REQUIRE_VAR`] is set. Any other
+/// failure to open the device is a real error and panics.
+pub fn probe() -> bool {
+    let err = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(DEVICE_FILE)
+    {
+        Ok(_) => return true,
+        Err(err) => err,
+    };
+
+    let skippable = matches!(
+        err.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+    );
+    if !skippable || std::env::var_os(REQUIRE_VAR).is_some_and(|var| var == "1") {
+        panic!("cannot open {DEVICE_FILE}: {err}");
+    }
+    eprintln!("skipped: cannot open {DEVICE_FILE}: {err}");
+    false
+}
+
+/// Returns from the calling test if [`probe`] fails.
+#[macro_export]
+macro_rules! require_vhci {
+    () => {
+        if !$crate::common::probe() {
+            return;
+        }
+    };
+}
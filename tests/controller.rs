@@ -0,0 +1,624 @@
+#![cfg(feature = "controller")]
+
+//! Tests against the real `usb-vhci-hcd` kernel module. They are
+//! skipped when the module is not available, see [`common::probe`].
+
+mod common;
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    task::Context,
+    time::{Duration, Instant},
+};
+
+use usb_vhci::{
+    libc,
+    prelude::*,
+    utils::{BoundedI16, BoundedU8, Clock, ManualClock},
+    ControllerSet, TaggedWork,
+};
+
+const NUM_PORTS: BoundedU8<1, 32> = BoundedU8::new(1).unwrap();
+
+#[test]
+fn remote_outlives_controller() {
+    require_vhci!();
+    let mut vhci = Controller::open(NUM_PORTS).unwrap();
+    let remote = vhci.remote();
+    let port = vhci.port_connect_any(DataRate::Full).unwrap();
+    drop(vhci);
+    // Would take over the fd number if dropping the controller had
+    // closed it.
+    let _null = std::fs::File::open("/dev/null").unwrap();
+    // The port is not being reset, but the ioctl still reaches the
+    // controller instead of failing with EBADF or ENOTTY.
+    if let Err(err) = remote.port_reset_done(port, true) {
+        let errno = err.raw_os_error();
+        assert_ne!(errno, Some(nix::libc::EBADF));
+        assert_ne!(errno, Some(nix::libc::ENOTTY));
+    }
+}
+
+#[test]
+fn can_create_vhci() {
+    require_vhci!();
+    let _vhci = Controller::open(NUM_PORTS).unwrap();
+}
+
+#[test]
+fn fd_is_not_inherited() {
+    require_vhci!();
+    let vhci = Controller::open(NUM_PORTS).unwrap();
+    assert!(!vhci.is_inheritable().unwrap());
+    vhci.set_inheritable(true).unwrap();
+    assert!(vhci.is_inheritable().unwrap());
+    vhci.set_inheritable(false).unwrap();
+    assert!(!vhci.is_inheritable().unwrap());
+}
+
+#[test]
+fn can_connect_disconnect_port() {
+    require_vhci!();
+    let mut vhci = Controller::open(NUM_PORTS).unwrap();
+    let port = vhci.port_connect_any(DataRate::High).unwrap();
+    vhci.port_disconnect(port).unwrap();
+}
+
+#[test]
+fn can_wait_for_enabled() {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    require_vhci!();
+    let mut vhci = Controller::open(NUM_PORTS).unwrap();
+    let port = Port::new(1).unwrap();
+
+    vhci.wait_for(port, PortMilestone::Powered, TIMEOUT)
+        .unwrap();
+    vhci.port_connect(port, DataRate::High).unwrap();
+    vhci.wait_for(port, PortMilestone::Enabled, TIMEOUT)
+        .unwrap();
+    vhci.drain_buffered_work().for_each(drop);
+}
+
+#[test]
+fn can_wait_for_two_ports() {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    require_vhci!();
+    let mut vhci = Controller::open(BoundedU8::new(2).unwrap()).unwrap();
+    let (one, two) = (Port::new(1).unwrap(), Port::new(2).unwrap());
+
+    // The host powers both ports at once, and the stat of port 2 is
+    // recorded while waiting for port 1.
+    vhci.wait_for(one, PortMilestone::Powered, TIMEOUT).unwrap();
+    vhci.wait_for(two, PortMilestone::Powered, TIMEOUT).unwrap();
+    while let Some(work) = vhci.pop_buffered_work() {
+        if let Ok(Work::PortStat(stat)) = Work::try_from(work) {
+            assert!(vhci.port_stat_events(stat).unwrap().is_empty());
+        }
+    }
+}
+
+#[test]
+fn reservation_released_on_drop() {
+    require_vhci!();
+    let mut vhci = Controller::open(BoundedU8::new(2).unwrap()).unwrap();
+    let reservation = vhci.reserve_port().unwrap();
+    assert_eq!(vhci.free_ports(), 1);
+    let err = vhci
+        .port_connect(reservation.port(), DataRate::Full)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+
+    drop(reservation);
+    assert_eq!(vhci.free_ports(), 2);
+}
+
+#[test]
+fn reservation_can_connect() {
+    require_vhci!();
+    let mut vhci = Controller::open(BoundedU8::new(2).unwrap()).unwrap();
+    let first = vhci.reserve_port().unwrap();
+    let second = vhci.reserve_port().unwrap();
+    assert_ne!(first.port(), second.port());
+    assert!(vhci.reserve_port().is_none());
+
+    let port = first.connect(&mut vhci, DataRate::Full).unwrap();
+    assert_eq!(vhci.free_ports(), 0);
+    drop(second);
+    assert_eq!(vhci.free_ports(), 1);
+    vhci.port_disconnect(port).unwrap();
+}
+
+#[test]
+fn can_poll_for_work() {
+    struct CountingWaker(AtomicU32);
+
+    impl std::task::Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    require_vhci!();
+    let vhci = Controller::open(NUM_PORTS).unwrap();
+    let recv = vhci.work_receiver().unwrap();
+    let counter = Arc::new(CountingWaker(AtomicU32::new(0)));
+    let waker = std::task::Waker::from(Arc::clone(&counter));
+    let mut cx = Context::from_waker(&waker);
+    let mut work = IocWork::default();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut pending = 0;
+    while recv.poll_fetch_work(&mut cx, &mut work).is_pending() {
+        pending += 1;
+        // Polled again only once woken, like an executor would.
+        while counter.0.load(Ordering::Relaxed) < pending {
+            assert!(Instant::now() < deadline, "no work arrived");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    // The wake comes with the work, so there is no second pending.
+    assert!(pending <= 1);
+    assert!(matches!(work.get(), WorkRef::PortStat(_)));
+    vhci.return_work_receiver(recv).unwrap();
+}
+
+#[test]
+fn concurrent_splits_hand_out_one_receiver() {
+    require_vhci!();
+    let vhci = Controller::open(NUM_PORTS).unwrap();
+    for _ in 0..100 {
+        let receivers: Vec<_> = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8).map(|_| s.spawn(|| vhci.work_receiver())).collect();
+            threads
+                .into_iter()
+                .filter_map(|thread| thread.join().unwrap())
+                .collect()
+        });
+        assert_eq!(receivers.len(), 1);
+        let err = vhci.fetch_work().unwrap_err();
+        assert!(matches!(err, Error::WorkReceiverOut));
+        for recv in receivers {
+            vhci.return_work_receiver(recv).unwrap();
+        }
+    }
+}
+
+#[test]
+fn unlimited_fetch_waits_for_connect() {
+    require_vhci!();
+    let mut vhci = Controller::open(NUM_PORTS).unwrap();
+    // Drop the work of powering on the root hub.
+    let timeout = TimeoutMillis::Time(BoundedI16::new(300).unwrap());
+    while vhci.fetch_work_timeout(timeout).is_ok() {}
+
+    let recv = vhci.work_receiver().unwrap();
+    let fetcher = std::thread::spawn(move || {
+        let work = recv.fetch_work_timeout(TimeoutMillis::Unlimited);
+        (recv, work)
+    });
+    // Longer than a single step of the wait.
+    std::thread::sleep(Duration::from_millis(1500));
+    assert!(!fetcher.is_finished());
+
+    let port = vhci.port_connect_any(DataRate::Full).unwrap();
+    let (recv, work) = fetcher.join().unwrap();
+    match work.unwrap().get() {
+        WorkRef::PortStat(stat) => assert_eq!(stat.index(), port),
+        _ => panic!("expected port-stat work"),
+    }
+    vhci.return_work_receiver(recv).unwrap();
+}
+
+#[test]
+fn long_fetch_times_out() {
+    require_vhci!();
+    let vhci = Controller::open(NUM_PORTS).unwrap();
+    while vhci.fetch_work_for(Duration::from_millis(300)).is_ok() {}
+
+    let start = Instant::now();
+    let err = vhci
+        .fetch_work_for(Duration::from_millis(2500))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(2500));
+}
+
+extern "C" fn ignore_signal(_: libc::c_int) {}
+
+#[test]
+fn signals_do_not_cut_fetches_short() {
+    require_vhci!();
+    let vhci = Controller::open(NUM_PORTS).unwrap();
+    while vhci.fetch_work_for(Duration::from_millis(300)).is_ok() {}
+
+    // Without SA_RESTART the kernel fails a wait that is interrupted
+    // by the signal with EINTR.
+    // SAFETY: The handler does nothing and the action is zeroed
+    //         apart from it.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = ignore_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        assert_eq!(
+            libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+            0
+        );
+    }
+
+    let recv = vhci.work_receiver().unwrap();
+    let fetch_interrupted = |recv: WorkReceiver| {
+        let (send, thread) = std::sync::mpsc::channel();
+        let fetcher = std::thread::spawn(move || {
+            // SAFETY: Always safe to call.
+            send.send(unsafe { libc::pthread_self() }).unwrap();
+            let start = Instant::now();
+            let timeout = TimeoutMillis::Time(BoundedI16::new(800).unwrap());
+            let result = recv.fetch_work_timeout(timeout);
+            (recv, result, start.elapsed())
+        });
+        let thread = thread.recv().unwrap();
+        while !fetcher.is_finished() {
+            // SAFETY: The thread is not joined yet, so its id is valid.
+            unsafe { libc::pthread_kill(thread, libc::SIGUSR1) };
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        fetcher.join().unwrap()
+    };
+
+    let (recv, result, elapsed) = fetch_interrupted(recv);
+    assert!(matches!(result, Err(Error::TimedOut)), "{result:?}");
+    assert!(elapsed >= Duration::from_millis(750), "{elapsed:?}");
+
+    vhci.set_retry_on_eintr(false);
+    let (recv, result, elapsed) = fetch_interrupted(recv);
+    let err = result.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    assert!(elapsed < Duration::from_millis(750), "{elapsed:?}");
+    vhci.return_work_receiver(recv).unwrap();
+}
+
+#[test]
+fn immediate_fetch_does_not_wait() {
+    require_vhci!();
+    let vhci = Controller::open(NUM_PORTS).unwrap();
+
+    // Powering on the root hub queues port-stat work.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let work = loop {
+        match vhci.fetch_work_timeout(TimeoutMillis::IMMEDIATE) {
+            Ok(work) => break work,
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::WouldBlock),
+        }
+        assert!(Instant::now() < deadline, "no work arrived");
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert!(matches!(work.get(), WorkRef::PortStat(_)));
+
+    let err = loop {
+        match vhci.fetch_work_timeout(TimeoutMillis::IMMEDIATE) {
+            Ok(_) => continue,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    let start = Instant::now();
+    assert!(vhci.fetch_work_timeout(TimeoutMillis::IMMEDIATE).is_err());
+    assert!(start.elapsed() < Duration::from_millis(50));
+}
+
+#[test]
+fn try_fetch_drains_queued_work() {
+    require_vhci!();
+    let vhci = Controller::open(NUM_PORTS).unwrap();
+    // Wait for the work of powering on the root hub.
+    let first = vhci.fetch_work_for(Duration::from_secs(5)).unwrap();
+    assert!(matches!(first.get(), WorkRef::PortStat(_)));
+
+    std::thread::sleep(Duration::from_millis(100));
+    while let Some(work) = vhci.try_fetch_work().unwrap() {
+        assert!(matches!(work.get(), WorkRef::PortStat(_)));
+    }
+    assert!(vhci.try_fetch_work().unwrap().is_none());
+}
+
+#[test]
+fn scoped_receiver_and_ports() {
+    require_vhci!();
+    let mut vhci = Controller::open(NUM_PORTS).unwrap();
+    let owned = vhci.work_receiver().unwrap();
+    assert!(vhci.work_receiver_scoped().is_none());
+    vhci.return_work_receiver(owned).unwrap();
+
+    let (recv, mut ports) = vhci.work_receiver_scoped().unwrap();
+    std::thread::scope(|s| {
+        let fetcher = s.spawn(|| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                if let Ok(work) = recv.fetch_work() {
+                    return Some(work);
+                }
+            }
+            None
+        });
+        let port = ports.port_connect_any(DataRate::Full).unwrap();
+        assert_eq!(ports.free_ports(), 0);
+        assert!(fetcher.join().unwrap().is_some());
+        ports.port_disconnect(port).unwrap();
+    });
+    assert_eq!(vhci.free_ports(), 1);
+}
+
+#[test]
+fn can_fetch_work() {
+    require_vhci!();
+    let num_ports = BoundedU8::new(2).unwrap();
+    let mut vhci = Controller::open(num_ports).unwrap();
+    let mut tracker = PortStateTracker::new();
+
+    let (urb, handle) = loop {
+        let work = vhci.fetch_work_for(Duration::from_secs(5)).unwrap();
+        match Work::try_from(work).unwrap() {
+            Work::ProcessUrb(urb) => break urb,
+            Work::CancelUrb(_handle) => unreachable!(),
+            Work::PortStat(stat) => {
+                for event in tracker.observe(stat) {
+                    match event {
+                        PortEvent::PoweredOn(port) => {
+                            vhci.port_connect(port, DataRate::Full).unwrap()
+                        }
+                        PortEvent::ResetRequested(port) => {
+                            vhci.port_reset_done(port, true).unwrap()
+                        }
+                        PortEvent::ResumeRequested(port) => vhci.port_resumed(port).unwrap(),
+                        PortEvent::SuspendRequested(port) => vhci.port_suspended(port).unwrap(),
+                        PortEvent::PoweredOff(_) | PortEvent::ConnectionChanged { .. } => (),
+                    }
+                }
+            }
+        }
+    };
+
+    // The first URB is the hub asking for the device descriptor.
+    let mut urb = UrbWithData::from_ioctl(urb, handle);
+    assert!(ControlTransaction::new(&mut urb).is_some());
+    urb.set_status(Status::Stall);
+    let outcome = vhci.remote().giveback(&mut urb).unwrap();
+    assert_eq!(outcome, GivebackOutcome::Completed);
+}
+
+#[test]
+fn runner_stops_at_deadline() {
+    require_vhci!();
+    let mut vhci = Controller::open(NUM_PORTS).unwrap();
+    let summary = Runner::new(&mut vhci)
+        .until(Instant::now() + Duration::from_millis(300))
+        .run(|_, _| Ok(()))
+        .unwrap();
+    assert!(summary.duration >= Duration::from_millis(300));
+    assert!(summary.port_stats > 0);
+
+    // The controller is still usable afterwards.
+    let stop = Arc::new(AtomicBool::new(true));
+    let summary = Runner::new(&mut vhci)
+        .until_flag(stop)
+        .run(|_, _| Ok(()))
+        .unwrap();
+    assert_eq!(summary.work(), 0);
+}
+
+#[test]
+fn runner_follows_its_clock() {
+    require_vhci!();
+    let mut vhci = Controller::open(NUM_PORTS).unwrap();
+    let clock = ManualClock::new();
+    let deadline = clock.now() + Duration::from_secs(60);
+    let ticks = clock.clone();
+    let summary = Runner::new(&mut vhci)
+        .clock(clock)
+        .until(deadline)
+        .until_stats(|summary| {
+            if summary.work() > 0 {
+                ticks.advance(Duration::from_secs(60));
+            }
+            false
+        })
+        .run(|_, _| Ok(()))
+        .unwrap();
+    assert_eq!(summary.work(), 1);
+    assert_eq!(summary.duration, Duration::from_secs(60));
+}
+
+#[test]
+fn connect_respects_quota() {
+    require_vhci!();
+    let mut vhci = Controller::open(BoundedU8::new(3).unwrap()).unwrap();
+    let reservation = vhci.reserve_port().unwrap();
+
+    // A tenant may only connect while another port stays free.
+    let quota = |free| free > 1;
+    let port = vhci.try_connect_if(DataRate::Full, quota).unwrap();
+    assert!(port.is_some());
+    assert_eq!(vhci.try_connect_if(DataRate::Full, quota).unwrap(), None);
+
+    let usage = vhci.port_usage();
+    assert_eq!((usage.connected, usage.reserved, usage.free()), (1, 1, 1));
+    drop(reservation);
+    assert!(vhci
+        .try_connect_if(DataRate::Full, quota)
+        .unwrap()
+        .is_some());
+    assert_eq!(vhci.port_usage().free(), 1);
+}
+
+#[test]
+fn work_is_tagged_per_controller() {
+    require_vhci!();
+    let mut first = Controller::open(NUM_PORTS).unwrap();
+    let second = Controller::open(NUM_PORTS).unwrap();
+    let recv = second.work_receiver().unwrap();
+
+    let fetch = |fetch: &dyn Fn() -> usb_vhci::Result<TaggedWork>| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match fetch() {
+                Ok(work) => return work,
+                Err(_) if Instant::now() < deadline => continue,
+                Err(err) => panic!("{err}"),
+            }
+        }
+    };
+    let timeout = TimeoutMillis::Time(BoundedI16::new(100).unwrap());
+    let a = fetch(&|| first.fetch_tagged_work_timeout(timeout)).tag;
+    let b = fetch(&|| recv.fetch_tagged_work_timeout(timeout)).tag;
+    // Connecting makes the host send more work.
+    first
+        .port_connect(Port::new(1).unwrap(), DataRate::Full)
+        .unwrap();
+    let c = fetch(&|| first.fetch_tagged_work_timeout(timeout)).tag;
+
+    assert_ne!(a.controller_id, b.controller_id);
+    assert_ne!(a.usb_busnum, b.usb_busnum);
+    assert_eq!((a.seq, b.seq, c.seq), (0, 0, 1));
+    // Only the controller it was split off takes it back.
+    let recv = first.return_work_receiver(recv).unwrap_err();
+    second.return_work_receiver(recv).unwrap();
+}
+
+#[test]
+fn set_selects_across_controllers() {
+    require_vhci!();
+    let controllers = [
+        Controller::open(NUM_PORTS).unwrap(),
+        Controller::open(NUM_PORTS).unwrap(),
+    ];
+    let mut set = ControllerSet::new(
+        controllers
+            .iter()
+            .map(|vhci| vhci.work_receiver().unwrap())
+            .collect(),
+    );
+
+    // Powering on the root hubs queues work on both.
+    let mut seen = [false; 2];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while seen != [true, true] && Instant::now() < deadline {
+        if let Some((index, work)) = set.select(Duration::from_millis(500)) {
+            work.unwrap();
+            seen[index] = true;
+        }
+    }
+    assert_eq!(seen, [true, true]);
+
+    for (vhci, recv) in controllers.iter().zip(set.into_inner()) {
+        vhci.return_work_receiver(recv).unwrap();
+    }
+}
+
+#[test]
+fn open_through_symlink() {
+    require_vhci!();
+    let dir = std::env::temp_dir().join(format!("usb-vhci-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let link = dir.join("usb-vhci0");
+    let _ = std::fs::remove_file(&link);
+    std::os::unix::fs::symlink("/dev/usb-vhci", &link).unwrap();
+
+    let opened = Controller::open_path(&link, NUM_PORTS);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let mut vhci = opened.unwrap();
+    let port = vhci.port_connect_any(DataRate::Full).unwrap();
+    vhci.port_disconnect(port).unwrap();
+}
+
+#[test]
+fn adopt_open_fd() {
+    require_vhci!();
+    // Blocking, like an fd opened by a supervisor that didn't care.
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/usb-vhci")
+        .unwrap();
+    let fd = std::os::fd::OwnedFd::from(file);
+    let mut vhci = Controller::from_fd(fd, NUM_PORTS).unwrap();
+
+    // Nonblocking now: no work times out instead of hanging.
+    let start = Instant::now();
+    let err = vhci
+        .fetch_work_timeout(TimeoutMillis::Time(BoundedI16::new(10).unwrap()))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let port = vhci.port_connect_any(DataRate::Full).unwrap();
+    vhci.port_disconnect(port).unwrap();
+}
+
+#[test]
+fn adopting_registered_fd_fails() {
+    require_vhci!();
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/usb-vhci")
+        .unwrap();
+    // Shares the open file description, and with it the registration.
+    let dup = file.try_clone().unwrap();
+    let _vhci = Controller::from_fd(file.into(), NUM_PORTS).unwrap();
+    let err = Controller::from_fd(dup.into(), NUM_PORTS).unwrap_err();
+    assert!(err.raw_os_error().is_some(), "{err}");
+}
+
+#[test]
+fn metadata_after_open() {
+    require_vhci!();
+    let num_ports = BoundedU8::new(4).unwrap();
+    let vhci = Controller::open(num_ports).unwrap();
+    assert!(vhci.usb_busnum() > 0);
+    assert!(!vhci.bus_id().is_empty());
+    assert_eq!(vhci.num_ports(), num_ports);
+    assert_eq!(
+        vhci.to_string(),
+        format!("{} (bus {}, 4 ports)", vhci.bus_id(), vhci.usb_busnum())
+    );
+}
+
+#[test]
+fn connect_any_runs_out_of_ports() {
+    require_vhci!();
+    let mut vhci = Controller::open(BoundedU8::new(2).unwrap()).unwrap();
+    for _ in 0..2 {
+        assert!(vhci.has_free_port());
+        vhci.port_connect_any(DataRate::Full).unwrap();
+    }
+    assert!(!vhci.has_free_port());
+    assert_eq!(vhci.free_ports(), 0);
+    let err = vhci.port_connect_any(DataRate::Full).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+}
+
+#[test]
+fn connect_and_disconnect_twice() {
+    require_vhci!();
+    let mut vhci = Controller::open(NUM_PORTS).unwrap();
+    let port = Port::new(1).unwrap();
+    vhci.port_connect(port, DataRate::Full).unwrap();
+    let err = vhci.port_connect(port, DataRate::Full).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+    vhci.port_disconnect(port).unwrap();
+    let err = vhci.port_disconnect(port).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+    let err = vhci
+        .port_connect(Port::new(2).unwrap(), DataRate::Full)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}